@@ -1,5 +1,5 @@
 // see in tsconfig.json what path is wasm
-import init,  {Universe} from '@wasm';
+import init,  {Universe, PickedObject, world_parts} from '@wasm';
 
 
 let canvas = <HTMLCanvasElement>document.getElementById("canvas");
@@ -32,7 +32,8 @@ attribute float phase;
 varying vec4 v_color;
 
 void main() {
-    vec4 shift = vec4(cos(time*frequency+phase) * ondulation_vec, 1.0);
+    // w = 0: coordinates already has w = 1, the shift is only a displacement
+    vec4 shift = vec4(cos(time*frequency+phase) * ondulation_vec, 0.0);
     gl_Position = projection * (coordinates + shift);
     v_color = vec4(color, 0.5); 
 }
@@ -62,60 +63,6 @@ gl.useProgram(shaderProgram);
 
 
 
-// _            __  __               
-//| |__  _   _ / _|/ _| ___ _ __ ___ 
-//| '_ \| | | | |_| |_ / _ \ '__/ __|
-//| |_) | |_| |  _|  _|  __/ |  \__ \
-//|_.__/ \__,_|_| |_|  \___|_|  |___/
-
-// index buffer
-var index_buffer = gl.createBuffer();
-gl.bindBuffer(gl.ELEMENT_ARRAY_BUFFER, index_buffer);
-
-
-
-// point buffer
-let point_buffer = gl.createBuffer();
-gl.bindBuffer(gl.ARRAY_BUFFER, point_buffer);
-
-
-const SIZE_VERTEX = 12;
-/* The POINT buffer has 12 elements:
-    * x y z      |      r g b      |      dx dy dz     | frequency  | phase | other
-* position          color       ondulation vector    other params of vertex
-*/
-let offset = 0;
-function define_float_buffer(name: string, size:number) {
-    let loc = gl.getAttribLocation(shaderProgram, name);
-    gl.vertexAttribPointer(
-        loc, size, gl.FLOAT, false,
-        SIZE_VERTEX * Float32Array.BYTES_PER_ELEMENT, 
-        offset      * Float32Array.BYTES_PER_ELEMENT
-    );
-
-    gl.enableVertexAttribArray(loc);
-    offset += size;
-}
-
-// 3 coordinates by vertex
-define_float_buffer("coordinates", 3); 
-// r,g,b for each vertex
-define_float_buffer("color", 3);
-// 3 coordinates for ondulation vector
-define_float_buffer("ondulation_vec", 3); 
-// 1 number for frequency
-define_float_buffer("phase", 1);
-// 1 number for phase
-define_float_buffer("frequency", 1);
-
-
-
-// uniforms (values passed to vertex shader)
-let trans_loc = gl.getUniformLocation(shaderProgram, "projection");
-let time_loc = gl.getUniformLocation(shaderProgram, "time");
-
-
-
 // key manager
 enum Keys {
     Left = 37,
@@ -124,6 +71,7 @@ enum Keys {
     Up = 38,
     Space = 32,
     Shift = 9,
+    Enter = 13,
 };
 
 var pressedKeys: Record<number, boolean> = {};
@@ -132,6 +80,9 @@ var pressedKeys: Record<number, boolean> = {};
 window.onkeyup = (e: KeyboardEvent) => { console.log(e.key); pressedKeys[e.keyCode] = false; }
 window.onkeydown = (e: KeyboardEvent) => { pressedKeys[e.keyCode] = true; }
 
+// enter switches between triangles and ray marching
+let sdfRendering = false;
+
 // time manager
 const initialTime = Date.now();
 const FPS_THROTTLE = 1000.0 / 20.0;
@@ -151,8 +102,119 @@ function get_controlls() : [boolean, boolean, boolean, boolean, boolean, boolean
     ];
 };
 
+// save bytes as a file
+function download(bytes: Uint8Array, name: string) {
+    let link = document.createElement("a");
+    link.href = URL.createObjectURL(new Blob([bytes]));
+    link.download = name;
+    link.click();
+    URL.revokeObjectURL(link.href);
+}
+
+// o, p, s and g export the triangles in these formats
+const exportFormats: Record<string, [string, string]> = {
+    "o": ["obj", "world.obj"],
+    "p": ["ply", "world.ply"],
+    "s": ["stl", "world.stl"],
+    "g": ["glb", "world.glb"],
+};
+
+// n generates the next world, only the octree can be sculpted
+const scenes = ["octree", "forest", "trees", "spheres", "terrain"];
+let scene = 0;
+
+// the octree of the world is generated by a pool of web workers.
+// Without them, it is generated a little at each update
+function create_workers(universe: Universe): Worker[] {
+    let workers: Worker[] = [];
+    let n = Math.min(navigator.hardwareConcurrency || 4, world_parts());
+    try {
+        for (let i = 0; i < n; i++) {
+            let worker = new Worker(new URL("./worker.js", import.meta.url), {type: "module"});
+            worker.onmessage = (e: MessageEvent) => {
+                let {request, part, points, indices, grid_points, error} = e.data;
+                try {
+                    if (error) {
+                        // the failure of an older world does not matter anymore
+                        if (!universe.is_world_request_pending(request)) {
+                            return;
+                        }
+                        throw error;
+                    }
+                    universe.receive_world_part(request, part, points, indices, grid_points);
+                } catch (error) {
+                    console.error(`cannot generate the part ${part} of the world: ${error}`);
+                    universe.set_workers(false);
+                }
+            };
+            worker.onerror = (e: ErrorEvent) => {
+                console.error(`the workers cannot generate the world: ${e.message}`);
+                universe.set_workers(false);
+            };
+            workers.push(worker);
+        }
+    } catch (error) {
+        console.error(`no web workers: ${error}`);
+        return [];
+    }
+    universe.set_workers(true);
+    return workers;
+}
+
 function create_universe_loop(universe: Universe) {
-    setInterval(() => universe.update(lastDrawTime, ...get_controlls()), FPS_THROTTLE/3);
+    let workers = create_workers(universe);
+    window.addEventListener("keydown", (e: KeyboardEvent) => {
+        if (e.keyCode == Keys.Enter) {
+            sdfRendering = !sdfRendering;
+            universe.set_sdf_rendering(sdfRendering);
+        }
+        if (e.ctrlKey && e.key == "z") {
+            universe.undo();
+        }
+        if (e.ctrlKey && e.key == "y") {
+            universe.redo();
+        }
+        if (!e.ctrlKey && e.key == "n") {
+            scene = (scene + 1) % scenes.length;
+            universe.set_scene(scenes[scene]);
+        }
+        if (!e.ctrlKey && e.key in exportFormats) {
+            let [format, name] = exportFormats[e.key];
+            download(universe.export(format), name);
+        }
+    });
+    // sculpt with alt or shift, otherwise log what is under the mouse
+    canvas.onclick = (e: MouseEvent) => {
+        if (e.altKey || e.shiftKey) {
+            universe.sculpt(e.offsetX, e.offsetY, e.shiftKey);
+            return;
+        }
+        let hit = universe.pick(e.offsetX, e.offsetY);
+        if (hit) {
+            console.log(`${PickedObject[hit.object]} ${hit.id} at (${hit.x}, ${hit.y}, ${hit.z})`);
+        }
+    };
+    // drop obj, gltf or glb files on the canvas to add them to the world
+    canvas.ondragover = (e: DragEvent) => e.preventDefault();
+    canvas.ondrop = async (e: DragEvent) => {
+        e.preventDefault();
+        for (let file of Array.from(e.dataTransfer.files)) {
+            try {
+                universe.import(file.name, new Uint8Array(await file.arrayBuffer()));
+            } catch (error) {
+                console.error(`cannot import ${file.name}: ${error}`);
+            }
+        }
+    };
+    setInterval(() => {
+        universe.update(lastDrawTime, ...get_controlls());
+        let request = universe.take_world_request();
+        if (request !== undefined) {
+            for (let part = 0; part < world_parts(); part++) {
+                workers[part % workers.length].postMessage({request, part});
+            }
+        }
+    }, FPS_THROTTLE/3);
     render(universe);
 }
 
@@ -180,4 +242,4 @@ function render(universe: Universe) {
     requestAnimationFrame(() => render(universe));
 }
 
-init().then(() => {create_universe_loop(new Universe(gl, trans_loc, time_loc, Date.now()))})
+init().then(() => {create_universe_loop(new Universe(gl, shaderProgram, Date.now()))})
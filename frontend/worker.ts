@@ -0,0 +1,18 @@
+// generate the parts of the world asked by the page, so that the page does not freeze.
+// The buffers are transferred, not copied
+import init, {generate_world_part} from '@wasm';
+
+const ready = init();
+
+onmessage = async (e: MessageEvent) => {
+    await ready;
+    let {request, part} = e.data;
+    try {
+        let world_part = generate_world_part(part);
+        let [points, indices, grid_points] = [world_part.points(), world_part.indices(), world_part.grid_points()];
+        world_part.free();
+        (self as unknown as Worker).postMessage({request, part, points, indices, grid_points}, [points.buffer, indices.buffer, grid_points.buffer]);
+    } catch (error) {
+        (self as unknown as Worker).postMessage({request, part, error: `${error}`});
+    }
+};
@@ -0,0 +1,110 @@
+use crate::geometry::{V3, Range};
+use crate::geometry::bounds::Sphere;
+
+/// a plane `a*x + b*y + c*z + d = 0`.
+/// The normal (a, b, c) points toward the visible side
+#[derive(Copy, Clone, Debug)]
+struct Plane {
+    normal: V3,
+    d: f32,
+}
+
+impl Plane {
+    fn new(a: f32, b: f32, c: f32, d: f32) -> Self {
+        // normalize, so that the equation give the real distance
+        let norm = V3::new(a, b, c).norm();
+        Plane {normal: V3::new(a, b, c).scale(1.0/norm), d: d/norm}
+    }
+
+    fn signed_dist(&self, p: V3) -> f32 {
+        V3::dot(self.normal, p) + self.d
+    }
+}
+
+/// The 6 planes (left, right, bottom, top, near, far)
+/// that delimit the region of space seen by the camera
+pub struct Frustum {
+    planes: [Plane; 6],
+}
+
+impl Frustum {
+    /// extract the planes from a projection matrix (column major, like webgl).
+    /// A point p is visible when -w < x, y, z < w with (x, y, z, w) = m*p,
+    /// so each plane is the sum or the difference of the last row and another row
+    pub fn from_matrix(m: [f32; 16]) -> Self {
+        let row = |i: usize| [m[i], m[4+i], m[8+i], m[12+i]];
+        let w = row(3);
+        let plane = |r: [f32; 4], sign: f32| Plane::new(
+            w[0] + sign*r[0],
+            w[1] + sign*r[1],
+            w[2] + sign*r[2],
+            w[3] + sign*r[3],
+        );
+
+        Frustum {
+            planes: [
+                plane(row(0),  1.0),
+                plane(row(0), -1.0),
+                plane(row(1),  1.0),
+                plane(row(1), -1.0),
+                plane(row(2),  1.0),
+                plane(row(2), -1.0),
+            ]
+        }
+    }
+
+    /// false if the sphere is completely outside
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes.iter()
+            .all(|p| p.signed_dist(sphere.center) >= -sphere.radius)
+    }
+
+    /// false if the box is completely outside.
+    /// For each plane, only the corner that is the most on the visible side is tested
+    pub fn intersects_aabb(&self, range: &Range) -> bool {
+        let s = range.smaller_corner;
+        let g = range.greater_corner;
+        self.planes.iter().all(|p| {
+            let corner = V3::new(
+                if p.normal.x > 0.0 {g.x} else {s.x},
+                if p.normal.y > 0.0 {g.y} else {s.y},
+                if p.normal.z > 0.0 {g.z} else {s.z},
+            );
+            p.signed_dist(corner) >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Frustum, Sphere, V3, Range};
+    use crate::camera::Camera;
+
+    fn cube_around(p: V3, half_size: f32) -> Range {
+        let h = V3::new(half_size, half_size, half_size);
+        Range::new(p - h, p + h)
+    }
+
+    #[test]
+    fn culling() {
+        let camera = Camera {x: 0.0, y: 0.0, z: 0.0, angle: 0.0};
+        let frustum = Frustum::from_matrix(camera.get_transform(800, 600));
+
+        // the camera looks in the direction of the x axis
+        let in_front = V3::new(10.0, 0.0, 0.0);
+        let behind = V3::new(-10.0, 0.0, 0.0);
+        let too_far = V3::new(200.0, 0.0, 0.0);
+        let on_the_side = V3::new(1.0, 50.0, 0.0);
+
+        assert!(frustum.intersects_sphere(&Sphere {center: in_front, radius: 1.0}));
+        assert!(frustum.intersects_aabb(&cube_around(in_front, 1.0)));
+
+        for &p in &[behind, too_far, on_the_side] {
+            assert!(!frustum.intersects_sphere(&Sphere {center: p, radius: 1.0}));
+            assert!(!frustum.intersects_aabb(&cube_around(p, 1.0)));
+        }
+
+        // a big object around the camera is visible
+        assert!(frustum.intersects_aabb(&cube_around(behind, 11.0)));
+    }
+}
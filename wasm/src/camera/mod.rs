@@ -1,4 +1,8 @@
 mod matrix;
+pub mod frustum;
+
+use crate::geometry::V3;
+use crate::geometry::ray::Ray;
 
 
 pub struct Camera {
@@ -66,4 +70,40 @@ impl Camera {
             matrix::projection(a, 1.5, 0.1, 100.0),
             inv_cam)
     }
+
+    /// matrix that transforms a point of the screen to a point of the world
+    pub fn get_inverse_transform(&self, width: u32, height: u32) -> [f32; 16] {
+        matrix::inverse(self.get_transform(width, height))
+            .expect("camera transform is not invertible")
+    }
+
+    /// ray that goes from the camera through a pixel of the screen.
+    /// (x, y) is the position of the pixel, from the top left of the canvas
+    pub fn get_ray(&self, x: f32, y: f32, width: u32, height: u32) -> Ray {
+        let inv = self.get_inverse_transform(width, height);
+
+        // position on the screen between -1 and 1
+        let screen_x = 2.0*x/(width as f32) - 1.0;
+        let screen_y = 1.0 - 2.0*y/(height as f32);
+
+        // unproject a point on the near plane and a point on the far plane
+        let near = V3::from(matrix::transform_point(inv, [screen_x, screen_y, -1.0]));
+        let far = V3::from(matrix::transform_point(inv, [screen_x, screen_y, 1.0]));
+        Ray::new(near, far - near)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Camera, V3};
+
+    #[test]
+    fn ray_through_center_of_screen() {
+        let camera = Camera {x: 1.0, y: 2.0, z: 0.5, angle: 0.3};
+        let ray = camera.get_ray(400.0, 300.0, 800, 600);
+
+        // the ray starts near the camera and looks forward
+        assert!((ray.origin - V3::new(1.0, 2.0, 0.5)).norm() < 0.2);
+        assert!((ray.dir - V3::new(0.3f32.cos(), 0.3f32.sin(), 0.0)).norm() < 1e-3);
+    }
 }
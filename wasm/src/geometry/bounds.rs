@@ -0,0 +1,130 @@
+use super::V3;
+use super::Range;
+use super::SIZE_VERTEX;
+use super::get_point;
+
+/// A sphere containing every point of a mesh
+#[derive(Copy, Clone, Debug)]
+pub struct Sphere {
+    pub center: V3,
+    pub radius: f32,
+}
+
+/// A part of the index buffer that is drawn with a single draw call.
+/// `first_index` and `n_indices` are counted in indices, not in bytes
+#[derive(Copy, Clone, Debug)]
+pub struct Chunk {
+    pub first_index: usize,
+    pub n_indices: usize,
+    pub aabb: Range,
+    pub sphere: Sphere,
+}
+
+/// length of the ondulation vector of a vertex.
+/// The vertex shader moves the point by at most this distance
+fn ondulation_amplitude(points: &[f32], i: u16) -> f32 {
+    let i = i as usize*SIZE_VERTEX;
+    V3::new(points[i+6], points[i+7], points[i+8]).norm()
+}
+
+/// the largest distance a vertex of the buffer is moved by the shader
+pub fn max_ondulation(points: &[f32]) -> f32 {
+    (0..points.len()/SIZE_VERTEX)
+        .map(|i| ondulation_amplitude(points, i as u16))
+        .fold(0.0, f32::max)
+}
+
+/// compute the bounding box and the bounding sphere of some triangles.
+/// Both are inflated by the ondulation, so that the animated mesh stays inside.
+/// None if there is no triangle
+pub fn bounding_volumes(points: &[f32], indices: &[u16]) -> Option<(Range, Sphere)> {
+    let aabb = Range::from_points(indices.iter().map(|&i| get_point(points, i)))?;
+    let max_shift = indices.iter()
+        .map(|&i| ondulation_amplitude(points, i))
+        .fold(0.0, f32::max);
+
+    let center = aabb.center();
+    let radius = indices.iter()
+        .map(|&i| (get_point(points, i) - center).norm())
+        .fold(0.0, f32::max);
+
+    Some((aabb.inflate(max_shift), Sphere {center, radius: radius + max_shift}))
+}
+
+/// cut the index buffer into chunks of at most `max_triangles` triangles.
+/// The generators push their triangles close to each other in the buffer,
+/// so consecutive triangles make small chunks that are easy to cull
+pub fn split_in_chunks(points: &[f32], indices: &[u16], max_triangles: usize) -> Vec<Chunk> {
+    (0..).map_while(|i| chunk(points, indices, max_triangles, i)).collect()
+}
+
+/// the `i`th chunk of `split_in_chunks`, None after the last one
+pub fn chunk(points: &[f32], indices: &[u16], max_triangles: usize, i: usize) -> Option<Chunk> {
+    let first_index = i*3*max_triangles;
+    let triangles = indices.get(first_index..indices.len().min(first_index + 3*max_triangles))?;
+    let (aabb, sphere) = bounding_volumes(points, triangles)?;
+    Some(Chunk {first_index, n_indices: triangles.len(), aabb, sphere})
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bounding_volumes, split_in_chunks};
+    use super::super::{get_point, test_sphere};
+    use super::super::bvh::Bvh;
+    use super::super::ray::{Ray, ray_triangle};
+    use super::super::random::Rng;
+
+    #[test]
+    fn chunks_bound_their_triangles() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        test_sphere(&mut points, &mut indices);
+        assert!(bounding_volumes(&points, &[]).is_none());
+        assert!(split_in_chunks(&points, &[], 64).is_empty());
+
+        let chunks = split_in_chunks(&points, &indices, 64);
+        assert_eq!(chunks.iter().map(|c| c.n_indices).sum::<usize>(), indices.len());
+        for c in &chunks {
+            for &i in &indices[c.first_index..c.first_index+c.n_indices] {
+                let p = get_point(&points, i);
+                assert!(c.aabb.contain(p));
+                assert!((p - c.sphere.center).norm() <= c.sphere.radius + 1e-4);
+            }
+        }
+    }
+
+    #[test]
+    fn closest_hit_of_the_chunks() {
+        let mut rng = Rng::new(1);
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        test_sphere(&mut points, &mut indices);
+        let chunks = split_in_chunks(&points, &indices, 64);
+        let bvh = Bvh::new(&points, &indices);
+
+        let mut n_hits = 0;
+        for _ in 0..50 {
+            let target = get_point(&points, rng.below(points.len()/12) as u16);
+            let origin = rng.unit_v3().scale(80.0);
+            let ray = Ray::new(origin, target - origin);
+
+            // the closest hit of all the chunks, without the hierarchy
+            let brute_force = chunks.iter()
+                .flat_map(|c| indices[c.first_index..c.first_index+c.n_indices].chunks(3))
+                .filter_map(|t| ray_triangle(&ray, get_point(&points, t[0]), get_point(&points, t[1]), get_point(&points, t[2])))
+                .reduce(f32::min);
+            let hit = bvh.cast(&ray);
+            // the rays go through vertices, so they graze triangles hit at almost the same distance
+            assert_eq!(hit.is_some(), brute_force.is_some());
+            if let (Some(h), Some(t)) = (hit, brute_force) {
+                assert!((h.t - t).abs() < 1e-4*t);
+            }
+
+            if let Some(hit) = hit {
+                n_hits += 1;
+                assert!((hit.object as usize) < indices.len()/3);
+            }
+        }
+        assert!(n_hits > 0);
+    }
+}
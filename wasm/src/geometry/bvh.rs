@@ -0,0 +1,570 @@
+use super::V3;
+use super::Range;
+use super::get_point;
+use super::ray::{Ray, Hit, ray_triangle};
+
+// maximum number of triangles in a leaf
+const MAX_LEAF_SIZE: usize = 4;
+// number of buckets used to evaluate the splits
+const N_BINS: usize = 12;
+// cost of visiting a node, compared to the cost of a triangle test
+const TRAVERSAL_COST: f32 = 1.0;
+
+/// A node of the hierarchy.
+/// The nodes are stored in depth-first order, so the first child of a node
+/// is always the next node in the array.
+/// If `count` is 0, the node is interior and `start` is the index of the second child.
+/// Otherwise it is a leaf containing the triangles `start..start+count`
+#[derive(Clone, Debug)]
+struct BvhNode {
+    aabb: Range,
+    start: u32,
+    count: u32,
+}
+
+impl BvhNode {
+    fn is_leaf(&self) -> bool {
+        self.count > 0
+    }
+}
+
+/// Bounding volume hierarchy over the triangles of a mesh.
+/// The triangles are copied in the order of the leaves, so that
+/// a leaf reads contiguous memory
+#[derive(Clone, Debug)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    triangles: Vec<[V3; 3]>,
+    // index of each triangle in the original index buffer (divided by 3)
+    ids: Vec<u32>,
+}
+
+fn triangle_aabb(t: &[V3; 3]) -> Range {
+    Range::new(t[0], t[0]).expand(t[1]).expand(t[2])
+}
+
+fn centroid(t: &[V3; 3]) -> V3 {
+    (t[0] + t[1] + t[2]).scale(1.0/3.0)
+}
+
+/// Closest point to `p` on the triangle (a, b, c).
+/// Look in which region (vertex, edge or face) the projection of p falls
+pub fn closest_point_on_triangle(p: V3, a: V3, b: V3, c: V3) -> V3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = V3::dot(ab, ap);
+    let d2 = V3::dot(ac, ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a
+    }
+
+    let bp = p - b;
+    let d3 = V3::dot(ab, bp);
+    let d4 = V3::dot(ac, bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b
+    }
+
+    let vc = d1*d4 - d3*d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab.scale(d1/(d1 - d3))
+    }
+
+    let cp = p - c;
+    let d5 = V3::dot(ab, cp);
+    let d6 = V3::dot(ac, cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c
+    }
+
+    let vb = d5*d2 - d1*d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac.scale(d2/(d2 - d6))
+    }
+
+    let va = d3*d6 - d5*d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b).scale((d4 - d3)/((d4 - d3) + (d5 - d6)))
+    }
+
+    // inside the face
+    let denom = 1.0/(va + vb + vc);
+    a + ab.scale(vb*denom) + ac.scale(vc*denom)
+}
+
+/// A `Bvh` under construction, built a few nodes at a time.
+/// The nodes are built in depth-first order, with a stack of the ranges of triangles left
+pub struct BvhBuilder {
+    nodes: Vec<BvhNode>,
+    triangles: Vec<[V3; 3]>,
+    ids: Vec<u32>,
+    // triangles `start..end` of a node to build, with its parent if it is a second child
+    pending: Vec<(usize, usize, Option<usize>)>,
+}
+
+impl BvhBuilder {
+    pub fn new(points: &[f32], indices: &[u16]) -> Self {
+        let triangles: Vec<[V3; 3]> = indices
+            .chunks(3)
+            .map(|t| [get_point(points, t[0]), get_point(points, t[1]), get_point(points, t[2])])
+            .collect();
+        let ids: Vec<u32> = (0..triangles.len() as u32).collect();
+        let pending = if triangles.is_empty() {Vec::new()} else {vec![(0, triangles.len(), None)]};
+        BvhBuilder {
+            nodes: Vec::with_capacity(2*triangles.len()/MAX_LEAF_SIZE + 1),
+            triangles,
+            ids,
+            pending,
+        }
+    }
+
+    /// Build nodes until about `n_triangles` triangles were sorted.
+    /// Return the hierarchy when it is finished
+    pub fn step(&mut self, n_triangles: usize) -> Option<Bvh> {
+        let mut work = 0;
+        while work < n_triangles {
+            let (start, end, parent) = match self.pending.pop() {
+                Some(range) => range,
+                None => break,
+            };
+            let id = self.nodes.len();
+            if let Some(parent) = parent {
+                self.nodes[parent].start = id as u32;
+            }
+            if let Some(middle) = self.build(start, end) {
+                // the first child is the next node
+                self.pending.push((middle, end, Some(id)));
+                self.pending.push((start, middle, None));
+            }
+            work += end - start;
+        }
+        if !self.pending.is_empty() {
+            return None
+        }
+        Some(Bvh {
+            nodes: std::mem::take(&mut self.nodes),
+            triangles: std::mem::take(&mut self.triangles),
+            ids: std::mem::take(&mut self.ids),
+        })
+    }
+
+    /// Add the node of the triangles `start..end`.
+    /// If it is split, return where the triangles of the second child start
+    fn build(&mut self, start: usize, end: usize) -> Option<usize> {
+        let triangles = &mut self.triangles;
+        let aabb = triangles[start..end].iter()
+            .map(triangle_aabb)
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+
+        let id = self.nodes.len();
+        self.nodes.push(BvhNode {aabb, start: start as u32, count: (end-start) as u32});
+
+        let count = end - start;
+        if count <= MAX_LEAF_SIZE {
+            return None
+        }
+
+        let (axis, position) = Bvh::find_split(&triangles[start..end], &aabb)?;
+
+        // partition the triangles on each side of the plane
+        let mut middle = start;
+        for i in start..end {
+            if centroid(&triangles[i]).to_array()[axis] < position {
+                triangles.swap(i, middle);
+                self.ids.swap(i, middle);
+                middle += 1;
+            }
+        }
+        if middle == start || middle == end {
+            return None
+        }
+
+        // the start of the second child is set when it is built
+        self.nodes[id].count = 0;
+        Some(middle)
+    }
+}
+
+impl Bvh {
+    /// build the hierarchy over the triangles of an index buffer,
+    /// with the surface area heuristic
+    pub fn new(points: &[f32], indices: &[u16]) -> Self {
+        let mut builder = BvhBuilder::new(points, indices);
+        loop {
+            if let Some(bvh) = builder.step(usize::MAX) {
+                return bvh
+            }
+        }
+    }
+
+    /// Find the best plane to split the triangles, by putting the centroids into bins.
+    /// Return the axis and the position of the plane,
+    /// or None if it is cheaper to keep a leaf
+    fn find_split(triangles: &[[V3; 3]], aabb: &Range) -> Option<(usize, f32)> {
+        let centroids = Range::from_points(triangles.iter().map(centroid))?;
+        let smaller = centroids.smaller_corner.to_array();
+        let size = centroids.diagonal().to_array();
+
+        let mut best: Option<(f32, usize, f32)> = None;
+        for axis in 0..3 {
+            if size[axis] <= 0.0 {
+                continue
+            }
+            let bin_of = |t: &[V3; 3]| {
+                let x = (centroid(t).to_array()[axis] - smaller[axis]) / size[axis];
+                ((x*N_BINS as f32) as usize).min(N_BINS-1)
+            };
+
+            let mut counts = [0usize; N_BINS];
+            let mut bounds: [Option<Range>; N_BINS] = [None; N_BINS];
+            for t in triangles {
+                let b = bin_of(t);
+                counts[b] += 1;
+                let aabb = triangle_aabb(t);
+                bounds[b] = Some(bounds[b].map_or(aabb, |r| r.union(&aabb)));
+            }
+
+            // cost of each of the N_BINS-1 planes between the bins
+            for plane in 1..N_BINS {
+                let side = |bins: &[Option<Range>], counts: &[usize]| {
+                    let n: usize = counts.iter().sum();
+                    let area = bins.iter()
+                        .flatten()
+                        .copied()
+                        .reduce(|a, b| a.union(&b))
+                        .map_or(0.0, |r| r.surface_area());
+                    n as f32 * area
+                };
+                let cost = side(&bounds[..plane], &counts[..plane])
+                         + side(&bounds[plane..], &counts[plane..]);
+
+                if best.map_or(true, |(c, _, _)| cost < c) {
+                    let position = smaller[axis] + size[axis]*(plane as f32)/(N_BINS as f32);
+                    best = Some((cost, axis, position));
+                }
+            }
+        }
+
+        let (cost, axis, position) = best?;
+        let leaf_cost = triangles.len() as f32;
+        let split_cost = TRAVERSAL_COST + cost/aabb.surface_area();
+        if split_cost < leaf_cost || triangles.len() > 4*MAX_LEAF_SIZE {
+            Some((axis, position))
+        }
+        else {
+            None
+        }
+    }
+
+    /// number of triangles in the hierarchy
+    pub fn len(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triangles.is_empty()
+    }
+
+    /// closest triangle hit by the ray.
+    /// `object` in the result is the index of the triangle in the index buffer
+    pub fn cast(&self, ray: &Ray) -> Option<Hit> {
+        self.cast_with(ray, 0.0, |k| self.triangles[k])
+    }
+
+    /// closest triangle hit by the ray when the vertices have moved since the hierarchy was built,
+    /// for example by the ondulation of the shader.
+    /// `triangle` gives the current position of a triangle from its index in the index buffer,
+    /// and no point can be further than `margin` from where it was
+    pub fn cast_moving(&self, ray: &Ray, margin: f32, triangle: impl Fn(u32) -> [V3; 3]) -> Option<Hit> {
+        self.cast_with(ray, margin, |k| triangle(self.ids[k]))
+    }
+
+    /// `triangle` gives the triangle at a position of `self.triangles`
+    fn cast_with(&self, ray: &Ray, margin: f32, triangle: impl Fn(usize) -> [V3; 3]) -> Option<Hit> {
+        let mut best: Option<(f32, usize)> = None;
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        let box_hit = |n: usize| self.nodes[n].aabb
+            .inflate(margin)
+            .ray_intersection(ray.origin, ray.dir);
+
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            match box_hit(i) {
+                Some((t_min, _)) if best.map_or(true, |(t, _)| t_min <= t) => (),
+                _ => continue,
+            }
+
+            if node.is_leaf() {
+                let range = node.start as usize..(node.start + node.count) as usize;
+                for k in range {
+                    let [a, b, c] = triangle(k);
+                    match ray_triangle(ray, a, b, c) {
+                        Some(t) if best.map_or(true, |(best_t, _)| t < best_t) => best = Some((t, k)),
+                        _ => (),
+                    }
+                }
+            }
+            else {
+                // visit the closest child first, so it is pushed last
+                let (first, second) = (i+1, node.start as usize);
+                let dist = |n: usize| box_hit(n).map_or(f32::INFINITY, |(t, _)| t);
+                if dist(first) <= dist(second) {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                else {
+                    stack.push(first);
+                    stack.push(second);
+                }
+            }
+        }
+
+        best.map(|(t, k)| {
+            let [a, b, c] = triangle(k);
+            let n = V3::cross(b-a, c-a).normalize();
+            // return the side of the triangle that faces the ray
+            let normal = if V3::dot(n, ray.dir) > 0.0 {n.scale(-1.0)} else {n};
+            Hit {t, point: ray.at(t), normal, object: self.ids[k]}
+        })
+    }
+
+    /// indices of the triangles whose bounding box touches the range
+    pub fn overlapping(&self, range: &Range) -> Vec<u32> {
+        let mut result = Vec::new();
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            if !node.aabb.intersects(range) {
+                continue
+            }
+            if node.is_leaf() {
+                let range_in_leaf = node.start as usize..(node.start + node.count) as usize;
+                for k in range_in_leaf {
+                    if triangle_aabb(&self.triangles[k]).intersects(range) {
+                        result.push(self.ids[k]);
+                    }
+                }
+            }
+            else {
+                stack.push(i+1);
+                stack.push(node.start as usize);
+            }
+        }
+        result
+    }
+
+    /// Closest point of the mesh from `p`.
+    /// Return the index of the triangle and the point on this triangle.
+    /// The subtrees further than the current best point are skipped
+    pub fn closest_point(&self, p: V3) -> Option<(u32, V3)> {
+        let mut best: Option<(f32, usize, V3)> = None;
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+
+        let dist_to_box = |n: usize| {
+            let d = p - self.nodes[n].aabb.clamp(p);
+            V3::dot(d, d)
+        };
+
+        while let Some(i) = stack.pop() {
+            if best.is_some_and(|(d, _, _)| dist_to_box(i) > d) {
+                continue
+            }
+            let node = &self.nodes[i];
+            if node.is_leaf() {
+                let range = node.start as usize..(node.start + node.count) as usize;
+                for k in range {
+                    let [a, b, c] = self.triangles[k];
+                    let q = closest_point_on_triangle(p, a, b, c);
+                    let d = V3::dot(p-q, p-q);
+                    if best.map_or(true, |(best_d, _, _)| d < best_d) {
+                        best = Some((d, k, q));
+                    }
+                }
+            }
+            else {
+                let (first, second) = (i+1, node.start as usize);
+                if dist_to_box(first) <= dist_to_box(second) {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                else {
+                    stack.push(first);
+                    stack.push(second);
+                }
+            }
+        }
+
+        best.map(|(_, k, q)| (self.ids[k], q))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bvh, BvhBuilder, closest_point_on_triangle, triangle_aabb};
+    use super::super::{V3, Range, get_point, displaced_point, test_sphere, rand_surface};
+    use super::super::bounds::max_ondulation;
+    use super::super::ray::{Ray, ray_triangle};
+    use super::super::random::Rng;
+
+    fn mesh() -> (Vec<f32>, Vec<u16>) {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        test_sphere(&mut points, &mut indices);
+        (points, indices)
+    }
+
+    fn triangles(points: &[f32], indices: &[u16]) -> Vec<[V3; 3]> {
+        indices.chunks(3)
+            .map(|t| [get_point(points, t[0]), get_point(points, t[1]), get_point(points, t[2])])
+            .collect()
+    }
+
+    #[test]
+    fn ray_queries() {
+        let mut rng = Rng::new(1);
+        let (points, indices) = mesh();
+        let bvh = Bvh::new(&points, &indices);
+        let all = triangles(&points, &indices);
+        assert_eq!(bvh.len(), all.len());
+
+        let mut n_hits = 0;
+        for _ in 0..200 {
+            // aim at a random vertex, so that a lot of rays hit something
+            let target = get_point(&points, (rng.float()*(points.len()/12) as f32) as u16);
+            let origin = rng.unit_v3().scale(80.0);
+            let ray = Ray::new(origin, target - origin);
+
+            let brute_force = all.iter()
+                .filter_map(|&[a, b, c]| ray_triangle(&ray, a, b, c))
+                .fold(None, |best: Option<f32>, t| Some(best.map_or(t, |b| b.min(t))));
+            let hit = bvh.cast(&ray);
+            // the rays go through vertices, so they often graze several triangles
+            // that are hit at almost the same distance
+            assert_eq!(hit.is_some(), brute_force.is_some());
+            if let (Some(h), Some(t)) = (hit, brute_force) {
+                assert!((h.t - t).abs() < 1e-4*t);
+            }
+            if let Some(h) = hit {
+                n_hits += 1;
+                let [a, b, c] = all[h.object as usize];
+                assert_eq!(ray_triangle(&ray, a, b, c), Some(h.t));
+            }
+        }
+        assert!(n_hits > 0);
+    }
+
+    #[test]
+    fn same_hierarchy_in_small_steps() {
+        let (points, indices) = mesh();
+        let bvh = Bvh::new(&points, &indices);
+
+        let mut builder = BvhBuilder::new(&points, &indices);
+        let mut steps = 1;
+        let in_steps = loop {
+            if let Some(bvh) = builder.step(100) {
+                break bvh
+            }
+            steps += 1;
+        };
+        assert!(steps > 10);
+        assert_eq!(format!("{:?}", in_steps), format!("{:?}", bvh));
+
+        assert_eq!(BvhBuilder::new(&[], &[]).step(100).map(|bvh| bvh.len()), Some(0));
+    }
+
+    #[test]
+    fn moving_triangles() {
+        let mut rng = Rng::new(3);
+        let (points, indices) = mesh();
+        let bvh = Bvh::new(&points, &indices);
+        let time = 1.7;
+        let margin = max_ondulation(&points);
+        let moved: Vec<[V3; 3]> = indices.chunks(3)
+            .map(|t| [t[0], t[1], t[2]].map(|i| displaced_point(&points, i, time)))
+            .collect();
+
+        let mut n_hits = 0;
+        for _ in 0..200 {
+            let target = get_point(&points, rng.below(points.len()/12) as u16);
+            let origin = rng.unit_v3().scale(80.0);
+            let ray = Ray::new(origin, target - origin);
+
+            let brute_force = moved.iter()
+                .filter_map(|&[a, b, c]| ray_triangle(&ray, a, b, c))
+                .fold(None, |best: Option<f32>, t| Some(best.map_or(t, |b| b.min(t))));
+            let hit = bvh.cast_moving(&ray, margin, |id| moved[id as usize]);
+            assert_eq!(hit.map(|h| h.t), brute_force);
+            if let Some(h) = hit {
+                n_hits += 1;
+                let [a, b, c] = moved[h.object as usize];
+                assert_eq!(ray_triangle(&ray, a, b, c), Some(h.t));
+            }
+        }
+        assert!(n_hits > 0);
+    }
+
+    #[test]
+    fn aabb_queries() {
+        let mut rng = Rng::new(2);
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        rand_surface(&mut points, &mut indices);
+        let bvh = Bvh::new(&points, &indices);
+        let all = triangles(&points, &indices);
+
+        for _ in 0..50 {
+            let c = rng.unit_v3().scale(50.0);
+            let range = Range::new(c, c).inflate(1.0 + 5.0*rng.float());
+
+            let mut expected: Vec<u32> = (0..all.len() as u32)
+                .filter(|&i| triangle_aabb(&all[i as usize]).intersects(&range))
+                .collect();
+            let mut result = bvh.overlapping(&range);
+            expected.sort_unstable();
+            result.sort_unstable();
+            assert_eq!(result, expected);
+        }
+    }
+
+    #[test]
+    fn closest_point_queries() {
+        let mut rng = Rng::new(3);
+        let (points, indices) = mesh();
+        let bvh = Bvh::new(&points, &indices);
+        let all = triangles(&points, &indices);
+
+        for _ in 0..50 {
+            let p = rng.unit_v3().scale(60.0*rng.float());
+            let brute_force = all.iter()
+                .map(|&[a, b, c]| (closest_point_on_triangle(p, a, b, c) - p).norm())
+                .fold(f32::INFINITY, f32::min);
+            let (id, q) = bvh.closest_point(p).unwrap();
+            assert!(((q - p).norm() - brute_force).abs() < 1e-4);
+            let [a, b, c] = all[id as usize];
+            assert!((closest_point_on_triangle(p, a, b, c) - q).norm() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn closest_point_regions() {
+        let (a, b, c) = (V3::new(0.0, 0.0, 0.0), V3::new(1.0, 0.0, 0.0), V3::new(0.0, 1.0, 0.0));
+        let face = closest_point_on_triangle(V3::new(0.2, 0.2, 1.0), a, b, c);
+        assert!((face - V3::new(0.2, 0.2, 0.0)).norm() < 1e-6);
+        let vertex = closest_point_on_triangle(V3::new(-1.0, -1.0, 0.0), a, b, c);
+        assert!((vertex - a).norm() < 1e-6);
+        let edge = closest_point_on_triangle(V3::new(1.0, 1.0, 0.0), a, b, c);
+        assert!((edge - V3::new(0.5, 0.5, 0.0)).norm() < 1e-6);
+    }
+}
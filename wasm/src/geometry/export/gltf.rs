@@ -0,0 +1,426 @@
+//! glTF 2.0 in a single binary file (.glb).
+//!
+//! The vertex shader moves each point by `cos(time*frequency + phase) * ondulation_vec`.
+//! Since `cos(wt + phase) = cos(wt) cos(phase) - sin(wt) sin(phase)`, the vertices that share
+//! a frequency become one mesh with 2 morph targets, `cos(phase) * ondulation_vec`
+//! and `-sin(phase) * ondulation_vec`, whose weights are animated with `cos(wt)` and `sin(wt)`.
+//! A triangle whose vertices move at several frequencies has 2 morph targets per frequency,
+//! and a vertex only moves in the targets of its own frequency.
+//! The vertices with a frequency of 0 do not move, but they are offset by `cos(phase) * ondulation_vec`
+
+use std::collections::HashMap;
+use std::f32::consts::TAU;
+
+use super::n_vertices;
+use super::super::{V3, SIZE_VERTEX, get_point};
+use super::super::{ONDULATION, FREQUENCY, PHASE};
+use super::super::json::Json;
+
+const MAGIC: &[u8; 4] = b"glTF";
+const JSON_CHUNK: u32 = 0x4E4F_534A;
+const BIN_CHUNK: u32 = 0x004E_4942;
+
+// component types and buffer targets
+const FLOAT: usize = 5126;
+const UNSIGNED_SHORT: usize = 5123;
+const ARRAY_BUFFER: usize = 34962;
+const ELEMENT_ARRAY_BUFFER: usize = 34963;
+
+/// number of keyframes in a period of the ondulation
+const SAMPLES: usize = 24;
+/// most keyframes in the animation, whatever the frequencies
+const MAX_SAMPLES: usize = 1024;
+
+/// the triangles whose vertices move at the same frequencies, with their own indices.
+/// A vertex is in all the groups of its triangles
+#[derive(Default)]
+struct Group {
+    // the frequencies of the moving vertices, without duplicates
+    frequencies: Vec<f32>,
+    // index in the buffer of the generators of each vertex of the group
+    vertices: Vec<u16>,
+    indices: Vec<u16>,
+    renumber: HashMap<u16, u16>,
+}
+
+impl Group {
+    fn add_vertex(&mut self, i: u16) -> u16 {
+        let vertices = &mut self.vertices;
+        *self.renumber.entry(i).or_insert_with(|| {
+            vertices.push(i);
+            (vertices.len() - 1) as u16
+        })
+    }
+
+    fn moves(&self) -> bool {
+        !self.frequencies.is_empty()
+    }
+}
+
+fn ondulation(points: &[f32], i: u16) -> (V3, f32, f32) {
+    let i = i as usize*SIZE_VERTEX;
+    let ondulation = V3::new(points[i+ONDULATION], points[i+ONDULATION+1], points[i+ONDULATION+2]);
+    (ondulation, points[i+FREQUENCY], points[i+PHASE])
+}
+
+/// the frequency of a vertex, or `None` if it does not move
+fn frequency(points: &[f32], i: u16) -> Option<f32> {
+    let (v, frequency, _) = ondulation(points, i);
+    Some(frequency).filter(|&f| f != 0.0 && v.norm() != 0.0)
+}
+
+/// Split the triangles by the frequencies of their vertices.
+/// The triangles that do not move are put together
+fn group_by_frequency(points: &[f32], indices: &[u16]) -> Vec<Group> {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut by_frequencies: HashMap<Vec<u32>, usize> = HashMap::new();
+    for t in indices.chunks_exact(3) {
+        let mut frequencies: Vec<f32> = t.iter().filter_map(|&i| frequency(points, i)).collect();
+        frequencies.sort_by(f32::total_cmp);
+        frequencies.dedup();
+        let key = frequencies.iter().map(|f| f.to_bits()).collect();
+        let g = *by_frequencies.entry(key).or_insert_with(|| {
+            groups.push(Group {frequencies, ..Group::default()});
+            groups.len() - 1
+        });
+        for &i in t {
+            let local = groups[g].add_vertex(i);
+            groups[g].indices.push(local);
+        }
+    }
+    groups
+}
+
+/// the position of a vertex at the start of the animation, with the offset of the vertices
+/// that do not move
+fn position(points: &[f32], i: u16) -> V3 {
+    let (v, frequency, phase) = ondulation(points, i);
+    let offset = if frequency == 0.0 {v.scale(phase.cos())} else {V3::new(0.0, 0.0, 0.0)};
+    get_point(points, i) + offset
+}
+
+/// the 2 morph targets of a vertex
+fn targets(points: &[f32], i: u16) -> [V3; 2] {
+    let (v, _, phase) = ondulation(points, i);
+    [v.scale(phase.cos()), v.scale(-phase.sin())]
+}
+
+/// the 2 morph targets of a vertex for each frequency of its group,
+/// which are 0 for the other frequencies
+fn group_targets(points: &[f32], i: u16, frequencies: &[f32]) -> Vec<V3> {
+    let zero = V3::new(0.0, 0.0, 0.0);
+    frequencies.iter()
+        .flat_map(|&f| if frequency(points, i) == Some(f) {targets(points, i)} else {[zero, zero]})
+        .collect()
+}
+
+/// the weights of the morph targets at a time
+fn weights(frequency: f32, time: f32) -> [f32; 2] {
+    [(frequency*time).cos(), (frequency*time).sin()]
+}
+
+/// the weights of the morph targets of a group at a time
+fn group_weights(frequencies: &[f32], time: f32) -> Vec<f32> {
+    frequencies.iter().flat_map(|&f| weights(f, time)).collect()
+}
+
+/// the binary chunk, and the description of its content
+#[derive(Default)]
+struct Builder {
+    bin: Vec<u8>,
+    buffer_views: Vec<Json>,
+    accessors: Vec<Json>,
+}
+
+impl Builder {
+    fn view(&mut self, bytes: &[u8], target: Option<usize>) -> usize {
+        let mut view = Json::object(vec![
+            ("buffer", 0usize.into()),
+            ("byteOffset", self.bin.len().into()),
+            ("byteLength", bytes.len().into()),
+        ]);
+        if let Some(target) = target {
+            view.insert("target", target.into());
+        }
+        self.bin.extend_from_slice(bytes);
+        // each view starts on 4 bytes
+        self.bin.resize(self.bin.len().div_ceil(4) * 4, 0);
+        self.buffer_views.push(view);
+        self.buffer_views.len() - 1
+    }
+
+    fn accessor(&mut self, view: usize, component: usize, count: usize, kind: &str) -> &mut Json {
+        self.accessors.push(Json::object(vec![
+            ("bufferView", view.into()),
+            ("componentType", component.into()),
+            ("count", count.into()),
+            ("type", kind.into()),
+        ]));
+        self.accessors.last_mut().unwrap()
+    }
+
+    /// vectors, with their bounds (needed for the positions)
+    fn vectors(&mut self, vectors: &[V3], target: Option<usize>) -> usize {
+        let bytes: Vec<u8> = vectors.iter()
+            .flat_map(|v| v.to_array())
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        let view = self.view(&bytes, target);
+        let min = vectors.iter().fold(vectors[0], |a, &b| V3::min(a, b));
+        let max = vectors.iter().fold(vectors[0], |a, &b| V3::max(a, b));
+        let accessor = self.accessor(view, FLOAT, vectors.len(), "VEC3");
+        accessor.insert("min", min.to_array().to_vec().into());
+        accessor.insert("max", max.to_array().to_vec().into());
+        self.accessors.len() - 1
+    }
+
+    fn scalars(&mut self, values: &[f32]) -> usize {
+        let bytes: Vec<u8> = values.iter().flat_map(|x| x.to_le_bytes()).collect();
+        let view = self.view(&bytes, None);
+        let min = values.iter().cloned().fold(f32::INFINITY, f32::min);
+        let max = values.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let accessor = self.accessor(view, FLOAT, values.len(), "SCALAR");
+        accessor.insert("min", vec![min].into());
+        accessor.insert("max", vec![max].into());
+        self.accessors.len() - 1
+    }
+
+    fn indices(&mut self, indices: &[u16]) -> usize {
+        let bytes: Vec<u8> = indices.iter().flat_map(|i| i.to_le_bytes()).collect();
+        let view = self.view(&bytes, Some(ELEMENT_ARRAY_BUFFER));
+        self.accessor(view, UNSIGNED_SHORT, indices.len(), "SCALAR");
+        self.accessors.len() - 1
+    }
+}
+
+/// the color of a vertex for glTF, between 0 and 1
+fn color(points: &[f32], i: u16) -> V3 {
+    super::get_color(points, i as usize)
+}
+
+/// A scene with a node per group of triangles, under a node that turns the z axis up
+/// into the y axis up of glTF. The ondulation is a looping animation of the morph targets
+pub fn to_glb(points: &[f32], indices: &[u16]) -> Vec<u8> {
+    assert!(n_vertices(points) <= u16::MAX as usize + 1);
+    let mut builder = Builder::default();
+    let mut meshes = Vec::new();
+    let mut nodes = vec![Json::Null];
+    let mut samplers = Vec::new();
+    let mut channels = Vec::new();
+
+    // all the groups share the keyframes of one period of the slowest frequency,
+    // sampled finely enough for the fastest one
+    let groups = group_by_frequency(points, indices);
+    let all_frequencies = groups.iter().flat_map(|g| g.frequencies.iter().map(|f| f.abs()));
+    let slowest = all_frequencies.clone().fold(f32::INFINITY, f32::min);
+    let fastest = all_frequencies.fold(0.0, f32::max);
+    let period = TAU / slowest;
+    let n_samples = ((SAMPLES as f32 * (fastest / slowest).ceil()) as usize).clamp(SAMPLES, MAX_SAMPLES);
+    let times: Vec<f32> = (0..=n_samples).map(|k| k as f32 * period / n_samples as f32).collect();
+    let mut input = None;
+
+    for group in groups {
+        let vertices = &group.vertices;
+        let position = builder.vectors(&vertices.iter().map(|&i| position(points, i)).collect::<Vec<_>>(), Some(ARRAY_BUFFER));
+        let colors = builder.vectors(&vertices.iter().map(|&i| color(points, i)).collect::<Vec<_>>(), Some(ARRAY_BUFFER));
+        let indices = builder.indices(&group.indices);
+
+        let mut primitive = Json::object(vec![
+            ("attributes", Json::object(vec![("POSITION", position.into()), ("COLOR_0", colors.into())])),
+            ("indices", indices.into()),
+        ]);
+        let mut mesh = Json::object(vec![]);
+
+        if group.moves() {
+            let frequencies = &group.frequencies;
+            let vertex_targets: Vec<Vec<V3>> = vertices.iter().map(|&i| group_targets(points, i, frequencies)).collect();
+            let morphs: Vec<Json> = (0..2*frequencies.len()).map(|k| {
+                let target: Vec<V3> = vertex_targets.iter().map(|t| t[k]).collect();
+                Json::object(vec![("POSITION", builder.vectors(&target, Some(ARRAY_BUFFER)).into())])
+            }).collect();
+            primitive.insert("targets", Json::Array(morphs));
+            mesh.insert("weights", group_weights(frequencies, 0.0).into());
+
+            let input = *input.get_or_insert_with(|| builder.scalars(&times));
+            let values: Vec<f32> = times.iter().flat_map(|&t| group_weights(frequencies, t)).collect();
+            samplers.push(Json::object(vec![
+                ("input", input.into()),
+                ("output", builder.scalars(&values).into()),
+                ("interpolation", "LINEAR".into()),
+            ]));
+            channels.push(Json::object(vec![
+                ("sampler", (samplers.len() - 1).into()),
+                ("target", Json::object(vec![("node", nodes.len().into()), ("path", "weights".into())])),
+            ]));
+        }
+        mesh.insert("primitives", Json::Array(vec![primitive]));
+        meshes.push(mesh);
+        nodes.push(Json::object(vec![("mesh", (meshes.len() - 1).into())]));
+    }
+
+    let half = std::f32::consts::FRAC_1_SQRT_2;
+    nodes[0] = Json::object(vec![
+        ("name", "world".into()),
+        ("rotation", vec![-half, 0.0, 0.0, half].into()),
+        ("children", (1..nodes.len()).collect::<Vec<usize>>().into()),
+    ]);
+
+    let mut gltf = Json::object(vec![
+        ("asset", Json::object(vec![("version", "2.0".into()), ("generator", "web-3d".into())])),
+        ("scene", 0usize.into()),
+        ("scenes", Json::Array(vec![Json::object(vec![("nodes", vec![0usize].into())])])),
+        ("nodes", Json::Array(nodes)),
+        ("meshes", Json::Array(meshes)),
+    ]);
+    if !channels.is_empty() {
+        gltf.insert("animations", Json::Array(vec![Json::object(vec![
+            ("name", "ondulation".into()),
+            ("samplers", Json::Array(samplers)),
+            ("channels", Json::Array(channels)),
+        ])]));
+    }
+    gltf.insert("accessors", Json::Array(builder.accessors));
+    gltf.insert("bufferViews", Json::Array(builder.buffer_views));
+    gltf.insert("buffers", Json::Array(vec![Json::object(vec![("byteLength", builder.bin.len().into())])]));
+
+    // the chunks are padded to 4 bytes, with spaces for the json
+    let mut json = gltf.to_string().into_bytes();
+    json.resize(json.len().div_ceil(4) * 4, b' ');
+    let bin = builder.bin;
+
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend_from_slice(&2u32.to_le_bytes());
+    bytes.extend_from_slice(&((12 + 8 + json.len() + 8 + bin.len()) as u32).to_le_bytes());
+    for (chunk_type, data) in [(JSON_CHUNK, &json), (BIN_CHUNK, &bin)] {
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&chunk_type.to_le_bytes());
+        bytes.extend_from_slice(data);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use super::{to_glb, group_by_frequency, group_targets, group_weights, targets, weights, ondulation, position};
+    use super::{MAX_SAMPLES, FREQUENCY, ONDULATION, PHASE};
+    use super::super::tests::tetrahedron;
+    use super::super::super::{V3, SIZE_VERTEX, get_point, displaced_point};
+
+    #[test]
+    fn morph_targets_follow_the_shader() {
+        let (mut points, _) = tetrahedron();
+        // the frequency and the phase differ, so that a swap shows
+        points[SIZE_VERTEX+FREQUENCY] = 2.0;
+        points[SIZE_VERTEX+PHASE] = 0.2;
+        for i in 0..4 {
+            let (v, frequency, phase) = ondulation(&points, i);
+            for &time in &[0.0, 0.3, 1.7, 4.0] {
+                let shader = v.scale((time*frequency + phase).cos());
+                let [w1, w2] = weights(frequency, time);
+                let [t1, t2] = targets(&points, i);
+                assert!((t1.scale(w1) + t2.scale(w2) - shader).norm() < 1e-5);
+                // what the engine draws
+                let moved = get_point(&points, i) + t1.scale(w1) + t2.scale(w2);
+                assert!((moved - displaced_point(&points, i, time)).norm() < 1e-5);
+            }
+        }
+    }
+
+    #[test]
+    fn groups() {
+        let (mut points, indices) = tetrahedron();
+        // the second vertex moves faster
+        points[SIZE_VERTEX+FREQUENCY] = 2.0;
+        let groups = group_by_frequency(&points, &indices);
+        let frequencies: Vec<Vec<f32>> = groups.iter().map(|g| g.frequencies.clone()).collect();
+        assert_eq!(frequencies, vec![vec![1.0, 2.0], vec![1.0]]);
+        // the triangles are the same, with other indices
+        let triangles = |g: &super::Group| g.indices.iter()
+            .map(|&i| get_point(&points, g.vertices[i as usize]).to_array())
+            .collect::<Vec<_>>();
+        assert_eq!(triangles(&groups[0]).len() + triangles(&groups[1]).len(), indices.len());
+        assert_eq!(triangles(&groups[0])[2], get_point(&points, 1).to_array());
+
+        // each vertex moves at its own frequency, in all its groups
+        for group in &groups {
+            for &i in &group.vertices {
+                let (v, frequency, phase) = ondulation(&points, i);
+                let targets = group_targets(&points, i, &group.frequencies);
+                for &time in &[0.0, 0.3, 1.7, 4.0] {
+                    let moved = targets.iter().zip(group_weights(&group.frequencies, time))
+                        .fold(V3::new(0.0, 0.0, 0.0), |a, (&t, w)| a + t.scale(w));
+                    assert!((moved - v.scale((time*frequency + phase).cos())).norm() < 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn still_vertices_keep_their_offset() {
+        let (mut points, indices) = tetrahedron();
+        points[FREQUENCY] = 0.0;
+        let groups = group_by_frequency(&points, &indices);
+        // the first vertex does not move, and is in all the groups
+        assert!(groups.iter().all(|g| g.frequencies == vec![1.0]));
+        assert_eq!(group_targets(&points, 0, &[1.0]), vec![V3::new(0.0, 0.0, 0.0); 2]);
+        let (v, _, phase) = ondulation(&points, 0);
+        assert_eq!(position(&points, 0), get_point(&points, 0) + v.scale(phase.cos()));
+        assert_eq!(position(&points, 1), get_point(&points, 1));
+    }
+
+    #[test]
+    fn glb_layout() {
+        let (points, indices) = tetrahedron();
+        let bytes = to_glb(&points, &indices);
+        let word = |i: usize| u32::from_le_bytes(bytes[i..i+4].try_into().unwrap()) as usize;
+        assert_eq!(&bytes[0..4], b"glTF");
+        assert_eq!(word(4), 2);
+        assert_eq!(word(8), bytes.len());
+
+        let json_length = word(12);
+        assert_eq!(json_length % 4, 0);
+        assert_eq!(word(16), 0x4E4F534A);
+        let json = std::str::from_utf8(&bytes[20..20+json_length]).unwrap();
+        assert!(json.starts_with(r#"{"asset":{"version":"2.0""#));
+        assert!(json.contains(r#""targets":[{"POSITION":3},{"POSITION":4}]"#));
+        assert!(json.contains(r#""path":"weights""#));
+
+        let bin_length = word(20+json_length);
+        assert_eq!(word(24+json_length), 0x004E4942);
+        assert_eq!(28 + json_length + bin_length, bytes.len());
+        // positions, colors, indices (padded), 2 targets, then the keyframes
+        let vec3 = 4 * 3 * 4;
+        assert_eq!(bin_length, 2*vec3 + 24 + 2*vec3 + 25*4 + 25*2*4);
+        // the vertices are in the order of the triangles
+        let first = 28 + json_length;
+        let float = |i: usize| f32::from_le_bytes(bytes[first+i..first+i+4].try_into().unwrap());
+        assert_eq!(V3::new(float(12), float(16), float(20)).to_array(), [0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn still_mesh_has_no_animation() {
+        let (mut points, indices) = tetrahedron();
+        for vertex in points.chunks_mut(SIZE_VERTEX) {
+            vertex[ONDULATION+1] = 0.0;
+        }
+        let bytes = to_glb(&points, &indices);
+        let json = String::from_utf8_lossy(&bytes);
+        assert!(!json.contains("targets") && !json.contains("animations"));
+    }
+
+    #[test]
+    fn groups_share_the_keyframes() {
+        let (mut points, indices) = tetrahedron();
+        // without a limit, 24 keyframes per period of the fastest vertex
+        points[SIZE_VERTEX+FREQUENCY] = 1000.0;
+        let bytes = to_glb(&points, &indices);
+        let json = String::from_utf8_lossy(&bytes);
+        let inputs: Vec<&str> = json.split(r#""input":"#).skip(1)
+            .map(|s| s.split(',').next().unwrap())
+            .collect();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0], inputs[1]);
+        assert!(json.contains(&format!(r#""count":{},"type":"SCALAR""#, MAX_SAMPLES + 1)));
+    }
+}
@@ -0,0 +1,99 @@
+//! Write the output of the generators (12 floats per vertex, and the indices of the triangles)
+//! in file formats that other tools can read
+
+mod obj;
+mod ply;
+mod stl;
+mod gltf;
+
+pub use obj::to_obj;
+pub use ply::{to_ply_ascii, to_ply_binary};
+pub use stl::to_stl;
+pub use gltf::to_glb;
+
+use super::{V3, SIZE_VERTEX, get_point};
+
+/// the file formats of `export`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Obj,
+    PlyAscii,
+    PlyBinary,
+    Stl,
+    Glb,
+}
+
+impl Format {
+    /// `"obj"`, `"ply"` (binary), `"ply-ascii"`, `"stl"` or `"glb"`
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "obj" => Some(Format::Obj),
+            "ply" => Some(Format::PlyBinary),
+            "ply-ascii" => Some(Format::PlyAscii),
+            "stl" => Some(Format::Stl),
+            "glb" => Some(Format::Glb),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Obj => "obj",
+            Format::PlyAscii | Format::PlyBinary => "ply",
+            Format::Stl => "stl",
+            Format::Glb => "glb",
+        }
+    }
+}
+
+/// the content of the file
+pub fn export(format: Format, points: &[f32], indices: &[u16]) -> Vec<u8> {
+    match format {
+        Format::Obj => to_obj(points, indices),
+        Format::PlyAscii => to_ply_ascii(points, indices),
+        Format::PlyBinary => to_ply_binary(points, indices),
+        Format::Stl => to_stl(points, indices),
+        Format::Glb => to_glb(points, indices),
+    }
+}
+
+fn n_vertices(points: &[f32]) -> usize {
+    points.len() / SIZE_VERTEX
+}
+
+/// color of a vertex. The shading can push it out of [0, 1]
+fn get_color(points: &[f32], i: usize) -> V3 {
+    let i = i*SIZE_VERTEX;
+    V3::new(points[i+3], points[i+4], points[i+5]).map(|c| c.clamp(0.0, 1.0))
+}
+
+/// the 3 corners of each triangle
+fn triangles<'a>(points: &'a [f32], indices: &'a [u16]) -> impl Iterator<Item=[V3; 3]> + 'a {
+    indices.chunks_exact(3).map(move |t| [
+        get_point(points, t[0]),
+        get_point(points, t[1]),
+        get_point(points, t[2]),
+    ])
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::super::{V3, SIZE_VERTEX};
+
+    /// a tetrahedron with a different color on each corner
+    pub fn tetrahedron() -> (Vec<f32>, Vec<u16>) {
+        let corners = [
+            (V3::new(0.0, 0.0, 0.0), V3::new(1.0, 0.0, 0.0)),
+            (V3::new(1.0, 0.0, 0.0), V3::new(0.0, 1.0, 0.0)),
+            (V3::new(0.0, 1.0, 0.0), V3::new(0.0, 0.0, 1.0)),
+            // the shading makes the last one too bright
+            (V3::new(0.0, 0.0, 1.0), V3::new(1.5, 0.5, 0.5)),
+        ];
+        let mut points = Vec::new();
+        for &(p, color) in &corners {
+            push_point!(points, p, color, [0.0, 0.1, 0.0], [1.0, 0.5, 0.0]);
+        }
+        assert_eq!(points.len(), 4*SIZE_VERTEX);
+        (points, vec![0, 2, 1,  0, 1, 3,  1, 2, 3,  0, 3, 2])
+    }
+}
@@ -0,0 +1,48 @@
+use std::fmt::Write;
+
+use super::{n_vertices, get_color};
+use super::super::get_point;
+
+/// Wavefront OBJ, with the color after the position of each vertex
+/// (`v x y z r g b`, understood by Blender and MeshLab)
+pub fn to_obj(points: &[f32], indices: &[u16]) -> Vec<u8> {
+    let mut text = String::from("# generated by web-3d\n");
+    for i in 0..n_vertices(points) {
+        let p = get_point(points, i as u16);
+        let c = get_color(points, i);
+        writeln!(text, "v {} {} {} {} {} {}", p.x, p.y, p.z, c.x, c.y, c.z).unwrap();
+    }
+    // the indices start at 1
+    for t in indices.chunks_exact(3) {
+        writeln!(text, "f {} {} {}", t[0]+1, t[1]+1, t[2]+1).unwrap();
+    }
+    text.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_obj;
+    use super::super::tests::tetrahedron;
+
+    #[test]
+    fn parse_back() {
+        let (points, indices) = tetrahedron();
+        let text = String::from_utf8(to_obj(&points, &indices)).unwrap();
+
+        let mut vertices = Vec::new();
+        let mut faces = Vec::new();
+        for line in text.lines() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("v") => vertices.push(words.map(|w| w.parse::<f32>().unwrap()).collect::<Vec<_>>()),
+                Some("f") => faces.extend(words.map(|w| w.parse::<u16>().unwrap() - 1)),
+                _ => (),
+            }
+        }
+        assert_eq!(faces, indices);
+        assert_eq!(vertices.len(), 4);
+        assert_eq!(vertices[1], vec![1.0, 0.0, 0.0,  0.0, 1.0, 0.0]);
+        // the colors are clamped
+        assert_eq!(vertices[3], vec![0.0, 0.0, 1.0,  1.0, 0.5, 0.5]);
+    }
+}
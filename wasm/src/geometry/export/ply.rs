@@ -0,0 +1,135 @@
+use std::fmt::Write;
+
+use super::{n_vertices, get_color};
+use super::super::{V3, get_point};
+
+fn header(format: &str, points: &[f32], indices: &[u16]) -> String {
+    let mut text = String::new();
+    writeln!(text, "ply").unwrap();
+    writeln!(text, "format {} 1.0", format).unwrap();
+    writeln!(text, "comment generated by web-3d").unwrap();
+    writeln!(text, "element vertex {}", n_vertices(points)).unwrap();
+    for property in &["float x", "float y", "float z", "uchar red", "uchar green", "uchar blue"] {
+        writeln!(text, "property {}", property).unwrap();
+    }
+    writeln!(text, "element face {}", indices.len() / 3).unwrap();
+    writeln!(text, "property list uchar uint vertex_indices").unwrap();
+    writeln!(text, "end_header").unwrap();
+    text
+}
+
+fn to_bytes(color: V3) -> [u8; 3] {
+    color.to_array().map(|c| (c*255.0).round() as u8)
+}
+
+/// PLY in text, with a color per vertex
+pub fn to_ply_ascii(points: &[f32], indices: &[u16]) -> Vec<u8> {
+    let mut text = header("ascii", points, indices);
+    for i in 0..n_vertices(points) {
+        let p = get_point(points, i as u16);
+        let [r, g, b] = to_bytes(get_color(points, i));
+        writeln!(text, "{} {} {} {} {} {}", p.x, p.y, p.z, r, g, b).unwrap();
+    }
+    for t in indices.chunks_exact(3) {
+        writeln!(text, "3 {} {} {}", t[0], t[1], t[2]).unwrap();
+    }
+    text.into_bytes()
+}
+
+/// PLY in little endian binary, with a color per vertex
+pub fn to_ply_binary(points: &[f32], indices: &[u16]) -> Vec<u8> {
+    let mut bytes = header("binary_little_endian", points, indices).into_bytes();
+    for i in 0..n_vertices(points) {
+        for x in &get_point(points, i as u16).to_array() {
+            bytes.extend_from_slice(&x.to_le_bytes());
+        }
+        bytes.extend_from_slice(&to_bytes(get_color(points, i)));
+    }
+    for t in indices.chunks_exact(3) {
+        bytes.push(3);
+        for &i in t {
+            bytes.extend_from_slice(&(i as u32).to_le_bytes());
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use super::{to_ply_ascii, to_ply_binary};
+    use super::super::tests::tetrahedron;
+
+    // vertices (position and color) and faces
+    type Ply = (Vec<([f32; 3], [u8; 3])>, Vec<u32>);
+
+    fn split_header(bytes: &[u8]) -> (Vec<String>, &[u8]) {
+        let end = b"end_header\n";
+        let position = bytes.windows(end.len()).position(|w| w == end).unwrap() + end.len();
+        let header = std::str::from_utf8(&bytes[..position]).unwrap();
+        (header.lines().map(String::from).collect(), &bytes[position..])
+    }
+
+    fn parse_ascii(bytes: &[u8]) -> Ply {
+        let (header, body) = split_header(bytes);
+        assert!(header.contains(&"format ascii 1.0".to_string()));
+        let mut vertices = Vec::new();
+        let mut faces = Vec::new();
+        for line in std::str::from_utf8(body).unwrap().lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() == 6 {
+                let p = [0, 1, 2].map(|i| words[i].parse().unwrap());
+                let c = [3, 4, 5].map(|i| words[i].parse().unwrap());
+                vertices.push((p, c));
+            }
+            else {
+                assert_eq!(words[0], "3");
+                faces.extend(words[1..].iter().map(|w| w.parse::<u32>().unwrap()));
+            }
+        }
+        (vertices, faces)
+    }
+
+    fn parse_binary(bytes: &[u8]) -> Ply {
+        let (header, mut body) = split_header(bytes);
+        assert!(header.contains(&"format binary_little_endian 1.0".to_string()));
+        let count = |element: &str| header.iter()
+            .find_map(|l| l.strip_prefix(element))
+            .unwrap()
+            .parse::<usize>()
+            .unwrap();
+        let mut take = |n: usize| {
+            let (start, end) = body.split_at(n);
+            body = end;
+            start
+        };
+        let vertices = (0..count("element vertex ")).map(|_| {
+            let p = [0, 1, 2].map(|_| f32::from_le_bytes(take(4).try_into().unwrap()));
+            let c = take(3).try_into().unwrap();
+            (p, c)
+        }).collect();
+        let mut faces = Vec::new();
+        for _ in 0..count("element face ") {
+            assert_eq!(take(1), [3]);
+            faces.extend((0..3).map(|_| u32::from_le_bytes(take(4).try_into().unwrap())));
+        }
+        assert!(body.is_empty());
+        (vertices, faces)
+    }
+
+    #[test]
+    fn parse_back() {
+        let (points, indices) = tetrahedron();
+        let expected_faces: Vec<u32> = indices.iter().map(|&i| i as u32).collect();
+
+        for (vertices, faces) in [
+            parse_ascii(&to_ply_ascii(&points, &indices)),
+            parse_binary(&to_ply_binary(&points, &indices)),
+        ] {
+            assert_eq!(faces, expected_faces);
+            assert_eq!(vertices.len(), 4);
+            assert_eq!(vertices[2], ([0.0, 1.0, 0.0], [0, 0, 255]));
+            assert_eq!(vertices[3], ([0.0, 0.0, 1.0], [255, 128, 128]));
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use super::triangles;
+use super::super::V3;
+
+/// Binary STL: only the triangles, with their normal
+pub fn to_stl(points: &[f32], indices: &[u16]) -> Vec<u8> {
+    let mut header = b"generated by web-3d".to_vec();
+    header.resize(80, 0);
+
+    let mut bytes = header;
+    bytes.extend_from_slice(&((indices.len() / 3) as u32).to_le_bytes());
+    for [a, b, c] in triangles(points, indices) {
+        let normal = V3::cross(b-a, c-a);
+        let normal = if normal.norm() > 0.0 {normal.scale(1.0 / normal.norm())} else {normal};
+        for v in &[normal, a, b, c] {
+            for x in &v.to_array() {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        // attribute byte count, unused
+        bytes.extend_from_slice(&[0, 0]);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use super::to_stl;
+    use super::super::tests::tetrahedron;
+    use super::super::super::{V3, get_point};
+
+    #[test]
+    fn parse_back() {
+        let (points, indices) = tetrahedron();
+        let bytes = to_stl(&points, &indices);
+        assert_eq!(bytes.len(), 84 + 4*50);
+        assert_eq!(u32::from_le_bytes(bytes[80..84].try_into().unwrap()), 4);
+
+        let float = |i: usize| f32::from_le_bytes(bytes[i..i+4].try_into().unwrap());
+        let vector = |i: usize| V3::new(float(i), float(i+4), float(i+8));
+        for (k, t) in indices.chunks(3).enumerate() {
+            let start = 84 + 50*k;
+            let corners = [vector(start+12), vector(start+24), vector(start+36)];
+            for (&i, &corner) in t.iter().zip(&corners) {
+                assert_eq!(corner.to_array(), get_point(&points, i).to_array());
+            }
+            // the normal points out of the tetrahedron
+            let normal = vector(start);
+            assert!((normal.norm() - 1.0).abs() < 1e-6);
+            let center = V3::new(0.25, 0.25, 0.25);
+            assert!(V3::dot(normal, corners[0] - center) > 0.0);
+        }
+    }
+}
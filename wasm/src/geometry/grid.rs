@@ -0,0 +1,412 @@
+use super::V3;
+use super::Range;
+use super::Dist;
+use super::interval::Interval;
+
+/// How a `GridSdf` is evaluated between the samples
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    /// continuous, stays between the samples
+    Trilinear,
+    /// smooth (Catmull-Rom splines), but can go a little beyond the samples
+    Tricubic,
+}
+
+// the weights of a Catmull-Rom spline add up to at most 1.25 in absolute value on each axis,
+// so the result is at most this fraction of the spread of the samples beyond them
+const TRICUBIC_OVERSHOOT: f32 = (1.25*1.25*1.25 - 1.0) / 2.0;
+
+const MAGIC: &[u8; 3] = b"GRD";
+const VERSION: u8 = 1;
+
+/// A distance function sampled on a regular grid, and interpolated between the samples.
+/// Evaluating it costs the same for every shape, so it can replace
+/// a slow function (a mesh, a big tree of operations) before building an octree.
+///
+/// The samples are stored on 16 bits, as multiples of `quantum`:
+/// the biggest sample gives the precision of the others
+#[derive(Clone, Debug)]
+pub struct GridSdf {
+    range: Range,
+    // number of samples on each axis, at least 2
+    size: [usize; 3],
+    // x first, then y, then z
+    values: Vec<i16>,
+    quantum: f32,
+    interpolation: Interpolation,
+}
+
+impl GridSdf {
+    /// Sample a function at `size[0]*size[1]*size[2]` points.
+    /// The first and last samples of each axis are on the sides of the range
+    pub fn sample(shape: &(impl Dist + Sync), range: Range, size: [usize; 3]) -> Self {
+        assert!(size.iter().all(|&n| n >= 2), "a grid needs 2 samples on each axis");
+        let mut grid = GridSdf {range, size, values: Vec::new(), quantum: 1.0, interpolation: Interpolation::Trilinear};
+
+        // one slice of constant z at a time
+        let slice = |k: usize| -> Vec<f32> {
+            let mut values = Vec::with_capacity(size[0]*size[1]);
+            for j in 0..size[1] {
+                for i in 0..size[0] {
+                    values.push(shape.dist(grid.position([i, j, k])));
+                }
+            }
+            values
+        };
+        #[cfg(feature = "parallel")]
+        let samples: Vec<f32> = {
+            use rayon::prelude::*;
+            (0..size[2]).into_par_iter().flat_map_iter(slice).collect()
+        };
+        #[cfg(not(feature = "parallel"))]
+        let samples: Vec<f32> = (0..size[2]).flat_map(slice).collect();
+
+        grid.set_values(&samples);
+        grid
+    }
+
+    fn set_values(&mut self, samples: &[f32]) {
+        // infinite distances (empty meshes...) are stored as the biggest finite one
+        let biggest = samples.iter().filter(|x| x.is_finite()).fold(0.0, |m: f32, x| m.max(x.abs()));
+        self.quantum = if biggest > 0.0 {biggest / i16::MAX as f32} else {1.0};
+        self.values = samples.iter()
+            .map(|x| (x / self.quantum).round().clamp(-i16::MAX as f32, i16::MAX as f32) as i16)
+            .collect();
+    }
+
+    pub fn with_interpolation(mut self, interpolation: Interpolation) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    pub fn range(&self) -> Range {
+        self.range
+    }
+
+    pub fn size(&self) -> [usize; 3] {
+        self.size
+    }
+
+    /// the largest error made by storing the samples on 16 bits
+    pub fn precision(&self) -> f32 {
+        self.quantum / 2.0
+    }
+
+    /// distance between 2 samples on each axis
+    fn step(&self) -> V3 {
+        let d = self.range.diagonal().to_array();
+        V3::new(
+            d[0] / (self.size[0]-1) as f32,
+            d[1] / (self.size[1]-1) as f32,
+            d[2] / (self.size[2]-1) as f32,
+        )
+    }
+
+    fn position(&self, sample: [usize; 3]) -> V3 {
+        let step = self.step();
+        self.range.smaller_corner + V3::new(
+            sample[0] as f32 * step.x,
+            sample[1] as f32 * step.y,
+            sample[2] as f32 * step.z,
+        )
+    }
+
+    fn value(&self, sample: [usize; 3]) -> f32 {
+        self.values[(sample[2]*self.size[1] + sample[1])*self.size[0] + sample[0]] as f32 * self.quantum
+    }
+
+    /// the cell that contains a point of the range, and the position inside the cell (in [0, 1])
+    fn locate(&self, p: V3) -> ([usize; 3], [f32; 3]) {
+        let relative = (p - self.range.smaller_corner).to_array();
+        let step = self.step().to_array();
+        let mut cell = [0; 3];
+        let mut t = [0.0; 3];
+        for d in 0..3 {
+            let x = (relative[d] / step[d]).max(0.0);
+            cell[d] = (x as usize).min(self.size[d] - 2);
+            t[d] = (x - cell[d] as f32).min(1.0);
+        }
+        (cell, t)
+    }
+
+    /// trilinear interpolation inside the range
+    fn trilinear(&self, p: V3) -> f32 {
+        let (cell, t) = self.locate(p);
+        let mut result = 0.0;
+        for corner in 0..8 {
+            let mut weight = 1.0;
+            let mut sample = cell;
+            for d in 0..3 {
+                if corner >> d & 1 == 1 {
+                    sample[d] += 1;
+                    weight *= t[d];
+                }
+                else {
+                    weight *= 1.0 - t[d];
+                }
+            }
+            result += weight * self.value(sample);
+        }
+        result
+    }
+
+    /// Catmull-Rom interpolation with the 4x4x4 samples around a point, one axis after the other.
+    /// On the sides of the grid, the missing samples are extrapolated linearly,
+    /// so that a linear function stays exact
+    fn tricubic(&self, p: V3) -> f32 {
+        let (cell, t) = self.locate(p);
+        // interpolate between the 2 samples in the middle
+        let cubic = |v: [f32; 4], t: f32| {
+            let (t2, t3) = (t*t, t*t*t);
+            0.5*((-t3 + 2.0*t2 - t)*v[0] + (3.0*t3 - 5.0*t2 + 2.0)*v[1]
+                + (-3.0*t3 + 4.0*t2 + t)*v[2] + (t3 - t2)*v[3])
+        };
+        // the 4 values of a line along an axis, from the samples before and after the cell
+        let line = |d: usize, value: &dyn Fn(usize) -> f32| {
+            let mut v = [0.0; 4];
+            v[1] = value(cell[d]);
+            v[2] = value(cell[d] + 1);
+            v[0] = if cell[d] > 0 {value(cell[d] - 1)} else {2.0*v[1] - v[2]};
+            v[3] = if cell[d] + 2 < self.size[d] {value(cell[d] + 2)} else {2.0*v[2] - v[1]};
+            cubic(v, t[d])
+        };
+        line(2, &|k| line(1, &|j| line(0, &|i| self.value([i, j, k]))))
+    }
+
+    /// Sample this grid again, on another range or with another resolution
+    pub fn resample(&self, range: Range, size: [usize; 3]) -> Self {
+        GridSdf::sample(self, range, size).with_interpolation(self.interpolation)
+    }
+
+    /// Combine the samples of 2 grids, on the samples of this one.
+    /// The other grid is interpolated if it has other samples
+    pub fn combine(&self, other: &GridSdf, f: impl Fn(f32, f32) -> f32) -> Self {
+        let same_samples = self.range == other.range && self.size == other.size;
+        let mut samples = Vec::with_capacity(self.values.len());
+        for k in 0..self.size[2] {
+            for j in 0..self.size[1] {
+                for i in 0..self.size[0] {
+                    let sample = [i, j, k];
+                    let b = if same_samples {other.value(sample)} else {other.dist(self.position(sample))};
+                    samples.push(f(self.value(sample), b));
+                }
+            }
+        }
+        let mut result = self.clone();
+        result.set_values(&samples);
+        result
+    }
+
+    /// the minimum of the 2 distances
+    pub fn union(&self, other: &GridSdf) -> Self {
+        self.combine(other, f32::min)
+    }
+
+    /// the maximum of the 2 distances
+    pub fn intersection(&self, other: &GridSdf) -> Self {
+        self.combine(other, f32::max)
+    }
+
+    /// Encode the samples, to keep a grid instead of computing it again.
+    /// The numbers are little endian: `b"GRD"`, version (1 byte), range (6 f32),
+    /// size (3 u32), interpolation (1 byte), quantum (f32), then the samples (i16)
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        for v in &[self.range.smaller_corner, self.range.greater_corner] {
+            for x in &v.to_array() {
+                bytes.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        for &n in &self.size {
+            bytes.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        bytes.push(match self.interpolation {
+            Interpolation::Trilinear => 0,
+            Interpolation::Tricubic => 1,
+        });
+        bytes.extend_from_slice(&self.quantum.to_le_bytes());
+        for v in &self.values {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decode a grid saved with `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let header = 3 + 1 + 24 + 12 + 1 + 4;
+        if bytes.len() < header || &bytes[..3] != MAGIC {
+            return Err("not a grid file".to_string())
+        }
+        if bytes[3] != VERSION {
+            return Err(format!("unsupported version {} (expected {})", bytes[3], VERSION))
+        }
+        let word = |i: usize| [bytes[i], bytes[i+1], bytes[i+2], bytes[i+3]];
+        let float = |i: usize| f32::from_le_bytes(word(i));
+        let vector = |i: usize| V3::new(float(i), float(i+4), float(i+8));
+        let range = Range::new(vector(4), vector(16));
+        let size = [28, 32, 36].map(|i| u32::from_le_bytes(word(i)) as usize);
+        let interpolation = match bytes[40] {
+            0 => Interpolation::Trilinear,
+            1 => Interpolation::Tricubic,
+            n => return Err(format!("unknown interpolation {}", n)),
+        };
+        let quantum = float(41);
+
+        let n = size.iter().try_fold(1usize, |n, &s| n.checked_mul(s)).unwrap_or(usize::MAX);
+        if size.iter().any(|&s| s < 2) || Some(bytes.len()) != n.checked_mul(2).map(|b| b + header) {
+            return Err("the size of the grid does not match the number of samples".to_string())
+        }
+        let values = bytes[header..].chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        Ok(GridSdf {range, size, values, quantum, interpolation})
+    }
+}
+
+/// Outside of the grid, the distance to the grid is added to the value on its side
+impl Dist for GridSdf {
+    fn dist(&self, point: V3) -> f32 {
+        let inside = self.range.clamp(point);
+        let value = match self.interpolation {
+            Interpolation::Trilinear => self.trilinear(inside),
+            Interpolation::Tricubic => self.tricubic(inside),
+        };
+        value + (point - inside).norm()
+    }
+
+    /// The interpolation stays between the samples of the cells that touch the range
+    /// (and the samples around them for the tricubic interpolation, that can go a little further)
+    fn bounds(&self, range: &Range) -> Interval {
+        let inside = Range::new(self.range.clamp(range.smaller_corner), self.range.clamp(range.greater_corner));
+        let (first, _) = self.locate(inside.smaller_corner);
+        let (last, t) = self.locate(inside.greater_corner);
+        let mut last: Vec<usize> = (0..3).map(|d| last[d] + (t[d] > 0.0) as usize).collect();
+        let mut first = first.to_vec();
+        if self.interpolation == Interpolation::Tricubic {
+            for d in 0..3 {
+                first[d] = first[d].saturating_sub(1);
+                last[d] = (last[d] + 1).min(self.size[d] - 1);
+            }
+        }
+
+        let mut bounds = Interval::new(f32::INFINITY, f32::NEG_INFINITY);
+        for k in first[2]..=last[2] {
+            for j in first[1]..=last[1] {
+                for i in first[0]..=last[0] {
+                    let v = self.value([i, j, k]);
+                    bounds = Interval::new(bounds.min.min(v), bounds.max.max(v));
+                }
+            }
+        }
+        if self.interpolation == Interpolation::Tricubic {
+            let overshoot = (bounds.max - bounds.min) * TRICUBIC_OVERSHOOT;
+            bounds = Interval::new(bounds.min - overshoot, bounds.max + overshoot);
+        }
+
+        // the farthest point of the range from the grid
+        let outside = V3::max(
+            self.range.smaller_corner - range.smaller_corner,
+            range.greater_corner - self.range.greater_corner,
+        ).map(|x| x.max(0.0));
+        Interval::new(bounds.min, bounds.max + outside.norm())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GridSdf, Interpolation};
+    use super::super::{V3, Range, Dist};
+    use super::super::sdf::Sdf;
+    use super::super::random::Rng;
+
+    fn cube() -> Range {
+        Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0))
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut rng = Rng::new(1);
+        // a plane is linear, so it is interpolated exactly, up to the precision of the samples
+        let plane = |p: V3| 0.3*p.x - 0.5*p.y + 0.2*p.z - 0.1;
+        let grid = GridSdf::sample(&plane, cube(), [5, 4, 3]);
+        let tricubic = grid.clone().with_interpolation(Interpolation::Tricubic);
+        assert!(grid.precision() < 1e-4);
+        for _ in 0..100 {
+            let p = rng.unit_v3();
+            assert!((grid.dist(p) - plane(p)).abs() < 1e-4);
+            assert!((tricubic.dist(p) - plane(p)).abs() < 1e-4);
+        }
+
+        let sphere = Sdf::Sphere {radius: 0.6};
+        let grid = GridSdf::sample(&sphere, cube(), [33, 33, 33]);
+        let tricubic = grid.clone().with_interpolation(Interpolation::Tricubic);
+        let samples: Vec<V3> = (0..200).map(|_| rng.unit_v3().scale(0.9)).collect();
+        let error = |grid: &GridSdf| samples.iter()
+            .map(|&p| (grid.dist(p) - sphere.dist(p)).abs())
+            .fold(0.0, f32::max);
+        assert!(error(&grid) < 0.01);
+        // the tricubic interpolation is closer to a curved surface
+        assert!(error(&tricubic) < error(&grid));
+
+        // outside of the grid
+        assert!((grid.dist(V3::new(3.0, 0.0, 0.0)) - 2.4).abs() < 0.01);
+    }
+
+    #[test]
+    fn bounds() {
+        let mut rng = Rng::new(2);
+        let grid = GridSdf::sample(&Sdf::Torus {major: 0.6, minor: 0.2}, cube(), [9, 9, 9]);
+        for interpolation in [Interpolation::Trilinear, Interpolation::Tricubic] {
+            let grid = grid.clone().with_interpolation(interpolation);
+            for _ in 0..200 {
+                let a = rng.unit_v3().scale(1.5);
+                let b = a + rng.unit_v3().map(f32::abs).scale(0.5);
+                let bounds = grid.bounds(&Range::new(a, b));
+                for _ in 0..20 {
+                    let t = rng.unit_v3().map(f32::abs);
+                    let p = a + V3::new(t.x*(b.x-a.x), t.y*(b.y-a.y), t.z*(b.z-a.z));
+                    // up to the rounding of the interpolation
+                    let d = grid.dist(p);
+                    assert!(bounds.min - 1e-5 <= d && d <= bounds.max + 1e-5, "{} not in {:?}", d, bounds);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn combinations() {
+        let mut rng = Rng::new(3);
+        let a = Sdf::Sphere {radius: 0.5}.translate(V3::new(0.3, 0.0, 0.0));
+        let b = Sdf::Sphere {radius: 0.4}.translate(V3::new(-0.3, 0.1, 0.0));
+        let grid_a = GridSdf::sample(&a, cube(), [17, 17, 17]);
+        let grid_b = GridSdf::sample(&b, cube(), [17, 17, 17]);
+        // on other samples, the second grid is interpolated
+        let finer_b = grid_b.resample(cube(), [25, 25, 25]);
+        for (union, intersection) in [
+            (grid_a.union(&grid_b), grid_a.intersection(&grid_b)),
+            (grid_a.union(&finer_b), grid_a.intersection(&finer_b)),
+        ] {
+            for _ in 0..100 {
+                let p = rng.unit_v3().scale(0.9);
+                assert!((union.dist(p) - a.dist(p).min(b.dist(p))).abs() < 0.03);
+                assert!((intersection.dist(p) - a.dist(p).max(b.dist(p))).abs() < 0.03);
+            }
+        }
+        assert_eq!(finer_b.size(), [25, 25, 25]);
+    }
+
+    #[test]
+    fn bytes() {
+        let grid = GridSdf::sample(&Sdf::Sphere {radius: 0.6}, cube(), [6, 7, 8])
+            .with_interpolation(Interpolation::Tricubic);
+        let bytes = grid.to_bytes();
+        // 2 bytes per sample
+        assert_eq!(bytes.len(), 45 + 2*6*7*8);
+        let loaded = GridSdf::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.to_bytes(), bytes);
+        let p = V3::new(0.1, 0.2, 0.3);
+        assert_eq!(loaded.dist(p), grid.dist(p));
+
+        assert!(GridSdf::from_bytes(&bytes[..bytes.len()-2]).is_err());
+        assert!(GridSdf::from_bytes(b"GRD").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+use std::ops::{Add, Sub, Mul, Div, Neg};
+
+use super::V3;
+use super::Range;
+use super::Dist;
+use super::interval::Interval;
+use super::program::{Program, Op};
+
+/// An implicit function of a point, written with the usual operators:
+/// `x()*x() + y() + y() - 0.5`.
+/// It is not a distance, but the bounds computed with intervals are exact,
+/// so it can be used to build an octree
+#[derive(Clone, Debug)]
+pub struct Expr {
+    prog: Program,
+}
+
+fn leaf(op: Op) -> Expr {
+    let mut prog = Program::new();
+    prog.push(op);
+    Expr {prog}
+}
+
+pub fn x() -> Expr {
+    leaf(Op::X)
+}
+pub fn y() -> Expr {
+    leaf(Op::Y)
+}
+pub fn z() -> Expr {
+    leaf(Op::Z)
+}
+pub fn constant(c: f32) -> Expr {
+    leaf(Op::Const(c))
+}
+
+impl Expr {
+    fn unary(mut self, f: impl Fn(usize) -> Op) -> Expr {
+        let a = self.prog.result();
+        self.prog.push_unique(f(a));
+        self
+    }
+
+    fn binary(mut self, other: Expr, f: impl Fn(usize, usize) -> Op) -> Expr {
+        let a = self.prog.result();
+        let b = self.prog.append(&other.prog);
+        self.prog.push_unique(f(a, b));
+        self
+    }
+
+    pub fn abs(self) -> Expr {
+        self.unary(Op::Abs)
+    }
+    pub fn sqrt(self) -> Expr {
+        self.unary(Op::Sqrt)
+    }
+    pub fn min(self, other: Expr) -> Expr {
+        self.binary(other, Op::Min)
+    }
+    pub fn max(self, other: Expr) -> Expr {
+        self.binary(other, Op::Max)
+    }
+
+    /// the list of operations, to be evaluated or translated to glsl
+    pub fn program(&self) -> &Program {
+        &self.prog
+    }
+}
+
+impl From<f32> for Expr {
+    fn from(c: f32) -> Expr {
+        constant(c)
+    }
+}
+
+impl From<Expr> for Program {
+    fn from(e: Expr) -> Program {
+        e.prog
+    }
+}
+
+// the operators accept an expression or a number on the right
+macro_rules! binary_operator {
+    ($trait:ident, $method:ident, $op:path) => {
+        impl<T: Into<Expr>> $trait<T> for Expr {
+            type Output = Expr;
+            fn $method(self, other: T) -> Expr {
+                self.binary(other.into(), $op)
+            }
+        }
+    };
+}
+
+binary_operator!(Add, add, Op::Add);
+binary_operator!(Sub, sub, Op::Sub);
+binary_operator!(Mul, mul, Op::Mul);
+binary_operator!(Div, div, Op::Div);
+
+impl Neg for Expr {
+    type Output = Expr;
+    fn neg(self) -> Expr {
+        self.unary(Op::Neg)
+    }
+}
+
+impl Dist for Expr {
+    fn dist(&self, p: V3) -> f32 {
+        self.prog.eval(p)
+    }
+    fn bounds(&self, range: &Range) -> Interval {
+        self.prog.bounds(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{x, y, z, Dist};
+    use super::super::{V3, Range};
+    use super::super::random::Rng;
+
+    #[test]
+    fn same_as_closure() {
+        let mut rng = Rng::new(1);
+        let e = (x()*x() + y()*2.0 - 0.5).max(z().abs().sqrt() / 3.0);
+        let f = |p: V3| f32::max(p.x*p.x + p.y*2.0 - 0.5, p.z.abs().sqrt() / 3.0);
+        for _ in 0..100 {
+            let p = rng.unit_v3().scale(5.0);
+            assert_eq!(e.dist(p), f(p));
+        }
+    }
+
+    #[test]
+    fn shared_operations() {
+        // x is computed once, so x*x is a square
+        let e = x()*x() + y() + y() - 0.5;
+        assert_eq!(e.program().to_glsl().unwrap().matches("p.x").count(), 1);
+
+        let range = Range::new(V3::new(-1.0, 0.0, 0.0), V3::new(1.0, 0.0, 0.0));
+        assert_eq!(e.bounds(&range).min, -0.5);
+    }
+
+    #[test]
+    fn bounds_are_sound() {
+        let mut rng = Rng::new(2);
+        let e = x()*x() + y() + y() - 0.5;
+        for _ in 0..100 {
+            let c = rng.unit_v3().scale(2.0);
+            let range = Range::new(c, c).inflate(rng.float());
+            let bounds = e.bounds(&range);
+            for &corner in &range.corners() {
+                assert!(bounds.contains(e.dist(corner)));
+            }
+        }
+    }
+}
@@ -0,0 +1,485 @@
+//! glTF 2.0, as a json file with embedded buffers (.gltf) or as a binary file (.glb).
+//!
+//! The meshes of the nodes of the scene are placed with the transforms of the nodes,
+//! then the y axis up of glTF becomes the z axis up of the engine.
+//! Only the triangles are read, with their position and color
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::convert::TryInto;
+
+use super::{push_vertex, check_size, default_color};
+use super::super::{V3, SIZE_VERTEX};
+use super::super::json::Json;
+
+// column major, like in glTF
+type M4 = [f32; 16];
+
+const IDENTITY: M4 = [
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+];
+
+// from y up to z up: (x, y, z) becomes (x, -z, y)
+const Y_UP_TO_Z_UP: M4 = [
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 0.0, 1.0, 0.0,
+    0.0, -1.0, 0.0, 0.0,
+    0.0, 0.0, 0.0, 1.0,
+];
+
+fn mult(a: M4, b: M4) -> M4 {
+    let mut m = [0.0; 16];
+    for col in 0..4 {
+        for row in 0..4 {
+            m[col*4+row] = (0..4).map(|k| a[k*4+row] * b[col*4+k]).sum();
+        }
+    }
+    m
+}
+
+fn apply(m: M4, p: V3) -> V3 {
+    V3::new(
+        m[0]*p.x + m[4]*p.y + m[8]*p.z + m[12],
+        m[1]*p.x + m[5]*p.y + m[9]*p.z + m[13],
+        m[2]*p.x + m[6]*p.y + m[10]*p.z + m[14],
+    )
+}
+
+/// translation * rotation * scale
+fn trs(t: [f32; 3], q: [f32; 4], s: [f32; 3]) -> M4 {
+    let [x, y, z, w] = q;
+    let rotation = [
+        1.0 - 2.0*(y*y + z*z), 2.0*(x*y + z*w), 2.0*(x*z - y*w), 0.0,
+        2.0*(x*y - z*w), 1.0 - 2.0*(x*x + z*z), 2.0*(y*z + x*w), 0.0,
+        2.0*(x*z + y*w), 2.0*(y*z - x*w), 1.0 - 2.0*(x*x + y*y), 0.0,
+        0.0, 0.0, 0.0, 1.0,
+    ];
+    let mut m = rotation;
+    for col in 0..3 {
+        for row in 0..3 {
+            m[col*4+row] *= s[col];
+        }
+    }
+    m[12] = t[0];
+    m[13] = t[1];
+    m[14] = t[2];
+    m
+}
+
+fn decode_base64(text: &str) -> Result<Vec<u8>, String> {
+    let value = |c: u8| match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' | b'-' => Some(62),
+        b'/' | b'_' => Some(63),
+        _ => None,
+    };
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut n_bits = 0;
+    for c in text.bytes().filter(|&c| c != b'=') {
+        bits = bits << 6 | value(c).ok_or_else(|| format!("{} is not in base64", c as char))? as u32;
+        n_bits += 6;
+        if n_bits >= 8 {
+            n_bits -= 8;
+            bytes.push((bits >> n_bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// the most values of an accessor without buffer view, which are not in the file
+const MAX_ZEROS: usize = 1 << 24;
+
+/// the json of a glTF, with the content of its buffers
+struct Document<'a> {
+    json: Json,
+    buffers: Vec<Cow<'a, [u8]>>,
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> Result<&'a Json, String> {
+    json.get(key).ok_or_else(|| format!("missing {} in the gltf", key))
+}
+
+fn index(json: &Json, key: &str) -> Result<usize, String> {
+    field(json, key)?.as_usize().ok_or_else(|| format!("{} is not an index", key))
+}
+
+fn numbers<const N: usize>(json: &Json, key: &str, default: [f32; N]) -> Result<[f32; N], String> {
+    match json.get(key) {
+        None => Ok(default),
+        Some(array) => array.elements().iter()
+            .map(|x| x.as_f64().map(|x| x as f32))
+            .collect::<Option<Vec<f32>>>()
+            .and_then(|v| v.try_into().ok())
+            .ok_or_else(|| format!("{} must have {} numbers", key, N)),
+    }
+}
+
+impl<'a> Document<'a> {
+    /// `bin` is the binary chunk of a .glb
+    fn new(json: &[u8], bin: Option<&'a [u8]>) -> Result<Self, String> {
+        let text = std::str::from_utf8(json).map_err(|_| "the json of the gltf is not in utf-8".to_string())?;
+        let json = Json::parse(text)?;
+        let mut buffers = Vec::new();
+        for buffer in field(&json, "buffers").map(Json::elements).unwrap_or(&[]) {
+            let data = match buffer.get("uri").and_then(Json::as_str) {
+                None => Cow::Borrowed(bin.ok_or("a buffer without uri needs a glb file")?),
+                Some(uri) if uri.starts_with("data:") => {
+                    let (_, data) = uri.split_once(";base64,").ok_or("the data uris must be in base64")?;
+                    Cow::Owned(decode_base64(data)?)
+                }
+                Some(uri) => return Err(format!("cannot read the external file {}", uri)),
+            };
+            if data.len() < index(buffer, "byteLength")? {
+                return Err("a buffer is shorter than its byteLength".to_string())
+            }
+            buffers.push(data);
+        }
+        Ok(Document {json, buffers})
+    }
+
+    fn get(&self, kind: &str, i: usize) -> Result<&Json, String> {
+        field(&self.json, kind)?.elements().get(i).ok_or_else(|| format!("there is no {} {}", kind, i))
+    }
+
+    /// the values of an accessor, converted to floats, by groups of `size`
+    fn accessor(&self, i: usize) -> Result<(Vec<f32>, usize), String> {
+        let accessor = self.get("accessors", i)?;
+        if accessor.get("sparse").is_some() {
+            return Err("sparse accessors are not supported".to_string())
+        }
+        let size = match field(accessor, "type")?.as_str() {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            _ => return Err(format!("accessor {} is not a scalar or a vector", i)),
+        };
+        let count = index(accessor, "count")?;
+        let normalized = accessor.get("normalized").and_then(Json::as_bool).unwrap_or(false);
+
+        // component size, and conversion
+        let component = index(accessor, "componentType")?;
+        let (component_size, read): (usize, fn(&[u8]) -> f32) = match component {
+            5120 => (1, |b| b[0] as i8 as f32),
+            5121 => (1, |b| b[0] as f32),
+            5122 => (2, |b| i16::from_le_bytes([b[0], b[1]]) as f32),
+            5123 => (2, |b| u16::from_le_bytes([b[0], b[1]]) as f32),
+            5125 => (4, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f32),
+            5126 => (4, |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            _ => return Err(format!("unknown component type {}", component)),
+        };
+        let scale = match (normalized, component) {
+            (false, _) | (true, 5126) => 1.0,
+            (true, 5120) => 1.0 / 127.0,
+            (true, 5121) => 1.0 / 255.0,
+            (true, 5122) => 1.0 / 32767.0,
+            (true, _) => 1.0 / 65535.0,
+        };
+
+        let out_of_view = || format!("accessor {} goes out of its buffer view", i);
+        // without buffer view, the values are zeros
+        let view = match accessor.get("bufferView") {
+            Some(view) => self.get("bufferViews", view.as_usize().ok_or("bufferView is not an index")?)?,
+            None => {
+                let n_values = count.checked_mul(size).filter(|&n| n <= MAX_ZEROS)
+                    .ok_or_else(|| format!("accessor {} has too many values without buffer view", i))?;
+                return Ok((vec![0.0; n_values], size))
+            }
+        };
+        let buffer = self.buffers.get(index(view, "buffer")?).ok_or("there is no such buffer")?;
+        let view_offset = view.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let start = view_offset.checked_add(accessor.get("byteOffset").and_then(Json::as_usize).unwrap_or(0))
+            .ok_or_else(out_of_view)?;
+        let stride = view.get("byteStride").and_then(Json::as_usize).unwrap_or(component_size * size);
+        // the elements cannot overlap, so the count is limited by the length of the view
+        if stride < component_size * size {
+            return Err(format!("the stride of accessor {} is shorter than its elements", i))
+        }
+        let end = view_offset.checked_add(index(view, "byteLength")?).ok_or_else(out_of_view)?.min(buffer.len());
+
+        // the end of the last element, so that the offsets below stay in the view
+        if count > 0 {
+            let last = (count - 1).checked_mul(stride)
+                .and_then(|x| x.checked_add(start))
+                .and_then(|x| x.checked_add(size * component_size));
+            if last.map_or(true, |last| last > end) {
+                return Err(out_of_view())
+            }
+        }
+        let mut values = Vec::with_capacity(count*size);
+        for element in 0..count {
+            for k in 0..size {
+                let offset = start + element*stride + k*component_size;
+                values.push(read(&buffer[offset..]) * scale);
+            }
+        }
+        Ok((values, size))
+    }
+
+    fn add_mesh(&self, mesh: usize, transform: M4, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+        for primitive in field(self.get("meshes", mesh)?, "primitives")?.elements() {
+            // only the triangles
+            if primitive.get("mode").and_then(Json::as_usize).unwrap_or(4) != 4 {
+                continue
+            }
+            let attributes = field(primitive, "attributes")?;
+            let (positions, size) = self.accessor(index(attributes, "POSITION")?)?;
+            if size != 3 {
+                return Err("the positions must be 3d vectors".to_string())
+            }
+            let n = positions.len() / 3;
+            // rgb or rgba
+            let colors = match attributes.get("COLOR_0") {
+                Some(color) => {
+                    let (colors, size) = self.accessor(color.as_usize().ok_or("COLOR_0 is not an index")?)?;
+                    if size < 3 || colors.len() != n*size {
+                        return Err("the colors must be rgb or rgba, one per vertex".to_string())
+                    }
+                    colors.chunks(size).map(|c| V3::new(c[0], c[1], c[2])).collect()
+                }
+                None => vec![default_color(); n],
+            };
+
+            let first = points.len() / SIZE_VERTEX;
+            for (p, &color) in positions.chunks(3).zip(&colors) {
+                push_vertex(points, apply(transform, V3::new(p[0], p[1], p[2])), color);
+            }
+            check_size(points)?;
+
+            let corners: Vec<usize> = match primitive.get("indices") {
+                Some(i) => self.accessor(i.as_usize().ok_or("indices is not an index")?)?.0
+                    .into_iter()
+                    .map(|i| i as usize)
+                    .collect(),
+                None => (0..n).collect(),
+            };
+            if corners.iter().any(|&i| i >= n) {
+                return Err("an index goes after the last vertex".to_string())
+            }
+            // a transform that mirrors the mesh would turn the triangles inside out
+            let mirror = {
+                let [a, b, c] = [0, 4, 8].map(|k| V3::new(transform[k], transform[k+1], transform[k+2]));
+                V3::dot(V3::cross(a, b), c) < 0.0
+            };
+            for t in corners.chunks_exact(3) {
+                let t = if mirror {[t[0], t[2], t[1]]} else {[t[0], t[1], t[2]]};
+                push_index!(indices, [first + t[0], first + t[1], first + t[2]]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a node and its children. The nodes of a scene must form a strict tree:
+    /// `visited` are the nodes already added, a node reached twice is an error
+    fn add_node(&self, node: usize, parent: M4, depth: usize, visited: &mut HashSet<usize>, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+        if !visited.insert(node) {
+            return Err(format!("the node {} is reached twice, the nodes do not form a tree", node))
+        }
+        // a tree can still be too deep for the stack
+        if depth > 64 {
+            return Err("the nodes are too deep".to_string())
+        }
+        let json = self.get("nodes", node)?;
+        let local = match json.get("matrix") {
+            Some(_) => numbers(json, "matrix", IDENTITY)?,
+            None => trs(
+                numbers(json, "translation", [0.0; 3])?,
+                numbers(json, "rotation", [0.0, 0.0, 0.0, 1.0])?,
+                numbers(json, "scale", [1.0; 3])?,
+            ),
+        };
+        let transform = mult(parent, local);
+        if let Some(mesh) = json.get("mesh") {
+            self.add_mesh(mesh.as_usize().ok_or("mesh is not an index")?, transform, points, indices)?;
+        }
+        for child in json.get("children").map(Json::elements).unwrap_or(&[]) {
+            let child = child.as_usize().ok_or("children must be indices")?;
+            self.add_node(child, transform, depth+1, visited, points, indices)?;
+        }
+        Ok(())
+    }
+
+    /// the nodes of the default scene, or the first one
+    fn add_scene(&self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+        let scene = self.json.get("scene").and_then(Json::as_usize).unwrap_or(0);
+        let mut visited = HashSet::new();
+        for node in field(self.get("scenes", scene)?, "nodes")?.elements() {
+            let node = node.as_usize().ok_or("the nodes of a scene must be indices")?;
+            self.add_node(node, Y_UP_TO_Z_UP, 0, &mut visited, points, indices)?;
+        }
+        Ok(())
+    }
+}
+
+/// glTF in json, with the buffers in data uris
+pub fn from_gltf(bytes: &[u8], points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+    Document::new(bytes, None)?.add_scene(points, indices)
+}
+
+/// binary glTF
+pub fn from_glb(bytes: &[u8], points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+    let word = |i: usize| bytes.get(i..i+4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(|| "the glb file is too short".to_string());
+    if bytes.get(0..4) != Some(b"glTF") || word(4)? != 2 {
+        return Err("not a glb file of version 2".to_string())
+    }
+    let length = word(8)?.min(bytes.len());
+
+    let mut json = None;
+    let mut bin = None;
+    let mut position = 12;
+    while position + 8 <= length {
+        let (chunk_length, chunk_type) = (word(position)?, word(position+4)?);
+        let end = (position + 8).checked_add(chunk_length).filter(|&end| end <= length)
+            .ok_or("a chunk goes after the end of the file")?;
+        let data = &bytes[position+8..end];
+        match chunk_type {
+            0x4E4F534A => json = json.or(Some(data)),
+            0x004E4942 => bin = bin.or(Some(data)),
+            // unknown chunks are skipped
+            _ => (),
+        }
+        position = end;
+    }
+    let json = json.ok_or("the glb file has no json")?;
+    Document::new(json, bin)?.add_scene(points, indices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_gltf, from_glb, decode_base64};
+    use super::super::default_color;
+    use super::super::super::{V3, SIZE_VERTEX, get_point};
+    use super::super::super::export::to_glb;
+    use super::super::super::export::tests::tetrahedron;
+
+    fn close(a: V3, b: V3) -> bool {
+        (a - b).norm() < 1e-5
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(decode_base64("AAEC/w==").unwrap(), vec![0, 1, 2, 255]);
+        assert!(decode_base64("a b").is_err());
+    }
+
+    #[test]
+    fn triangle_fixture() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        from_gltf(include_bytes!("../../../fixtures/triangle.gltf"), &mut points, &mut indices).unwrap();
+        assert_eq!(indices, vec![0, 1, 2]);
+        // the node moves the triangle up by 2 along y, which is z in the engine
+        assert!(close(get_point(&points, 1), V3::new(1.0, 0.0, 2.0)));
+        assert!(close(get_point(&points, 2), V3::new(0.0, 0.0, 3.0)));
+        assert_eq!(points[3..6], default_color().to_array());
+    }
+
+    #[test]
+    fn quads_fixture() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        from_glb(include_bytes!("../../../fixtures/quads.glb"), &mut points, &mut indices).unwrap();
+        // the same square in 2 nodes, one with a matrix and one with a scale of -1
+        assert_eq!(points.len(), 8*SIZE_VERTEX);
+        assert_eq!(indices, vec![0, 1, 2, 0, 2, 3,  4, 6, 5, 4, 7, 6]);
+        // the colors are normalized bytes, with alpha
+        assert!(close(V3::new(points[3], points[4], points[5]), V3::new(1.0, 0.0, 0.0)));
+        assert!(close(get_point(&points, 2), V3::new(1.0, -1.0, 5.0)));
+        assert!(close(get_point(&points, 6), V3::new(-1.0, 0.0, 1.0)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let (points, indices) = tetrahedron();
+        let mut loaded = Vec::new();
+        let mut loaded_indices = Vec::new();
+        from_glb(&to_glb(&points, &indices), &mut loaded, &mut loaded_indices).unwrap();
+        // the same triangles, with the colors of the export
+        let triangles = |points: &[f32], indices: &[u16]| indices.iter()
+            .map(|&i| {
+                let i = i as usize*SIZE_VERTEX;
+                points[i..i+6].iter().map(|x| (x*1e4).round() as i32).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut clamped = points.clone();
+        for x in clamped.chunks_mut(SIZE_VERTEX).flat_map(|v| &mut v[3..6]) {
+            *x = x.clamp(0.0, 1.0);
+        }
+        assert_eq!(triangles(&loaded, &loaded_indices), triangles(&clamped, &indices));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        assert!(from_glb(b"glTF", &mut points, &mut indices).is_err());
+        assert!(from_gltf(b"{}", &mut points, &mut indices).is_err());
+        let external = br#"{"buffers": [{"uri": "mesh.bin", "byteLength": 4}], "scenes": [{"nodes": []}]}"#;
+        assert!(from_gltf(external, &mut points, &mut indices).unwrap_err().contains("mesh.bin"));
+
+        // a chunk longer than the file
+        let mut glb = b"glTF".to_vec();
+        for word in [2, 28, 0xFFFF_FFF8, 0x4E4F534A] {
+            glb.extend_from_slice(&u32::to_le_bytes(word));
+        }
+        glb.extend_from_slice(b"{}  ");
+        assert!(from_glb(&glb, &mut points, &mut indices).unwrap_err().contains("chunk"));
+        assert!(points.is_empty() && indices.is_empty());
+    }
+
+    // a triangle whose positions are read from a buffer of 36 bytes
+    fn triangle_with(accessor: &str, view: &str) -> String {
+        let buffer = "AAAAAAAAAAAAAAAAAACAPwAAAAAAAAAAAAAAAAAAgD8AAAAA";
+        format!(r#"{{
+            "scenes": [{{"nodes": [0]}}], "nodes": [{{"mesh": 0}}],
+            "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}}}]}}],
+            "accessors": [{{"componentType": 5126, "type": "VEC3", {}}}],
+            "bufferViews": [{{"buffer": 0, {}}}],
+            "buffers": [{{"byteLength": 36, "uri": "data:application/octet-stream;base64,{}"}}]
+        }}"#, accessor, view, buffer)
+    }
+
+    #[test]
+    fn nodes_form_a_tree() {
+        let load = |nodes: &str| {
+            let gltf = triangle_with(r#""count": 3, "bufferView": 0"#, r#""byteLength": 36"#)
+                .replace(r#""scenes": [{"nodes": [0]}], "nodes": [{"mesh": 0}]"#, nodes);
+            let mut points = Vec::new();
+            let mut indices = Vec::new();
+            from_gltf(gltf.as_bytes(), &mut points, &mut indices).map(|_| indices.len() / 3)
+        };
+        assert_eq!(load(r#""scenes": [{"nodes": [0, 2]}], "nodes": [{"children": [1]}, {"mesh": 0}, {"mesh": 0}]"#), Ok(2));
+        // a child shared by 2 parents, a node twice in the scene, and a cycle
+        assert!(load(r#""scenes": [{"nodes": [0, 1]}], "nodes": [{"children": [2]}, {"children": [2]}, {"mesh": 0}]"#).is_err());
+        assert!(load(r#""scenes": [{"nodes": [0, 0]}], "nodes": [{"mesh": 0}]"#).is_err());
+        assert!(load(r#""scenes": [{"nodes": [0]}], "nodes": [{"children": [1]}, {"mesh": 0, "children": [0]}]"#).unwrap_err().contains("twice"));
+    }
+
+    #[test]
+    fn accessors_stay_in_their_view() {
+        let load = |accessor: &str, view: &str| {
+            let mut points = Vec::new();
+            let mut indices = Vec::new();
+            from_gltf(triangle_with(accessor, view).as_bytes(), &mut points, &mut indices).map(|_| points.len() / SIZE_VERTEX)
+        };
+        assert_eq!(load(r#""count": 3, "bufferView": 0"#, r#""byteLength": 36"#), Ok(3));
+        assert!(load(r#""count": 4, "bufferView": 0"#, r#""byteLength": 36"#).is_err());
+        assert!(load(r#""count": 3, "bufferView": 0"#, r#""byteLength": 24"#).is_err());
+        // the offsets and the stride would overflow
+        assert!(load(r#""count": 3, "bufferView": 0, "byteOffset": 1e30"#, r#""byteLength": 36"#).is_err());
+        assert!(load(r#""count": 3, "bufferView": 0"#, r#""byteLength": 36, "byteOffset": 1e30"#).is_err());
+        assert!(load(r#""count": 3, "bufferView": 0"#, r#""byteLength": 36, "byteStride": 1e30"#).is_err());
+        assert!(load(r#""count": 1e30, "bufferView": 0"#, r#""byteLength": 36"#).is_err());
+        assert!(load(r#""count": 1e9, "bufferView": 0"#, r#""byteLength": 36, "byteStride": 0"#).is_err());
+        assert!(load(r#""count": 1e30"#, r#""byteLength": 36"#).is_err());
+    }
+}
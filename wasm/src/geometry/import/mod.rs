@@ -0,0 +1,63 @@
+//! Read meshes made by other tools into the vertex layout of the engine.
+//! The vertices get `default_color` when the file has no color, and they do not move
+
+mod obj;
+mod gltf;
+
+pub use obj::from_obj;
+pub use gltf::{from_gltf, from_glb};
+
+use super::{V3, SIZE_VERTEX};
+
+/// color of the vertices without color
+pub fn default_color() -> V3 {
+    V3::new(0.6, 0.6, 0.6)
+}
+
+/// add a vertex without ondulation
+fn push_vertex(points: &mut Vec<f32>, position: V3, color: V3) {
+    push_point!(points, position, color, [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]);
+}
+
+/// the indices are 16 bits numbers
+fn check_size(points: &[f32]) -> Result<(), String> {
+    let n = points.len() / SIZE_VERTEX;
+    if n > u16::MAX as usize + 1 {
+        return Err(format!("too many vertices: {} (the maximum is 65536)", n))
+    }
+    Ok(())
+}
+
+/// Read a file, with the format given by the extension of its name (obj, gltf or glb).
+/// Nothing is added to the buffers if the file is not valid
+pub fn import(name: &str, bytes: &[u8], points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+    let extension = name.rsplit('.').next().unwrap_or("").to_lowercase();
+    let read = match extension.as_str() {
+        "obj" => from_obj,
+        "gltf" => from_gltf,
+        "glb" => from_glb,
+        _ => return Err(format!("unknown file format: {}", name)),
+    };
+    let (n_points, n_indices) = (points.len(), indices.len());
+    let result = read(bytes, points, indices);
+    if result.is_err() {
+        points.truncate(n_points);
+        indices.truncate(n_indices);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::import;
+
+    #[test]
+    fn invalid_files_add_nothing() {
+        let mut points = vec![0.0; 12];
+        let mut indices = vec![0, 0, 0];
+        assert!(import("model.fbx", b"", &mut points, &mut indices).is_err());
+        // the first vertex is valid, not the face
+        assert!(import("model.obj", b"v 0 0 0\nf 1 2 3\n", &mut points, &mut indices).is_err());
+        assert_eq!((points.len(), indices.len()), (12, 3));
+    }
+}
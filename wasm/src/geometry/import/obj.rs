@@ -0,0 +1,103 @@
+use super::{push_vertex, check_size, default_color};
+use super::super::{V3, SIZE_VERTEX};
+
+fn parse_floats<'a>(words: impl Iterator<Item=&'a str>, line: usize) -> Result<Vec<f32>, String> {
+    words
+        .map(|w| w.parse::<f32>().map_err(|_| format!("line {}: {} is not a number", line, w)))
+        .collect()
+}
+
+/// Wavefront OBJ: the vertices (with an optional color after the position)
+/// and the faces, cut into triangles. The other statements are ignored
+pub fn from_obj(bytes: &[u8], points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+    let text = std::str::from_utf8(bytes).map_err(|_| "the obj file is not in utf-8".to_string())?;
+    let first = points.len() / SIZE_VERTEX;
+    let mut n_vertices = 0;
+
+    for (n, line) in text.lines().enumerate() {
+        let line_number = n + 1;
+        let line = line.split('#').next().unwrap_or("");
+        let mut words = line.split_whitespace();
+        match words.next() {
+            Some("v") => {
+                let values = parse_floats(words, line_number)?;
+                let position = match values.len() {
+                    // x y z, with an optional w
+                    3 | 4 | 6 | 7 => V3::new(values[0], values[1], values[2]),
+                    _ => return Err(format!("line {}: a vertex has 3 coordinates", line_number)),
+                };
+                let color = if values.len() >= 6 {
+                    let c = &values[values.len()-3..];
+                    V3::new(c[0], c[1], c[2])
+                } else {
+                    default_color()
+                };
+                push_vertex(points, position, color);
+                n_vertices += 1;
+            }
+            Some("f") => {
+                // `i`, `i/t`, `i//n` or `i/t/n`, starting at 1 or negative from the end
+                let corners = words.map(|w| {
+                    let i: i64 = w.split('/').next().unwrap_or("").parse()
+                        .map_err(|_| format!("line {}: {} is not a vertex", line_number, w))?;
+                    let i = if i < 0 {n_vertices as i64 + i} else {i - 1};
+                    if i < 0 || i >= n_vertices as i64 {
+                        return Err(format!("line {}: there is no vertex {}", line_number, w))
+                    }
+                    Ok(first + i as usize)
+                }).collect::<Result<Vec<usize>, String>>()?;
+                if corners.len() < 3 {
+                    return Err(format!("line {}: a face has at least 3 vertices", line_number))
+                }
+                // fan of triangles around the first corner
+                for k in 1..corners.len()-1 {
+                    push_index!(indices, [corners[0], corners[k], corners[k+1]]);
+                }
+            }
+            _ => (),
+        }
+    }
+    check_size(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_obj;
+    use super::super::default_color;
+    use super::super::super::{V3, SIZE_VERTEX, get_point};
+    use super::super::super::export::to_obj;
+    use super::super::super::export::tests::tetrahedron;
+
+    #[test]
+    fn cube_fixture() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        from_obj(include_bytes!("../../../fixtures/cube.obj"), &mut points, &mut indices).unwrap();
+        assert_eq!(points.len(), 8*SIZE_VERTEX);
+        // 6 squares
+        assert_eq!(indices.len(), 6*2*3);
+        assert_eq!(get_point(&points, 7).to_array(), [1.0, 1.0, 1.0]);
+        assert_eq!(points[3..6], default_color().to_array());
+        // no ondulation
+        assert!(points.chunks(SIZE_VERTEX).all(|v| v[6..].iter().all(|&x| x == 0.0)));
+
+        // the triangles of each square turn in the same direction, towards the outside
+        for t in indices.chunks(3) {
+            let [a, b, c] = [t[0], t[1], t[2]].map(|i| get_point(&points, i));
+            let normal = V3::cross(b-a, c-a);
+            assert!(V3::dot(normal, a - V3::new(0.5, 0.5, 0.5)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (points, indices) = tetrahedron();
+        let mut loaded = vec![0.0; SIZE_VERTEX];
+        let mut loaded_indices = Vec::new();
+        from_obj(&to_obj(&points, &indices), &mut loaded, &mut loaded_indices).unwrap();
+        // after the vertex that was already there
+        assert_eq!(loaded_indices, indices.iter().map(|i| i+1).collect::<Vec<u16>>());
+        assert_eq!(get_point(&loaded, 2).to_array(), [1.0, 0.0, 0.0]);
+        assert_eq!(loaded[SIZE_VERTEX+3..SIZE_VERTEX+6], [1.0, 0.0, 0.0]);
+    }
+}
@@ -0,0 +1,141 @@
+use std::ops::{Add, Sub, Mul, Div, Neg};
+
+/// A range of real numbers [min, max].
+/// The result of an operation on intervals contains every result
+/// of the operation on numbers taken in the intervals
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Interval {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Interval {
+    pub fn new(min: f32, max: f32) -> Self {
+        Interval {min, max}
+    }
+    /// interval containing a single number
+    pub fn point(x: f32) -> Self {
+        Interval {min: x, max: x}
+    }
+    pub fn everything() -> Self {
+        Interval {min: f32::NEG_INFINITY, max: f32::INFINITY}
+    }
+    pub fn contains(&self, x: f32) -> bool {
+        self.min <= x && x <= self.max
+    }
+    pub fn abs(self) -> Self {
+        if self.min >= 0.0 {
+            self
+        }
+        else if self.max <= 0.0 {
+            -self
+        }
+        else {
+            Interval::new(0.0, f32::max(-self.min, self.max))
+        }
+    }
+    /// more precise than `self*self`, because both factors are the same number
+    pub fn square(self) -> Self {
+        let a = self.abs();
+        Interval::new(a.min*a.min, a.max*a.max)
+    }
+    /// the negative part is ignored
+    pub fn sqrt(self) -> Self {
+        Interval::new(self.min.max(0.0).sqrt(), self.max.max(0.0).sqrt())
+    }
+    pub fn min(self, other: Self) -> Self {
+        Interval::new(f32::min(self.min, other.min), f32::min(self.max, other.max))
+    }
+    pub fn max(self, other: Self) -> Self {
+        Interval::new(f32::max(self.min, other.min), f32::max(self.max, other.max))
+    }
+}
+
+impl Add for Interval {
+    type Output = Self;
+    fn add(self, other: Self) -> Self {
+        Interval::new(self.min + other.min, self.max + other.max)
+    }
+}
+
+impl Sub for Interval {
+    type Output = Self;
+    fn sub(self, other: Self) -> Self {
+        Interval::new(self.min - other.max, self.max - other.min)
+    }
+}
+
+impl Neg for Interval {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Interval::new(-self.max, -self.min)
+    }
+}
+
+impl Mul for Interval {
+    type Output = Self;
+    fn mul(self, other: Self) -> Self {
+        let products = [
+            self.min*other.min,
+            self.min*other.max,
+            self.max*other.min,
+            self.max*other.max,
+        ];
+        Interval::new(
+            products.iter().cloned().fold(f32::INFINITY, f32::min),
+            products.iter().cloned().fold(f32::NEG_INFINITY, f32::max),
+        )
+    }
+}
+
+impl Div for Interval {
+    type Output = Self;
+    fn div(self, other: Self) -> Self {
+        if other.contains(0.0) {
+            // the quotient can be as big as we want
+            Interval::everything()
+        }
+        else {
+            self * Interval::new(1.0/other.max, 1.0/other.min)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interval;
+    use super::super::random::Rng;
+
+    fn rand_interval(rng: &mut Rng) -> Interval {
+        let (a, b) = (rng.float()*10.0 - 5.0, rng.float()*10.0 - 5.0);
+        Interval::new(a.min(b), a.max(b))
+    }
+
+    fn rand_in(rng: &mut Rng, i: Interval) -> f32 {
+        i.min + (i.max - i.min)*rng.float()
+    }
+
+    #[test]
+    fn operations_contain_results() {
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            let (a, b) = (rand_interval(&mut rng), rand_interval(&mut rng));
+            let (x, y) = (rand_in(&mut rng, a), rand_in(&mut rng, b));
+
+            assert!((a + b).contains(x + y));
+            assert!((a - b).contains(x - y));
+            assert!((a * b).contains(x * y));
+            assert!((-a).contains(-x));
+            assert!(a.abs().contains(x.abs()));
+            assert!(a.square().contains(x*x));
+            assert!(a.min(b).contains(x.min(y)));
+            assert!(a.max(b).contains(x.max(y)));
+            if x >= 0.0 {
+                assert!(a.sqrt().contains(x.sqrt()));
+            }
+            if y != 0.0 {
+                assert!((a / b).contains(x / y));
+            }
+        }
+    }
+}
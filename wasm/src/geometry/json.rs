@@ -0,0 +1,370 @@
+//! The JSON values of glTF, written and parsed without a serialization library
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// the keys stay in the order of insertion
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn object(pairs: Vec<(&str, Json)>) -> Self {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// add a key at the end of an object
+    pub fn insert(&mut self, key: &str, value: Json) {
+        match self {
+            Json::Object(pairs) => pairs.push((key.to_string(), value)),
+            _ => panic!("cannot add {} to a value that is not an object", key),
+        }
+    }
+
+    /// the value of a key, if this is an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        self.as_f64().filter(|x| x.fract() == 0.0 && *x >= 0.0).map(|x| x as usize)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// the elements of an array, or nothing for other values
+    pub fn elements(&self) -> &[Json] {
+        match self {
+            Json::Array(values) => values,
+            _ => &[],
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {text: text.as_bytes(), position: 0, depth: 0};
+        let value = parser.value()?;
+        parser.skip_spaces();
+        if parser.position != text.len() {
+            return Err(parser.error("end of the text"))
+        }
+        Ok(value)
+    }
+}
+
+/// the arrays and objects nested deeper are refused, so that the recursion does not overflow the stack
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    text: &'a [u8],
+    position: usize,
+    // number of arrays and objects around the current value
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, expected: &str) -> String {
+        format!("expected {} at byte {} of the json", expected, self.position)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.text.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_spaces();
+        self.text.get(self.position).copied()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("'{}'", c as char)))
+        }
+        self.position += 1;
+        Ok(())
+    }
+
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        if !self.text[self.position..].starts_with(word.as_bytes()) {
+            return Err(self.error(word))
+        }
+        self.position += word.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        if matches!(self.peek(), Some(b'[') | Some(b'{')) {
+            if self.depth == MAX_DEPTH {
+                return Err(format!("more than {} nested values at byte {} of the json", MAX_DEPTH, self.position))
+            }
+            self.depth += 1;
+            let value = self.container();
+            self.depth -= 1;
+            return value
+        }
+        match self.peek() {
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            _ => self.number(),
+        }
+    }
+
+    // an array or an object
+    fn container(&mut self) -> Result<Json, String> {
+        match self.peek() {
+            Some(b'[') => {
+                self.position += 1;
+                let mut values = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(values))
+                }
+                loop {
+                    values.push(self.value()?);
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(values))
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut pairs = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(pairs))
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("a key"))
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    pairs.push((key, self.value()?));
+                    match self.peek() {
+                        Some(b',') => self.position += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(pairs))
+            }
+            _ => Err(self.error("'[' or '{'")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.position;
+        while self.text.get(self.position).is_some_and(|c| c.is_ascii_digit() || b"+-.eE".contains(c)) {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.position]).ok()
+            .and_then(|s| s.parse().ok())
+            .map(Json::Number)
+            .ok_or_else(|| {self.position = start; self.error("a value")})
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self.text.get(self.position..self.position+4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok())
+            .ok_or_else(|| self.error("4 hexadecimal digits"))?;
+        self.position += 4;
+        Ok(digits)
+    }
+
+    // the position is on the opening quote
+    fn string(&mut self) -> Result<String, String> {
+        self.position += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let c = *self.text.get(self.position).ok_or_else(|| self.error("'\"'"))?;
+            self.position += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self.text.get(self.position).ok_or_else(|| self.error("an escaped character"))?;
+                    self.position += 1;
+                    let c = match escaped {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let mut code = self.hex()?;
+                            // characters outside of the basic plane are written as 2 surrogates
+                            if (0xD800..0xDC00).contains(&code) && self.text[self.position..].starts_with(b"\\u") {
+                                self.position += 2;
+                                let low = self.hex()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    self.position -= 4;
+                                    return Err(self.error("a low surrogate"))
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        c => c as char,
+                    };
+                    let mut buffer = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+                }
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("utf-8"))
+    }
+}
+
+impl From<f64> for Json {
+    fn from(x: f64) -> Self {Json::Number(x)}
+}
+
+impl From<f32> for Json {
+    fn from(x: f32) -> Self {Json::Number(x as f64)}
+}
+
+impl From<usize> for Json {
+    fn from(x: usize) -> Self {Json::Number(x as f64)}
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {Json::String(s.to_string())}
+}
+
+impl<T: Into<Json>> From<Vec<T>> for Json {
+    fn from(v: Vec<T>) -> Self {Json::Array(v.into_iter().map(Into::into).collect())}
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+/// compact JSON, without spaces
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            // JSON has no infinity
+            Json::Number(x) if !x.is_finite() => write!(f, "null"),
+            Json::Number(x) if x.fract() == 0.0 && x.abs() < 1e15 => write!(f, "{}", *x as i64),
+            Json::Number(x) => write!(f, "{}", x),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, v) in values.iter().enumerate() {
+                    if i > 0 {write!(f, ",")?;}
+                    write!(f, "{}", v)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in pairs.iter().enumerate() {
+                    if i > 0 {write!(f, ",")?;}
+                    write_string(f, k)?;
+                    write!(f, ":{}", v)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+
+    #[test]
+    fn display() {
+        let mut value = Json::object(vec![
+            ("name", "a \"cube\"\n".into()),
+            ("count", 3usize.into()),
+            ("min", vec![-0.5f32, 1.0].into()),
+        ]);
+        value.insert("extras", Json::Array(vec![Json::Null]));
+        assert_eq!(
+            value.to_string(),
+            r#"{"name":"a \"cube\"\n","count":3,"min":[-0.5,1],"extras":[null]}"#
+        );
+    }
+
+    #[test]
+    fn parse() {
+        let text = r#" { "a" : [1, -2.5e1, true, null], "b": {"c": "\u00e9\n\"", "d": []}, "e": {} } "#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(value.get("a").unwrap().elements().len(), 4);
+        assert_eq!(value.get("a").unwrap().elements()[1].as_f64(), Some(-25.0));
+        assert_eq!(value.get("a").unwrap().elements()[2].as_bool(), Some(true));
+        assert_eq!(value.get("b").and_then(|b| b.get("c")).and_then(Json::as_str), Some("é\n\""));
+        assert_eq!(value.get("e"), Some(&Json::Object(vec![])));
+
+        // the text written by `Display` is parsed back
+        assert_eq!(Json::parse(&value.to_string()).unwrap(), value);
+
+        for wrong in &["", "[1,", "{\"a\" 1}", "[1] 2", "tru", "\"abc", "{1: 2}"] {
+            assert!(Json::parse(wrong).is_err(), "{}", wrong);
+        }
+    }
+
+    #[test]
+    fn surrogates() {
+        assert_eq!(Json::parse(r#""\ud83d\ude00""#).unwrap().as_str(), Some("\u{1f600}"));
+        // the second code is not a low surrogate
+        assert!(Json::parse(r#""\ud83d\u0041""#).is_err());
+        assert!(Json::parse(r#""\ud83d\ud83d""#).is_err());
+        // a lone surrogate is replaced
+        assert_eq!(Json::parse(r#""\ud83d""#).unwrap().as_str(), Some("\u{fffd}"));
+    }
+
+    #[test]
+    fn nesting_depth() {
+        let nested = |n: usize| format!("{}{}", "[".repeat(n), "]".repeat(n));
+        assert!(Json::parse(&nested(super::MAX_DEPTH)).is_ok());
+        assert!(Json::parse(&nested(super::MAX_DEPTH + 1)).unwrap_err().contains("nested"));
+        assert!(Json::parse(&"[{\"a\":".repeat(100_000)).is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use super::V3;
+use super::Range;
+use super::Dist;
+use super::ray::gradient;
+
+/// A function that is not a true distance, but whose gradient is never
+/// greater than `bound`. Dividing by the bound gives a value that is never
+/// greater than the real distance, so cells are classified correctly
+/// and sphere tracing never goes through the surface
+#[derive(Clone, Debug)]
+pub struct Lipschitz<D> {
+    pub shape: D,
+    pub bound: f32,
+}
+
+impl<D: Dist> Lipschitz<D> {
+    pub fn new(shape: D, bound: f32) -> Self {
+        assert!(bound > 0.0, "the lipschitz bound must be positive");
+        Lipschitz {shape, bound}
+    }
+
+    /// Use the worst gradient found in a range as the bound.
+    /// It is only an estimation: use a margin greater than 1 for safety
+    pub fn estimate(shape: D, range: &Range, margin: f32) -> Self {
+        let worst = max_gradient(&shape, range, 16).norm;
+        Lipschitz::new(shape, f32::max(worst, 1.0)*margin)
+    }
+}
+
+impl<D: Dist> Dist for Lipschitz<D> {
+    fn dist(&self, point: V3) -> f32 {
+        self.shape.dist(point) / self.bound
+    }
+}
+
+/// the steepest gradient found in a range
+#[derive(Copy, Clone, Debug)]
+pub struct GradientReport {
+    pub norm: f32,
+    pub point: V3,
+}
+
+/// Sample the gradient of a field on a grid of `n*n*n` points inside a range.
+/// For a true distance, the result is 1 (or less for the inside of some shapes)
+pub fn max_gradient(shape: &impl Dist, range: &Range, n: usize) -> GradientReport {
+    let d = range.diagonal();
+    let step = |i: usize| (i as f32 + 0.5) / (n as f32);
+    let mut worst = GradientReport {norm: 0.0, point: range.center()};
+    for i in 0..n {
+        for j in 0..n {
+            for k in 0..n {
+                let point = range.smaller_corner + V3::new(d.x*step(i), d.y*step(j), d.z*step(k));
+                let norm = gradient(shape, point).norm();
+                if norm > worst.norm {
+                    worst = GradientReport {norm, point};
+                }
+            }
+        }
+    }
+    worst
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Lipschitz, max_gradient, V3, Range};
+
+    fn cube() -> Range {
+        Range::new(V3::new(-2.0, -2.0, -2.0), V3::new(2.0, 2.0, 2.0))
+    }
+
+    #[test]
+    fn true_distance() {
+        let sphere = |p: V3| p.norm() - 1.0;
+        let report = max_gradient(&sphere, &cube(), 10);
+        assert!((report.norm - 1.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn scaled_field() {
+        // the level sets are the same as the sphere, but the values are 3 times bigger
+        let steep = |p: V3| 3.0*(p.norm() - 1.0) + 0.2*(4.0*p.x).sin();
+        let report = max_gradient(&steep, &cube(), 10);
+        assert!(report.norm > 2.5);
+
+        let fixed = Lipschitz::estimate(steep, &cube(), 1.1);
+        assert!(max_gradient(&fixed, &cube(), 10).norm <= 1.0);
+        assert!(fixed.bound >= report.norm);
+    }
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use super::V3;
+use super::Range;
+use super::Dist;
+use super::get_point;
+use super::bvh::Bvh;
+use super::grid::GridSdf;
+
+/// Signed distance to a closed triangle mesh, negative inside.
+///
+/// The closest point is found with a `Bvh`. The sign comes from the angle weighted
+/// pseudo-normal of the closest vertex, edge or face (Bærentzen and Aanæs, 2005):
+/// it is exact as long as the mesh is closed and its triangles turn towards the outside.
+/// The vertices at the same position are merged first, so that the neighbours
+/// of each edge and vertex are known
+#[derive(Clone, Debug)]
+pub struct MeshSdf {
+    bvh: Bvh,
+    positions: Vec<V3>,
+    // corners of each triangle, after merging
+    triangles: Vec<[usize; 3]>,
+    face_normals: Vec<V3>,
+    vertex_normals: Vec<V3>,
+    // sum of the normals of the 2 faces of an edge, by ordered vertices
+    edge_normals: HashMap<(usize, usize), V3>,
+}
+
+fn edge(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+fn angle(u: V3, v: V3) -> f32 {
+    let cos = V3::dot(u, v) / (u.norm() * v.norm());
+    if cos.is_nan() {0.0} else {cos.clamp(-1.0, 1.0).acos()}
+}
+
+impl MeshSdf {
+    pub fn new(points: &[f32], indices: &[u16]) -> Self {
+        // merge the vertices with the same position
+        let mut positions = Vec::new();
+        let mut merged = HashMap::new();
+        let triangles: Vec<[usize; 3]> = indices.chunks_exact(3).map(|t| {
+            [0, 1, 2].map(|k| {
+                let p = get_point(points, t[k]);
+                *merged.entry(p.to_array().map(f32::to_bits)).or_insert_with(|| {
+                    positions.push(p);
+                    positions.len() - 1
+                })
+            })
+        }).collect();
+
+        let mut face_normals = Vec::with_capacity(triangles.len());
+        let mut vertex_normals = vec![V3::null(); positions.len()];
+        let mut edge_normals = HashMap::new();
+        for t in &triangles {
+            let [a, b, c] = t.map(|i| positions[i]);
+            let cross = V3::cross(b-a, c-a);
+            // degenerate triangles have no direction
+            let normal = if cross.norm() > 0.0 {cross.scale(1.0 / cross.norm())} else {V3::null()};
+            face_normals.push(normal);
+
+            for k in 0..3 {
+                let (i, j, l) = (t[k], t[(k+1)%3], t[(k+2)%3]);
+                vertex_normals[i] += normal.scale(angle(positions[j] - positions[i], positions[l] - positions[i]));
+                *edge_normals.entry(edge(i, j)).or_insert_with(V3::null) += normal;
+            }
+        }
+
+        MeshSdf {bvh: Bvh::new(points, indices), positions, triangles, face_normals, vertex_normals, edge_normals}
+    }
+
+    /// The pseudo-normal of the feature of a triangle where the closest point is:
+    /// a vertex, an edge or the inside of the face
+    fn pseudo_normal(&self, triangle: usize, weights: [f32; 3]) -> V3 {
+        const EPSILON: f32 = 1e-5;
+        let t = self.triangles[triangle];
+        let zeros: Vec<usize> = (0..3).filter(|&k| weights[k] < EPSILON).collect();
+        match zeros.as_slice() {
+            // on the vertex that is not zero
+            [a, b] => self.vertex_normals[t[3 - a - b]],
+            // on the edge opposite to the vertex
+            [a] => self.edge_normals[&edge(t[(a+1)%3], t[(a+2)%3])],
+            _ => self.face_normals[triangle],
+        }
+    }
+
+    /// Sample the distance on a grid of `size` points, to evaluate it faster,
+    /// for example in `Octree::new_from_dist`
+    pub fn bake(&self, range: Range, size: [usize; 3]) -> GridSdf {
+        GridSdf::sample(self, range, size)
+    }
+}
+
+/// barycentric coordinates of a point of a triangle
+fn barycentric(p: V3, [a, b, c]: [V3; 3]) -> [f32; 3] {
+    let (ab, ac, ap) = (b - a, c - a, p - a);
+    let (d00, d01, d11) = (V3::dot(ab, ab), V3::dot(ab, ac), V3::dot(ac, ac));
+    let (d20, d21) = (V3::dot(ap, ab), V3::dot(ap, ac));
+    let denominator = d00*d11 - d01*d01;
+    if denominator == 0.0 {
+        return [1.0, 0.0, 0.0]
+    }
+    let v = (d11*d20 - d01*d21) / denominator;
+    let w = (d00*d21 - d01*d20) / denominator;
+    [1.0 - v - w, v, w]
+}
+
+impl Dist for MeshSdf {
+    fn dist(&self, point: V3) -> f32 {
+        let (triangle, closest) = match self.bvh.closest_point(point) {
+            Some(found) => found,
+            None => return f32::INFINITY,
+        };
+        let triangle = triangle as usize;
+        let corners = self.triangles[triangle].map(|i| self.positions[i]);
+        let normal = self.pseudo_normal(triangle, barycentric(closest, corners));
+
+        let d = (point - closest).norm();
+        if V3::dot(point - closest, normal) < 0.0 {-d} else {d}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MeshSdf;
+    use super::super::{V3, Range, Dist};
+    use super::super::sdf::Sdf;
+    use super::super::octree::Octree;
+    use super::super::import::from_obj;
+    use super::super::random::Rng;
+
+    fn unit_cube() -> MeshSdf {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        from_obj(include_bytes!("../../fixtures/cube.obj"), &mut points, &mut indices).unwrap();
+        MeshSdf::new(&points, &indices)
+    }
+
+    #[test]
+    fn cube_is_exact() {
+        let mut rng = Rng::new(1);
+        let mesh = unit_cube();
+        let cube = Sdf::Cuboid {half_size: V3::new(0.5, 0.5, 0.5)}.translate(V3::new(0.5, 0.5, 0.5));
+        for _ in 0..500 {
+            let p = rng.unit_v3().scale(1.5) + V3::new(0.5, 0.5, 0.5);
+            assert!((mesh.dist(p) - cube.dist(p)).abs() < 1e-5, "{:?}", p);
+        }
+        // closest to a vertex, an edge and a face, inside and outside
+        for &p in &[
+            V3::new(-0.3, -0.2, -0.1),
+            V3::new(1.2, -0.2, 0.5),
+            V3::new(0.5, 0.5, 1.3),
+            V3::new(0.1, 0.1, 0.1),
+            V3::new(0.5, 0.9, 0.5),
+        ] {
+            assert!((mesh.dist(p) - cube.dist(p)).abs() < 1e-5, "{:?}", p);
+        }
+    }
+
+    fn n_triangles(shape: impl Dist + Sync, range: Range) -> f32 {
+        let mut indices = Vec::new();
+        Octree::new_from_dist(shape, range, 4).triangulate(&mut Vec::new(), &mut indices).unwrap();
+        indices.len() as f32 / 3.0
+    }
+
+    #[test]
+    fn octree_of_a_mesh() {
+        let mut rng = Rng::new(2);
+        // the blocky sphere of an octree is a closed mesh
+        let sphere = Sdf::Sphere {radius: 0.6};
+        let range = Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0));
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        Octree::new_from_dist(sphere.clone(), range, 4).triangulate(&mut points, &mut indices).unwrap();
+        let mesh = MeshSdf::new(&points, &indices);
+
+        // the cells are 0.125 wide
+        for _ in 0..200 {
+            let p = rng.unit_v3().scale(0.9);
+            assert!((mesh.dist(p) - sphere.dist(p)).abs() < 0.125*3f32.sqrt());
+        }
+
+        // the baked grid gives almost the same octree as the mesh
+        let grid = mesh.bake(range, [33, 33, 33]);
+        assert!(grid.dist(V3::null()) < -0.5);
+        let (exact, baked) = (n_triangles(mesh, range), n_triangles(grid, range));
+        assert!((exact - baked).abs() < 0.1*exact, "{} {}", exact, baked);
+    }
+}
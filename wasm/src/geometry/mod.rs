@@ -30,26 +30,93 @@ macro_rules! push_point {
     };
 }
 
-use super::console;
-
 mod noise;
-mod random;
+pub mod random;
 #[macro_use]
-mod octree;
+pub mod octree;
 
 mod vec_3d;
-use vec_3d::V3;
-use vec_3d::Range;
+pub use vec_3d::V3;
+pub use vec_3d::Range;
 use vec_3d::Dist;
 
-use octree::Octree;
+pub mod bounds;
+pub mod ray;
+pub mod bvh;
+pub mod interval;
+pub mod program;
+pub mod implicit;
+pub mod lipschitz;
+pub mod sdf;
+pub mod grid;
+pub mod mesh_sdf;
+pub mod simplify;
+pub mod smooth;
+pub mod surface;
+pub mod vegetation;
+pub mod scatter;
+pub mod export;
+mod json;
+pub mod import;
+use sdf::Sdf;
+use surface::{Surface, Attributes};
+
+use octree::{Octree, RegionMesh};
+use octree::build::OctreeBuilder;
 
 
 // a vertex currently has 12 values: x, y, z  |  r, g, b and so on
-const SIZE_VERTEX : usize = 12;
+pub const SIZE_VERTEX : usize = 12;
+// where the ondulation vector, its frequency and its phase are in a vertex
+pub const ONDULATION: usize = 6;
+pub const FREQUENCY: usize = 9;
+pub const PHASE: usize = 10;
+
+
+/// Fail if the vertices of a buffer cannot all be indexed with 16 bits
+pub fn check_indexable(points: &[f32]) -> Result<(), String> {
+    check_vertex_count(points.len()/SIZE_VERTEX)
+}
+
+fn check_vertex_count(n: usize) -> Result<(), String> {
+    if n > u16::MAX as usize + 1 {
+        return Err(format!("{} vertices cannot be indexed with 16 bits", n))
+    }
+    Ok(())
+}
+
+/// Add a mesh at the end of the buffers, with its indices moved after the vertices already there.
+/// Fail without changing anything if there would be too many vertices to index them with 16 bits
+pub fn append(points: &mut Vec<f32>, indices: &mut Vec<u16>, other_points: &[f32], other_indices: &[u16]) -> Result<(), String> {
+    let offset = points.len()/SIZE_VERTEX;
+    check_vertex_count(offset + other_points.len()/SIZE_VERTEX)?;
+    points.extend_from_slice(other_points);
+    indices.extend(other_indices.iter().map(|&i| i + offset as u16));
+    Ok(())
+}
 
+/// Keep only the vertices used by `triangles`, in the same order, and index the triangles with them.
+/// The triangles use the indices of the vertices in `points` before the call
+pub fn keep_used_vertices(points: &mut Vec<f32>, indices: &mut Vec<u16>, triangles: &[[usize; 3]]) {
+    let n_vertices = points.len()/SIZE_VERTEX;
+    let mut used = vec![false; n_vertices];
+    for &i in triangles.iter().flatten() {
+        used[i] = true;
+    }
+    let mut new_index = vec![0; n_vertices];
+    let mut kept = Vec::new();
+    for i in (0..n_vertices).filter(|&i| used[i]) {
+        new_index[i] = kept.len()/SIZE_VERTEX;
+        kept.extend_from_slice(&points[i*SIZE_VERTEX..(i+1)*SIZE_VERTEX]);
+    }
+    *points = kept;
+    indices.clear();
+    for &i in triangles.iter().flatten() {
+        push_index!(indices, [new_index[i]]);
+    }
+}
 
-fn get_point(points: &Vec<f32>, i: u16) -> V3 {
+fn get_point(points: &[f32], i: u16) -> V3 {
     let i = i as usize*SIZE_VERTEX;
     V3::new(
         points[i],
@@ -58,85 +125,227 @@ fn get_point(points: &Vec<f32>, i: u16) -> V3 {
     )
 }
 
-fn set_point(points: &mut Vec<f32>, i:u16, p: V3) {
-    let i = i as usize*SIZE_VERTEX;
-    points[i  ] = p.x;
-    points[i+1] = p.y;
-    points[i+2] = p.z;
+/// where the vertex shader draws the point `i` at `time`, in seconds:
+/// it is moved by `cos(time*frequency + phase) * ondulation_vec`
+pub fn displaced_point(points: &[f32], i: u16, time: f32) -> V3 {
+    let j = i as usize*SIZE_VERTEX;
+    let ondulation = V3::new(points[j+ONDULATION], points[j+ONDULATION+1], points[j+ONDULATION+2]);
+    let (frequency, phase) = (points[j+FREQUENCY], points[j+PHASE]);
+    get_point(points, i) + ondulation.scale((time*frequency + phase).cos())
 }
 
 
+
 fn pseudo_sphere(points: &mut Vec<f32>, indices: &mut Vec<u16>, center: V3, radius: f32, color: (f32, f32, f32)) {
     let frequency = 0.3+random::rand_float();
-    let i0 = points.len()/SIZE_VERTEX;
     let n = 30usize;
-    let pi = 3.15;
 
     let range = Range::new(V3::new(-2.1, -2.1, -2.1), V3::new(2.1, 2.1, 2.1));
     let shape_noise = noise::Perlin::new(range, (4, 4, 4), 2.4);
     let phase_noise = noise::Perlin::new(range, (8, 8, 8), 1.5);
 
-    // create points
-    for long in 0..n {
-        for lat in 0..n {
-            let a1 = lat as f32 / (n as f32) * 2.0 * pi;
-            let a2 = long as f32 / (n as f32 -1.0) * pi;
+    // the noise is sampled on the sphere of radius 1
+    let mut sphere = Surface::uv_sphere(n, n).scale(radius);
+    sphere.displace(|p| shape_noise.noise(p.scale(1.0/radius)));
+
+    sphere.translate(center).push(points, indices, |p, _| {
+        let direction = (p - center).normalize();
+        Attributes {
+            color: V3::from(color),
+            ondulation: direction.scale(0.2)+random::rand_v3().scale(0.3),
+            frequency,
+            phase: phase_noise.noise(direction),
+        }
+    });
+}
 
-            let p = V3::new(
-                a1.cos()*a2.sin(), 
-                a1.sin()*a2.sin(),
-                a2.cos()         ,
-            );
+/// Triangles added to the vertex and index buffers a few at a time,
+/// so that the generation of the world does not freeze the page
+pub trait TriangleBuilder {
+    /// add the next triangles, return true when they are all there
+    fn step(&mut self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> bool;
+}
 
-            let rad_vector = p.scale(radius+shape_noise.noise(p));
+/// add all the triangles of a builder at once
+pub fn build_all(mut builder: impl TriangleBuilder, points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+    while !builder.step(points, indices) {}
+}
 
-            let v = p.scale(0.2)+random::rand_v3().scale(0.3);
+/// spheres floating above the ground, one per step
+struct Spheres {
+    left: usize,
+}
 
-            push_point!(points,
-                (center+rad_vector), // coordinates
-                (V3::from(color)), // color
-                v, // 
-                [frequency, phase_noise.noise(p), 0.0]
-            );
+impl TriangleBuilder for Spheres {
+    fn step(&mut self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> bool {
+        use random::rand_float;
+        if self.left > 0 {
+            let v = random::rand_v3().scale(20.0+rand_float()*40.0);
+            let center = V3::new(v.x, v.y, 2.0+rand_float()*8.0);
+            let color = (rand_float(), rand_float(), rand_float());
+            pseudo_sphere(points, indices, center, 0.5+rand_float(), color);
+            self.left -= 1;
         }
+        self.left == 0
     }
+}
 
-    // create triangles
-    for long in 0..n-1 {
-        for lat in 0..n-1 {
-            let i = i0 + long*n+lat;
-            let p = [i, i+1, i+n, i+n+1];
-            push_index!(indices, p.[0, 3, 1, 0, 2, 3]);
+pub fn test_sphere_builder() -> Box<dyn TriangleBuilder> {
+    Box::new(Spheres {left: 30})
+}
+
+pub fn test_sphere(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+    build_all(Spheres {left: 30}, points, indices)
+}
+
+/// bushes drawn by an L-system and trees grown by space colonization, swaying in the wind.
+/// A bush or a tree is added at each step
+struct Trees {
+    rng: random::Rng,
+    wind: V3,
+    bush: String,
+    bushes_left: usize,
+    trees_left: usize,
+}
+
+impl Trees {
+    fn new() -> Self {
+        let bush = vegetation::LSystem::new("X").rule('X', "F[+!X][-!X]&[^!X]/FX").expand(3);
+        Trees {rng: random::Rng::from_entropy(), wind: V3::new(1.0, 0.3, 0.0).normalize(), bush, bushes_left: 4, trees_left: 3}
+    }
+}
+
+impl TriangleBuilder for Trees {
+    fn step(&mut self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> bool {
+        use random::rand_float;
+        use vegetation::{Turtle, Colonization, Sway, tubes, ellipsoid_points};
+
+        let wind = self.wind;
+        if self.bushes_left > 0 {
+            let turtle = Turtle {step: 0.25, angle: 0.45, radius: 0.06, thinning: 0.7};
+            let v = random::rand_v3().scale(4.0+rand_float()*6.0);
+            let sway = Sway {direction: wind, amplitude: 0.04, frequency: 1.0+rand_float(), phase: rand_float()*6.0};
+            tubes(points, indices, &turtle.draw(&self.bush, V3::new(v.x, v.y, 0.0)), 5, V3::new(0.25, 0.5, 0.2), sway);
+            self.bushes_left -= 1;
+        }
+        else if self.trees_left > 0 {
+            let colonization = Colonization {influence: 1.5, kill: 0.3, step: 0.25, max_iterations: 100, tip_radius: 0.02};
+            let v = random::rand_v3().scale(8.0+rand_float()*8.0);
+            let root = V3::new(v.x, v.y, 0.0);
+            let crown = ellipsoid_points(&mut self.rng, root + V3::new(0.0, 0.0, 3.5), V3::new(1.5, 1.5, 1.2), 150);
+            let sway = Sway {direction: wind, amplitude: 0.02, frequency: 0.6+rand_float()*0.4, phase: rand_float()*6.0};
+            tubes(points, indices, &colonization.grow(root, crown), 6, V3::new(0.35, 0.25, 0.15), sway);
+            self.trees_left -= 1;
         }
+        self.bushes_left == 0 && self.trees_left == 0
     }
-    for long in 0..n-2 {
-        let i = i0 + long*n;
-        let p = [i+n-1, i+n, i+n+n-1, i+n+n];
-            push_index!(indices, p.[0, 3, 1,   0, 2, 3]);
+}
+
+pub fn test_trees_builder() -> Box<dyn TriangleBuilder> {
+    Box::new(Trees::new())
+}
+
+pub fn test_trees(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+    build_all(Trees::new(), points, indices)
+}
+
+/// number of bushes added to the forest at each step
+const BUSHES_PER_STEP: usize = 200;
+
+enum ForestStage {
+    // make the terrain and the bush
+    Terrain,
+    // scatter the bushes and add the terrain
+    Scatter,
+    // add the bushes from the `i`th
+    Place(Vec<scatter::Instance>, usize),
+}
+
+/// A terrain with bushes on the gentle slopes of the valleys, thinned out by a noise.
+/// The bushes are added a few hundred at a time
+struct Forest {
+    stage: ForestStage,
+    rng: random::Rng,
+    terrain: Vec<f32>,
+    terrain_indices: Vec<u16>,
+    bush: Vec<f32>,
+    bush_indices: Vec<u16>,
+}
+
+impl Forest {
+    fn new() -> Self {
+        Forest {
+            stage: ForestStage::Terrain,
+            rng: random::Rng::from_entropy(),
+            terrain: Vec::new(),
+            terrain_indices: Vec::new(),
+            bush: Vec::new(),
+            bush_indices: Vec::new(),
+        }
     }
 }
 
-pub fn test_sphere(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
-    use random::rand_float;
-    for _ in 0..30 {
-        let v = random::rand_v3().scale(20.0+rand_float()*40.0);
-        let center = V3::new(v.x, v.y, 2.0+rand_float()*8.0);
-        let color = (rand_float(), rand_float(), rand_float());
-        pseudo_sphere(points, indices, center, 0.5+rand_float(), color);
+impl TriangleBuilder for Forest {
+    fn step(&mut self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> bool {
+        use vegetation::{LSystem, Turtle, Sway, tubes};
+        use scatter::{Scatter, place, noise_density};
+
+        match &mut self.stage {
+            ForestStage::Terrain => {
+                rand_surface(&mut self.terrain, &mut self.terrain_indices);
+                let word = LSystem::new("X").rule('X', "F[+!X][-!X]&[^!X]/FX").expand(2);
+                let turtle = Turtle {step: 0.4, angle: 0.45, radius: 0.08, thinning: 0.7};
+                let sway = Sway {direction: V3::new(1.0, 0.3, 0.0).normalize(), amplitude: 0.04, frequency: 1.0, phase: 0.0};
+                tubes(&mut self.bush, &mut self.bush_indices, &turtle.draw(&word, V3::null()), 4, V3::new(0.25, 0.5, 0.2), sway);
+                self.stage = ForestStage::Scatter;
+                false
+            }
+            ForestStage::Scatter => {
+                let scatter = Scatter {
+                    spacing: 3.0,
+                    max_slope: 0.6,
+                    altitude: (-10.0, 2.0),
+                    scale: (0.7, 1.4),
+                    align: 0.3,
+                    ..Scatter::default()
+                };
+                let range = Range::new(V3::new(-50.0, -50.0, -20.0), V3::new(50.0, 50.0, 20.0));
+                let instances = scatter.scatter(&mut self.rng, &self.terrain, &self.terrain_indices, noise_density(range, (6, 6, 2)));
+                if let Err(e) = append(points, indices, &self.terrain, &self.terrain_indices) {
+                    log!("no room for the terrain of the forest: {}", e);
+                    return true
+                }
+                self.stage = ForestStage::Place(instances, 0);
+                false
+            }
+            ForestStage::Place(instances, next) => {
+                let batch = &instances[*next..instances.len().min(*next + BUSHES_PER_STEP)];
+                let placed = place(batch, &self.bush, &self.bush_indices, points, indices);
+                *next += placed;
+                if placed < batch.len() {
+                    log!("only {} bushes of {} fit in the forest", next, instances.len());
+                    return true
+                }
+                *next == instances.len()
+            }
+        }
     }
 }
 
-pub fn test_octree_shape(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+pub fn test_forest_builder() -> Box<dyn TriangleBuilder> {
+    Box::new(Forest::new())
+}
+
+pub fn test_forest(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+    build_all(Forest::new(), points, indices)
+}
+
+/// the octree of the world, built a few nodes at a time by the world generation
+pub fn test_octree_builder() -> OctreeBuilder {
     let range = Range::new(
         V3::new(-3.0, -3.0, -3.0),
         V3::new(3.0, 3.0, 3.0)
     );
-    let k = 0.7;
-    let clamp = |v, min, max| if v < min {min} else if v > max {max} else {v};
-    let smooth_min = |a:f32, b:f32| {
-        let h = clamp(0.5+0.5*(a-b)/k, 0.0, 1.0);
-        a*(1.0-h) + h*b -k*h*(1.0-h)
-    };
     let dist_function = 
         |p: V3| f32::min(
             f32::min(
@@ -145,18 +354,57 @@ pub fn test_octree_shape(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
             ),
             (p.x*p.x+p.y*p.y + p.z*p.z).sqrt() - 1.0,
         ) - 0.2;
-    let oct = Octree::new_from_dist(dist_function, range, 7);
-    oct.triangulate(points, indices);
+    OctreeBuilder::new(dist_function, range, 7)
+        // moss on the top, clay at the end of the arms
+        .with_materials(|p| if p.z > 0.9 {2} else if p.x.abs().max(p.z.abs()) > 2.0 {1} else {0})
+}
+
+pub fn test_octree() -> Octree {
+    test_octree_builder().build()
+}
+
+/// number of parts of the world made by `world_part`
+pub const WORLD_PARTS: usize = 8;
+
+/// The triangles of an eighth of the octree of the world, built and triangulated on their own
+/// (in a web worker), to merge them with `Triangulation::merge`
+pub fn world_part(part: usize) -> Result<RegionMesh, String> {
+    let builder = test_octree_builder();
+    let octant = builder.whole().octant(part);
+    // the cells around the part give the faces and the colors of its border
+    builder.with_focus(octant.expand(1)).build().mesh_region(&octant)
+}
+
+pub fn test_octree_shape(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+    if let Err(e) = test_octree().triangulate(points, indices) {
+        log!("cannot triangulate the octree: {}", e);
+    }
 }
 
 
 
+/// scene made of distance functions, that can be rendered without triangles
+pub fn test_sdf_scene() -> Sdf {
+    let ground = Sdf::Plane {normal: V3::new(0.0, 0.0, 1.0), offset: -1.5};
+    let blob = Sdf::Sphere {radius: 0.8}
+        .smooth_union(Sdf::Torus {major: 1.2, minor: 0.25}.rotate(V3::new(1.0, 0.0, 0.0), 0.5), 0.5)
+        .displace(0.05, 4.0)
+        .translate(V3::new(0.0, 3.0, 0.0));
+    let carved_box = Sdf::Cuboid {half_size: V3::new(0.7, 0.7, 0.7)}
+        .difference(Sdf::Sphere {radius: 0.9})
+        .translate(V3::new(-2.5, 4.0, -0.5));
+
+    ground.union(blob).union(carved_box)
+}
+
 pub fn rand_surface(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
     // we generate fractal noise with 2d slices of 3d perlin noise
     let range = Range::new(V3::new(-100.0, -100.0, -1.0), V3::new(100.0, 100.0, 1.0));
     let perlin_1 = noise::Perlin::new(range, (5, 5, 3), 15.0);
     let perlin_2 = noise::Perlin::new(range, (30, 30, 3), 5.5);
 
+    // 6.28 and not TAU: the exact constant would change the generated terrain
+    #[allow(clippy::approx_constant)]
     let phase_noise = noise::Perlin::new(range, (30, 30, 3), 6.28);
 
     let i0 = points.len()/SIZE_VERTEX;
@@ -192,7 +440,7 @@ pub fn rand_surface(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
 
 
 // shading algorithm
-pub fn shade(points: &mut Vec<f32>, indices: &Vec<u16>) {
+pub fn shade(points: &mut [f32], indices: &[u16]) {
     let light_dir : V3 = V3::new(0.3, 0.3, 0.3);
 
     // this vector will store the average normal of each point
@@ -220,3 +468,57 @@ pub fn shade(points: &mut Vec<f32>, indices: &Vec<u16>) {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{append, keep_used_vertices, test_forest, SIZE_VERTEX};
+
+    #[test]
+    fn append_checks_the_indices() {
+        let mut points = vec![0.0; 3*SIZE_VERTEX];
+        let mut indices = vec![0, 1, 2];
+        append(&mut points, &mut indices, &[1.0; 2*SIZE_VERTEX], &[1, 0, 1]).unwrap();
+        assert_eq!(indices, vec![0, 1, 2, 4, 3, 4]);
+
+        // 65536 vertices can be indexed, not 65537
+        let big = vec![0.0; (65536 - 5)*SIZE_VERTEX];
+        append(&mut points, &mut indices, &big, &[65530]).unwrap();
+        assert_eq!(indices.last(), Some(&65535));
+        assert!(append(&mut points, &mut indices, &[0.0; SIZE_VERTEX], &[0]).is_err());
+        assert_eq!(points.len(), 65536*SIZE_VERTEX);
+    }
+
+    #[test]
+    fn unused_vertices_are_removed() {
+        let mut points: Vec<f32> = (0..5).flat_map(|i| vec![i as f32; SIZE_VERTEX]).collect();
+        let mut indices = Vec::new();
+        keep_used_vertices(&mut points, &mut indices, &[[4, 1, 3], [3, 1, 4]]);
+        assert_eq!(indices, vec![2, 0, 1, 1, 0, 2]);
+        let first_values: Vec<f32> = points.chunks(SIZE_VERTEX).map(|v| v[0]).collect();
+        assert_eq!(first_values, vec![1.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn forest_fits_in_16_bits() {
+        let (mut points, mut indices) = (Vec::new(), Vec::new());
+        test_forest(&mut points, &mut indices);
+        // the terrain has 10000 vertices, and the bushes about 20000
+        let n = points.len()/SIZE_VERTEX;
+        assert!(n > 15000 && n <= 65536, "{} vertices", n);
+        assert!(indices.iter().all(|&i| (i as usize) < n));
+
+        // after other meshes, the bushes that do not fit are left out
+        let mut points = vec![0.0; 40000*SIZE_VERTEX];
+        let mut indices = Vec::new();
+        test_forest(&mut points, &mut indices);
+        let n = points.len()/SIZE_VERTEX;
+        assert!(n > 50000 && n <= 65536, "{} vertices", n);
+        assert!(indices.iter().all(|&i| i >= 40000 && (i as usize) < n));
+
+        // and the terrain that does not fit either
+        let mut points = vec![0.0; 60000*SIZE_VERTEX];
+        let mut indices = Vec::new();
+        test_forest(&mut points, &mut indices);
+        assert_eq!(points.len(), 60000*SIZE_VERTEX);
+        assert!(indices.is_empty());
+    }
+}
@@ -1,17 +1,22 @@
 use std::ops::{Index, IndexMut};
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::collections::HashMap;
 use array_init::array_init;
 
 use super::V3;
 use super::Dist;
 use super::Range;
-use super::random::rand_v3;
+use super::SIZE_VERTEX;
+use super::interval::Interval;
 
-use super::console;
+pub mod sculpt;
+pub mod serialize;
+pub mod build;
+
+/// the octrees are linked and triangulated by blocks of 2^BLOCK_DEPTH cells on each side
+pub const BLOCK_DEPTH: u8 = 4;
 
 // bool structure: intersection, union and negation
-trait BoolLike {
+pub trait BoolLike {
     fn union(a: Self, b: Self) -> Self;
     fn inter(a: Self, b: Self) -> Self;
     fn not(self) -> Self;
@@ -62,24 +67,60 @@ impl<T> IndexMut<CubeCorner> for [T; 8] {
     }
 }
 
-pub fn sub_corner(corner_pos: V3, half_size: V3, corner: [bool; 3]) -> V3 {
-    corner_pos + V3::new(
-        if corner[0] {half_size.x} else {0.0},
-        if corner[1] {half_size.y} else {0.0},
-        if corner[2] {half_size.z} else {0.0},
-    )
-}
-
 //              _     
 // _ _  ___  __| |___ 
 //| ' \/ _ \/ _` / -_)
 //|_||_\___/\__,_\___|
 
+/// position of a cell in the grid of the smallest cells
 type NodeIndex = [i32; 3];
 
-#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+/// position of a node in the arena of the octree
+type NodeId = usize;
+
+/// A box of cells, from `min` (included) to `max` (excluded)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CellRange {
+    pub min: [i32; 3],
+    pub max: [i32; 3],
+}
+
+impl CellRange {
+    /// the cells of a node of size `size` at `pos`
+    fn of_node(pos: NodeIndex, size: i32) -> Self {
+        CellRange {min: pos, max: pos.map(|x| x + size)}
+    }
+    #[cfg(test)]
+    fn contains(&self, pos: NodeIndex) -> bool {
+        (0..3).all(|d| self.min[d] <= pos[d] && pos[d] < self.max[d])
+    }
+    pub fn intersects(&self, other: &CellRange) -> bool {
+        (0..3).all(|d| self.min[d] < other.max[d] && other.min[d] < self.max[d])
+    }
+    pub fn union(self, other: CellRange) -> Self {
+        CellRange {
+            min: array_init(|d| self.min[d].min(other.min[d])),
+            max: array_init(|d| self.max[d].max(other.max[d])),
+        }
+    }
+    /// add n cells on each side
+    pub fn expand(self, n: i32) -> Self {
+        CellRange {min: self.min.map(|x| x - n), max: self.max.map(|x| x + n)}
+    }
+    /// the `i`th eighth of the range, with the same order as the children of a node
+    pub fn octant(&self, i: usize) -> Self {
+        let corner = CubeCorner(i).bools();
+        CellRange {
+            min: array_init(|d| if corner[d] {(self.min[d] + self.max[d]) / 2} else {self.min[d]}),
+            max: array_init(|d| if corner[d] {self.max[d]} else {(self.min[d] + self.max[d]) / 2}),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
 enum NodeState {
     Inside,
+    #[default]
     Outside
 }
 
@@ -95,37 +136,47 @@ impl NodeState {
     }
 }
 
-impl Default for NodeState {
-    fn default() -> Self {Outside}
+/// what a cell is made of
+pub type Material = u8;
+
+/// color of each material in the triangles
+fn default_palette() -> Vec<V3> {
+    vec![
+        V3::new(0.3, 0.3, 0.3),  // stone
+        V3::new(0.6, 0.45, 0.3), // clay
+        V3::new(0.2, 0.5, 0.2),  // moss
+        V3::new(0.7, 0.2, 0.15), // paint
+    ]
 }
 
-#[derive(Debug)]
-struct CellInfo<'octree> {
-    cube_indices: RefCell<[Option<usize>; 8]>,
-    neighbourgs: [[Result<&'octree CellInfo<'octree>, NodeState>; 2]; 3],
+#[derive(Clone, Debug)]
+struct CellInfo {
+    /// the 6 cells that share a face with this one, by dimension and side
+    neighbourgs: [[Result<NodeId, NodeState>; 2]; 3],
+    material: Material,
 }
 
-impl<'octree> Default for CellInfo<'octree> {
-    fn default() -> CellInfo<'octree> {
-        let neighbourgs = array_init(|_| array_init(|_| Err(Outside)));
-        CellInfo {cube_indices:RefCell::new([None; 8]), neighbourgs}
+impl Default for CellInfo {
+    fn default() -> CellInfo {
+        CellInfo {neighbourgs: [[Err(Outside); 2]; 3], material: 0}
     }
 }
 
 /// The Node object for the octree.
 /// it can be either:
-/// - a Cell (or leaf) that contain a refcell to a struct. This is the end of the recursion, at max
-/// depth
+/// - a Cell (or leaf) with the information needed for the triangulation.
+///   This is the end of the recursion, at max depth
 /// - a State, `Inside` or `Outside`.
-/// That means that this region of space is completely inside the shape or outside the shape
-/// - 8 Subcubes (a cube is splited into 2 in the 3 directions of space)
-enum Node<'octree> {
-    Sub([Box<Node<'octree>>; 8]), 
-    Cell(CellInfo<'octree>),
+///   That means that this region of space is completely inside the shape or outside the shape
+/// - 8 Subcubes (a cube is splited into 2 in the 3 directions of space), stored in the same arena
+#[derive(Clone, Debug)]
+enum Node {
+    Sub([NodeId; 8]),
+    Cell(CellInfo),
     Completely(NodeState),
 }
 
-impl <'octree> Node<'octree> where {
+impl Node {
     /// Get state of the cell. 
     /// if completely outside or inside, return it.
     /// otherwise null
@@ -136,247 +187,180 @@ impl <'octree> Node<'octree> where {
         else {None}
     }
 
-    /// index cell with 3 numbers
-    fn index(&self, id: NodeIndex, depth: u8) -> Result<&'octree CellInfo, NodeState> {
+    #[cfg(feature = "parallel")]
+    /// the same node, when its arena is moved at `offset` in a bigger one
+    fn shifted(self, offset: usize) -> Self {
         match self {
-            Node::Sub(cubes) => {
-                let m = 1 << (depth-1);
-                let corner = map_array(id, |v| v >= m);
-                cubes[CubeCorner::from(corner)]
-                    .index(
-                        map_array(id, |x| x%m),
-                        depth-1
-                    )
-            }
-            Node::Cell(x) => Ok(x),
-            Node::Completely(s) => Err(*s),
+            Node::Sub(cubes) => Node::Sub(cubes.map(|c| c + offset)),
+            node => node,
         }
     }
+}
 
-    /// index cell with 3 numbers
-    fn index_mut(&mut self, id: NodeIndex, depth: u8) -> Result<&'octree mut CellInfo, NodeState>{
-        match self {
-            Node::Sub(cubes) => {
-                let m = 1 << (depth-1);
-                let corner = map_array(id, |v| v >= m);
-                cubes[CubeCorner::from(corner)]
-                    .index_mut(
-                        map_array(id, |x| x%m),
-                        depth-1
-                    )
-            }
-            Node::Cell(x) => Ok(x),
-            Node::Completely(s) => Err(*s),
-        }
+/// The node of a region from the bounds of the distance function over it,
+/// or None if it must be split in 8 subcubes
+fn leaf(bounds: Interval, depth: u8) -> Option<Node> {
+    if bounds.min > 0.0 {
+        // if the function is positive everywhere, it is outside
+        Some(Node::Completely(Outside))
     }
-    /// get all references to the cells with corresponding index
-    /// useless ? 
-    fn get_cells_with_indices(&'octree self, id: NodeIndex, depth: u8, result: &mut Vec<(NodeIndex, &'octree CellInfo<'octree>)>) {
-        match self {
-            Node::Sub(cubes) => {
-                let m = 1 << (depth-1);
-                for i in 0..8 {
-                    let corner = CubeCorner(i).bools();
-                    let new_id = array_init(|d| id[d] + if corner[d] {m} else {0});
-                    cubes[i].get_cells_with_indices(new_id, depth-1, result);
-                }
-            }
-            Node::Cell(x) => result.push((id, x)),
-            _ => (),
-        }
+    else if bounds.max < 0.0 {
+        // same thing with opposite sign: we are inside
+        Some(Node::Completely(Inside))
     }
+    else if depth == 0 {
+        // if max depth, the surface goes through this cell
+        Some(Node::Cell(CellInfo::default()))
+    }
+    else {
+        None
+    }
+}
 
-    /// approximate a distance function.
-    /// TODO: use a Range instead of 2 V3
-    fn approximate(corner: V3, half_size: V3, shape: &impl Dist, depth: u8) -> Self {
-        // approximate distance function with octree
-        let center = corner + half_size;
-        // calculate the distance from the center to the nearest point of the shape
-        let dist = shape.dist(center);
-
-        // calculate the size of the diagonal (from the center to a corner)
-        let diag = half_size.norm();
+/// Approximate a distance function inside a range, and add the nodes to the arena.
+/// Return the id of the node of the whole range
+fn approximate(range: Range, shape: &(impl Dist + Sync), depth: u8, nodes: &mut Vec<Node>) -> NodeId {
+    // bounds of the function over the whole cell
+    let node = leaf(shape.bounds(&range), depth)
+        .unwrap_or_else(|| Node::Sub(subdivide(range, shape, depth, nodes)));
+    nodes.push(node);
+    nodes.len() - 1
+}
 
-        if dist > diag { 
-            // if distance is greater than the diagonal, it is outside
-            Node::Completely(Outside)
-        }
-        else if dist < -diag {
-            // same thing with opposite sign: we are inside
-            Node::Completely(Inside)
-        }
-        else {
-            if depth == 0 {
-                // if max depth, add the center of the cell as a leaf
-                Node::Cell(CellInfo::default())
-            }
-            else {
-                // Otherwise, generate 8 subcubes
-                Node::Sub(array_init(|i|
-                        Box::new(Node::approximate(
-                                sub_corner(corner, half_size, CubeCorner(i).into()),
-                                half_size.scale(0.5),
-                                shape, 
-                                depth-1)
-                        )
-                ))
+// with the `parallel` feature, the 8 children of the big cubes are computed on different threads
+#[cfg(feature = "parallel")]
+const PARALLEL_MIN_DEPTH: u8 = 3;
+
+fn subdivide(range: Range, shape: &(impl Dist + Sync), depth: u8, nodes: &mut Vec<Node>) -> [NodeId; 8] {
+    #[cfg(feature = "parallel")]
+    {
+        if depth >= PARALLEL_MIN_DEPTH {
+            use rayon::prelude::*;
+            // each child is built in its own arena, then they are moved into this one
+            let arenas: Vec<(Vec<Node>, NodeId)> = (0..8).into_par_iter().map(|i| {
+                let mut arena = Vec::new();
+                let root = approximate(range.octant(CubeCorner(i).into()), shape, depth-1, &mut arena);
+                (arena, root)
+            }).collect();
+
+            let mut children = [0; 8];
+            for (child, (arena, root)) in children.iter_mut().zip(arenas) {
+                let offset = nodes.len();
+                nodes.extend(arena.into_iter().map(|n| n.shifted(offset)));
+                *child = root + offset;
             }
+            return children;
         }
     }
+    array_init(|i| approximate(range.octant(CubeCorner(i).into()), shape, depth-1, nodes))
 }
 
 
-/// zip 2 arrays with a function.
-/// Soon, map and zip will be part of stable-rust !!!
-fn zip_array_with<T, S, F, const N: usize>(a: [T; N], b: [T; N], mut f: F) -> [S; N] 
-where F: FnMut(T, T) -> S
-{
-    use array_init::from_iter;
-    use std::array::IntoIter;
-    let zip = IntoIter::new(a).zip(IntoIter::new(b));
-    from_iter(zip
-        .map(|(a, b)| f(a, b))
-    ).unwrap()
+/// The triangles of a region triangulated on its own, on another thread or in a web worker,
+/// with the point of the grid of each vertex so that it can be merged with the other regions
+#[derive(Clone, Debug, Default)]
+pub struct RegionMesh {
+    pub points: Vec<f32>,
+    pub indices: Vec<u16>,
+    pub grid_points: Vec<[i32; 3]>,
 }
 
-/// map an array with a function.
-/// this will also be part of rust soon
-fn map_array<T, S, F, const N: usize>(a: [T; N], f: F) -> [S; N] 
-where F: FnMut(T) -> S
-{
-    use array_init::from_iter;
-    use std::array::IntoIter;
-    let iter = IntoIter::new(a);
-    from_iter(iter
-        .map(f)
-    ).unwrap()
+/// The vertices created by the triangulation of some regions of an octree,
+/// so that the next regions share them
+#[derive(Default)]
+pub struct Triangulation {
+    vertices: HashMap<NodeIndex, usize>,
 }
 
-
-impl<'octree> BoolLike for Node<'octree>
-{
-    fn union(a: Self, b: Self) -> Self {
-        // union of 2 octree nodes at the same location
-        match (a, b) {
-            (Node::Sub(cubes_a), Node::Sub(cubes_b)) => {
-                // calculate the union of the sub_cubes
-                let cubes = zip_array_with(
-                    cubes_a,
-                    cubes_b, 
-                    |a, b| Box::new(BoolLike::union(*a, *b))
-                );
-
-                let mut fusion = cubes[0].get_state();
-                for c in &cubes {
-                    fusion = match (fusion, c.get_state()) {
-                        (Some(Outside), Some(Outside)) => Some(Outside),
-                        (Some(Inside), Some(Inside)) => Some(Inside),
-                        _ => None,
-                    };
-                }
-
-                // if the new contain only empty or full blocks, return one of them
-                match fusion {
-                    Some(state) => Node::Completely(state.opposite()),
-                    None => Node::Sub(cubes)
-                }
-            },
-            // if one is empty, return the other
-            (Node::Completely(Outside), b) => b,
-            (a, Node::Completely(Outside)) => a,
-            // if one is full, return full
-            (Node::Completely(Inside), _) => Node::Completely(Inside),
-            (_, Node::Completely(Inside)) => Node::Completely(Inside),
-            // if one is point, return it
-            (Node::Cell(x), _) => Node::Cell(x),
-            (_, Node::Cell(x)) => Node::Cell(x),
-        }
+impl Triangulation {
+    /// Add the triangles of a region to the buffers.
+    /// Fail if there are too many vertices to index them with 16 bits
+    pub fn add(&mut self, octree: &Octree, region: &CellRange, point_array: &mut Vec<f32>, index_array: &mut Vec<u16>) -> Result<(), String> {
+        octree.triangulate_cells(region, &mut self.vertices, point_array, index_array);
+        super::check_indexable(point_array)
     }
 
-    fn inter(a: Self, b: Self) -> Self {
-        // intersection of 2 octree nodes at the same location
-        match (a, b) {
-            (Node::Sub(cubes_a), Node::Sub(cubes_b)) => {
-                // calculate the intersection of the sub_cubes
-                let cubes = zip_array_with(
-                    cubes_a,
-                    cubes_b, 
-                    |a, b| Box::new(BoolLike::inter(*a, *b))
-                );
-
-                let mut fusion = cubes[0].get_state();
-                for c in &cubes {
-                    fusion = match (fusion, c.get_state()) {
-                        (Some(Outside), Some(Outside)) => Some(Outside),
-                        (Some(Inside), Some(Inside)) => Some(Inside),
-                        _ => None,
-                    };
-                }
-
-                // if the new contain only empty or full blocks, return one of them
-                match fusion {
-                    Some(state) => Node::Completely(state.opposite()),
-                    None => Node::Sub(cubes)
-                }
-            },
-            // if one is full, return the other
-            (Node::Completely(Inside), b) => b,
-            (a, Node::Completely(Inside)) => a,
-            // if one is empty, return empty
-            (Node::Completely(Outside), _) => Node::Completely(Outside),
-            (_, Node::Completely(Outside)) => Node::Completely(Outside),
-            // if one is leaf, return it
-            (Node::Cell(x), _) => Node::Cell(x),
-            (_, Node::Cell(x)) => Node::Cell(x),
-        }
-    }
-
-    fn not(self) -> Self {
-        match self {
-            Node::Sub(cubes) => Node::Sub(map_array(cubes, |c| Box::new(c.not()))),
-            Node::Cell(x) => Node::Cell(x),
-            Node::Completely(state) => Node::Completely(state.opposite()),
-        }
+    /// Add the triangles of a region triangulated on its own.
+    /// The buffers are the same as if the region was added with `add`
+    pub fn merge(&mut self, region: &RegionMesh, point_array: &mut Vec<f32>, index_array: &mut Vec<u16>) -> Result<(), String> {
+        let new_indices: Vec<usize> = region.grid_points.iter().enumerate()
+            .map(|(i, &grid_point)| *self.vertices.entry(grid_point).or_insert_with(|| {
+                point_array.extend_from_slice(&region.points[i*SIZE_VERTEX..(i+1)*SIZE_VERTEX]);
+                point_array.len()/SIZE_VERTEX - 1
+            }))
+            .collect();
+        index_array.extend(region.indices.iter().map(|&i| new_indices[i as usize] as u16));
+        super::check_indexable(point_array)
     }
 }
 
-
-pub struct Octree<'octree> {
+/// An octree stored in an arena: the nodes refer to each other by their position in a vector,
+/// so the octree can be cloned, modified and sent to other threads
+#[derive(Clone, Debug)]
+pub struct Octree {
     range: Range,
     depth: u8,
-    root: Node<'octree>,
+    nodes: Vec<Node>,
+    root: NodeId,
+    // size of the smallest cells
     scale: V3,
+    // color of each material
+    palette: Vec<V3>,
 }
 
 
-impl <'octree> Octree<'octree> {
-    fn index(&'octree self, id: NodeIndex) -> Result<&'octree CellInfo<'octree>, NodeState> {
+impl Octree {
+    /// create the octree and link the cells to their neighbourgs
+    fn from_nodes(range: Range, depth: u8, nodes: Vec<Node>, root: NodeId) -> Self {
+        let scale = range.diagonal().scale(1.0 / (1 << depth) as f32);
+        let mut result = Self {range, depth, nodes, root, scale, palette: default_palette()};
+        result.link_cells(&result.whole());
+        result
+    }
+
+    /// find the node at an index: a cell, or a region completely inside or outside
+    fn find(&self, id: NodeIndex) -> Result<NodeId, NodeState> {
         let max_id = 1 << self.depth;
-        if id.iter().all(|&n| n >= 0 && n < max_id)
-        {
-            // if index is inside the octree
-            self.root.index(id, self.depth)
+        if !id.iter().all(|&n| n >= 0 && n < max_id) {
+            // the outside of the octree is empty
+            return Err(Outside)
         }
-        else {
-            // otherwise return error
-            Err(Outside)
+        let mut node = self.root;
+        let mut depth = self.depth;
+        loop {
+            match &self.nodes[node] {
+                Node::Sub(cubes) => {
+                    let m = 1 << (depth-1);
+                    node = cubes[CubeCorner::from(id.map(|v| v & m != 0))];
+                    depth -= 1;
+                }
+                Node::Cell(_) => return Ok(node),
+                Node::Completely(s) => return Err(*s),
+            }
         }
     }
 
-    fn index_mut(&'octree mut self, id: NodeIndex) -> Result<&'octree mut CellInfo<'octree>, NodeState> {
-        let max_id = 1 << self.depth;
-        if id.iter().all(|&n| n >= 0 && n < max_id)
-        {
-            // if index is inside the octree
-            self.root.index_mut(id, self.depth)
+    fn cell(&self, id: NodeId) -> &CellInfo {
+        match &self.nodes[id] {
+            Node::Cell(x) => x,
+            _ => panic!("node {} is not a cell", id),
         }
-        else {
-            // otherwise return error
-            Err(Outside)
+    }
+
+    fn cell_mut(&mut self, id: NodeId) -> &mut CellInfo {
+        match &mut self.nodes[id] {
+            Node::Cell(x) => x,
+            _ => panic!("node {} is not a cell", id),
         }
     }
 
+    /// index cell with 3 numbers
+    fn index(&self, id: NodeIndex) -> Result<&CellInfo, NodeState> {
+        self.find(id).map(move |n| self.cell(n))
+    }
+
     /// give the position in space that correspond to an index in this octree
+    /// (the smallest corner of the cell)
     fn index_to_point(&self, pos: NodeIndex) -> V3 {
         let vec_from_corner = V3::new(
             (pos[0] as f32) * self.scale.x,
@@ -386,115 +370,273 @@ impl <'octree> Octree<'octree> {
         vec_from_corner + self.range.smaller_corner
     }
 
-    /// get all cells and indices inside the octree.
-    fn get_cells_with_indices(&'octree self) -> Vec<(NodeIndex, &'octree CellInfo<'octree>)> {
+    fn collect_cells(&self, node: NodeId, pos: NodeIndex, depth: u8, region: &CellRange, result: &mut Vec<(NodeIndex, NodeId)>) {
+        if !CellRange::of_node(pos, 1 << depth).intersects(region) {
+            return
+        }
+        match &self.nodes[node] {
+            Node::Sub(cubes) => {
+                let m = 1 << (depth-1);
+                for (i, &c) in cubes.iter().enumerate() {
+                    let corner = CubeCorner(i).bools();
+                    let new_pos = array_init(|d| pos[d] + if corner[d] {m} else {0});
+                    self.collect_cells(c, new_pos, depth-1, region, result);
+                }
+            }
+            Node::Cell(_) => result.push((pos, node)),
+            Node::Completely(_) => (),
+        }
+    }
+
+    /// all the cells of the octree
+    pub fn whole(&self) -> CellRange {
+        CellRange::of_node([0, 0, 0], 1 << self.depth)
+    }
+
+    /// the cells cut in cubes of 2^block_depth cells on each side, x first
+    pub fn blocks(&self, block_depth: u8) -> Vec<CellRange> {
+        let size = 1 << block_depth.min(self.depth);
+        let n = (1 << self.depth) / size;
+        (0..n*n*n)
+            .map(|i| CellRange::of_node([i % n, i/n % n, i/(n*n)].map(|x| x * size), size))
+            .collect()
+    }
+
+    /// get the cells and indices inside a region
+    fn cells_in(&self, region: &CellRange) -> Vec<(NodeIndex, NodeId)> {
         let mut result = Vec::new();
-        self.root.get_cells_with_indices([0, 0, 0], self.depth, &mut result);
+        self.collect_cells(self.root, [0, 0, 0], self.depth, region, &mut result);
         result
     }
 
-    /// approximate a distance function with an octree.
-    /// `d`: struct that implement a distance function
-    /// `range`: range of the octree (region of space in a tile)
-    /// `depth`: depth you want (maximum 8)
-    pub fn new_from_dist(d: impl Dist, range: Range, depth: u8) -> Self {
-        let size = range.diagonal();
-        let root = Node::approximate(range.smaller_corner, size.scale(0.5), &d, depth);
-        let max_index = (1<<depth)-1;
-        let scale = V3::new(
-            size.x / (max_index as f32),
-            size.y / (max_index as f32),
-            size.z / (max_index as f32),
-        );
-        
-        let mut result =  Self {range, depth, root, scale};
-        
-        // FIXME
-        for (pos, _cell) in result.get_cells_with_indices(){ 
-            let mut cell = result.index_mut(pos).unwrap();
-            // get 6 neighbourgs of current cell
-            cell.neighbourgs = array_init(
+    /// get all cells and indices inside the octree.
+    fn get_cells_with_indices(&self) -> Vec<(NodeIndex, NodeId)> {
+        self.cells_in(&self.whole())
+    }
+
+    /// find the 6 neighbourgs of each cell of a region
+    fn link_cells(&mut self, region: &CellRange) {
+        for (pos, id) in self.cells_in(region) {
+            let neighbourgs = array_init(
                 |dim| array_init(
-                    |side| result.index({
-                        let mut id = pos.clone();
-                        id[dim] = if side==1 {id[dim]+1} else {id[dim]-1};
+                    |side| self.find({
+                        let mut id = pos;
+                        id[dim] += if side==1 {1} else {-1};
                         id
                     })
                 )
             );
+            self.cell_mut(id).neighbourgs = neighbourgs;
         }
+    }
 
-        result
+    /// approximate a distance function with an octree.
+    /// `d`: struct that implement a distance function, bounded once per node
+    /// (give it `sdf.compile()` rather than a `Sdf`)
+    /// `range`: range of the octree (region of space in a tile)
+    /// `depth`: depth you want (maximum 8)
+    pub fn new_from_dist(d: impl Dist + Sync, range: Range, depth: u8) -> Self {
+        let mut nodes = Vec::new();
+        let root = approximate(range, &d, depth, &mut nodes);
+        Octree::from_nodes(range, depth, nodes, root)
+    }
+
+    /// Set the material of each cell with a function of its center.
+    /// Only the cells on the surface have a material
+    pub fn set_materials(&mut self, material: impl Fn(V3) -> Material) {
+        let half_cell = self.scale.scale(0.5);
+        for (pos, id) in self.get_cells_with_indices() {
+            let center = self.index_to_point(pos) + half_cell;
+            self.cell_mut(id).material = material(center);
+        }
+    }
+
+    /// colors of the materials. The materials without color are drawn with the first one
+    pub fn set_palette(&mut self, colors: Vec<V3>) {
+        assert!(!colors.is_empty(), "the palette must have at least one color");
+        self.palette = colors;
+    }
+
+    fn color(&self, material: Material) -> V3 {
+        *self.palette.get(material as usize).unwrap_or(&self.palette[0])
+    }
+
+    /// the cell that touches the corner of another cell, after a move in the dimensions of `m`
+    fn cell_around_corner(pos: NodeIndex, corner: [bool; 3], m: usize) -> NodeIndex {
+        array_init(|d| match (m>>d & 1 != 0, corner[d]) {
+            (false, _) => pos[d],
+            (true, true) => pos[d] + 1,
+            (true, false) => pos[d] - 1,
+        })
+    }
+
+    /// Average color of the cells around a corner,
+    /// so that the colors are blended between 2 materials
+    fn corner_color(&self, pos: NodeIndex, corner: [bool; 3]) -> V3 {
+        let colors: Vec<V3> = (0..8)
+            .filter_map(|m| self.index(Octree::cell_around_corner(pos, corner, m)).ok())
+            .map(|cell| self.color(cell.material))
+            .collect();
+        colors.iter().fold(V3::null(), |a, &b| a + b).scale(1.0 / colors.len() as f32)
+    }
+
+    /// Index of the vertex at a corner of a cell.
+    /// The vertices are shared by all the cells around a point of the grid
+    fn corner_index(&self, pos: NodeIndex, corner: usize, vertices: &mut HashMap<NodeIndex, usize>, point_array: &mut Vec<f32>) -> usize {
+        let bools = CubeCorner(corner).bools();
+        let grid_point = array_init(|d| pos[d] + bools[d] as i32);
+        *vertices.entry(grid_point).or_insert_with(|| {
+            let t = point_array.len()/SIZE_VERTEX;
+            let point = self.index_to_point(grid_point);
+            let col = self.corner_color(pos, bools);
+            push_point!(point_array, point, col, [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]);
+            t
+        })
+    }
+
+    /// Triangulate the cells of a region: each face of a cell that touches the outside
+    /// becomes a square. The vertices already in `vertices`, by point of the grid, are reused
+    fn triangulate_cells(&self, region: &CellRange, vertices: &mut HashMap<NodeIndex, usize>, point_array: &mut Vec<f32>, index_array: &mut Vec<u16>) {
+        for (pos, id) in self.cells_in(region) {
+            let neighbourgs = self.cell(id).neighbourgs;
+            for (d, sides) in neighbourgs.iter().enumerate() {
+                for (side, &neighbourg) in sides.iter().enumerate() {
+                    if neighbourg != Err(Outside) {
+                        continue
+                    }
+                    // corners of the square, going around it in the 2 other dimensions
+                    let (a, b) = ((d+1)%3, (d+2)%3);
+                    let square = [0, 1<<a, 1<<a | 1<<b, 1<<b]
+                        .map(|c| self.corner_index(pos, c | side<<d, vertices, point_array));
+
+                    // counter-clockwise when seen from the outside
+                    if side == 1 {
+                        push_index!(index_array, square.[0, 1, 2, 0, 2, 3]);
+                    }
+                    else {
+                        push_index!(index_array, square.[0, 2, 1, 0, 3, 2]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Triangulate the octree. The blocks are triangulated on their own,
+    /// on all the cores with the `parallel` feature, then merged in order:
+    /// the corners are shared between the cells, so the surface is closed
+    pub fn triangulate(&self, point_array: &mut Vec<f32>, index_array: &mut Vec<u16>) -> Result<(), String> {
+        log!("number of cells: {:?}", self.get_cells_with_indices().len());
+        let blocks = self.blocks(BLOCK_DEPTH);
+        #[cfg(feature = "parallel")]
+        let meshes: Vec<RegionMesh> = {
+            use rayon::prelude::*;
+            blocks.par_iter().map(|block| self.mesh_region(block)).collect::<Result<_, _>>()?
+        };
+        #[cfg(not(feature = "parallel"))]
+        let meshes: Vec<RegionMesh> = blocks.iter().map(|block| self.mesh_region(block)).collect::<Result<_, _>>()?;
+
+        let mut triangulation = Triangulation::default();
+        for mesh in &meshes {
+            triangulation.merge(mesh, point_array, index_array)?;
+        }
+        Ok(())
     }
 
-    /// triangulate octree with the surface-net algorithm
-    pub fn triangulate(&self, point_array: &mut Vec<f32>, index_array: &mut Vec<u16>) {
-        // get all cells in octree with the corresponding indices
-        let all_cells = self.get_cells_with_indices();
-
-        log!("number of cells: {:?}", all_cells.len());
-        // for each cell in the octree, set index of all the corners
-        for (cell_pos, x) in &all_cells {
-
-            // modify temp value with new indices
-            let indices = x.cube_indices.borrow_mut();
-            let neighbourgs = x.neighbourgs;
-
-            // (*temp_ref) = Some(array_init(
-            //         |i| {
-            //             let mut tmp_corner_index = None;
-
-            //             for d in 0..3 {
-            //                 // look at 3 neighbourgs cells (like up, down and front)
-            //                 tmp_corner_index = tmp_corner_index.or(
-            //                     // if there is a value defined, 
-            //                     neighbourgs[d][if CubeCorner(i).bools()[d] {1} else {0}].as_ref()
-            //                     .ok()
-            //                     .and_then(|ref_corner| ref_corner.borrow().cube_indices)
-            //                     .map(|corners| corners[i^(1<<d)])
-            //                 );
-            //             }
-            //             match tmp_corner_index {
-            //                 // if one of the neighbourgs cell have an index for this corner, return
-            //                 // this number
-            //                 Some(id) => id,
-            //                 // otherwise, create a new id
-            //                 None => {
-            //                     let t = point_array.len()/12; 
-            //                     let col = V3::new(0.3, 0.3, 0.3)+rand_v3().scale(0.1);
-            //                     push_point!(point_array, self.index_to_point(*cell_pos), col, [0.0, 0.0, 0.0], [0.0, 0.0, 0.0]);
-            //                     t
-            //                 }
-            //             }
-            //         }));
+    /// triangulate a region on its own, to merge it later
+    pub fn mesh_region(&self, region: &CellRange) -> Result<RegionMesh, String> {
+        let mut mesh = RegionMesh::default();
+        let mut vertices = HashMap::new();
+        self.triangulate_cells(region, &mut vertices, &mut mesh.points, &mut mesh.indices);
+        super::check_indexable(&mesh.points)?;
+        mesh.grid_points = vec![[0; 3]; vertices.len()];
+        for (grid_point, i) in vertices {
+            mesh.grid_points[i] = grid_point;
         }
+        Ok(mesh)
+    }
+
+    /// Triangulate the cells of a region only.
+    /// The vertices are not shared with the cells outside of the region,
+    /// so that each region can be triangulated again on its own
+    pub fn triangulate_region(&self, region: CellRange, point_array: &mut Vec<f32>, index_array: &mut Vec<u16>) -> Result<(), String> {
+        Triangulation::default().add(self, &region, point_array, index_array)
+    }
+
+    /// copy a node and its children in another arena
+    fn copy_node(&self, id: NodeId, nodes: &mut Vec<Node>) -> NodeId {
+        let node = match &self.nodes[id] {
+            Node::Sub(cubes) => Node::Sub(cubes.map(|c| self.copy_node(c, nodes))),
+            node => node.clone(),
+        };
+        nodes.push(node);
+        nodes.len() - 1
+    }
+
+    /// Combine the node `a` of this octree and the node `b` of another one, at the same place.
+    /// `absorbing` is the state that wins: `Inside` for a union, `Outside` for an intersection
+    fn combine(&self, a: NodeId, other: &Octree, b: NodeId, absorbing: NodeState, nodes: &mut Vec<Node>) -> NodeId {
+        let node = match (&self.nodes[a], &other.nodes[b]) {
+            (Node::Completely(s), _) | (_, Node::Completely(s)) if *s == absorbing => Node::Completely(absorbing),
+            // the other state does not change anything: return the other node
+            (Node::Completely(_), _) => return other.copy_node(b, nodes),
+            (_, Node::Completely(_)) => return self.copy_node(a, nodes),
+            (Node::Sub(cubes_a), Node::Sub(cubes_b)) => {
+                let start = nodes.len();
+                let cubes: [NodeId; 8] = array_init(|i| self.combine(cubes_a[i], other, cubes_b[i], absorbing, nodes));
+
+                // if the new contain only empty or full blocks, return one of them
+                match nodes[cubes[0]].get_state() {
+                    Some(state) if cubes.iter().all(|&c| nodes[c].get_state() == Some(state)) => {
+                        nodes.truncate(start);
+                        Node::Completely(state)
+                    }
+                    _ => Node::Sub(cubes)
+                }
+            }
+            // if one is leaf, return it
+            (Node::Cell(x), _) | (_, Node::Cell(x)) => Node::Cell(x.clone()),
+        };
+        nodes.push(node);
+        nodes.len() - 1
+    }
 
-        // for (pos, x) in &all_cells {
-        //     // create triangles for each cell
-        //     for d in 0..3 {
-        //         for &side in &[0, 1] {
-        //             let pos_in_this_dir = {
-        //                 let mut new_pos = pos.clone();
-        //                 new_pos[d] += side*2-1;
-        //                 new_pos
-        //             };
-
-        //             if let Err(Outside) = self.index(pos_in_this_dir){
-        //                 // if neighbourg cell in this direction is outside, create triangles: 
-        //                 let points_id_in_cube = x.borrow().cube_indices.unwrap();
-        //                 let corners_squares = (0..8).filter(|&c| ((c as usize)>>d&1)==side as usize);
-        //                 let points_id_in_square: Vec<_> = corners_squares.map(|i| points_id_in_cube[i]).collect();
-        //                 push_index!( index_array, points_id_in_square.[0, 1, 2, 1, 3, 2]);
-        //             }
-        //         }
-        //     }
-        // }
-    } 
+    fn combined(a: &Octree, b: &Octree, absorbing: NodeState) -> Octree {
+        assert_eq!(a.depth, b.depth, "the octrees must have the same cells");
+        let mut nodes = Vec::new();
+        let root = a.combine(a.root, b, b.root, absorbing, &mut nodes);
+        Octree::from_nodes(a.range, a.depth, nodes, root)
+    }
+}
+
+/// The octrees must cover the same range with the same depth
+impl BoolLike for Octree {
+    fn union(a: Self, b: Self) -> Self {
+        Octree::combined(&a, &b, Inside)
+    }
+
+    fn inter(a: Self, b: Self) -> Self {
+        Octree::combined(&a, &b, Outside)
+    }
+
+    fn not(mut self) -> Self {
+        for node in &mut self.nodes {
+            if let Node::Completely(state) = node {
+                *state = state.opposite();
+            }
+        }
+        self.link_cells(&self.whole());
+        self
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{CubeCorner, Octree, Range, V3, map_array};
+    use super::{CubeCorner, Octree, Node, NodeId, NodeState, BoolLike, Range, V3, Dist, Triangulation};
+    use super::build::OctreeBuilder;
+    use super::super::implicit::{x, y, z};
+    use super::super::sdf::Sdf;
+    use super::super::lipschitz::Lipschitz;
+    use super::super::random::Rng;
     use getrandom;
 
     #[test]
@@ -505,38 +647,188 @@ mod tests {
         }
     }
 
-    #[test]
-    fn octree_indexing() {
-        let range = Range::new(
+    fn cube() -> Range {
+        Range::new(
             V3::new(-1.0, -1.0, -1.0),
             V3::new( 1.0,  1.0,  1.0),
-        );
-        let oct = Octree::new_from_dist(|v: V3| v.x*v.x+v.y+v.y-0.5, range, 4);
+        )
+    }
 
-        for (_pos, ref_value) in oct.get_cells_with_indices() {
-            let random_numbers = {
-                let mut tmp = [0; 8];
+    #[test]
+    fn octree_indexing() {
+        let mut oct = Octree::new_from_dist(x()*x()+y()+y()-0.5, cube(), 4);
+
+        for (pos, _id) in oct.get_cells_with_indices() {
+            let random_number = {
+                let mut tmp = [0; 1];
                 getrandom::getrandom(&mut tmp).unwrap();
-                map_array(tmp, |x| x as usize)
+                tmp[0]
             };
-            ref_value.borrow_mut().cube_indices = Some(random_numbers);
+            let id = oct.find(pos).unwrap();
+            oct.cell_mut(id).material = random_number;
         }
 
-        for (pos, ref_value) in oct.get_cells_with_indices() {
+        for (pos, id) in oct.get_cells_with_indices() {
             assert_eq!(
-                oct.index(pos).unwrap().borrow().cube_indices, 
-                ref_value.cube_indices
+                oct.index(pos).unwrap().material,
+                oct.cell(id).material
                 );
         }
     }
 
     #[test]
     fn octree_triangulation() {
-        let range = Range::new(
-            V3::new(-1.0, -1.0, -1.0),
-            V3::new( 1.0,  1.0,  1.0),
-        );
-        let oct = Octree::new_from_dist(|v: V3| v.x*v.x+v.y+v.y-0.5, range, 5);
-        oct.triangulate(&mut Vec::new(), &mut Vec::new())
+        let oct = Octree::new_from_dist(x()*x()+y()+y()-0.5, cube(), 5);
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        oct.triangulate(&mut points, &mut indices).unwrap();
+        assert!(!indices.is_empty());
+        assert_eq!(indices.len() % 6, 0);
+
+        // a shape inside the octree gives a closed surface:
+        // each edge is shared by exactly 2 triangles, in opposite directions
+        let oct = Octree::new_from_dist(Sdf::Sphere {radius: 0.7}.compile(), cube(), 5);
+        let mut indices = Vec::new();
+        oct.triangulate(&mut points, &mut indices).unwrap();
+        let mut edges = std::collections::HashMap::new();
+        for t in indices.chunks(3) {
+            for k in 0..3 {
+                *edges.entry((t[k], t[(k+1)%3])).or_insert(0) += 1;
+            }
+        }
+        for (&(a, b), &n) in &edges {
+            assert_eq!(n, 1);
+            assert_eq!(edges.get(&(b, a)), Some(&1));
+        }
+    }
+
+    #[test]
+    fn merged_regions_are_triangulated_in_order() {
+        let oct = Octree::new_from_dist(Sdf::Torus {major: 0.6, minor: 0.3}.compile(), cube(), 6);
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        oct.triangulate(&mut points, &mut indices).unwrap();
+
+        // the blocks one after the other, sharing their vertices
+        let mut triangulation = Triangulation::default();
+        let (mut sequential_points, mut sequential_indices) = (Vec::new(), Vec::new());
+        for block in oct.blocks(super::BLOCK_DEPTH) {
+            triangulation.add(&oct, &block, &mut sequential_points, &mut sequential_indices).unwrap();
+        }
+        assert!(!indices.is_empty());
+        assert_eq!(points, sequential_points);
+        assert_eq!(indices, sequential_indices);
+
+        // the octants of the octree of a part, built with a margin of a cell around it
+        let mut triangulation = Triangulation::default();
+        let (mut part_points, mut part_indices) = (Vec::new(), Vec::new());
+        for i in 0..8 {
+            let octant = oct.whole().octant(i);
+            let part = OctreeBuilder::new(Sdf::Torus {major: 0.6, minor: 0.3}.compile(), cube(), 6)
+                .with_focus(octant.expand(1))
+                .build();
+            triangulation.merge(&part.mesh_region(&octant).unwrap(), &mut part_points, &mut part_indices).unwrap();
+        }
+        let triangles = |points: &[f32], indices: &[u16]| {
+            let mut triangles: Vec<String> = indices.chunks(3)
+                .map(|t| format!("{:?}", t.iter().map(|&i| &points[i as usize*super::SIZE_VERTEX..][..super::SIZE_VERTEX]).collect::<Vec<_>>()))
+                .collect();
+            triangles.sort();
+            triangles
+        };
+        assert_eq!(part_points.len(), points.len());
+        assert_eq!(triangles(&part_points, &part_indices), triangles(&points, &indices));
+    }
+
+    #[test]
+    fn material_colors() {
+        let red = V3::new(1.0, 0.0, 0.0);
+        let blue = V3::new(0.0, 0.0, 1.0);
+        let mut oct = Octree::new_from_dist(Sdf::Cuboid {half_size: V3::new(0.5, 0.5, 0.5)}.compile(), cube(), 4);
+        oct.set_palette(vec![red, blue]);
+        oct.set_materials(|p| if p.x < 0.0 {0} else {1});
+
+        let mut points = Vec::new();
+        oct.triangulate(&mut points, &mut Vec::new()).unwrap();
+        for vertex in points.chunks(super::SIZE_VERTEX) {
+            let (x, color) = (vertex[0], V3::new(vertex[3], vertex[4], vertex[5]));
+            let expected = if x < -0.01 {
+                red
+            }
+            else if x > 0.01 {
+                blue
+            }
+            else {
+                // blended on the boundary
+                V3::new(0.5, 0.0, 0.5)
+            };
+            assert!((color - expected).norm() < 1e-6, "{:?} at x = {}", color, x);
+        }
+    }
+
+    #[test]
+    fn octree_is_sendable() {
+        let oct = Octree::new_from_dist(Sdf::Sphere {radius: 0.5}.compile(), cube(), 4);
+        let copy = oct.clone();
+        let n_cells = std::thread::spawn(move || copy.get_cells_with_indices().len())
+            .join()
+            .unwrap();
+        assert_eq!(n_cells, oct.get_cells_with_indices().len());
+    }
+
+    #[test]
+    fn boolean_operations() {
+        let a = Octree::new_from_dist(x() - 0.3, cube(), 4);
+        let b = Octree::new_from_dist(-x() - 0.3, cube(), 4);
+        let c = Octree::new_from_dist(z()*z() - 0.25, cube(), 4);
+
+        // the union covers everything, the intersection is the slab between the 2 planes
+        let union = BoolLike::union(a.clone(), b.clone());
+        assert_eq!(union.nodes[union.root].get_state(), Some(NodeState::Inside));
+        let inter = BoolLike::inter(a.clone(), b.clone());
+        assert!(!inter.get_cells_with_indices().is_empty());
+        assert_eq!(inter.find([0, 0, 0]), Err(NodeState::Outside));
+
+        // the complement exchanges inside and outside
+        let not_c = c.clone().not();
+        assert_eq!(c.find([8, 8, 8]), Err(NodeState::Inside));
+        assert_eq!(not_c.find([8, 8, 8]), Err(NodeState::Outside));
+        assert_eq!(not_c.get_cells_with_indices().len(), c.get_cells_with_indices().len());
+    }
+
+    /// check that the cells completely inside or outside have the right sign
+    fn check_states(rng: &mut Rng, oct: &Octree, node: NodeId, range: Range, shape: &impl Dist) {
+        match &oct.nodes[node] {
+            Node::Sub(cubes) => {
+                for (i, &c) in cubes.iter().enumerate() {
+                    check_states(rng, oct, c, range.octant(CubeCorner(i).into()), shape);
+                }
+            }
+            Node::Completely(state) => {
+                let d = range.diagonal();
+                for _ in 0..20 {
+                    let p = range.smaller_corner + V3::new(d.x*rng.float(), d.y*rng.float(), d.z*rng.float());
+                    let inside = shape.dist(p) < 0.0;
+                    assert_eq!(inside, *state == NodeState::Inside);
+                }
+            }
+            Node::Cell(_) => (),
+        }
+    }
+
+    fn check_octree(shape: impl Dist + Sync + Clone) {
+        let oct = Octree::new_from_dist(shape.clone(), cube(), 5);
+        check_states(&mut Rng::new(1), &oct, oct.root, cube(), &shape);
+    }
+
+    #[test]
+    fn approximation_is_sound() {
+        // not a distance: the value changes faster than the distance to the surface
+        check_octree(x()*x()+y()+y()-0.5);
+
+        check_octree(Sdf::Sphere {radius: 0.7}.displace(0.2, 5.0).compile());
+
+        // 4 times steeper than a distance, with a smooth blend
+        check_octree(Lipschitz::new(|p: V3| 4.0*(p.norm() - 0.7) + 0.3*(5.0*p.x).sin(), 5.6));
     }
 }
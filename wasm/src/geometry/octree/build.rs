@@ -0,0 +1,212 @@
+//! Build an octree a few nodes at a time, so that the work can be spread over several frames
+
+use super::{Octree, Node, NodeId, NodeIndex, CellInfo, CubeCorner, CellRange, Material, BLOCK_DEPTH, default_palette};
+use super::super::{V3, Range, Dist};
+
+enum Stage {
+    // classify the pending nodes
+    Subdivide,
+    // link the cells of the `i`th block to their neighbourgs
+    Link(usize),
+}
+
+/// An octree under construction.
+/// The nodes are classified from the root, as in `Octree::new_from_dist`,
+/// then the cells are linked to their neighbourgs one block at a time
+pub struct OctreeBuilder {
+    shape: Box<dyn Dist + Send + Sync>,
+    range: Range,
+    depth: u8,
+    material: fn(V3) -> Material,
+    // the nodes that do not touch these cells are left outside
+    focus: Option<CellRange>,
+    // a node is replaced when it is classified. The nodes that are not classified yet are `Outside`
+    nodes: Vec<Node>,
+    // nodes to classify, with their range, the position of their first cell and their depth
+    pending: Vec<(NodeId, Range, NodeIndex, u8)>,
+    stage: Stage,
+    // the octree without links, once all the nodes are classified
+    octree: Option<Octree>,
+    blocks: Vec<CellRange>,
+}
+
+impl OctreeBuilder {
+    /// same arguments as `Octree::new_from_dist`
+    pub fn new(shape: impl Dist + Send + Sync + 'static, range: Range, depth: u8) -> Self {
+        OctreeBuilder {
+            shape: Box::new(shape),
+            range,
+            depth,
+            material: |_| 0,
+            focus: None,
+            nodes: vec![Node::Completely(Default::default())],
+            pending: vec![(0, range, [0, 0, 0], depth)],
+            stage: Stage::Subdivide,
+            octree: None,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// material of the cells, from their center, as in `Octree::set_materials`
+    pub fn with_materials(mut self, material: fn(V3) -> Material) -> Self {
+        self.material = material;
+        self
+    }
+
+    /// Only build the nodes that touch some cells, to triangulate a part of the octree.
+    /// The cells around the part are needed to triangulate it, so the focus must be larger
+    pub fn with_focus(mut self, cells: CellRange) -> Self {
+        self.focus = Some(cells);
+        self
+    }
+
+    /// all the cells of the octree
+    pub fn whole(&self) -> CellRange {
+        CellRange::of_node([0, 0, 0], 1 << self.depth)
+    }
+
+    fn classify(&mut self, id: NodeId, range: Range, pos: NodeIndex, depth: u8) {
+        let outside_focus = self.focus.is_some_and(|focus| !CellRange::of_node(pos, 1 << depth).intersects(&focus));
+        let leaf = if outside_focus {
+            Some(Node::Completely(Default::default()))
+        }
+        else {
+            super::leaf(self.shape.bounds(&range), depth)
+        };
+        let node = match leaf {
+            Some(Node::Cell(_)) => {
+                let scale = self.range.diagonal().scale(1.0 / (1 << self.depth) as f32);
+                let center = self.range.smaller_corner
+                    + V3::new(pos[0] as f32 * scale.x, pos[1] as f32 * scale.y, pos[2] as f32 * scale.z)
+                    + scale.scale(0.5);
+                Node::Cell(CellInfo {material: (self.material)(center), ..Default::default()})
+            }
+            Some(node) => node,
+            None => {
+                let first = self.nodes.len();
+                let cubes = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| first + i);
+                self.nodes.extend((0..8).map(|_| Node::Completely(Default::default())));
+                let m = 1 << (depth-1);
+                // in reverse, so that the first child is classified first
+                for (i, &c) in cubes.iter().enumerate().rev() {
+                    let corner = CubeCorner(i).bools();
+                    let child_pos = [0, 1, 2].map(|d| pos[d] + if corner[d] {m} else {0});
+                    self.pending.push((c, range.octant(corner), child_pos, depth-1));
+                }
+                Node::Sub(cubes)
+            }
+        };
+        self.nodes[id] = node;
+    }
+
+    /// Do a part of the work: classify at most `n_nodes` nodes, or link the cells of a block.
+    /// Return the octree when it is finished
+    pub fn step(&mut self, n_nodes: usize) -> Option<Octree> {
+        match self.stage {
+            Stage::Subdivide => {
+                for _ in 0..n_nodes {
+                    match self.pending.pop() {
+                        Some((id, range, pos, depth)) => self.classify(id, range, pos, depth),
+                        None => break,
+                    }
+                }
+                if self.pending.is_empty() {
+                    let nodes = std::mem::take(&mut self.nodes);
+                    let scale = self.range.diagonal().scale(1.0 / (1 << self.depth) as f32);
+                    let octree = Octree {range: self.range, depth: self.depth, nodes, root: 0, scale, palette: default_palette()};
+                    self.blocks = octree.blocks(BLOCK_DEPTH);
+                    self.octree = Some(octree);
+                    self.stage = Stage::Link(0);
+                }
+                None
+            }
+            Stage::Link(i) => {
+                let octree = self.octree.as_mut().expect("the nodes are classified");
+                if let Some(block) = self.blocks.get(i) {
+                    octree.link_cells(block);
+                    self.stage = Stage::Link(i+1);
+                }
+                if i+1 >= self.blocks.len() {
+                    return self.octree.take()
+                }
+                None
+            }
+        }
+    }
+
+    /// do all the work at once
+    pub fn build(mut self) -> Octree {
+        loop {
+            if let Some(octree) = self.step(usize::MAX) {
+                return octree
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::OctreeBuilder;
+    use super::super::{Octree, Node};
+    use super::super::super::{V3, Range};
+    use super::super::super::sdf::Sdf;
+
+    fn cube() -> Range {
+        Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0))
+    }
+
+    fn shape() -> Sdf {
+        Sdf::Sphere {radius: 0.6}.displace(0.1, 6.0)
+    }
+
+    // the cells with their material and the position of their neighbourgs,
+    // which does not depend on the order of the nodes in the arena
+    fn describe(octree: &Octree) -> Vec<String> {
+        let cells = octree.get_cells_with_indices();
+        let positions: HashMap<usize, [i32; 3]> = cells.iter().map(|&(pos, id)| (id, pos)).collect();
+        let mut result: Vec<String> = cells.iter()
+            .map(|&(pos, id)| {
+                let cell = octree.cell(id);
+                let neighbourgs = cell.neighbourgs.map(|sides| sides.map(|n| n.map(|id| positions[&id])));
+                format!("{:?} {} {:?}", pos, cell.material, neighbourgs)
+            })
+            .collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn same_octree_in_small_steps() {
+        let material = |p: V3| if p.z > 0.0 {1} else {0};
+        let mut direct = Octree::new_from_dist(shape().compile(), cube(), 5);
+        direct.set_materials(material);
+
+        let mut builder = OctreeBuilder::new(shape().compile(), cube(), 5).with_materials(material);
+        let mut steps = 1;
+        let octree = loop {
+            if let Some(octree) = builder.step(100) {
+                break octree
+            }
+            steps += 1;
+        };
+        assert!(steps > 10);
+        assert_eq!(describe(&octree), describe(&direct));
+        assert_eq!(octree.nodes.iter().filter(|n| matches!(n, Node::Sub(_))).count(),
+                   direct.nodes.iter().filter(|n| matches!(n, Node::Sub(_))).count());
+
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        octree.triangulate(&mut points, &mut indices).unwrap();
+        let (mut direct_points, mut direct_indices) = (Vec::new(), Vec::new());
+        direct.triangulate(&mut direct_points, &mut direct_indices).unwrap();
+        assert_eq!(points, direct_points);
+        assert_eq!(indices, direct_indices);
+    }
+
+    #[test]
+    fn octree_without_cells() {
+        let octree = OctreeBuilder::new(Sdf::Sphere {radius: 0.1}.translate(V3::new(5.0, 0.0, 0.0)).compile(), cube(), 4).build();
+        assert!(octree.get_cells_with_indices().is_empty());
+    }
+}
@@ -0,0 +1,373 @@
+use super::{Octree, Node, NodeId, NodeIndex, CellRange, CubeCorner, Material};
+use super::NodeState::{Inside, Outside};
+use super::super::{V3, Range, append};
+use super::super::sdf::Sdf;
+use super::super::program::Program;
+
+/// What an edit does to the cells touched by the brush
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// fill with a material
+    Add(Material),
+    /// remove everything
+    Carve,
+    /// change the material of the surface
+    Paint(Material),
+}
+
+/// A brush stamped on the octree
+#[derive(Clone, Debug)]
+pub struct Edit {
+    pub shape: Sdf,
+    pub operation: Operation,
+}
+
+impl Edit {
+    pub fn sphere(center: V3, radius: f32, operation: Operation) -> Self {
+        Edit {shape: Sdf::Sphere {radius}.translate(center), operation}
+    }
+
+    pub fn cuboid(range: Range, operation: Operation) -> Self {
+        Edit {shape: Sdf::Cuboid {half_size: range.extent()}.translate(range.center()), operation}
+    }
+}
+
+// an edit with its shape compiled once, because it is bounded on every node it touches
+struct Brush {
+    shape: Program,
+    operation: Operation,
+}
+
+/// What an edit changed in the arena, to undo it
+#[derive(Debug)]
+pub struct Change {
+    // the nodes created by the edit are at the end of the arena
+    arena_len: usize,
+    // previous value of the modified nodes, in the order of modification
+    old_nodes: Vec<(NodeId, Node)>,
+    /// cells whose triangles changed, with their neighbourgs
+    pub dirty: Option<CellRange>,
+}
+
+impl Change {
+    fn touch(&mut self, cells: CellRange) {
+        let cells = cells.expand(1);
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(cells),
+            None => cells,
+        });
+    }
+}
+
+impl Octree {
+    fn replace(&mut self, id: NodeId, node: Node, change: &mut Change) {
+        let old = std::mem::replace(&mut self.nodes[id], node);
+        change.old_nodes.push((id, old));
+    }
+
+    /// edit the children of a `Node::Sub`
+    fn edit_children(&mut self, id: NodeId, range: Range, pos: NodeIndex, depth: u8, brush: &Brush, change: &mut Change) {
+        let cubes = match self.nodes[id] {
+            Node::Sub(cubes) => cubes,
+            _ => panic!("node {} has no children", id),
+        };
+        let m = 1 << (depth-1);
+        for (i, &c) in cubes.iter().enumerate() {
+            let corner = CubeCorner(i).bools();
+            let child_pos = [0, 1, 2].map(|d| pos[d] + if corner[d] {m} else {0});
+            self.edit_node(c, range.octant(corner), child_pos, depth-1, brush, change);
+        }
+
+        // if the new contain only empty or full blocks, merge them
+        let first = self.nodes[cubes[0]].get_state();
+        if let Some(state) = first {
+            if cubes.iter().all(|&c| self.nodes[c].get_state() == first) {
+                self.replace(id, Node::Completely(state), change);
+            }
+        }
+    }
+
+    fn edit_node(&mut self, id: NodeId, range: Range, pos: NodeIndex, depth: u8, brush: &Brush, change: &mut Change) {
+        let bounds = brush.shape.bounds(&range);
+        if bounds.min > 0.0 {
+            // the brush does not touch this node
+            return
+        }
+        let cells = CellRange::of_node(pos, 1 << depth);
+
+        let (target, material) = match brush.operation {
+            Operation::Add(material) => (Inside, material),
+            Operation::Carve => (Outside, 0),
+            Operation::Paint(material) => {
+                match &self.nodes[id] {
+                    Node::Cell(cell) if cell.material != material => {
+                        let mut cell = cell.clone();
+                        cell.material = material;
+                        self.replace(id, Node::Cell(cell), change);
+                        change.touch(cells);
+                    }
+                    Node::Sub(_) => self.edit_children(id, range, pos, depth, brush, change),
+                    _ => (),
+                }
+                return
+            }
+        };
+
+        if self.nodes[id].get_state() == Some(target) {
+            return
+        }
+        if bounds.max < 0.0 {
+            // the node is completely inside the brush
+            self.replace(id, Node::Completely(target), change);
+            change.touch(cells);
+            return
+        }
+
+        // the surface of the brush goes through the node
+        match self.nodes[id] {
+            Node::Sub(_) => self.edit_children(id, range, pos, depth, brush, change),
+            Node::Completely(state) => {
+                if depth == 0 {
+                    let cell = super::CellInfo {material, ..Default::default()};
+                    self.replace(id, Node::Cell(cell), change);
+                }
+                else {
+                    // split the node to edit a part of it
+                    let first = self.nodes.len();
+                    self.nodes.extend((0..8).map(|_| Node::Completely(state)));
+                    let cubes = [0, 1, 2, 3, 4, 5, 6, 7].map(|i| first + i);
+                    self.replace(id, Node::Sub(cubes), change);
+                    self.edit_children(id, range, pos, depth, brush, change);
+                }
+                change.touch(cells);
+            }
+            // a cell stays on the surface
+            Node::Cell(_) => (),
+        }
+    }
+
+    /// Apply an edit, and return what changed so that it can be undone
+    pub fn apply(&mut self, edit: &Edit) -> Change {
+        let mut change = Change {arena_len: self.nodes.len(), old_nodes: Vec::new(), dirty: None};
+        let brush = Brush {shape: edit.shape.compile(), operation: edit.operation};
+        self.edit_node(self.root, self.range, [0, 0, 0], self.depth, &brush, &mut change);
+        if let Some(dirty) = change.dirty {
+            self.link_cells(&dirty);
+        }
+        change
+    }
+
+    /// put back the nodes modified by an edit. The edits done after must be undone first
+    pub fn undo(&mut self, change: Change) {
+        for (id, node) in change.old_nodes.into_iter().rev() {
+            self.nodes[id] = node;
+        }
+        self.nodes.truncate(change.arena_len);
+        if let Some(dirty) = change.dirty {
+            self.link_cells(&dirty);
+        }
+    }
+}
+
+/// triangles of a block of cells
+#[derive(Clone, Debug, Default)]
+struct BlockMesh {
+    points: Vec<f32>,
+    indices: Vec<u16>,
+    dirty: bool,
+}
+
+/// An octree that can be edited, with undo and redo.
+/// The cells are grouped in blocks that are triangulated separately,
+/// so that an edit only triangulates the blocks it touches
+pub struct Sculpture {
+    octree: Octree,
+    undo_stack: Vec<(Edit, Change)>,
+    redo_stack: Vec<Edit>,
+    // the cells of each block
+    regions: Vec<CellRange>,
+    blocks: Vec<BlockMesh>,
+}
+
+impl Sculpture {
+    /// the blocks are cubes of 2^block_depth cells on each side
+    pub fn new(octree: Octree, block_depth: u8) -> Self {
+        let regions = octree.blocks(block_depth);
+        Sculpture {
+            octree,
+            undo_stack: Vec::new(),
+            redo_stack: Vec::new(),
+            blocks: vec![BlockMesh {dirty: true, ..Default::default()}; regions.len()],
+            regions,
+        }
+    }
+
+    pub fn octree(&self) -> &Octree {
+        &self.octree
+    }
+
+    fn mark_dirty(&mut self, change: &Change) {
+        if let Some(dirty) = change.dirty {
+            for i in 0..self.blocks.len() {
+                if self.regions[i].intersects(&dirty) {
+                    self.blocks[i].dirty = true;
+                }
+            }
+        }
+    }
+
+    pub fn apply(&mut self, edit: Edit) {
+        let change = self.octree.apply(&edit);
+        if change.dirty.is_some() {
+            self.mark_dirty(&change);
+            self.undo_stack.push((edit, change));
+            self.redo_stack.clear();
+        }
+    }
+
+    /// undo the last edit. Return false if there is nothing to undo
+    pub fn undo(&mut self) -> bool {
+        match self.undo_stack.pop() {
+            Some((edit, change)) => {
+                self.mark_dirty(&change);
+                self.octree.undo(change);
+                self.redo_stack.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// apply again the last undone edit. Return false if there is nothing to redo
+    pub fn redo(&mut self) -> bool {
+        match self.redo_stack.pop() {
+            Some(edit) => {
+                let change = self.octree.apply(&edit);
+                self.mark_dirty(&change);
+                self.undo_stack.push((edit, change));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// triangulate the blocks changed since the last call, and return them
+    pub fn update(&mut self) -> Result<Vec<usize>, String> {
+        let mut changed = Vec::new();
+        for (i, (block, &region)) in self.blocks.iter_mut().zip(&self.regions).enumerate() {
+            if block.dirty {
+                *block = BlockMesh::default();
+                self.octree.triangulate_region(region, &mut block.points, &mut block.indices)?;
+                changed.push(i);
+            }
+        }
+        Ok(changed)
+    }
+
+    pub fn n_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// the vertices and the indices of the triangles of a block
+    pub fn block(&self, i: usize) -> (&[f32], &[u16]) {
+        (&self.blocks[i].points, &self.blocks[i].indices)
+    }
+
+    /// The triangles of all the blocks in a single buffer.
+    /// Fail if there are too many vertices to index them with 16 bits
+    pub fn mesh(&self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+        for block in &self.blocks {
+            append(points, indices, &block.points, &block.indices)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sculpture, Edit, Operation, Inside, Outside};
+    use super::super::{Octree, CellRange};
+    use super::super::super::{V3, Range};
+    use super::super::super::sdf::Sdf;
+
+    fn cube() -> Range {
+        Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0))
+    }
+
+    fn triangles(sculpture: &Sculpture) -> Vec<[V3; 3]> {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        sculpture.mesh(&mut points, &mut indices).unwrap();
+        let point = |i: u16| {
+            let i = i as usize*super::super::super::SIZE_VERTEX;
+            V3::new(points[i], points[i+1], points[i+2])
+        };
+        indices.chunks(3).map(|t| [point(t[0]), point(t[1]), point(t[2])]).collect()
+    }
+
+    #[test]
+    fn carve_and_undo() {
+        let octree = Octree::new_from_dist(Sdf::Sphere {radius: 0.6}.compile(), cube(), 5);
+        let n_nodes = octree.nodes.len();
+        let mut sculpture = Sculpture::new(octree, 2);
+        assert_eq!(sculpture.update().unwrap().len(), 512);
+        let before = triangles(&sculpture);
+        let center = sculpture.octree().whole().max.map(|x| x/2);
+        let in_hole = [center[0], center[1], center[2] + 7];
+        assert_eq!(sculpture.octree().find(in_hole), Err(Inside));
+
+        // dig a hole on the top of the sphere
+        sculpture.apply(Edit::sphere(V3::new(0.0, 0.0, 0.6), 0.3, Operation::Carve));
+        let remeshed = sculpture.update().unwrap().len();
+        assert!(0 < remeshed && remeshed < 64);
+        let carved = triangles(&sculpture);
+        assert!(carved.iter().any(|t| t[0].z < 0.4 && t[0].x.abs() < 0.1 && t[0].y.abs() < 0.1));
+
+        // the cells inside the hole are outside now
+        assert_eq!(sculpture.octree().find(in_hole), Err(Outside));
+
+        assert!(sculpture.undo());
+        sculpture.update().unwrap();
+        assert_eq!(triangles(&sculpture).len(), before.len());
+        assert_eq!(sculpture.octree().nodes.len(), n_nodes);
+
+        assert!(sculpture.redo());
+        sculpture.update().unwrap();
+        assert_eq!(triangles(&sculpture).len(), carved.len());
+        assert!(!sculpture.redo());
+    }
+
+    #[test]
+    fn add_collapses_nodes() {
+        let octree = Octree::new_from_dist(Sdf::Sphere {radius: 0.3}.compile(), cube(), 4);
+        let mut sculpture = Sculpture::new(octree, 2);
+        // a box bigger than the octree fills everything
+        sculpture.apply(Edit::cuboid(cube().inflate(0.5), Operation::Add(1)));
+        let octree = sculpture.octree();
+        assert!(octree.get_cells_with_indices().is_empty());
+        assert_eq!(octree.nodes[octree.root].get_state(), Some(Inside));
+        sculpture.update().unwrap();
+        assert!(triangles(&sculpture).is_empty());
+    }
+
+    #[test]
+    fn paint() {
+        let octree = Octree::new_from_dist(Sdf::Sphere {radius: 0.6}.compile(), cube(), 4);
+        let mut sculpture = Sculpture::new(octree, 2);
+        sculpture.apply(Edit::sphere(V3::new(0.6, 0.0, 0.0), 0.3, Operation::Paint(3)));
+
+        let octree = sculpture.octree();
+        let painted: Vec<_> = octree.get_cells_with_indices().into_iter()
+            .filter(|&(_, id)| octree.cell(id).material == 3)
+            .map(|(pos, _)| pos)
+            .collect();
+        assert!(!painted.is_empty());
+        // only on the side of the brush
+        let half = CellRange {min: [8, 0, 0], max: [16, 16, 16]};
+        assert!(painted.iter().all(|&p| half.contains(p)));
+
+        assert!(sculpture.undo());
+        let octree = sculpture.octree();
+        assert!(octree.get_cells_with_indices().iter().all(|&(_, id)| octree.cell(id).material == 0));
+    }
+}
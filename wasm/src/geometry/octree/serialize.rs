@@ -0,0 +1,315 @@
+//! Binary format of the octrees, to ship prebuilt worlds.
+//!
+//! All the numbers are little endian:
+//! - header: `b"OCT"`, then the version (1 byte)
+//! - range: smaller and greater corners (6 f32), then the depth (1 byte)
+//! - flags (1 byte): `MATERIALS` and `PALETTE` tell which payloads are present
+//! - number of nodes (u32), then the nodes in pre-order, 2 bits each
+//!   (`Sub`, `Cell`, `Completely(Inside)`, `Completely(Outside)`), packed in bytes
+//! - if `MATERIALS`: the material of each cell, in the same order (1 byte each)
+//! - if `PALETTE`: the number of colors minus 1 (1 byte), then the colors (3 f32 each).
+//!   There are at most 256 colors, one per material
+
+use super::{Octree, Node, NodeId, CellInfo, Material, default_palette};
+use super::NodeState::{Inside, Outside};
+use super::super::{V3, Range};
+
+const MAGIC: &[u8; 3] = b"OCT";
+const VERSION: u8 = 1;
+
+// flags
+const MATERIALS: u8 = 1;
+const PALETTE: u8 = 2;
+
+// code of each kind of node in the bitstream
+const SUB: u8 = 0;
+const CELL: u8 = 1;
+const INSIDE: u8 = 2;
+const OUTSIDE: u8 = 3;
+
+/// write 2 bits per node
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    n: usize,
+}
+
+impl BitWriter {
+    fn push(&mut self, code: u8) {
+        if self.n % 4 == 0 {
+            self.bytes.push(0);
+        }
+        *self.bytes.last_mut().unwrap() |= code << (2 * (self.n % 4));
+        self.n += 1;
+    }
+}
+
+/// read the bytes one after the other, with an error at the end of the data
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        let end = self.position + n;
+        let slice = self.bytes.get(self.position..end)
+            .ok_or_else(|| format!("unexpected end of data at byte {}", self.position))?;
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn f32(&mut self) -> Result<f32, String> {
+        let b = self.take(4)?;
+        Ok(f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn v3(&mut self) -> Result<V3, String> {
+        Ok(V3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+}
+
+fn push_v3(bytes: &mut Vec<u8>, v: V3) {
+    for x in &[v.x, v.y, v.z] {
+        bytes.extend_from_slice(&x.to_le_bytes());
+    }
+}
+
+/// rebuild the nodes of the bitstream, and the cells in pre-order
+struct Decoder<'a> {
+    codes: &'a [u8],
+    n_codes: usize,
+    next: usize,
+    nodes: Vec<Node>,
+    cells: Vec<NodeId>,
+}
+
+impl Decoder<'_> {
+    fn node(&mut self, depth: u8) -> Result<NodeId, String> {
+        if self.next >= self.n_codes {
+            return Err("the tree is longer than the number of nodes".to_string())
+        }
+        let code = self.codes[self.next / 4] >> (2 * (self.next % 4)) & 3;
+        self.next += 1;
+        let node = match code {
+            SUB if depth > 0 => {
+                let mut cubes = [0; 8];
+                for cube in cubes.iter_mut() {
+                    *cube = self.node(depth - 1)?;
+                }
+                Node::Sub(cubes)
+            }
+            SUB => return Err("subdivided node below the maximum depth".to_string()),
+            CELL if depth == 0 => Node::Cell(CellInfo::default()),
+            CELL => return Err(format!("cell at depth {} above the smallest cells", depth)),
+            INSIDE => Node::Completely(Inside),
+            _ => Node::Completely(Outside),
+        };
+        self.nodes.push(node);
+        let id = self.nodes.len() - 1;
+        if code == CELL {
+            self.cells.push(id);
+        }
+        Ok(id)
+    }
+}
+
+impl Octree {
+    fn encode(&self, node: NodeId, codes: &mut BitWriter, materials: &mut Vec<u8>) {
+        match &self.nodes[node] {
+            Node::Sub(cubes) => {
+                codes.push(SUB);
+                for &c in cubes {
+                    self.encode(c, codes, materials);
+                }
+            }
+            Node::Cell(info) => {
+                codes.push(CELL);
+                materials.push(info.material);
+            }
+            Node::Completely(Inside) => codes.push(INSIDE),
+            Node::Completely(Outside) => codes.push(OUTSIDE),
+        }
+    }
+
+    /// Encode the octree in the binary format of this module.
+    /// Only the nodes reachable from the root are saved, so the arena is compacted.
+    /// The materials and the palette are left out when they have their default value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut codes = BitWriter::default();
+        let mut materials = Vec::new();
+        self.encode(self.root, &mut codes, &mut materials);
+
+        let mut flags = 0;
+        if materials.iter().any(|&m| m != 0) {
+            flags |= MATERIALS;
+        }
+        if self.palette != default_palette() {
+            flags |= PALETTE;
+        }
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        push_v3(&mut bytes, self.range.smaller_corner);
+        push_v3(&mut bytes, self.range.greater_corner);
+        bytes.push(self.depth);
+        bytes.push(flags);
+        bytes.extend_from_slice(&(codes.n as u32).to_le_bytes());
+        bytes.extend_from_slice(&codes.bytes);
+        if flags & MATERIALS != 0 {
+            bytes.extend_from_slice(&materials);
+        }
+        if flags & PALETTE != 0 {
+            // the other colors cannot be used by a material
+            let colors = &self.palette[..self.palette.len().min(Material::MAX as usize + 1)];
+            bytes.push((colors.len() - 1) as u8);
+            for &color in colors {
+                push_v3(&mut bytes, color);
+            }
+        }
+        bytes
+    }
+
+    /// Decode an octree saved with `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let mut reader = Reader {bytes, position: 0};
+        if reader.take(3)? != MAGIC {
+            return Err("not an octree file".to_string())
+        }
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported version {} (expected {})", version, VERSION))
+        }
+        let range = Range::new(reader.v3()?, reader.v3()?);
+        let depth = reader.u8()?;
+        if depth > 10 {
+            return Err(format!("depth {} is too big", depth))
+        }
+        let flags = reader.u8()?;
+        let n_codes = reader.u32()? as usize;
+
+        let mut decoder = Decoder {
+            codes: reader.take(n_codes.div_ceil(4))?,
+            n_codes,
+            next: 0,
+            nodes: Vec::with_capacity(n_codes),
+            cells: Vec::new(),
+        };
+        let root = decoder.node(depth)?;
+        if decoder.next != n_codes {
+            return Err(format!("{} nodes after the end of the tree", n_codes - decoder.next))
+        }
+        let Decoder {mut nodes, cells, ..} = decoder;
+
+        if flags & MATERIALS != 0 {
+            for (&id, &material) in cells.iter().zip(reader.take(cells.len())?) {
+                if let Node::Cell(info) = &mut nodes[id] {
+                    info.material = material;
+                }
+            }
+        }
+        let mut octree = Octree::from_nodes(range, depth, nodes, root);
+        if flags & PALETTE != 0 {
+            let n = reader.u8()? as usize + 1;
+            let colors = (0..n).map(|_| reader.v3()).collect::<Result<Vec<V3>, String>>()?;
+            octree.set_palette(colors);
+        }
+        if reader.position != bytes.len() {
+            return Err(format!("{} bytes after the end of the octree", bytes.len() - reader.position))
+        }
+        Ok(octree)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Octree;
+    use super::super::sculpt::{Edit, Operation};
+    use super::super::super::{V3, Range, SIZE_VERTEX};
+    use super::super::super::sdf::Sdf;
+
+    fn cube() -> Range {
+        Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0))
+    }
+
+    fn mesh(octree: &mut Octree) -> (Vec<f32>, Vec<u16>) {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        octree.triangulate(&mut points, &mut indices).unwrap();
+        (points, indices)
+    }
+
+    #[test]
+    fn round_trip() {
+        let shape = Sdf::Torus {major: 0.6, minor: 0.25}.union(Sdf::Sphere {radius: 0.3});
+        let mut octree = Octree::new_from_dist(shape.compile(), cube(), 5);
+        let bytes = octree.to_bytes();
+        // 2 bits per node, and no payload
+        assert_eq!(bytes.len(), 3 + 1 + 24 + 2 + 4 + octree.nodes.len().div_ceil(4));
+
+        let mut loaded = Octree::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded.range, octree.range);
+        assert_eq!(loaded.depth, octree.depth);
+        assert_eq!(loaded.to_bytes(), bytes);
+        assert_eq!(mesh(&mut loaded), mesh(&mut octree));
+    }
+
+    #[test]
+    fn payloads() {
+        let mut octree = Octree::new_from_dist(Sdf::Sphere {radius: 0.7}.compile(), cube(), 4);
+        octree.set_materials(|p| if p.z > 0.0 {2} else {0});
+        octree.set_palette(vec![V3::new(1.0, 0.0, 0.0), V3::new(0.0, 1.0, 0.0), V3::new(0.0, 0.0, 1.0)]);
+
+        let mut loaded = Octree::from_bytes(&octree.to_bytes()).unwrap();
+        assert_eq!(loaded.palette, octree.palette);
+        let (points, _) = mesh(&mut loaded);
+        assert_eq!(points, mesh(&mut octree).0);
+        // the colors of the materials are saved
+        assert!(points.chunks(SIZE_VERTEX).any(|v| v[5] == 1.0));
+    }
+
+    #[test]
+    fn full_palette() {
+        let mut octree = Octree::new_from_dist(Sdf::Sphere {radius: 0.7}.compile(), cube(), 3);
+        let colors: Vec<V3> = (0..256).map(|i| V3::new(i as f32 / 255.0, 0.0, 1.0)).collect();
+        octree.set_palette(colors.clone());
+        assert_eq!(Octree::from_bytes(&octree.to_bytes()).unwrap().palette, colors);
+
+        // the colors after the last material are not saved
+        octree.set_palette((0..300).map(|i| V3::new(i as f32, 0.0, 0.0)).collect());
+        assert_eq!(Octree::from_bytes(&octree.to_bytes()).unwrap().palette.len(), 256);
+    }
+
+    #[test]
+    fn sculpted_arena_is_compacted() {
+        let mut octree = Octree::new_from_dist(Sdf::Sphere {radius: 0.6}.compile(), cube(), 5);
+        octree.apply(&Edit::sphere(V3::new(0.0, 0.0, 0.6), 0.3, Operation::Carve));
+        let loaded = Octree::from_bytes(&octree.to_bytes()).unwrap();
+        assert!(loaded.nodes.len() < octree.nodes.len());
+        assert_eq!(mesh(&mut loaded.clone()), mesh(&mut octree));
+    }
+
+    #[test]
+    fn invalid_data() {
+        let bytes = Octree::new_from_dist(Sdf::Sphere {radius: 0.6}.compile(), cube(), 3).to_bytes();
+        assert!(Octree::from_bytes(b"PLY").is_err());
+        assert!(Octree::from_bytes(&bytes[..bytes.len()-1]).is_err());
+
+        let mut next_version = bytes.clone();
+        next_version[3] += 1;
+        assert!(Octree::from_bytes(&next_version).unwrap_err().contains("version"));
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert!(Octree::from_bytes(&trailing).is_err());
+    }
+}
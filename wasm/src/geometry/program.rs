@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+
+use super::V3;
+use super::Range;
+use super::Dist;
+use super::interval::Interval;
+
+/// An operation of a `Program`.
+/// The arguments are the indices of previous operations
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Op {
+    // coordinates of the point
+    X,
+    Y,
+    Z,
+    Const(f32),
+    Add(usize, usize),
+    Sub(usize, usize),
+    Mul(usize, usize),
+    Div(usize, usize),
+    Neg(usize),
+    Abs(usize),
+    Sqrt(usize),
+    Min(usize, usize),
+    Max(usize, usize),
+    /// smooth noise between -1 and 1 at the point (x, y, z)
+    Noise(usize, usize, usize),
+}
+
+/// A function of a point, written as a list of simple operations on numbers.
+/// Each operation is computed once, and the result is the last one.
+/// The same list is evaluated on the cpu, with intervals,
+/// or translated to glsl for the gpu. The gpu computes with less precision,
+/// so its results are only close to the ones of the cpu
+#[derive(Clone, Debug, Default)]
+pub struct Program {
+    ops: Vec<Op>,
+    // index of the first occurrence of each operation
+    index: HashMap<Key, usize>,
+}
+
+// an operation that can be hashed: the constants are compared bit by bit
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct Key(std::mem::Discriminant<Op>, [usize; 3]);
+
+impl Key {
+    fn of(op: &Op) -> Self {
+        let args = match *op {
+            Op::X | Op::Y | Op::Z => [0, 0, 0],
+            Op::Const(c) => [c.to_bits() as usize, 0, 0],
+            Op::Add(a, b) | Op::Sub(a, b) | Op::Mul(a, b) | Op::Div(a, b)
+                | Op::Min(a, b) | Op::Max(a, b) => [a, b, 0],
+            Op::Neg(a) | Op::Abs(a) | Op::Sqrt(a) => [a, 0, 0],
+            Op::Noise(x, y, z) => [x, y, z],
+        };
+        Key(std::mem::discriminant(op), args)
+    }
+}
+
+// the constants are written like in the glsl code
+#[allow(clippy::excessive_precision)]
+fn hash(p: V3) -> f32 {
+    let x = (V3::dot(p, V3::new(127.1, 311.7, 74.7))).sin() * 43758.5453;
+    x - x.floor()
+}
+
+/// Value noise between -1 and 1: random values at the points of integer coordinates,
+/// smoothly interpolated in between.
+/// It is written like the glsl function `noise` in `NOISE_GLSL`, but the hash is the `sin`
+/// of large numbers, so the gpu (mediump or another `sin`) gives another noise
+pub fn value_noise(p: V3) -> f32 {
+    let i = p.map(f32::floor);
+    let f = p - i;
+    let u = f.map(|t| t*t*(3.0 - 2.0*t));
+    let mix = |a: f32, b: f32, t: f32| a*(1.0 - t) + b*t;
+    let h = |x: f32, y: f32, z: f32| hash(i + V3::new(x, y, z));
+
+    mix(
+        mix(mix(h(0.0, 0.0, 0.0), h(1.0, 0.0, 0.0), u.x),
+            mix(h(0.0, 1.0, 0.0), h(1.0, 1.0, 0.0), u.x), u.y),
+        mix(mix(h(0.0, 0.0, 1.0), h(1.0, 0.0, 1.0), u.x),
+            mix(h(0.0, 1.0, 1.0), h(1.0, 1.0, 1.0), u.x), u.y),
+        u.z) * 2.0 - 1.0
+}
+
+const NOISE_GLSL: &str = "
+float hash(vec3 p) {
+    return fract(sin(dot(p, vec3(127.1, 311.7, 74.7))) * 43758.5453);
+}
+
+float noise(vec3 p) {
+    vec3 i = floor(p);
+    vec3 f = p - i;
+    vec3 u = f*f*(3.0 - 2.0*f);
+    return mix(
+        mix(mix(hash(i), hash(i + vec3(1.0, 0.0, 0.0)), u.x),
+            mix(hash(i + vec3(0.0, 1.0, 0.0)), hash(i + vec3(1.0, 1.0, 0.0)), u.x), u.y),
+        mix(mix(hash(i + vec3(0.0, 0.0, 1.0)), hash(i + vec3(1.0, 0.0, 1.0)), u.x),
+            mix(hash(i + vec3(0.0, 1.0, 1.0)), hash(i + vec3(1.0, 1.0, 1.0)), u.x), u.y),
+        u.z) * 2.0 - 1.0;
+}
+";
+
+impl Program {
+    pub fn new() -> Self {
+        Program::default()
+    }
+
+    /// add an operation, and return the index of its result
+    pub fn push(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        let i = self.ops.len() - 1;
+        self.index.entry(Key::of(&op)).or_insert(i);
+        i
+    }
+
+    /// add an operation, unless the same one was already computed
+    pub fn push_unique(&mut self, op: Op) -> usize {
+        match self.index.get(&Key::of(&op)) {
+            Some(&i) => i,
+            None => self.push(op),
+        }
+    }
+
+    /// index of the result of the program
+    pub fn result(&self) -> usize {
+        self.ops.len() - 1
+    }
+
+    /// Add the operations of another program after these ones,
+    /// and return the index of its result.
+    /// The operations already computed are shared
+    pub fn append(&mut self, other: &Program) -> usize {
+        let mut new_index = Vec::with_capacity(other.ops.len());
+        for op in &other.ops {
+            let i = |a: usize| new_index[a];
+            let op = match *op {
+                Op::Add(a, b) => Op::Add(i(a), i(b)),
+                Op::Sub(a, b) => Op::Sub(i(a), i(b)),
+                Op::Mul(a, b) => Op::Mul(i(a), i(b)),
+                Op::Div(a, b) => Op::Div(i(a), i(b)),
+                Op::Neg(a) => Op::Neg(i(a)),
+                Op::Abs(a) => Op::Abs(i(a)),
+                Op::Sqrt(a) => Op::Sqrt(i(a)),
+                Op::Min(a, b) => Op::Min(i(a), i(b)),
+                Op::Max(a, b) => Op::Max(i(a), i(b)),
+                Op::Noise(x, y, z) => Op::Noise(i(x), i(y), i(z)),
+                op @ Op::X | op @ Op::Y | op @ Op::Z | op @ Op::Const(_) => op,
+            };
+            let index = self.push_unique(op);
+            new_index.push(index);
+        }
+        *new_index.last().expect("empty program")
+    }
+
+    /// compute all the operations with values of type T
+    fn run<T: Copy>(&self, p: [T; 3], constant: impl Fn(f32) -> T, f: impl Fn(&Op, &[T]) -> T) -> T {
+        let mut values: Vec<T> = Vec::with_capacity(self.ops.len());
+        for op in &self.ops {
+            let v = match op {
+                Op::X => p[0],
+                Op::Y => p[1],
+                Op::Z => p[2],
+                Op::Const(c) => constant(*c),
+                _ => f(op, &values),
+            };
+            values.push(v);
+        }
+        *values.last().expect("empty program")
+    }
+
+    /// value of the function at a point
+    pub fn eval(&self, p: V3) -> f32 {
+        self.run(p.to_array(), |c| c, |op, v| match *op {
+            Op::Add(a, b) => v[a] + v[b],
+            Op::Sub(a, b) => v[a] - v[b],
+            Op::Mul(a, b) => v[a] * v[b],
+            Op::Div(a, b) => v[a] / v[b],
+            Op::Neg(a) => -v[a],
+            Op::Abs(a) => v[a].abs(),
+            Op::Sqrt(a) => v[a].sqrt(),
+            Op::Min(a, b) => f32::min(v[a], v[b]),
+            Op::Max(a, b) => f32::max(v[a], v[b]),
+            Op::Noise(x, y, z) => value_noise(V3::new(v[x], v[y], v[z])),
+            Op::X | Op::Y | Op::Z | Op::Const(_) => unreachable!(),
+        })
+    }
+
+    /// Bounds of the function over a range: for every point p in the range,
+    /// `self.eval(p)` is in the result
+    pub fn bounds(&self, range: &Range) -> Interval {
+        let s = range.smaller_corner;
+        let g = range.greater_corner;
+        let p = [
+            Interval::new(s.x, g.x),
+            Interval::new(s.y, g.y),
+            Interval::new(s.z, g.z),
+        ];
+        self.run(p, Interval::point, |op, v| match *op {
+            // a number multiplied by itself cannot be negative
+            Op::Mul(a, b) if a == b => v[a].square(),
+            Op::Add(a, b) => v[a] + v[b],
+            Op::Sub(a, b) => v[a] - v[b],
+            Op::Mul(a, b) => v[a] * v[b],
+            Op::Div(a, b) => v[a] / v[b],
+            Op::Neg(a) => -v[a],
+            Op::Abs(a) => v[a].abs(),
+            Op::Sqrt(a) => v[a].sqrt(),
+            Op::Min(a, b) => v[a].min(v[b]),
+            Op::Max(a, b) => v[a].max(v[b]),
+            Op::Noise(..) => Interval::new(-1.0, 1.0),
+            Op::X | Op::Y | Op::Z | Op::Const(_) => unreachable!(),
+        })
+    }
+
+    /// Glsl function `float scene(vec3 p)`, one variable per operation.
+    /// Glsl has no literal for the infinities and NaN, so their constants are an error
+    pub fn to_glsl(&self) -> Result<String, String> {
+        let lines: Vec<String> = self.ops.iter().enumerate().map(|(i, op)| {
+            let value = match *op {
+                Op::Const(c) if !c.is_finite() => return Err(format!("constant {} of v{} is not finite", c, i)),
+                Op::X => "p.x".to_string(),
+                Op::Y => "p.y".to_string(),
+                Op::Z => "p.z".to_string(),
+                Op::Const(c) => format!("{:?}", c),
+                Op::Add(a, b) => format!("v{} + v{}", a, b),
+                Op::Sub(a, b) => format!("v{} - v{}", a, b),
+                Op::Mul(a, b) => format!("v{} * v{}", a, b),
+                Op::Div(a, b) => format!("v{} / v{}", a, b),
+                Op::Neg(a) => format!("-v{}", a),
+                Op::Abs(a) => format!("abs(v{})", a),
+                Op::Sqrt(a) => format!("sqrt(v{})", a),
+                Op::Min(a, b) => format!("min(v{}, v{})", a, b),
+                Op::Max(a, b) => format!("max(v{}, v{})", a, b),
+                Op::Noise(x, y, z) => format!("noise(vec3(v{}, v{}, v{}))", x, y, z),
+            };
+            Ok(format!("    float v{} = {};", i, value))
+        }).collect::<Result<_, _>>()?;
+
+        let uses_noise = self.ops.iter().any(|op| matches!(op, Op::Noise(..)));
+        Ok(format!(
+            "{}float scene(vec3 p) {{\n{}\n    return v{};\n}}\n",
+            if uses_noise {NOISE_GLSL} else {""},
+            lines.join("\n"),
+            self.ops.len() - 1))
+    }
+}
+
+impl Dist for Program {
+    fn dist(&self, p: V3) -> f32 {
+        self.eval(p)
+    }
+    fn bounds(&self, range: &Range) -> Interval {
+        Program::bounds(self, range)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{Program, Op, value_noise};
+    use super::super::{V3, Range};
+    use super::super::random::Rng;
+
+    /// Run the function `scene` written by `Program::to_glsl` at a point, line by line,
+    /// to check the text given to the gpu. The noise is the one of the cpu
+    pub fn run_glsl(glsl: &str, p: V3) -> f32 {
+        let start = glsl.find("float scene(vec3 p) {\n").expect("no scene function");
+        let mut values: Vec<f32> = Vec::new();
+        for line in glsl[start..].lines().skip(1) {
+            let var = |name: &str| values[name.trim().strip_prefix('v').unwrap().parse::<usize>().unwrap()];
+            let args = |call: &str| -> Vec<f32> {
+                let inside = &call[call.rfind('(').unwrap()+1 .. call.find(')').unwrap()];
+                inside.split(',').map(var).collect()
+            };
+            if let Some(result) = line.trim().strip_prefix("return ") {
+                return var(result.strip_suffix(';').unwrap());
+            }
+            let (name, expr) = line.trim().strip_prefix("float ").unwrap()
+                .strip_suffix(';').unwrap()
+                .split_once(" = ").unwrap();
+            assert_eq!(name, format!("v{}", values.len()));
+            let binary = [" + ", " - ", " * ", " / "].iter().find_map(|op| expr.split_once(op).map(|(a, b)| (op.trim(), var(a), var(b))));
+            let v = match expr {
+                "p.x" => p.x,
+                "p.y" => p.y,
+                "p.z" => p.z,
+                _ if expr.starts_with("abs(") => args(expr)[0].abs(),
+                _ if expr.starts_with("sqrt(") => args(expr)[0].sqrt(),
+                _ if expr.starts_with("min(") => args(expr).into_iter().fold(f32::INFINITY, f32::min),
+                _ if expr.starts_with("max(") => args(expr).into_iter().fold(f32::NEG_INFINITY, f32::max),
+                _ if expr.starts_with("noise(vec3(") => {
+                    let a = args(expr);
+                    value_noise(V3::new(a[0], a[1], a[2]))
+                }
+                _ if expr.starts_with("-v") => -var(&expr[1..]),
+                _ => match binary {
+                    Some(("+", a, b)) => a + b,
+                    Some(("-", a, b)) => a - b,
+                    Some(("*", a, b)) => a * b,
+                    Some(("/", a, b)) => a / b,
+                    _ => expr.parse().unwrap_or_else(|_| panic!("unknown expression {}", expr)),
+                }
+            };
+            values.push(v);
+        }
+        panic!("no return")
+    }
+
+    #[test]
+    fn noise_is_smooth_and_bounded() {
+        let mut rng = Rng::new(1);
+        for _ in 0..1000 {
+            let p = rng.unit_v3().scale(20.0);
+            let n = value_noise(p);
+            assert!((-1.0..=1.0).contains(&n));
+            assert!((value_noise(p + V3::new(1e-3, 0.0, 0.0)) - n).abs() < 0.1);
+        }
+    }
+
+    #[test]
+    fn bounds_contain_values() {
+        let mut rng = Rng::new(2);
+        // (x*x + y/z, noise) mixed with min, max and sqrt
+        let mut prog = Program::new();
+        let x = prog.push(Op::X);
+        let y = prog.push(Op::Y);
+        let z = prog.push(Op::Z);
+        let xx = prog.push(Op::Mul(x, x));
+        let yz = prog.push(Op::Div(y, z));
+        let a = prog.push(Op::Add(xx, yz));
+        let n = prog.push(Op::Noise(x, y, z));
+        let s = prog.push(Op::Sqrt(xx));
+        let m = prog.push(Op::Min(a, n));
+        prog.push(Op::Max(m, s));
+
+        for _ in 0..100 {
+            let c = rng.unit_v3().scale(3.0);
+            let range = Range::new(c, c).inflate(rng.float());
+            let bounds = prog.bounds(&range);
+            for &corner in &range.corners() {
+                assert!(bounds.contains(prog.eval(corner)));
+            }
+            let d = range.diagonal();
+            let p = range.smaller_corner + V3::new(d.x*rng.float(), d.y*rng.float(), d.z*rng.float());
+            assert!(bounds.contains(prog.eval(p)));
+        }
+    }
+
+    #[test]
+    fn glsl_computes_the_same_values() {
+        let mut rng = Rng::new(3);
+        let mut prog = Program::new();
+        let x = prog.push(Op::X);
+        let y = prog.push(Op::Y);
+        let z = prog.push(Op::Z);
+        let c = prog.push(Op::Const(-0.25));
+        let d = prog.push(Op::Div(y, c));
+        let s = prog.push(Op::Sub(x, d));
+        let a = prog.push(Op::Abs(s));
+        let n = prog.push(Op::Noise(z, x, y));
+        let m = prog.push(Op::Neg(n));
+        prog.push(Op::Min(a, m));
+        let glsl = prog.to_glsl().unwrap();
+        for _ in 0..100 {
+            let p = rng.unit_v3().scale(3.0);
+            assert_eq!(run_glsl(&glsl, p), prog.eval(p));
+        }
+    }
+
+    #[test]
+    fn infinite_constants_are_rejected() {
+        let mut prog = Program::new();
+        let x = prog.push(Op::X);
+        let c = prog.push(Op::Const(f32::INFINITY));
+        prog.push(Op::Mul(x, c));
+        assert!(prog.to_glsl().is_err());
+        prog.push(Op::Const(f32::NAN));
+        assert!(prog.to_glsl().is_err());
+    }
+}
@@ -1,5 +1,5 @@
 use getrandom;
-use super::V3;
+use super::{V3, Range, get_point};
 
 
 pub fn rand_float() -> f32 {
@@ -21,3 +21,327 @@ pub fn rand_v3() -> V3 {
 
     (V3{x, y, z}.scale(SCALE_FACTOR)-V3::new(1.0, 1.0, 1.0)).normalize()
 }
+
+
+/// A random generator that always gives the same numbers for the same seed (splitmix64)
+#[derive(Clone, Debug)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng {state: seed}
+    }
+
+    /// a generator with a random seed
+    pub fn from_entropy() -> Self {
+        let mut buff = [0; 8];
+        getrandom::getrandom(&mut buff).unwrap();
+        Rng::new(u64::from_le_bytes(buff))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// between 0 and 1, 1 excluded
+    pub fn float(&mut self) -> f32 {
+        // the 24 bits of the mantissa
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + self.float() * (max - min)
+    }
+
+    /// between 0 and `n`, `n` excluded
+    pub fn below(&mut self, n: usize) -> usize {
+        ((self.next_u64() as u128 * n as u128) >> 64) as usize
+    }
+
+    /// a direction, with the same probability everywhere on the unit sphere
+    pub fn unit_v3(&mut self) -> V3 {
+        let z = self.range(-1.0, 1.0);
+        let (sin, cos) = (self.float() * std::f32::consts::TAU).sin_cos();
+        let r = (1.0 - z * z).max(0.0).sqrt();
+        V3::new(r * cos, r * sin, z)
+    }
+
+    /// a point of a triangle, with the same probability everywhere
+    pub fn in_triangle(&mut self, [a, b, c]: [V3; 3]) -> V3 {
+        let (r1, r2) = (self.float().sqrt(), self.float());
+        a.scale(1.0 - r1) + b.scale(r1 * (1.0 - r2)) + c.scale(r1 * r2)
+    }
+}
+
+/// Random points on the triangles of a mesh, with the same probability everywhere on the surface
+pub struct MeshSampler {
+    triangles: Vec<[V3; 3]>,
+    // the area of the triangles before each one, this one included
+    areas: Vec<f32>,
+}
+
+impl MeshSampler {
+    pub fn new(points: &[f32], indices: &[u16]) -> Self {
+        let triangles: Vec<[V3; 3]> = indices.chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]].map(|i| get_point(points, i)))
+            .collect();
+        let mut total = 0.0;
+        let areas = triangles.iter()
+            .map(|t| {
+                total += V3::cross(t[1] - t[0], t[2] - t[0]).norm() / 2.0;
+                total
+            })
+            .collect();
+        MeshSampler {triangles, areas}
+    }
+
+    pub fn area(&self) -> f32 {
+        self.areas.last().copied().unwrap_or(0.0)
+    }
+
+    /// a point and the index of its triangle, `None` if the mesh has no area
+    pub fn sample(&self, rng: &mut Rng) -> Option<(V3, usize)> {
+        let total = self.area();
+        if total <= 0.0 {
+            return None
+        }
+        let x = rng.float() * total;
+        let i = self.areas.partition_point(|&a| a <= x).min(self.triangles.len() - 1);
+        Some((rng.in_triangle(self.triangles[i]), i))
+    }
+
+    pub fn triangle(&self, i: usize) -> [V3; 3] {
+        self.triangles[i]
+    }
+}
+
+// number of points tried around a point before it is done
+const POISSON_ATTEMPTS: usize = 30;
+
+/// Bridson's algorithm: points in the box from 0 to `size`, no closer than `radius`
+/// and such that there is no room for another one
+fn bridson<const N: usize>(rng: &mut Rng, size: [f32; N], radius: f32) -> Vec<[f32; N]> {
+    assert!(radius > 0.0, "the points of a Poisson-disk sampling must be apart, not {}", radius);
+    // a cell contains at most one point
+    let cell = radius / (N as f32).sqrt();
+    let cells = size.map(|s| ((s / cell).ceil() as usize).max(1));
+    let index = |p: &[f32; N]| -> usize {
+        (0..N).rev().fold(0, |i, k| i * cells[k] + ((p[k] / cell) as usize).min(cells[k] - 1))
+    };
+    let mut grid = vec![usize::MAX; cells.iter().product()];
+    // the cells around a point that can contain a point closer than `radius`
+    let reach = (N as f32).sqrt().ceil() as isize;
+    let side = 2 * reach as usize + 1;
+    let too_close = |grid: &[usize], samples: &[[f32; N]], p: &[f32; N]| {
+        let center = p.map(|x| (x / cell) as isize);
+        (0..side.pow(N as u32)).any(|mut offset| {
+            let mut i = 0;
+            for k in (0..N).rev() {
+                let c = center[k] + (offset % side) as isize - reach;
+                offset /= side;
+                if c < 0 || c >= cells[k] as isize {
+                    return false
+                }
+                i = i * cells[k] + c as usize;
+            }
+            let j = grid[i];
+            j != usize::MAX && (0..N).map(|k| (samples[j][k] - p[k]).powi(2)).sum::<f32>() < radius * radius
+        })
+    };
+
+    let first = size.map(|s| rng.float() * s);
+    let mut samples = vec![first];
+    grid[index(&first)] = 0;
+    let mut active = vec![0];
+    while !active.is_empty() {
+        let a = rng.below(active.len());
+        let center = samples[active[a]];
+        let mut found = false;
+        for _ in 0..POISSON_ATTEMPTS {
+            // a direction, then a distance between `radius` and `2*radius`, uniform in volume
+            let direction = loop {
+                let d = [0.0; N].map(|_| rng.range(-1.0, 1.0));
+                let norm = d.iter().map(|x| x * x).sum::<f32>().sqrt();
+                if norm > 1e-3 && norm <= 1.0 {
+                    break d.map(|x| x / norm)
+                }
+            };
+            let growth = (1u32 << N) as f32 - 1.0;
+            let distance = radius * (1.0 + rng.float() * growth).powf(1.0 / N as f32);
+            let mut p = center;
+            for k in 0..N {
+                p[k] += direction[k] * distance;
+            }
+            if (0..N).any(|k| p[k] < 0.0 || p[k] >= size[k]) || too_close(&grid, &samples, &p) {
+                continue
+            }
+            grid[index(&p)] = samples.len();
+            active.push(samples.len());
+            samples.push(p);
+            found = true;
+            break
+        }
+        if !found {
+            active.swap_remove(a);
+        }
+    }
+    samples
+}
+
+/// blue noise: points of the rectangle from (0, 0) to (`width`, `height`), at least `radius` apart
+pub fn poisson_2d(rng: &mut Rng, width: f32, height: f32, radius: f32) -> Vec<[f32; 2]> {
+    bridson(rng, [width, height], radius)
+}
+
+/// blue noise: points of `range`, at least `radius` apart
+pub fn poisson_3d(rng: &mut Rng, range: Range, radius: f32) -> Vec<V3> {
+    bridson(rng, range.diagonal().to_array(), radius).into_iter()
+        .map(|p| range.smaller_corner + V3::from(p))
+        .collect()
+}
+
+/// One point in each cell of a grid of `n_x` by `n_y` cells over the rectangle from (0, 0) to (`width`, `height`).
+/// With a `jitter` of 0 the points are the centers of the cells, with 1 they are anywhere in them
+pub fn stratified_2d(rng: &mut Rng, (width, height): (f32, f32), (n_x, n_y): (usize, usize), jitter: f32) -> Vec<[f32; 2]> {
+    let (w, h) = (width / n_x as f32, height / n_y as f32);
+    let mut samples = Vec::with_capacity(n_x * n_y);
+    for y in 0..n_y {
+        for x in 0..n_x {
+            let dx = 0.5 + jitter * (rng.float() - 0.5);
+            let dy = 0.5 + jitter * (rng.float() - 0.5);
+            samples.push([(x as f32 + dx) * w, (y as f32 + dy) * h]);
+        }
+    }
+    samples
+}
+
+/// one point in each cell of a grid over `range`, see `stratified_2d`
+pub fn stratified_3d(rng: &mut Rng, range: Range, (n_x, n_y, n_z): (usize, usize, usize), jitter: f32) -> Vec<V3> {
+    let diagonal = range.diagonal();
+    let cell = V3::new(diagonal.x / n_x as f32, diagonal.y / n_y as f32, diagonal.z / n_z as f32);
+    let mut samples = Vec::with_capacity(n_x * n_y * n_z);
+    for z in 0..n_z {
+        for y in 0..n_y {
+            for x in 0..n_x {
+                let d = [0.0; 3].map(|_| 0.5 + jitter * (rng.float() - 0.5));
+                samples.push(range.smaller_corner + V3::new(
+                    (x as f32 + d[0]) * cell.x,
+                    (y as f32 + d[1]) * cell.y,
+                    (z as f32 + d[2]) * cell.z,
+                ));
+            }
+        }
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rng, MeshSampler, poisson_2d, poisson_3d, stratified_2d, stratified_3d};
+    use super::super::{V3, Range};
+
+    #[test]
+    fn seeds() {
+        let (mut a, mut b, mut c) = (Rng::new(42), Rng::new(42), Rng::new(43));
+        let first: Vec<u64> = (0..10).map(|_| a.next_u64()).collect();
+        assert_eq!(first, (0..10).map(|_| b.next_u64()).collect::<Vec<u64>>());
+        assert_ne!(first, (0..10).map(|_| c.next_u64()).collect::<Vec<u64>>());
+
+        let floats: Vec<f32> = (0..10_000).map(|_| a.float()).collect();
+        assert!(floats.iter().all(|x| (0.0..1.0).contains(x)));
+        let mean = floats.iter().sum::<f32>() / floats.len() as f32;
+        assert!((mean - 0.5).abs() < 0.02);
+        assert!((0..1000).all(|_| a.below(7) < 7));
+
+        // the directions are unit vectors and cancel out on average
+        let mut sum = V3::null();
+        for _ in 0..10_000 {
+            let v = a.unit_v3();
+            assert!((v.norm() - 1.0).abs() < 1e-4);
+            sum += v;
+        }
+        assert!(sum.norm() / 10_000.0 < 0.03);
+    }
+
+    #[test]
+    fn poisson() {
+        let points = poisson_2d(&mut Rng::new(1), 10.0, 5.0, 0.5);
+        for (i, a) in points.iter().enumerate() {
+            assert!(a[0] >= 0.0 && a[0] < 10.0 && a[1] >= 0.0 && a[1] < 5.0);
+            for b in &points[i+1..] {
+                assert!((a[0]-b[0]).hypot(a[1]-b[1]) >= 0.5);
+            }
+        }
+        // no hole: every point of the rectangle is close to a sample
+        for x in 0..40 {
+            for y in 0..20 {
+                let (x, y) = (x as f32 * 0.25, y as f32 * 0.25);
+                assert!(points.iter().any(|p| (p[0]-x).hypot(p[1]-y) < 1.0));
+            }
+        }
+        assert_eq!(points, poisson_2d(&mut Rng::new(1), 10.0, 5.0, 0.5));
+
+        let range = Range::new(V3::new(-1.0, -2.0, 0.0), V3::new(2.0, 2.0, 1.0));
+        let points = poisson_3d(&mut Rng::new(2), range, 0.4);
+        assert!(points.len() > 50);
+        for (i, &a) in points.iter().enumerate() {
+            assert!(range.contain(a));
+            assert!(points[i+1..].iter().all(|&b| (a - b).norm() >= 0.4));
+        }
+    }
+
+    #[test]
+    fn stratified() {
+        let mut rng = Rng::new(3);
+        let points = stratified_2d(&mut rng, (4.0, 2.0), (4, 2), 1.0);
+        assert_eq!(points.len(), 8);
+        for (i, p) in points.iter().enumerate() {
+            assert_eq!([p[0] as usize, p[1] as usize], [i % 4, i / 4]);
+        }
+        assert_eq!(stratified_2d(&mut rng, (4.0, 2.0), (4, 2), 0.0)[5], [1.5, 1.5]);
+
+        let range = Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0));
+        let points = stratified_3d(&mut rng, range, (2, 2, 2), 1.0);
+        assert_eq!(points.len(), 8);
+        for (i, p) in points.iter().enumerate() {
+            let expected = [i % 2, i / 2 % 2, i / 4].map(|k| k == 1);
+            assert_eq!([p.x > 0.0, p.y > 0.0, p.z > 0.0], expected);
+        }
+    }
+
+    #[test]
+    fn triangles() {
+        let mut rng = Rng::new(4);
+        let t = [V3::new(0.0, 0.0, 1.0), V3::new(3.0, 0.0, 1.0), V3::new(0.0, 3.0, 1.0)];
+        let mut mean = V3::null();
+        for _ in 0..10_000 {
+            let p = rng.in_triangle(t);
+            assert!(p.x >= 0.0 && p.y >= 0.0 && p.x + p.y <= 3.0 + 1e-5 && (p.z - 1.0).abs() < 1e-6);
+            mean += p.scale(1e-4);
+        }
+        // the centroid
+        assert!((mean - V3::new(1.0, 1.0, 1.0)).norm() < 0.05);
+
+        // a triangle of area 1 and one of area 3
+        let mut points = Vec::new();
+        for [x, y, z] in [[0, 0, 0], [2, 0, 0], [0, 1, 0], [0, 0, 5], [6, 0, 5], [0, 1, 5]] {
+            push_point!(points, [x, y, z], [0, 0, 0], [0, 0, 0], [0, 0, 0]);
+        }
+        let sampler = MeshSampler::new(&points, &[0, 1, 2, 3, 4, 5]);
+        assert_eq!(sampler.area(), 4.0);
+        let mut counts = [0; 2];
+        for _ in 0..10_000 {
+            let (p, i) = sampler.sample(&mut rng).unwrap();
+            assert!((p.z - [0.0, 5.0][i]).abs() < 1e-5);
+            counts[i] += 1;
+        }
+        assert!((counts[1] as f32 / 10_000.0 - 0.75).abs() < 0.02);
+        assert!(MeshSampler::new(&points, &[]).sample(&mut rng).is_none());
+    }
+}
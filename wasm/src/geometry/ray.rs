@@ -0,0 +1,127 @@
+use super::V3;
+use super::Dist;
+
+/// half-line `origin + t*dir` with t >= 0.
+/// `dir` is normalized, so t is a distance
+#[derive(Copy, Clone, Debug)]
+pub struct Ray {
+    pub origin: V3,
+    pub dir: V3,
+}
+
+/// A point where a ray hits a shape
+#[derive(Copy, Clone, Debug)]
+pub struct Hit {
+    /// distance along the ray
+    pub t: f32,
+    pub point: V3,
+    /// normal of the surface, facing the origin of the ray
+    pub normal: V3,
+    /// id of the object that was hit (the triangle for a mesh)
+    pub object: u32,
+}
+
+impl Ray {
+    pub fn new(origin: V3, dir: V3) -> Self {
+        Ray {origin, dir: dir.scale(1.0/dir.norm())}
+    }
+    pub fn at(&self, t: f32) -> V3 {
+        self.origin + self.dir.scale(t)
+    }
+}
+
+// sphere tracing parameters
+const MAX_STEPS: usize = 256;
+const HIT_DIST: f32 = 1e-4;
+
+/// gradient of a distance function with central differences.
+/// For a real distance function, it is the normal of the surface
+pub fn gradient(shape: &impl Dist, p: V3) -> V3 {
+    let h = 1e-3;
+    let dx = V3::new(h, 0.0, 0.0);
+    let dy = V3::new(0.0, h, 0.0);
+    let dz = V3::new(0.0, 0.0, h);
+    V3::new(
+        shape.dist(p+dx) - shape.dist(p-dx),
+        shape.dist(p+dy) - shape.dist(p-dy),
+        shape.dist(p+dz) - shape.dist(p-dz),
+    ).scale(0.5/h)
+}
+
+/// Sphere tracing: advance along the ray by the distance to the shape,
+/// since there cannot be any surface closer than that.
+/// Only correct if `shape` never overestimates the distance
+pub fn march(shape: &impl Dist, ray: &Ray, max_dist: f32) -> Option<Hit> {
+    let mut t = 0.0;
+    for _ in 0..MAX_STEPS {
+        let point = ray.at(t);
+        let d = shape.dist(point);
+        if d.abs() < HIT_DIST*(1.0+t) {
+            let n = gradient(shape, point);
+            return Some(Hit {t, point, normal: n.scale(1.0/n.norm()), object: 0})
+        }
+        t += d.abs();
+        if t > max_dist {
+            break
+        }
+    }
+    None
+}
+
+/// Möller–Trumbore intersection between a ray and the triangle (a, b, c).
+/// Return the distance along the ray, both sides of the triangle are hit
+pub fn ray_triangle(ray: &Ray, a: V3, b: V3, c: V3) -> Option<f32> {
+    let e1 = b - a;
+    let e2 = c - a;
+    let p = V3::cross(ray.dir, e2);
+    let det = V3::dot(e1, p);
+    if det.abs() < 1e-8 {
+        // the ray is parallel to the triangle
+        return None
+    }
+    let inv_det = 1.0/det;
+
+    // barycentric coordinates
+    let s = ray.origin - a;
+    let u = V3::dot(s, p)*inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None
+    }
+    let q = V3::cross(s, e1);
+    let v = V3::dot(ray.dir, q)*inv_det;
+    if v < 0.0 || u+v > 1.0 {
+        return None
+    }
+
+    let t = V3::dot(e2, q)*inv_det;
+    if t >= 0.0 {Some(t)} else {None}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ray, V3, march, ray_triangle};
+
+    #[test]
+    fn march_sphere() {
+        let sphere = |p: V3| p.norm() - 1.0;
+        let ray = Ray::new(V3::new(-5.0, 0.0, 0.0), V3::new(1.0, 0.0, 0.0));
+        let hit = march(&sphere, &ray, 100.0).unwrap();
+        assert!((hit.t - 4.0).abs() < 1e-3);
+        assert!((hit.normal - V3::new(-1.0, 0.0, 0.0)).norm() < 1e-2);
+
+        let miss = Ray::new(V3::new(-5.0, 2.0, 0.0), V3::new(1.0, 0.0, 0.0));
+        assert!(march(&sphere, &miss, 100.0).is_none());
+    }
+
+    #[test]
+    fn triangle() {
+        let (a, b, c) = (V3::new(0.0, 0.0, 0.0), V3::new(1.0, 0.0, 0.0), V3::new(0.0, 1.0, 0.0));
+        let down = Ray::new(V3::new(0.2, 0.2, 3.0), V3::new(0.0, 0.0, -1.0));
+        assert!((ray_triangle(&down, a, b, c).unwrap() - 3.0).abs() < 1e-5);
+
+        let outside = Ray::new(V3::new(0.8, 0.8, 3.0), V3::new(0.0, 0.0, -1.0));
+        assert!(ray_triangle(&outside, a, b, c).is_none());
+        let up = Ray::new(V3::new(0.2, 0.2, 3.0), V3::new(0.0, 0.0, 1.0));
+        assert!(ray_triangle(&up, a, b, c).is_none());
+    }
+}
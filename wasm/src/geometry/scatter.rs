@@ -0,0 +1,355 @@
+//! Placement of objects on a surface, for example rocks and trees on the terrain.
+//!
+//! The positions are a Poisson-disk sampling of the horizontal plane under the mesh
+//! (Bridson's algorithm, in `random::poisson_2d`), moved up or down onto the triangles.
+//! The points on the ground are at least as far apart as on the plane
+
+use super::{V3, Range, SIZE_VERTEX, get_point};
+use super::noise::Perlin;
+use super::random::{Rng, poisson_2d};
+
+// never more than about this number of points tried: the spacing is larger on very big meshes
+const MAX_SAMPLES: usize = 200_000;
+
+/// The position, orientation and size of a copy of an object
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Instance {
+    pub position: V3,
+    /// the directions of the x, y and z axes of the object
+    pub axes: [V3; 3],
+    pub scale: f32,
+}
+
+impl Instance {
+    /// the transformation of the object, a 4x4 matrix by columns
+    pub fn matrix(&self) -> [f32; 16] {
+        let [x, y, z] = self.axes.map(|a| a.scale(self.scale));
+        [
+            x.x, x.y, x.z, 0.0,
+            y.x, y.y, y.z, 0.0,
+            z.x, z.y, z.z, 0.0,
+            self.position.x, self.position.y, self.position.z, 1.0,
+        ]
+    }
+
+    /// a point of the object, in the world
+    pub fn apply(&self, p: V3) -> V3 {
+        self.position + (self.axes[0].scale(p.x) + self.axes[1].scale(p.y) + self.axes[2].scale(p.z)).scale(self.scale)
+    }
+}
+
+/// the triangles of a mesh seen from above, by cells of the horizontal plane
+struct Ground {
+    triangles: Vec<[V3; 3]>,
+    // the rectangle under the mesh
+    corner: [f32; 2],
+    width: f32,
+    height: f32,
+    cell: f32,
+    n_x: usize,
+    // the triangles whose bounding box touches each cell, row by row
+    cells: Vec<Vec<usize>>,
+}
+
+impl Ground {
+    /// `None` if the mesh is not seen from above
+    fn new(points: &[f32], indices: &[u16]) -> Option<Self> {
+        let triangles: Vec<[V3; 3]> = indices.chunks_exact(3)
+            .map(|t| [t[0], t[1], t[2]].map(|i| get_point(points, i)))
+            // the vertical triangles have no ground
+            .filter(|t| V3::cross(t[1] - t[0], t[2] - t[0]).z != 0.0)
+            .collect();
+        let first = triangles.first()?[0];
+        let (min, max) = triangles.iter().flatten()
+            .fold((first, first), |(min, max), &p| (V3::min(min, p), V3::max(max, p)));
+        let (width, height) = (max.x - min.x, max.y - min.y);
+        // about one triangle by cell. A mesh in a vertical plane has no ground
+        let cell = (width * height / triangles.len() as f32).sqrt();
+        if !cell.is_normal() {
+            return None
+        }
+        let (n_x, n_y) = ((width / cell) as usize + 1, (height / cell) as usize + 1);
+        let mut ground = Ground {triangles: Vec::new(), corner: [min.x, min.y], width, height, cell, n_x, cells: vec![Vec::new(); n_x * n_y]};
+        for (i, t) in triangles.iter().enumerate() {
+            let (low, high) = t.iter().fold((t[0], t[0]), |(low, high), &p| (V3::min(low, p), V3::max(high, p)));
+            let [x0, y0] = ground.cell_of(low.x, low.y);
+            let [x1, y1] = ground.cell_of(high.x, high.y);
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    ground.cells[y * n_x + x].push(i);
+                }
+            }
+        }
+        ground.triangles = triangles;
+        Some(ground)
+    }
+
+    fn cell_of(&self, x: f32, y: f32) -> [usize; 2] {
+        let n_y = self.cells.len() / self.n_x;
+        let x = (((x - self.corner[0]) / self.cell).max(0.0) as usize).min(self.n_x - 1);
+        let y = (((y - self.corner[1]) / self.cell).max(0.0) as usize).min(n_y - 1);
+        [x, y]
+    }
+
+    /// the highest point of the mesh above or below (x, y), and its triangle
+    fn under(&self, x: f32, y: f32) -> Option<(V3, [V3; 3])> {
+        let [cx, cy] = self.cell_of(x, y);
+        let cross = |a: V3, b: V3| a.x * b.y - a.y * b.x;
+        let p = V3::new(x, y, 0.0);
+        self.cells[cy * self.n_x + cx].iter()
+            .filter_map(|&i| {
+                let [a, b, c] = self.triangles[i];
+                let area = cross(b - a, c - a);
+                // barycentric coordinates in the horizontal plane
+                let (u, v) = (cross(c - b, p - b) / area, cross(a - c, p - c) / area);
+                let w = 1.0 - u - v;
+                let inside = u >= -1e-6 && v >= -1e-6 && w >= -1e-6;
+                inside.then(|| (V3::new(x, y, u * a.z + v * b.z + w * c.z), self.triangles[i]))
+            })
+            .max_by(|(p, _), (q, _)| p.z.total_cmp(&q.z))
+    }
+}
+
+/// Where and how the objects are placed
+#[derive(Copy, Clone, Debug)]
+pub struct Scatter {
+    /// minimal distance between 2 objects
+    pub spacing: f32,
+    /// greatest angle between the ground and the horizontal, in radians
+    pub max_slope: f32,
+    /// the objects are between these 2 heights
+    pub altitude: (f32, f32),
+    /// the scale of each object is chosen at random between these 2 values
+    pub scale: (f32, f32),
+    /// 0: the objects stand up, 1: they are perpendicular to the ground
+    pub align: f32,
+    pub max_instances: usize,
+}
+
+impl Default for Scatter {
+    fn default() -> Self {
+        Scatter {
+            spacing: 1.0,
+            max_slope: std::f32::consts::FRAC_PI_2,
+            altitude: (f32::NEG_INFINITY, f32::INFINITY),
+            scale: (1.0, 1.0),
+            align: 0.0,
+            max_instances: usize::MAX,
+        }
+    }
+}
+
+/// A density between 0 and 1 that changes smoothly over `range`, with `cells` noise cells on each axis
+pub fn noise_density(range: Range, cells: (usize, usize, usize)) -> impl Fn(V3) -> f32 {
+    // the noise is not defined on the far side of its range
+    let perlin = Perlin::new(range.inflate(range.diagonal().norm() * 1e-3), cells, 1.0);
+    move |p| (0.5 + perlin.noise(range.clamp(p))).clamp(0.0, 1.0)
+}
+
+impl Scatter {
+    /// Place objects on the triangles of a mesh, at least `spacing` apart (which must be positive).
+    /// A point is kept with the probability `density(point)`.
+    /// The ground is the side of the triangles that faces up. The same seed gives the same places
+    pub fn scatter(&self, rng: &mut Rng, points: &[f32], indices: &[u16], density: impl Fn(V3) -> f32) -> Vec<Instance> {
+        let ground = match Ground::new(points, indices) {
+            Some(ground) => ground,
+            None => return Vec::new(),
+        };
+        let (corner, width, height) = (ground.corner, ground.width, ground.height);
+        // the points of the ground are at least as far apart as their projections
+        let spacing = self.spacing.max((width * height / MAX_SAMPLES as f32).sqrt());
+
+        let mut instances = Vec::new();
+        for [x, y] in poisson_2d(rng, width, height, spacing) {
+            if instances.len() >= self.max_instances {
+                break
+            }
+            let Some((p, t)) = ground.under(corner[0] + x, corner[1] + y) else {
+                continue
+            };
+            let normal = V3::cross(t[1] - t[0], t[2] - t[0]);
+            let normal = normal.scale(normal.z.signum() / normal.norm());
+            let slope = normal.z.clamp(-1.0, 1.0).acos();
+            if slope > self.max_slope || p.z < self.altitude.0 || p.z > self.altitude.1 {
+                continue
+            }
+            if rng.float() >= density(p) {
+                continue
+            }
+            instances.push(self.instance(rng, p, normal));
+        }
+        instances
+    }
+
+    /// an object at `p`, turned at random around its axis
+    fn instance(&self, rng: &mut Rng, p: V3, normal: V3) -> Instance {
+        let angle = rng.float() * std::f32::consts::TAU;
+        let (sin, cos) = angle.sin_cos();
+        let mut axes = [V3::new(cos, sin, 0.0), V3::new(-sin, cos, 0.0), V3::new(0.0, 0.0, 1.0)];
+
+        // lean towards the normal of the ground
+        let vertical = V3::new(0.0, 0.0, 1.0);
+        let up = vertical.scale(1.0 - self.align) + normal.scale(self.align);
+        let axis = V3::cross(vertical, up);
+        if axis.norm() > 1e-6 {
+            let lean = V3::dot(vertical, up.scale(1.0 / up.norm())).clamp(-1.0, 1.0).acos();
+            let axis = axis.scale(1.0 / axis.norm());
+            axes = axes.map(|a| a.rotate(axis, lean));
+        }
+        let scale = rng.range(self.scale.0, self.scale.1);
+        Instance {position: p, axes, scale}
+    }
+}
+
+/// the matrices of the instances one after the other, for a buffer of instanced rendering
+pub fn instance_buffer(instances: &[Instance]) -> Vec<f32> {
+    instances.iter().flat_map(|i| i.matrix()).collect()
+}
+
+/// Add a copy of a mesh for each instance, without instanced rendering.
+/// The ondulation keeps the same direction, like a wind, and grows with the scale.
+/// Return the number of copies added before the indices reach the limit of 16 bits
+pub fn place(instances: &[Instance], mesh_points: &[f32], mesh_indices: &[u16], points: &mut Vec<f32>, indices: &mut Vec<u16>) -> usize {
+    let n_vertices = mesh_points.len() / SIZE_VERTEX;
+    for (n, instance) in instances.iter().enumerate() {
+        let first = points.len() / SIZE_VERTEX;
+        if first + n_vertices > u16::MAX as usize + 1 {
+            return n
+        }
+        for vertex in mesh_points.chunks_exact(SIZE_VERTEX) {
+            let p = instance.apply(V3::new(vertex[0], vertex[1], vertex[2]));
+            push_point!(points, p, [vertex[3], vertex[4], vertex[5]]);
+            points.extend(vertex[6..9].iter().map(|x| x * instance.scale));
+            points.extend_from_slice(&vertex[9..]);
+        }
+        indices.extend(mesh_indices.iter().map(|&i| (first + i as usize) as u16));
+    }
+    instances.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scatter, Instance, place, instance_buffer, noise_density};
+    use super::super::random::Rng;
+    use super::super::{V3, Range, SIZE_VERTEX, get_point, rand_surface};
+    use super::super::export::tests::tetrahedron;
+    use super::super::surface::Attributes;
+    use super::super::surface::tests::grid;
+
+    fn transform(m: &[f32; 16], p: V3) -> V3 {
+        V3::new(
+            m[0]*p.x + m[4]*p.y + m[8]*p.z + m[12],
+            m[1]*p.x + m[5]*p.y + m[9]*p.z + m[13],
+            m[2]*p.x + m[6]*p.y + m[10]*p.z + m[14],
+        )
+    }
+
+    /// a flat square from (0, 0) to (10, 10), tilted by `slope` along x
+    fn square(slope: f32) -> (Vec<f32>, Vec<u16>) {
+        let mut square = grid(2).scale(10.0);
+        for p in &mut square.positions {
+            p.z = p.x*slope;
+        }
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        square.push(&mut points, &mut indices, |_, _| Attributes::still(V3::null()));
+        (points, indices)
+    }
+
+    #[test]
+    fn poisson_disks() {
+        let (points, indices) = square(0.0);
+        let mut rng = Rng::new(5);
+        let scatter = Scatter {spacing: 1.0, ..Scatter::default()};
+        let instances = scatter.scatter(&mut rng, &points, &indices, |_| 1.0);
+        for (i, a) in instances.iter().enumerate() {
+            assert!(a.position.z == 0.0);
+            assert!(a.position.x >= 0.0 && a.position.x <= 10.0 && a.position.y >= 0.0 && a.position.y <= 10.0);
+            for b in &instances[i+1..] {
+                assert!((a.position - b.position).norm() >= 1.0);
+            }
+        }
+        // the square is well covered: a disk of radius 1 around each point covers most of it
+        assert!(instances.len() > 50, "{}", instances.len());
+
+        // half the density, about half the points
+        let half = scatter.scatter(&mut rng, &points, &indices, |p| if p.x < 5.0 {1.0} else {0.0});
+        assert!(half.iter().all(|i| i.position.x < 5.0));
+        assert!(half.len() > 20);
+
+        let limited = Scatter {max_instances: 7, ..scatter}.scatter(&mut rng, &points, &indices, |_| 1.0);
+        assert_eq!(limited.len(), 7);
+
+        // the same seed, the same instances
+        assert_eq!(instances, scatter.scatter(&mut Rng::new(5), &points, &indices, |_| 1.0));
+    }
+
+    #[test]
+    fn constraints() {
+        // 45°
+        let (points, indices) = square(1.0);
+        let mut rng = Rng::new(6);
+        let steep = Scatter {spacing: 1.0, max_slope: 0.7, ..Scatter::default()};
+        assert!(steep.scatter(&mut rng, &points, &indices, |_| 1.0).is_empty());
+        let gentle = Scatter {max_slope: 0.8, altitude: (2.0, 4.0), ..steep};
+        let instances = gentle.scatter(&mut rng, &points, &indices, |_| 1.0);
+        assert!(!instances.is_empty());
+        assert!(instances.iter().all(|i| i.position.z >= 2.0 && i.position.z <= 4.0));
+        // on the slope, the points are farther apart than on the plane
+        for (i, a) in instances.iter().enumerate() {
+            assert!(instances[i+1..].iter().all(|b| (a.position - b.position).norm() >= 1.0));
+        }
+
+        // perpendicular to the ground, with a random scale
+        let aligned = Scatter {align: 1.0, scale: (0.5, 2.0), ..gentle};
+        let normal = V3::new(-1.0, 0.0, 1.0).scale(0.5f32.sqrt());
+        for i in aligned.scatter(&mut rng, &points, &indices, |_| 1.0) {
+            assert!((i.axes[2] - normal).norm() < 1e-4);
+            assert!(i.scale >= 0.5 && i.scale <= 2.0);
+            // the axes stay perpendicular
+            assert!(V3::dot(i.axes[0], i.axes[1]).abs() < 1e-4 && V3::dot(i.axes[0], i.axes[2]).abs() < 1e-4);
+        }
+
+        // a density between 0 and 1
+        let range = Range::new(V3::new(0.0, 0.0, 0.0), V3::new(10.0, 10.0, 10.0));
+        let density = noise_density(range, (4, 4, 4));
+        for x in 0..20 {
+            let d = density(V3::new(x as f32, 3.0, 20.0));
+            assert!((0.0..=1.0).contains(&d));
+        }
+    }
+
+    #[test]
+    fn copies() {
+        let (mesh_points, mesh_indices) = tetrahedron();
+        let instance = Instance {
+            position: V3::new(1.0, 2.0, 3.0),
+            axes: [V3::new(0.0, 1.0, 0.0), V3::new(-1.0, 0.0, 0.0), V3::new(0.0, 0.0, 1.0)],
+            scale: 2.0,
+        };
+        let m = instance.matrix();
+        let p = V3::new(1.0, 0.5, 0.25);
+        assert!((transform(&m, p) - instance.apply(p)).norm() < 1e-6);
+        assert!((instance.apply(p) - V3::new(0.0, 4.0, 3.5)).norm() < 1e-6);
+        assert_eq!(instance_buffer(&[instance, instance]).len(), 32);
+
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        assert_eq!(place(&[instance, instance], &mesh_points, &mesh_indices, &mut points, &mut indices), 2);
+        assert_eq!(points.len(), 2*mesh_points.len());
+        assert_eq!(indices[mesh_indices.len()..], mesh_indices.iter().map(|i| i + 4).collect::<Vec<u16>>()[..]);
+        assert_eq!(get_point(&points, 5), instance.apply(get_point(&mesh_points, 1)));
+        // same color and frequency, bigger ondulation
+        assert_eq!(points[SIZE_VERTEX+3..SIZE_VERTEX+6], mesh_points[SIZE_VERTEX+3..SIZE_VERTEX+6]);
+        assert_eq!(points[SIZE_VERTEX+7], 2.0 * mesh_points[SIZE_VERTEX+7]);
+        assert_eq!(points[SIZE_VERTEX+9], mesh_points[SIZE_VERTEX+9]);
+
+        // the terrain is too big for a second copy
+        let (mut points, mut indices) = (Vec::new(), Vec::new());
+        let (mut terrain, mut terrain_indices) = (Vec::new(), Vec::new());
+        rand_surface(&mut terrain, &mut terrain_indices);
+        let copies = place(&[instance; 10], &terrain, &terrain_indices, &mut points, &mut indices);
+        assert_eq!(copies, 6);
+        assert_eq!(points.len(), 6*terrain.len());
+    }
+}
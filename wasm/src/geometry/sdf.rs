@@ -0,0 +1,424 @@
+use super::V3;
+use super::Range;
+use super::Dist;
+use super::interval::Interval;
+use super::program::{Program, Op, value_noise};
+
+/// A shape described by a tree of signed distance functions.
+/// Unlike a closure, the tree can be inspected, evaluated on the cpu,
+/// or translated to glsl to be rendered directly by the gpu
+#[derive(Clone, Debug)]
+pub enum Sdf {
+    Sphere {radius: f32},
+    /// box centered on the origin
+    Cuboid {half_size: V3},
+    /// torus around the z axis
+    Torus {major: f32, minor: f32},
+    /// the half space below the plane `dot(p, normal) = offset`
+    Plane {normal: V3, offset: f32},
+    Union(Box<Sdf>, Box<Sdf>),
+    Intersection(Box<Sdf>, Box<Sdf>),
+    /// first shape minus the second one
+    Difference(Box<Sdf>, Box<Sdf>),
+    /// union with a smooth junction of size `k`
+    SmoothUnion(f32, Box<Sdf>, Box<Sdf>),
+    Translate(V3, Box<Sdf>),
+    /// multiply the size of the shape
+    Scale(f32, Box<Sdf>),
+    /// Rotate the shape. The rows of the matrix are applied to the point,
+    /// so they are the columns of the rotation (its inverse)
+    Rotate([V3; 3], Box<Sdf>),
+    /// move the surface by `amplitude * noise(frequency * p)`
+    Displace {amplitude: f32, frequency: f32, shape: Box<Sdf>},
+}
+
+/// Translate the tree to a program, each node adds a few operations
+struct Lowering {
+    prog: Program,
+}
+
+impl Lowering {
+    fn op(&mut self, op: Op) -> usize {
+        self.prog.push(op)
+    }
+    fn constant(&mut self, c: f32) -> usize {
+        self.op(Op::Const(c))
+    }
+    /// length of the vector made of these coordinates
+    fn length(&mut self, coords: &[usize]) -> usize {
+        let squares: Vec<usize> = coords.iter().map(|&c| self.op(Op::Mul(c, c))).collect();
+        let sum = squares[1..].iter().fold(squares[0], |acc, &s| self.op(Op::Add(acc, s)));
+        self.op(Op::Sqrt(sum))
+    }
+    /// a*p.x + b*p.y + c*p.z
+    fn dot(&mut self, v: V3, p: [usize; 3]) -> usize {
+        let terms: Vec<usize> = v.to_array().iter().zip(&p).map(|(&k, &x)| {
+            let k = self.constant(k);
+            self.op(Op::Mul(k, x))
+        }).collect();
+        let s = self.op(Op::Add(terms[0], terms[1]));
+        self.op(Op::Add(s, terms[2]))
+    }
+
+    /// add the operations for the distance from `p` to the shape, and return the result
+    fn lower(&mut self, shape: &Sdf, p: [usize; 3]) -> usize {
+        match shape {
+            Sdf::Sphere {radius} => {
+                let l = self.length(&p);
+                let r = self.constant(*radius);
+                self.op(Op::Sub(l, r))
+            }
+            Sdf::Cuboid {half_size} => {
+                let zero = self.constant(0.0);
+                let h = half_size.to_array();
+                let mut q = [0; 3];
+                let mut positive = [0; 3];
+                for i in 0..3 {
+                    let a = self.op(Op::Abs(p[i]));
+                    let hi = self.constant(h[i]);
+                    q[i] = self.op(Op::Sub(a, hi));
+                    positive[i] = self.op(Op::Max(q[i], zero));
+                }
+                let outside = self.length(&positive);
+                let m = self.op(Op::Max(q[1], q[2]));
+                let m = self.op(Op::Max(q[0], m));
+                let inside = self.op(Op::Min(m, zero));
+                self.op(Op::Add(outside, inside))
+            }
+            Sdf::Torus {major, minor} => {
+                let l = self.length(&p[..2]);
+                let big = self.constant(*major);
+                let a = self.op(Op::Sub(l, big));
+                let l = self.length(&[a, p[2]]);
+                let small = self.constant(*minor);
+                self.op(Op::Sub(l, small))
+            }
+            Sdf::Plane {normal, offset} => {
+                let d = self.dot(*normal, p);
+                let o = self.constant(*offset);
+                self.op(Op::Sub(d, o))
+            }
+            Sdf::Union(a, b) => {
+                let (a, b) = (self.lower(a, p), self.lower(b, p));
+                self.op(Op::Min(a, b))
+            }
+            Sdf::Intersection(a, b) => {
+                let (a, b) = (self.lower(a, p), self.lower(b, p));
+                self.op(Op::Max(a, b))
+            }
+            Sdf::Difference(a, b) => {
+                let (a, b) = (self.lower(a, p), self.lower(b, p));
+                let minus_b = self.op(Op::Neg(b));
+                self.op(Op::Max(a, minus_b))
+            }
+            Sdf::SmoothUnion(k, a, b) => {
+                let (a, b) = (self.lower(a, p), self.lower(b, p));
+                // h = clamp(0.5 + 0.5*(b-a)/k, 0, 1)
+                let diff = self.op(Op::Sub(b, a));
+                let factor = self.constant(0.5 / k);
+                let t = self.op(Op::Mul(diff, factor));
+                let half = self.constant(0.5);
+                let t = self.op(Op::Add(t, half));
+                let zero = self.constant(0.0);
+                let one = self.constant(1.0);
+                let t = self.op(Op::Max(t, zero));
+                let h = self.op(Op::Min(t, one));
+                // mix(b, a, h) - k*h*(1-h)
+                let a_minus_b = self.op(Op::Sub(a, b));
+                let t = self.op(Op::Mul(a_minus_b, h));
+                let mix = self.op(Op::Add(b, t));
+                let one_minus_h = self.op(Op::Sub(one, h));
+                let t = self.op(Op::Mul(h, one_minus_h));
+                let k = self.constant(*k);
+                let t = self.op(Op::Mul(t, k));
+                self.op(Op::Sub(mix, t))
+            }
+            Sdf::Translate(v, shape) => {
+                let v = v.to_array();
+                let mut q = [0; 3];
+                for i in 0..3 {
+                    let c = self.constant(v[i]);
+                    q[i] = self.op(Op::Sub(p[i], c));
+                }
+                self.lower(shape, q)
+            }
+            Sdf::Scale(s, shape) => {
+                let inv = self.constant(1.0/s);
+                let q = [
+                    self.op(Op::Mul(p[0], inv)),
+                    self.op(Op::Mul(p[1], inv)),
+                    self.op(Op::Mul(p[2], inv)),
+                ];
+                let d = self.lower(shape, q);
+                let s = self.constant(*s);
+                self.op(Op::Mul(d, s))
+            }
+            Sdf::Rotate(rows, shape) => {
+                let q = [
+                    self.dot(rows[0], p),
+                    self.dot(rows[1], p),
+                    self.dot(rows[2], p),
+                ];
+                self.lower(shape, q)
+            }
+            Sdf::Displace {amplitude, frequency, shape} => {
+                let d = self.lower(shape, p);
+                let f = self.constant(*frequency);
+                let q = [
+                    self.op(Op::Mul(p[0], f)),
+                    self.op(Op::Mul(p[1], f)),
+                    self.op(Op::Mul(p[2], f)),
+                ];
+                let n = self.op(Op::Noise(q[0], q[1], q[2]));
+                let a = self.constant(*amplitude);
+                let n = self.op(Op::Mul(n, a));
+                self.op(Op::Add(d, n))
+            }
+        }
+    }
+}
+
+impl Sdf {
+    pub fn union(self, other: Sdf) -> Sdf {
+        Sdf::Union(Box::new(self), Box::new(other))
+    }
+    pub fn intersection(self, other: Sdf) -> Sdf {
+        Sdf::Intersection(Box::new(self), Box::new(other))
+    }
+    pub fn difference(self, other: Sdf) -> Sdf {
+        Sdf::Difference(Box::new(self), Box::new(other))
+    }
+    pub fn smooth_union(self, other: Sdf, k: f32) -> Sdf {
+        Sdf::SmoothUnion(k, Box::new(self), Box::new(other))
+    }
+    pub fn translate(self, v: V3) -> Sdf {
+        Sdf::Translate(v, Box::new(self))
+    }
+    pub fn scale(self, s: f32) -> Sdf {
+        Sdf::Scale(s, Box::new(self))
+    }
+    /// rotate around an axis (Rodrigues' formula), the angle is in radians
+    pub fn rotate(self, axis: V3, angle: f32) -> Sdf {
+        let a = axis.normalize();
+        let (sin, cos) = angle.sin_cos();
+        let t = 1.0 - cos;
+        // columns of the rotation
+        let rows = [
+            V3::new(t*a.x*a.x + cos,     t*a.x*a.y + sin*a.z, t*a.x*a.z - sin*a.y),
+            V3::new(t*a.x*a.y - sin*a.z, t*a.y*a.y + cos,     t*a.y*a.z + sin*a.x),
+            V3::new(t*a.x*a.z + sin*a.y, t*a.y*a.z - sin*a.x, t*a.z*a.z + cos),
+        ];
+        Sdf::Rotate(rows, Box::new(self))
+    }
+    pub fn displace(self, amplitude: f32, frequency: f32) -> Sdf {
+        Sdf::Displace {amplitude, frequency, shape: Box::new(self)}
+    }
+
+    /// translate the tree to a list of operations
+    pub fn compile(&self) -> Program {
+        let mut lowering = Lowering {prog: Program::new()};
+        let p = [
+            lowering.op(Op::X),
+            lowering.op(Op::Y),
+            lowering.op(Op::Z),
+        ];
+        lowering.lower(self, p);
+        lowering.prog
+    }
+
+    /// glsl function `float scene(vec3 p)` that computes the distance to the shape
+    pub fn to_glsl(&self) -> Result<String, String> {
+        self.compile().to_glsl()
+    }
+}
+
+/// the range that contains the points of a range moved by `f`,
+/// which is a translation, a scale or a rotation
+fn moved_range(range: &Range, f: impl Fn(V3) -> V3) -> Range {
+    Range::from_points(range.corners().iter().map(|&c| f(c))).expect("a range has corners")
+}
+
+impl Dist for Sdf {
+    fn dist(&self, p: V3) -> f32 {
+        // the operations are done in the same order as in `Lowering::lower`,
+        // so that the tree and its program give the same result on the cpu
+        match self {
+            Sdf::Sphere {radius} => p.norm() - radius,
+            Sdf::Cuboid {half_size} => {
+                let q = p.map(f32::abs) - *half_size;
+                let outside = q.map(|x| x.max(0.0)).norm();
+                let inside = f32::max(q.x, f32::max(q.y, q.z)).min(0.0);
+                outside + inside
+            }
+            Sdf::Torus {major, minor} => {
+                let a = (p.x*p.x + p.y*p.y).sqrt() - major;
+                (a*a + p.z*p.z).sqrt() - minor
+            }
+            Sdf::Plane {normal, offset} => {
+                normal.x*p.x + normal.y*p.y + normal.z*p.z - offset
+            }
+            Sdf::Union(a, b) => f32::min(a.dist(p), b.dist(p)),
+            Sdf::Intersection(a, b) => f32::max(a.dist(p), b.dist(p)),
+            Sdf::Difference(a, b) => f32::max(a.dist(p), -b.dist(p)),
+            Sdf::SmoothUnion(k, a, b) => {
+                let (a, b) = (a.dist(p), b.dist(p));
+                let h = ((b - a)*(0.5/k) + 0.5).clamp(0.0, 1.0);
+                (b + (a - b)*h) - h*(1.0 - h)*k
+            }
+            Sdf::Translate(v, shape) => shape.dist(p - *v),
+            Sdf::Scale(s, shape) => shape.dist(p.scale(1.0/s)) * s,
+            Sdf::Rotate(rows, shape) => {
+                let dot = |r: V3| r.x*p.x + r.y*p.y + r.z*p.z;
+                shape.dist(V3::new(dot(rows[0]), dot(rows[1]), dot(rows[2])))
+            }
+            Sdf::Displace {amplitude, frequency, shape} => {
+                shape.dist(p) + value_noise(p.scale(*frequency))*amplitude
+            }
+        }
+    }
+
+    /// The noise makes the displaced shapes change faster than a distance,
+    /// so the bounds are combined node by node, and the noise adds its amplitude.
+    /// They are looser than the ones of `self.compile()`, which should be preferred
+    /// to bound many ranges
+    fn bounds(&self, range: &Range) -> Interval {
+        match self {
+            Sdf::Sphere {..} | Sdf::Cuboid {..} | Sdf::Torus {..} | Sdf::Plane {..} => {
+                let d = self.dist(range.center());
+                let r = range.extent().norm();
+                Interval::new(d - r, d + r)
+            }
+            Sdf::Union(a, b) => a.bounds(range).min(b.bounds(range)),
+            Sdf::Intersection(a, b) => a.bounds(range).max(b.bounds(range)),
+            Sdf::Difference(a, b) => a.bounds(range).max(-b.bounds(range)),
+            // the junction is at most k/4 below the union
+            Sdf::SmoothUnion(k, a, b) => {
+                a.bounds(range).min(b.bounds(range)) + Interval::new(-k.abs()/4.0, 0.0)
+            }
+            Sdf::Translate(v, shape) => shape.bounds(&moved_range(range, |p| p - *v)),
+            Sdf::Scale(s, shape) => {
+                Interval::point(*s) * shape.bounds(&moved_range(range, |p| p.scale(1.0/s)))
+            }
+            Sdf::Rotate(rows, shape) => {
+                let dot = |r: V3, p: V3| r.x*p.x + r.y*p.y + r.z*p.z;
+                let rotate = |p| V3::new(dot(rows[0], p), dot(rows[1], p), dot(rows[2], p));
+                shape.bounds(&moved_range(range, rotate))
+            }
+            Sdf::Displace {amplitude, shape, ..} => {
+                shape.bounds(range) + Interval::new(-amplitude.abs(), amplitude.abs())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sdf, V3, Range, Dist};
+    use super::super::random::Rng;
+    use super::super::program::tests::run_glsl;
+
+    fn scene() -> Sdf {
+        let ground = Sdf::Plane {normal: V3::new(0.0, 0.0, 1.0), offset: -1.5};
+        let blob = Sdf::Sphere {radius: 0.8}
+            .smooth_union(Sdf::Torus {major: 1.2, minor: 0.25}, 0.5)
+            .rotate(V3::new(1.0, 1.0, 0.0), 0.7)
+            .translate(V3::new(0.0, 3.0, 0.0));
+        let carved_box = Sdf::Cuboid {half_size: V3::new(0.7, 0.4, 0.7)}
+            .difference(Sdf::Sphere {radius: 0.9})
+            .scale(1.5)
+            .intersection(Sdf::Sphere {radius: 2.0})
+            .translate(V3::new(-2.5, 4.0, -0.5));
+
+        ground.union(blob).union(carved_box).displace(0.1, 3.0)
+    }
+
+    #[test]
+    fn tree_and_program_agree() {
+        let mut rng = Rng::new(1);
+        let shape = scene();
+        let program = shape.compile();
+        let glsl = shape.to_glsl().unwrap();
+        for _ in 0..1000 {
+            let p = rng.unit_v3().scale(6.0*rng.float());
+            let cpu = shape.dist(p);
+            let compiled = program.eval(p);
+            assert!((cpu - compiled).abs() < 1e-5, "{} != {} at {:?}", cpu, compiled, p);
+            let emitted = run_glsl(&glsl, p);
+            assert!((cpu - emitted).abs() < 1e-5, "{} != {} at {:?}", cpu, emitted, p);
+        }
+    }
+
+    #[test]
+    fn glsl_has_one_variable_per_operation() {
+        let shape = scene();
+        let glsl = shape.to_glsl().unwrap();
+        let n_ops = glsl.matches("    float v").count();
+
+        assert!(glsl.contains("float noise(vec3 p)"));
+        assert!(glsl.contains("float scene(vec3 p) {"));
+        assert!(glsl.contains("    float v0 = p.x;"));
+        assert!(glsl.contains(&format!("    return v{};", n_ops - 1)));
+        assert_eq!(glsl.matches('{').count(), glsl.matches('}').count());
+    }
+
+    /// parse the glsl of the scene in a fragment shader and check its types with naga,
+    /// as the driver of the browser would do
+    fn validate_glsl(glsl: &str) -> Result<(), String> {
+        use naga::front::glsl::{Frontend, Options};
+        use naga::valid::{Validator, ValidationFlags, Capabilities};
+
+        let source = format!(
+            "#version 450\nlayout(location = 0) out vec4 color;\n{}\nvoid main() {{\n    color = vec4(scene(vec3(1.0, 2.0, 3.0)));\n}}\n",
+            glsl);
+        let module = Frontend::default()
+            .parse(&Options::from(naga::ShaderStage::Fragment), &source)
+            .map_err(|e| format!("{:?}\n{}", e, source))?;
+        Validator::new(ValidationFlags::all(), Capabilities::empty())
+            .validate(&module)
+            .map_err(|e| format!("{:?}\n{}", e, source))?;
+        Ok(())
+    }
+
+    #[test]
+    fn glsl_compiles() {
+        validate_glsl(&scene().to_glsl().unwrap()).unwrap();
+        validate_glsl(&Sdf::Sphere {radius: 1.0}.to_glsl().unwrap()).unwrap();
+        validate_glsl(&super::super::test_sdf_scene().to_glsl().unwrap()).unwrap();
+        // a shape scaled to nothing divides by 0
+        assert!(Sdf::Sphere {radius: 1.0}.scale(0.0).to_glsl().is_err());
+        assert!(validate_glsl("float scene(vec3 p) {\n    return q.x;\n}\n").is_err());
+    }
+
+    #[test]
+    fn primitives_are_distances() {
+        let o = V3::new(0.0, 0.0, 0.0);
+        let x = V3::new(1.0, 0.0, 0.0);
+        assert_eq!(Sdf::Sphere {radius: 1.0}.dist(x.scale(3.0)), 2.0);
+        assert_eq!(Sdf::Cuboid {half_size: V3::new(1.0, 2.0, 3.0)}.dist(o), -1.0);
+        assert_eq!(Sdf::Torus {major: 2.0, minor: 0.5}.dist(x.scale(2.0)), -0.5);
+        assert!((Sdf::Sphere {radius: 1.0}.scale(2.0).dist(x.scale(3.0)) - 1.0).abs() < 1e-6);
+
+        // rotation of a quarter turn around z moves x to y
+        let rotated = Sdf::Sphere {radius: 0.1}
+            .translate(x)
+            .rotate(V3::new(0.0, 0.0, 1.0), std::f32::consts::FRAC_PI_2);
+        assert!(rotated.dist(V3::new(0.0, 1.0, 0.0)).abs() < 0.1 + 1e-5);
+    }
+
+    #[test]
+    fn bounds_contain_distances() {
+        let mut rng = Rng::new(2);
+        let shape = scene();
+        let program = shape.compile();
+        for _ in 0..100 {
+            let c = rng.unit_v3().scale(4.0);
+            let range = Range::new(c, c).inflate(0.5*rng.float());
+            let bounds = shape.bounds(&range);
+            let compiled = program.bounds(&range);
+            for &corner in &range.corners() {
+                assert!(bounds.contains(shape.dist(corner)));
+                assert!(compiled.contains(shape.dist(corner)));
+            }
+        }
+    }
+}
@@ -0,0 +1,553 @@
+//! Decimation of indexed meshes by edge collapses, ordered by quadric error metrics
+//! (Garland and Heckbert, 1997). The quadrics measure the distance in the space of the
+//! positions and the colors (Garland and Heckbert, 1998), so the colors are kept too
+
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::cmp::Ordering;
+use std::ops::AddAssign;
+
+use super::{V3, SIZE_VERTEX, keep_used_vertices};
+
+// position and color
+const N: usize = 6;
+// a difference of 1 between 2 colors counts as this distance
+const COLOR_WEIGHT: f64 = 1.0;
+// weight of the planes that keep the boundaries in place, compared to the triangles
+const BOUNDARY_WEIGHT: f64 = 100.0;
+// a triangle cannot turn further than this (cosine of the angle), in a collapse or since the beginning
+const MIN_NORMAL_COS: f32 = 0.5;
+
+/// When to stop simplifying
+#[derive(Copy, Clone, Debug)]
+pub struct Target {
+    /// stop when there are at most this number of triangles
+    pub triangles: usize,
+    /// stop before the first collapse with a greater error: the sum of the squared distances
+    /// to the planes of the original triangles around the vertex (the colors count as coordinates)
+    pub max_error: f32,
+}
+
+impl Target {
+    /// simplify until there are `n` triangles, whatever the error
+    pub fn triangles(n: usize) -> Self {
+        Target {triangles: n, max_error: f32::INFINITY}
+    }
+
+    /// simplify as much as possible without an error greater than `max_error`
+    pub fn error(max_error: f32) -> Self {
+        Target {triangles: 0, max_error}
+    }
+}
+
+type Vector = [f64; N];
+
+/// vᵀAv + 2bᵀv + c: the sum of the squared distances of v to some planes
+#[derive(Copy, Clone, Debug)]
+struct Quadric {
+    a: [[f64; N]; N],
+    b: Vector,
+    c: f64,
+}
+
+impl AddAssign for Quadric {
+    fn add_assign(&mut self, other: Self) {
+        for i in 0..N {
+            for j in 0..N {
+                self.a[i][j] += other.a[i][j];
+            }
+            self.b[i] += other.b[i];
+        }
+        self.c += other.c;
+    }
+}
+
+fn dot(u: &Vector, v: &Vector) -> f64 {
+    (0..N).map(|i| u[i]*v[i]).sum()
+}
+
+impl Quadric {
+    fn zero() -> Self {
+        Quadric {a: [[0.0; N]; N], b: [0.0; N], c: 0.0}
+    }
+
+    /// the plane of a triangle, in the space of the positions and the colors.
+    /// None if the triangle is degenerate
+    fn triangle([p, q, r]: [Vector; 3]) -> Option<Self> {
+        let sub = |u: &Vector, v: &Vector| -> Vector {std::array::from_fn(|i| u[i] - v[i])};
+        let normalized = |u: Vector| -> Option<Vector> {
+            let norm = dot(&u, &u).sqrt();
+            if norm < 1e-12 {None} else {Some(u.map(|x| x / norm))}
+        };
+        // orthonormal basis of the plane
+        let e1 = normalized(sub(&q, &p))?;
+        let pr = sub(&r, &p);
+        let k = dot(&e1, &pr);
+        let e2 = normalized(std::array::from_fn(|i| pr[i] - k*e1[i]))?;
+
+        let (p1, p2) = (dot(&p, &e1), dot(&p, &e2));
+        let mut quadric = Quadric::zero();
+        for i in 0..N {
+            for j in 0..N {
+                let identity = if i == j {1.0} else {0.0};
+                quadric.a[i][j] = identity - e1[i]*e1[j] - e2[i]*e2[j];
+            }
+            quadric.b[i] = p1*e1[i] + p2*e2[i] - p[i];
+        }
+        quadric.c = dot(&p, &p) - p1*p1 - p2*p2;
+        Some(quadric)
+    }
+
+    /// a plane of positions (the color is free), with a unit normal
+    fn plane(normal: V3, point: V3, weight: f64) -> Self {
+        let n = normal.to_array().map(|x| x as f64);
+        let d = -(0..3).map(|i| n[i] * point.to_array()[i] as f64).sum::<f64>();
+        let mut quadric = Quadric::zero();
+        for i in 0..3 {
+            for j in 0..3 {
+                quadric.a[i][j] = weight * n[i]*n[j];
+            }
+            quadric.b[i] = weight * d*n[i];
+        }
+        quadric.c = weight * d*d;
+        quadric
+    }
+
+    fn error(&self, v: &Vector) -> f64 {
+        let av: Vector = std::array::from_fn(|i| dot(&self.a[i], v));
+        (dot(v, &av) + 2.0*dot(&self.b, v) + self.c).max(0.0)
+    }
+
+    /// the point with the smallest error, if there is only one
+    fn optimum(&self) -> Option<Vector> {
+        // Gaussian elimination of Av = -b, with partial pivoting
+        let mut m = self.a;
+        let mut v = self.b.map(|x| -x);
+        let scale = (0..N).map(|i| m[i][i].abs()).fold(0.0, f64::max);
+        for col in 0..N {
+            let pivot = (col..N).max_by(|&i, &j| m[i][col].abs().total_cmp(&m[j][col].abs()))?;
+            if m[pivot][col].abs() <= 1e-9 * scale {
+                return None
+            }
+            m.swap(col, pivot);
+            v.swap(col, pivot);
+            let pivot_row = m[col];
+            for row in col+1..N {
+                let k = m[row][col] / pivot_row[col];
+                for (x, p) in m[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                    *x -= k * p;
+                }
+                v[row] -= k * v[col];
+            }
+        }
+        for col in (0..N).rev() {
+            let rest: f64 = (col+1..N).map(|j| m[col][j] * v[j]).sum();
+            v[col] = (v[col] - rest) / m[col][col];
+        }
+        Some(v)
+    }
+}
+
+/// A possible collapse of the edge (u, v), in the heap.
+/// It is ignored if one of the vertices changed since it was computed
+#[derive(Clone, Debug)]
+struct Collapse {
+    cost: f64,
+    u: usize,
+    v: usize,
+    versions: (u32, u32),
+    vertex: [f32; SIZE_VERTEX],
+}
+
+impl PartialEq for Collapse {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Collapse {}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// the cheapest collapse is the greatest, to be on top of the heap
+impl Ord for Collapse {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.cost.total_cmp(&self.cost)
+    }
+}
+
+struct Mesh {
+    vertices: Vec<[f32; SIZE_VERTEX]>,
+    triangles: Vec<[usize; 3]>,
+    removed: Vec<bool>,
+    // triangles around each vertex, some of them removed
+    around: Vec<Vec<usize>>,
+    quadrics: Vec<Quadric>,
+    // the vertices on a border of the mesh
+    boundary: Vec<bool>,
+    // the normals before the simplification, that the triangles cannot turn away from
+    original_normals: Vec<V3>,
+    versions: Vec<u32>,
+}
+
+fn position(vertex: &[f32; SIZE_VERTEX]) -> V3 {
+    V3::new(vertex[0], vertex[1], vertex[2])
+}
+
+fn coordinates(vertex: &[f32; SIZE_VERTEX]) -> Vector {
+    std::array::from_fn(|i| if i < 3 {vertex[i] as f64} else {vertex[i] as f64 * COLOR_WEIGHT})
+}
+
+fn normal(t: [V3; 3]) -> V3 {
+    V3::cross(t[1] - t[0], t[2] - t[0])
+}
+
+impl Mesh {
+    fn new(points: &[f32], indices: &[u16]) -> Self {
+        // the vertices with exactly the same values are merged
+        let mut vertices = Vec::new();
+        let mut merged = HashMap::new();
+        let mut index = Vec::with_capacity(points.len() / SIZE_VERTEX);
+        for chunk in points.chunks_exact(SIZE_VERTEX) {
+            let vertex: [f32; SIZE_VERTEX] = std::array::from_fn(|i| chunk[i]);
+            index.push(*merged.entry(vertex.map(f32::to_bits)).or_insert_with(|| {
+                vertices.push(vertex);
+                vertices.len() - 1
+            }));
+        }
+        let triangles: Vec<[usize; 3]> = indices.chunks_exact(3)
+            .map(|t| [0, 1, 2].map(|k| index[t[k] as usize]))
+            .collect();
+
+        let mut around = vec![Vec::new(); vertices.len()];
+        let mut quadrics = vec![Quadric::zero(); vertices.len()];
+        // number of triangles on each side of the edges
+        let mut edges: HashMap<(usize, usize), usize> = HashMap::new();
+        for (n, t) in triangles.iter().enumerate() {
+            if let Some(quadric) = Quadric::triangle(t.map(|i| coordinates(&vertices[i]))) {
+                for &i in t {
+                    quadrics[i] += quadric;
+                }
+            }
+            for k in 0..3 {
+                around[t[k]].push(n);
+                let (a, b) = (t[k], t[(k+1)%3]);
+                *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+
+        // the edges with a single triangle are held by a plane perpendicular to the triangle
+        let mut boundary = vec![false; vertices.len()];
+        for t in &triangles {
+            for k in 0..3 {
+                let (a, b) = (t[k], t[(k+1)%3]);
+                if edges[&(a.min(b), a.max(b))] != 1 {
+                    continue
+                }
+                let (pa, pb) = (position(&vertices[a]), position(&vertices[b]));
+                let side = V3::cross(pb - pa, normal(t.map(|i| position(&vertices[i]))));
+                if side.norm() > 0.0 {
+                    let weight = BOUNDARY_WEIGHT * V3::dot(pb - pa, pb - pa) as f64;
+                    let quadric = Quadric::plane(side.normalize(), pa, weight);
+                    quadrics[a] += quadric;
+                    quadrics[b] += quadric;
+                }
+                boundary[a] = true;
+                boundary[b] = true;
+            }
+        }
+
+        let original_normals = triangles.iter().map(|t| normal(t.map(|i| position(&vertices[i])))).collect();
+        let n_vertices = vertices.len();
+        Mesh {vertices, removed: vec![false; triangles.len()], triangles, around, quadrics, boundary, original_normals, versions: vec![0; n_vertices]}
+    }
+
+    fn triangles_around(&self, u: usize) -> impl Iterator<Item=usize> + '_ {
+        self.around[u].iter().copied().filter(move |&t| !self.removed[t])
+    }
+
+    fn neighbours(&self, u: usize) -> HashSet<usize> {
+        self.triangles_around(u)
+            .flat_map(|t| self.triangles[t])
+            .filter(|&w| w != u)
+            .collect()
+    }
+
+    /// the best vertex to replace the edge (u, v), and its error
+    fn collapse(&self, u: usize, v: usize) -> Option<Collapse> {
+        let (a, b) = (&self.vertices[u], &self.vertices[v]);
+        // the frequencies of the ondulation cannot be mixed
+        if a[9] != b[9] {
+            return None
+        }
+        let mut quadric = self.quadrics[u];
+        quadric += self.quadrics[v];
+
+        let (ca, cb) = (coordinates(a), coordinates(b));
+        let length = (0..3).map(|i| (cb[i] - ca[i]).powi(2)).sum::<f64>().sqrt();
+        let middle: Vector = std::array::from_fn(|i| (ca[i] + cb[i]) / 2.0);
+        // the optimum is not used when it is far from the edge
+        let optimum = quadric.optimum()
+            .filter(|o| (0..3).map(|i| (o[i] - middle[i]).powi(2)).sum::<f64>().sqrt() <= length);
+        let (cost, best) = optimum.into_iter()
+            .chain([ca, cb, middle])
+            .map(|x| (quadric.error(&x), x))
+            .min_by(|x, y| x.0.total_cmp(&y.0))?;
+
+        // the other values are interpolated along the edge
+        let (pa, pb) = (position(a), position(b));
+        let p = V3::new(best[0] as f32, best[1] as f32, best[2] as f32);
+        let ab = pb - pa;
+        let t = if V3::dot(ab, ab) > 0.0 {(V3::dot(p - pa, ab) / V3::dot(ab, ab)).clamp(0.0, 1.0)} else {0.5};
+        let mut vertex: [f32; SIZE_VERTEX] = std::array::from_fn(|i| a[i] + t*(b[i] - a[i]));
+        for i in 0..N {
+            vertex[i] = if i < 3 {best[i] as f32} else {(best[i] / COLOR_WEIGHT) as f32};
+        }
+
+        Some(Collapse {cost, u, v, versions: (self.versions[u], self.versions[v]), vertex})
+    }
+
+    /// A collapse is refused if it makes the mesh non-manifold, or if it turns a triangle over
+    fn allowed(&self, c: &Collapse) -> bool {
+        let (u, v) = (c.u, c.v);
+        // triangles with both vertices, removed by the collapse
+        let shared: Vec<usize> = self.triangles_around(u)
+            .filter(|&t| self.triangles[t].contains(&v))
+            .collect();
+        if shared.is_empty() {
+            return false
+        }
+        // the border would be pinched by an edge through the mesh
+        if self.boundary[u] && self.boundary[v] && shared.len() != 1 {
+            return false
+        }
+        // link condition: the common neighbours are the opposite vertices of the shared triangles
+        let common = self.neighbours(u).intersection(&self.neighbours(v)).count();
+        if common != shared.len() {
+            return false
+        }
+
+        let p = position(&c.vertex);
+        self.triangles_around(u).chain(self.triangles_around(v))
+            .filter(|t| !shared.contains(t))
+            .all(|t| {
+                let corners = self.triangles[t].map(|i| position(&self.vertices[i]));
+                let moved = self.triangles[t].map(|i| if i == u || i == v {p} else {position(&self.vertices[i])});
+                let (before, after) = (normal(corners), normal(moved));
+                // degenerate triangles have no direction to keep
+                before.norm() == 0.0
+                    || (after.norm() > 0.0
+                        && V3::dot(before, after) >= MIN_NORMAL_COS * before.norm() * after.norm()
+                        && V3::dot(self.original_normals[t], after) >= MIN_NORMAL_COS * self.original_normals[t].norm() * after.norm())
+            })
+    }
+
+    /// merge v into u, and return the number of triangles removed
+    fn apply(&mut self, c: &Collapse) -> usize {
+        let (u, v) = (c.u, c.v);
+        let mut n_removed = 0;
+        for t in self.around[v].clone() {
+            if self.removed[t] {
+                continue
+            }
+            if self.triangles[t].contains(&u) {
+                self.removed[t] = true;
+                n_removed += 1;
+            }
+            else {
+                for i in self.triangles[t].iter_mut() {
+                    if *i == v {
+                        *i = u;
+                    }
+                }
+                self.around[u].push(t);
+            }
+        }
+        self.around[v].clear();
+        self.vertices[u] = c.vertex;
+        let q = self.quadrics[v];
+        self.quadrics[u] += q;
+        self.boundary[u] |= self.boundary[v];
+        self.versions[u] += 1;
+        self.versions[v] += 1;
+        n_removed
+    }
+}
+
+/// Remove triangles by merging the 2 vertices of an edge, the cheapest first,
+/// until there are `target.triangles` triangles, or the next error is greater than `target.max_error`.
+///
+/// The boundaries of the mesh stay in place, the triangles do not turn over, and
+/// the edges between vertices with different ondulation frequencies are kept.
+/// The buffers are replaced by the simplified mesh, without the vertices that are not used
+pub fn simplify(points: &mut Vec<f32>, indices: &mut Vec<u16>, target: Target) {
+    let mut mesh = Mesh::new(points, indices);
+    let mut n_triangles = mesh.triangles.len();
+
+    let mut heap = BinaryHeap::new();
+    let mut edges = HashSet::new();
+    for t in &mesh.triangles {
+        for k in 0..3 {
+            let (a, b) = (t[k], t[(k+1)%3]);
+            if a != b && edges.insert((a.min(b), a.max(b))) {
+                heap.extend(mesh.collapse(a, b));
+            }
+        }
+    }
+
+    while n_triangles > target.triangles {
+        let c = match heap.pop() {
+            Some(c) => c,
+            None => break,
+        };
+        if (mesh.versions[c.u], mesh.versions[c.v]) != c.versions {
+            continue
+        }
+        if c.cost > target.max_error as f64 {
+            break
+        }
+        if !mesh.allowed(&c) {
+            continue
+        }
+        n_triangles -= mesh.apply(&c);
+
+        for w in mesh.neighbours(c.u) {
+            heap.extend(mesh.collapse(c.u, w));
+        }
+    }
+
+    points.clear();
+    for vertex in &mesh.vertices {
+        points.extend_from_slice(vertex);
+    }
+    let triangles: Vec<[usize; 3]> = mesh.triangles.iter().zip(&mesh.removed)
+        .filter(|(_, &removed)| !removed)
+        .map(|(t, _)| *t)
+        .collect();
+    keep_used_vertices(points, indices, &triangles);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{simplify, Target, Quadric};
+    use super::super::{V3, SIZE_VERTEX, get_point, rand_surface, pseudo_sphere};
+    use super::super::surface::Attributes;
+    use super::super::surface::tests::grid;
+
+    fn normals(points: &[f32], indices: &[u16]) -> Vec<V3> {
+        indices.chunks(3)
+            .map(|t| {
+                let [a, b, c] = [t[0], t[1], t[2]].map(|i| get_point(points, i));
+                V3::cross(b - a, c - a)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn quadric() {
+        // the plane z = 1, with a constant color
+        let vertex = |x: f64, y: f64| [x, y, 1.0, 0.5, 0.5, 0.5];
+        let q = Quadric::triangle([vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(0.0, 1.0)]).unwrap();
+        assert!(q.error(&vertex(3.0, -2.0)) < 1e-9);
+        assert!((q.error(&[0.0, 0.0, 3.0, 0.5, 0.5, 0.5]) - 4.0).abs() < 1e-9);
+        assert!((q.error(&[0.0, 0.0, 1.0, 0.5, 0.5, 1.5]) - 1.0).abs() < 1e-9);
+        // a single plane does not give a point
+        assert!(q.optimum().is_none());
+
+        // 6 orthogonal planes meet at one point
+        let mut q = Quadric::triangle([vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(0.0, 1.0)]).unwrap();
+        q += Quadric::plane(V3::new(1.0, 0.0, 0.0), V3::new(2.0, 0.0, 0.0), 1.0);
+        q += Quadric::plane(V3::new(0.0, 1.0, 0.0), V3::new(0.0, 3.0, 0.0), 1.0);
+        let optimum = q.optimum().unwrap();
+        for (x, y) in optimum.iter().zip(&[2.0, 3.0, 1.0, 0.5, 0.5, 0.5]) {
+            assert!((x - y).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn flat_grid() {
+        // a flat square of 10x10 vertices, with 2 colors
+        let n = 10;
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        grid(n).push(&mut points, &mut indices, |p, _| Attributes::still(
+            if p.x < (n/2) as f32 - 0.5 {V3::new(1.0, 0.0, 0.0)} else {V3::new(0.0, 0.0, 1.0)}
+        ));
+
+        simplify(&mut points, &mut indices, Target::error(1e-6));
+        assert!(indices.len() < 2*9*9*3 / 4);
+        // the corners and the colors are kept
+        let vertices: Vec<&[f32]> = points.chunks(SIZE_VERTEX).collect();
+        let close = |a: &[f32], b: &[f32]| a.iter().zip(b).all(|(x, y)| (x - y).abs() < 1e-5);
+        for corner in [[0.0, 0.0], [9.0, 0.0], [0.0, 9.0], [9.0, 9.0]] {
+            assert!(vertices.iter().any(|v| close(&v[..2], &corner)));
+        }
+        for v in &vertices {
+            assert!(v[2].abs() < 1e-5);
+            assert!(close(&v[3..6], &[1.0, 0.0, 0.0]) || close(&v[3..6], &[0.0, 0.0, 1.0]), "{:?}", v);
+            assert!(v[0] > -1e-5 && v[0] < 9.0 + 1e-5 && v[1] > -1e-5 && v[1] < 9.0 + 1e-5);
+        }
+        // the triangles still face up
+        assert!(normals(&points, &indices).iter().all(|n| n.z > 0.0));
+        // the area is the same
+        let area: f32 = normals(&points, &indices).iter().map(|n| n.norm() / 2.0).sum();
+        assert!((area - 81.0).abs() < 1e-3, "{}", area);
+    }
+
+    #[test]
+    fn target_triangles() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        rand_surface(&mut points, &mut indices);
+        // flatter, so that all the triangles are less than 30° from the horizontal
+        for v in points.chunks_mut(SIZE_VERTEX) {
+            v[2] *= 0.2;
+        }
+        let n = indices.len() / 3;
+        let original = points.clone();
+        let up = normals(&points, &indices)[0].z.signum();
+        assert!(normals(&points, &indices).iter().all(|n| n.z * up > 0.87 * n.norm()));
+
+        simplify(&mut points, &mut indices, Target::triangles(n / 10));
+        assert!(indices.len() / 3 <= n / 10);
+        assert!(indices.len() / 3 > n / 20);
+        assert!(indices.iter().all(|&i| (i as usize) < points.len() / SIZE_VERTEX));
+        // they turn less than 60°, so none of them turns over
+        assert!(normals(&points, &indices).iter().all(|n| n.z * up > 0.0));
+
+        // the vertices stay close to the surface
+        let height = |p: V3| {
+            let (x, y) = ((p.x + 49.5).round() as usize, (p.y + 50.0).round() as usize);
+            get_point(&original, (x*100 + y) as u16).z
+        };
+        for v in points.chunks(SIZE_VERTEX) {
+            let p = V3::new(v[0], v[1], v[2]);
+            assert!((p.z - height(p)).abs() < 0.5, "{:?}", p);
+        }
+    }
+
+    #[test]
+    fn closed_meshes() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        pseudo_sphere(&mut points, &mut indices, V3::null(), 2.0, (0.5, 0.5, 0.5));
+        let n = indices.len();
+        simplify(&mut points, &mut indices, Target::triangles(n / 3 / 4));
+        assert!(indices.len() <= n / 4);
+        // no triangle appears twice
+        let mut triangles: Vec<[u16; 3]> = indices.chunks(3)
+            .map(|t| {
+                let mut t = [t[0], t[1], t[2]];
+                t.sort();
+                t
+            })
+            .collect();
+        let len = triangles.len();
+        triangles.sort();
+        triangles.dedup();
+        assert_eq!(triangles.len(), len);
+    }
+}
@@ -0,0 +1,339 @@
+//! Operators on the vertex and index buffers of a mesh: welding of the vertices
+//! at the same place, Laplacian and Taubin smoothing, and Loop subdivision.
+//!
+//! The edges with a single triangle (or more than 2) are borders: their vertices do not move
+//! in the smoothing, and they stay on the border in the subdivision
+
+use std::collections::HashMap;
+
+use super::{V3, SIZE_VERTEX, get_point, keep_used_vertices};
+
+/// the vertices at the ends of each edge, with the third vertex of the triangles on each side
+fn edges(indices: &[u16]) -> HashMap<(u16, u16), Vec<u16>> {
+    let mut edges: HashMap<(u16, u16), Vec<u16>> = HashMap::new();
+    for t in indices.chunks_exact(3) {
+        for k in 0..3 {
+            let (a, b, c) = (t[k], t[(k+1)%3], t[(k+2)%3]);
+            edges.entry((a.min(b), a.max(b))).or_default().push(c);
+        }
+    }
+    edges
+}
+
+/// the neighbours of each vertex, and whether it is on a border
+fn neighbours(n_vertices: usize, indices: &[u16]) -> (Vec<Vec<u16>>, Vec<bool>) {
+    let mut neighbours = vec![Vec::new(); n_vertices];
+    let mut border = vec![false; n_vertices];
+    for ((a, b), opposite) in edges(indices) {
+        neighbours[a as usize].push(b);
+        neighbours[b as usize].push(a);
+        if opposite.len() != 2 {
+            border[a as usize] = true;
+            border[b as usize] = true;
+        }
+    }
+    (neighbours, border)
+}
+
+/// Merge the vertices closer than `tolerance`, with the values of the first one.
+/// The triangles that become flat and the vertices without triangles are removed
+pub fn weld(points: &mut Vec<f32>, indices: &mut Vec<u16>, tolerance: f32) {
+    // the vertices kept so far, by cube of side `tolerance`
+    let mut cells: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    let cell = |p: V3| p.to_array().map(|x| if tolerance > 0.0 {(x / tolerance).floor() as i64} else {x.to_bits() as i64});
+    let n_vertices = points.len() / SIZE_VERTEX;
+    // in usize, since there can be 65536 vertices
+    let point = |i: usize| V3::new(points[i*SIZE_VERTEX], points[i*SIZE_VERTEX+1], points[i*SIZE_VERTEX+2]);
+    let mut merged = Vec::with_capacity(n_vertices);
+    for i in 0..n_vertices {
+        let p = point(i);
+        let [x, y, z] = cell(p);
+        let mut found = None;
+        // the close vertices are in the neighbouring cubes
+        'search: for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let key = [x + dx, y + dy, z + dz];
+                    if tolerance <= 0.0 && key != [x, y, z] {
+                        continue
+                    }
+                    for &j in cells.get(&key).into_iter().flatten() {
+                        if (point(j) - p).norm() <= tolerance {
+                            found = Some(j);
+                            break 'search
+                        }
+                    }
+                }
+            }
+        }
+        merged.push(found.unwrap_or_else(|| {
+            cells.entry([x, y, z]).or_default().push(i);
+            i
+        }));
+    }
+
+    let triangles: Vec<[usize; 3]> = indices.chunks_exact(3)
+        .map(|t| [t[0], t[1], t[2]].map(|i| merged[i as usize]))
+        .filter(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0])
+        .collect();
+    keep_used_vertices(points, indices, &triangles);
+}
+
+/// move each vertex towards (or away from, if `factor` is negative) the average of its neighbours
+fn relax(points: &mut [f32], neighbours: &[Vec<u16>], border: &[bool], factor: f32) {
+    let moves: Vec<V3> = neighbours.iter().enumerate()
+        .map(|(i, around)| {
+            if border[i] || around.is_empty() {
+                return V3::null()
+            }
+            let sum = around.iter().fold(V3::null(), |sum, &j| sum + get_point(points, j));
+            (sum.scale(1.0 / around.len() as f32) - get_point(points, i as u16)).scale(factor)
+        })
+        .collect();
+    for (vertex, m) in points.chunks_exact_mut(SIZE_VERTEX).zip(moves) {
+        vertex[0] += m.x;
+        vertex[1] += m.y;
+        vertex[2] += m.z;
+    }
+}
+
+/// Laplacian smoothing: each vertex moves by `lambda` (between 0 and 1) of the way
+/// to the average of its neighbours, `iterations` times. The shape shrinks a little each time
+pub fn laplacian(points: &mut [f32], indices: &[u16], iterations: usize, lambda: f32) {
+    let (neighbours, border) = neighbours(points.len() / SIZE_VERTEX, indices);
+    for _ in 0..iterations {
+        relax(points, &neighbours, &border, lambda);
+    }
+}
+
+/// Taubin smoothing: a Laplacian step with `lambda`, then a step back with `mu`
+/// (negative, a little bigger than `lambda`, ex. 0.5 and -0.53), so the shape does not shrink
+pub fn taubin(points: &mut [f32], indices: &[u16], iterations: usize, lambda: f32, mu: f32) {
+    let (neighbours, border) = neighbours(points.len() / SIZE_VERTEX, indices);
+    for _ in 0..iterations {
+        relax(points, &neighbours, &border, lambda);
+        relax(points, &neighbours, &border, mu);
+    }
+}
+
+/// the sum of vertices with weights, for all the values of the vertices
+fn combine(points: &[f32], weights: &[(u16, f32)]) -> [f32; SIZE_VERTEX] {
+    let mut vertex = [0.0; SIZE_VERTEX];
+    for &(i, w) in weights {
+        let i = i as usize * SIZE_VERTEX;
+        for (x, y) in vertex.iter_mut().zip(&points[i..i+SIZE_VERTEX]) {
+            *x += w * y;
+        }
+    }
+    vertex
+}
+
+/// Loop subdivision: each triangle is cut in 4, with a new vertex on each edge,
+/// and the vertices move to approach a smooth surface.
+/// All the values of the vertices (colors, ondulation) are smoothed like the positions.
+/// Fails without changing anything if there would be more than 65536 vertices
+pub fn loop_subdivide(points: &mut Vec<f32>, indices: &mut Vec<u16>) -> Result<(), String> {
+    let n_vertices = points.len() / SIZE_VERTEX;
+    let edges = edges(indices);
+    if n_vertices + edges.len() > u16::MAX as usize + 1 {
+        return Err(format!("too many vertices after the subdivision: {}", n_vertices + edges.len()))
+    }
+    let (neighbours, border) = neighbours(n_vertices, indices);
+
+    // the old vertices: on a border, only the 2 neighbours on the border count
+    let mut subdivided = Vec::with_capacity((n_vertices + edges.len()) * SIZE_VERTEX);
+    for (i, around) in neighbours.iter().enumerate() {
+        let v = i as u16;
+        let weights = if border[i] || around.is_empty() {
+            let on_border: Vec<u16> = around.iter().copied()
+                .filter(|&j| edges[&(v.min(j), v.max(j))].len() != 2)
+                .collect();
+            match on_border.as_slice() {
+                &[a, b] => vec![(v, 0.75), (a, 0.125), (b, 0.125)],
+                // a corner where more than 2 borders meet does not move, nor a vertex without triangles
+                _ => vec![(v, 1.0)],
+            }
+        }
+        else {
+            let n = around.len() as f32;
+            let c = 0.375 + 0.25 * (std::f32::consts::TAU / n).cos();
+            let beta = (0.625 - c*c) / n;
+            let mut weights = vec![(v, 1.0 - n*beta)];
+            weights.extend(around.iter().map(|&j| (j, beta)));
+            weights
+        };
+        subdivided.extend_from_slice(&combine(points, &weights));
+    }
+
+    // a vertex on each edge
+    let mut middles = HashMap::with_capacity(edges.len());
+    for (&(a, b), opposite) in &edges {
+        let weights = match opposite.as_slice() {
+            &[c, d] => vec![(a, 0.375), (b, 0.375), (c, 0.125), (d, 0.125)],
+            _ => vec![(a, 0.5), (b, 0.5)],
+        };
+        middles.insert((a, b), (subdivided.len() / SIZE_VERTEX) as u16);
+        subdivided.extend_from_slice(&combine(points, &weights));
+    }
+
+    let mut triangles = Vec::with_capacity(indices.len() * 4);
+    for t in indices.chunks_exact(3) {
+        let middle = |k: usize| {
+            let (a, b) = (t[k], t[(k+1)%3]);
+            middles[&(a.min(b), a.max(b))]
+        };
+        let (ab, bc, ca) = (middle(0), middle(1), middle(2));
+        triangles.extend_from_slice(&[t[0], ab, ca,  ab, t[1], bc,  ca, bc, t[2],  ab, bc, ca]);
+    }
+    *points = subdivided;
+    *indices = triangles;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{weld, laplacian, taubin, loop_subdivide, edges};
+    use std::f32::consts::{PI, TAU};
+    use super::super::{V3, SIZE_VERTEX, get_point};
+    use super::super::surface::{self, Surface, Attributes};
+    use super::super::random::Rng;
+
+    /// a square of n x n vertices in the plane z = 0, with some noise on z if there is a generator
+    fn grid(n: usize, mut noise: Option<&mut Rng>) -> (Vec<f32>, Vec<u16>) {
+        let mut grid = surface::tests::grid(n);
+        for p in &mut grid.positions {
+            p.z = noise.as_mut().map_or(0.0, |rng| rng.float() - 0.5);
+        }
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        grid.push(&mut points, &mut indices, |_, _| Attributes::still(V3::new(0.5, 0.5, 0.5)));
+        (points, indices)
+    }
+
+    /// a closed mesh of 6 vertices at distance 1 from the center
+    fn octahedron() -> (Vec<f32>, Vec<u16>) {
+        let mut points = Vec::new();
+        for v in [[1.0, 0.0, 0.0], [-1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0], [0.0, 0.0, -1.0]] {
+            push_point!(points, (V3::from(v)), [1, 0, 0], [0, 0, 0], [1, 0, 0]);
+        }
+        let indices = vec![
+            0, 2, 4,  2, 1, 4,  1, 3, 4,  3, 0, 4,
+            2, 0, 5,  1, 2, 5,  3, 1, 5,  0, 3, 5,
+        ];
+        (points, indices)
+    }
+
+    /// the distance to the plane z = 0 of the vertices that are not on the border of `grid(n, _)`
+    fn roughness(points: &[f32], n: usize) -> f32 {
+        let inside = |x: f32| x > 0.0 && x < (n-1) as f32;
+        points.chunks(SIZE_VERTEX).filter(|v| inside(v[0]) && inside(v[1])).map(|v| v[2].abs()).sum()
+    }
+
+    fn is_closed(indices: &[u16]) -> bool {
+        edges(indices).values().all(|opposite| opposite.len() == 2)
+    }
+
+    #[test]
+    fn welding() {
+        // a sphere with its sides not joined: the first and last points of each parallel are the same
+        let sphere = Surface::parametric(|u, v| {
+            let (longitude, colatitude) = (u * TAU, (1.0 - v) * PI);
+            V3::new(longitude.cos() * colatitude.sin(), longitude.sin() * colatitude.sin(), colatitude.cos())
+        }, 31, 30, false, false);
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        sphere.push(&mut points, &mut indices, |_, _| Attributes::still(V3::new(0.5, 0.5, 0.5)));
+        assert!(!is_closed(&indices));
+
+        weld(&mut points, &mut indices, 1e-5);
+        assert_eq!(points.len() / SIZE_VERTEX, 28*30 + 2);
+        assert_eq!(indices.len(), 3*sphere.triangles.len());
+        assert!(is_closed(&indices));
+
+        // only the same positions with a tolerance of 0
+        let (mut points, mut indices) = octahedron();
+        points.extend_from_within(..SIZE_VERTEX);
+        points[6*SIZE_VERTEX + 1] = 1e-4;
+        indices.extend_from_slice(&[6, 2, 4,  0, 6, 2]);
+        weld(&mut points, &mut indices, 0.0);
+        assert_eq!(points.len(), 7*SIZE_VERTEX);
+        // the last triangle becomes flat
+        weld(&mut points, &mut indices, 1e-3);
+        assert_eq!(points.len(), 6*SIZE_VERTEX);
+        assert_eq!(indices.len(), 9*3);
+        assert_eq!(indices[indices.len()-3..], [0, 2, 4]);
+    }
+
+    #[test]
+    fn welding_all_the_indexable_vertices() {
+        let (mut points, mut indices) = grid(256, None);
+        assert_eq!(points.len() / SIZE_VERTEX, 65536);
+        let before = indices.clone();
+        weld(&mut points, &mut indices, 1e-3);
+        assert_eq!(points.len() / SIZE_VERTEX, 65536);
+        assert_eq!(indices, before);
+    }
+
+    #[test]
+    fn smoothing() {
+        let (mut points, indices) = grid(10, Some(&mut Rng::new(1)));
+        let border: Vec<f32> = points.chunks(SIZE_VERTEX)
+            .filter(|v| v[0] == 0.0 || v[1] == 0.0 || v[0] == 9.0 || v[1] == 9.0)
+            .flatten()
+            .copied()
+            .collect();
+        let before = roughness(&points, 10);
+        laplacian(&mut points, &indices, 10, 0.5);
+        assert!(roughness(&points, 10) < before / 2.0);
+        // the border does not move
+        assert!(border.chunks(SIZE_VERTEX).all(|v| points.chunks(SIZE_VERTEX).any(|w| w == v)));
+
+        // a closed shape shrinks with the Laplacian smoothing, much less with Taubin's
+        let (mut shrunk, mut indices) = octahedron();
+        loop_subdivide(&mut shrunk, &mut indices).unwrap();
+        let mut kept = shrunk.clone();
+        let radius = |points: &[f32]| get_point(points, 0).norm();
+        let original = radius(&kept);
+        laplacian(&mut shrunk, &indices, 10, 0.5);
+        taubin(&mut kept, &indices, 10, 0.5, -0.53);
+        assert!((radius(&kept) - original).abs() < 0.5 * (radius(&shrunk) - original).abs());
+    }
+
+    #[test]
+    fn subdivision() {
+        let (mut points, mut indices) = octahedron();
+        loop_subdivide(&mut points, &mut indices).unwrap();
+        // 6 vertices and 12 edges
+        assert_eq!(points.len(), 18*SIZE_VERTEX);
+        assert_eq!(indices.len(), 4*8*3);
+        assert!(is_closed(&indices));
+        // the triangles still face outside
+        for t in indices.chunks(3) {
+            let [a, b, c] = [t[0], t[1], t[2]].map(|i| get_point(&points, i));
+            assert!(V3::dot(V3::cross(b-a, c-a), a + b + c) > 0.0);
+        }
+        // towards a sphere: all the vertices get closer to the same distance
+        for _ in 0..3 {
+            loop_subdivide(&mut points, &mut indices).unwrap();
+        }
+        let distances: Vec<f32> = points.chunks(SIZE_VERTEX).map(|v| V3::new(v[0], v[1], v[2]).norm()).collect();
+        let (min, max) = distances.iter().fold((f32::MAX, 0.0f32), |(a, b), &d| (a.min(d), b.max(d)));
+        assert!(max - min < 0.1 * max, "{} {}", min, max);
+        // the colors are kept
+        assert!(points.chunks(SIZE_VERTEX).all(|v| (v[3] - 1.0).abs() < 1e-5));
+
+        // a flat square stays flat
+        let (mut points, mut indices) = grid(3, None);
+        loop_subdivide(&mut points, &mut indices).unwrap();
+        assert_eq!(points.len(), (9 + 16)*SIZE_VERTEX);
+        assert!(points.chunks(SIZE_VERTEX).all(|v| v[2] == 0.0));
+        // the vertices of the border stay on the border, except the 4 corners that are rounded
+        let border = points.chunks(SIZE_VERTEX).filter(|v| v[0] == 0.0 || v[1] == 0.0 || v[0] == 2.0 || v[1] == 2.0).count();
+        assert_eq!(border, 16 - 4);
+
+        // too big
+        let (mut points, mut indices) = grid(200, None);
+        assert!(loop_subdivide(&mut points, &mut indices).is_err());
+        assert_eq!(indices.len(), 199*199*6);
+    }
+}
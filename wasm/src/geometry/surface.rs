@@ -0,0 +1,351 @@
+//! Meshes of parametric surfaces: a function f(u, v) sampled on a grid of (u, v) in [0, 1]²,
+//! with the sides of the grid joined when the surface wraps around, and the rows where
+//! all the points are at the same place (the poles) merged into a single vertex.
+//!
+//! The shapes are built around the origin, then moved, displaced along their normals
+//! and finally added to the vertex buffers with `Surface::push`
+
+use std::collections::HashMap;
+use std::f32::consts::{PI, TAU};
+
+use super::V3;
+
+/// the values of a vertex besides its position, given to `Surface::push`
+#[derive(Copy, Clone, Debug)]
+pub struct Attributes {
+    pub color: V3,
+    pub ondulation: V3,
+    pub frequency: f32,
+    pub phase: f32,
+}
+
+/// A triangle mesh that is not in the vertex buffers yet
+#[derive(Clone, Debug, Default)]
+pub struct Surface {
+    pub positions: Vec<V3>,
+    pub triangles: Vec<[usize; 3]>,
+}
+
+impl Surface {
+    /// Sample `f` on `n_u` x `n_v` points.
+    /// When the surface wraps around in u (or v), f(1, v) is f(0, v) and it is not sampled.
+    /// The triangles face the side of ∂f/∂u × ∂f/∂v
+    pub fn parametric(f: impl Fn(f32, f32) -> V3, n_u: usize, n_v: usize, wrap_u: bool, wrap_v: bool) -> Self {
+        let parameter = |i: usize, n: usize, wrap: bool| if wrap {i as f32 / n as f32} else {i as f32 / (n - 1) as f32};
+        let rows = (0..n_v)
+            .map(|j| {
+                let v = parameter(j, n_v, wrap_v);
+                (0..n_u).map(|i| f(parameter(i, n_u, wrap_u), v)).collect()
+            })
+            .collect();
+        Surface::from_rows(rows, wrap_u, wrap_v)
+    }
+
+    /// Join the rows of points (of the same length) with triangles.
+    /// A row with all its points at the same place is a single vertex
+    pub fn from_rows(rows: Vec<Vec<V3>>, wrap_u: bool, wrap_v: bool) -> Self {
+        assert!(rows.len() >= 2 && rows[0].len() >= 2, "a surface needs at least 2 rows of 2 points");
+        let size = rows.iter().flatten().fold(0.0f32, |size, p| size.max(p.norm()));
+        let tolerance = 1e-5 * size.max(1.0);
+
+        let mut surface = Surface::default();
+        // index of each point of the grid
+        let grid: Vec<Vec<usize>> = rows.iter()
+            .map(|row| {
+                let pole = row.iter().all(|&p| (p - row[0]).norm() <= tolerance);
+                if pole {
+                    surface.positions.push(row[0]);
+                    vec![surface.positions.len() - 1; row.len()]
+                }
+                else {
+                    let first = surface.positions.len();
+                    surface.positions.extend_from_slice(row);
+                    (first..first + row.len()).collect()
+                }
+            })
+            .collect();
+
+        let (n_u, n_v) = (grid[0].len(), grid.len());
+        let quads_u = if wrap_u {n_u} else {n_u - 1};
+        let quads_v = if wrap_v {n_v} else {n_v - 1};
+        for j in 0..quads_v {
+            for i in 0..quads_u {
+                let (i1, j1) = ((i + 1) % n_u, (j + 1) % n_v);
+                let quad = [grid[j][i], grid[j][i1], grid[j1][i], grid[j1][i1]];
+                for t in [[quad[0], quad[1], quad[3]], [quad[0], quad[3], quad[2]]] {
+                    // the triangles that touch a pole on a side are flat
+                    if t[0] != t[1] && t[1] != t[2] && t[2] != t[0] {
+                        surface.triangles.push(t);
+                    }
+                }
+            }
+        }
+        surface
+    }
+
+    /// Sphere of radius 1, with `n_u` points around each parallel and `n_v` parallels
+    /// from the south pole to the north pole (z = 1)
+    pub fn uv_sphere(n_u: usize, n_v: usize) -> Self {
+        Surface::parametric(|u, v| {
+            let (longitude, colatitude) = (u * TAU, (1.0 - v) * PI);
+            V3::new(
+                longitude.cos() * colatitude.sin(),
+                longitude.sin() * colatitude.sin(),
+                colatitude.cos(),
+            )
+        }, n_u, n_v, true, false)
+    }
+
+    /// Sphere of radius 1 made of triangles of almost the same size:
+    /// an icosahedron with each triangle cut in 4, `subdivisions` times
+    pub fn icosphere(subdivisions: usize) -> Self {
+        let t = (1.0 + 5f32.sqrt()) / 2.0;
+        let mut surface = Surface {
+            positions: [
+                [-1.0, t, 0.0], [1.0, t, 0.0], [-1.0, -t, 0.0], [1.0, -t, 0.0],
+                [0.0, -1.0, t], [0.0, 1.0, t], [0.0, -1.0, -t], [0.0, 1.0, -t],
+                [t, 0.0, -1.0], [t, 0.0, 1.0], [-t, 0.0, -1.0], [-t, 0.0, 1.0],
+            ].iter().map(|&p| V3::from(p).unit()).collect(),
+            triangles: vec![
+                [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+                [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+                [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+                [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1],
+            ],
+        };
+
+        for _ in 0..subdivisions {
+            // the vertex on each edge, shared by the 2 triangles of the edge
+            let mut middles = HashMap::new();
+            let mut middle = |positions: &mut Vec<V3>, a: usize, b: usize| {
+                *middles.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                    positions.push((positions[a] + positions[b]).unit());
+                    positions.len() - 1
+                })
+            };
+            let triangles = std::mem::take(&mut surface.triangles);
+            for [a, b, c] in triangles {
+                let ab = middle(&mut surface.positions, a, b);
+                let bc = middle(&mut surface.positions, b, c);
+                let ca = middle(&mut surface.positions, c, a);
+                surface.triangles.extend_from_slice(&[[a, ab, ca], [ab, b, bc], [ca, bc, c], [ab, bc, ca]]);
+            }
+        }
+        surface
+    }
+
+    /// Torus around the z axis: a circle of radius `minor` turning at a distance `major`
+    pub fn torus(major: f32, minor: f32, n_u: usize, n_v: usize) -> Self {
+        Surface::parametric(|u, v| {
+            let (a, b) = (u * TAU, v * TAU);
+            let r = major + minor * b.cos();
+            V3::new(r * a.cos(), r * a.sin(), minor * b.sin())
+        }, n_u, n_v, true, true)
+    }
+
+    /// Surface of revolution around the z axis, from a profile of (radius, z) points
+    /// that goes up on the outside. A point with a radius of 0 is a pole
+    pub fn revolution(profile: &[(f32, f32)], n_u: usize) -> Self {
+        let rows = profile.iter()
+            .map(|&(radius, z)| (0..n_u)
+                .map(|i| {
+                    let a = i as f32 / n_u as f32 * TAU;
+                    V3::new(radius * a.cos(), radius * a.sin(), z)
+                })
+                .collect())
+            .collect();
+        Surface::from_rows(rows, true, false)
+    }
+
+    /// Closed cylinder around the z axis, from z = 0 to `height`, with `n_v` rows on the side
+    pub fn cylinder(radius: f32, height: f32, n_u: usize, n_v: usize) -> Self {
+        let mut profile = vec![(0.0, 0.0)];
+        profile.extend((0..n_v).map(|j| (radius, j as f32 / (n_v - 1) as f32 * height)));
+        profile.push((0.0, height));
+        Surface::revolution(&profile, n_u)
+    }
+
+    /// Closed cone around the z axis, from its base at z = 0 to its tip at `height`
+    pub fn cone(radius: f32, height: f32, n_u: usize, n_v: usize) -> Self {
+        let mut profile = vec![(0.0, 0.0)];
+        profile.extend((0..n_v).map(|j| {
+            let t = j as f32 / (n_v - 1) as f32;
+            (radius * (1.0 - t), t * height)
+        }));
+        Surface::revolution(&profile, n_u)
+    }
+
+    /// Rectangle centered on the origin in the plane z = 0, facing up
+    pub fn plane(width: f32, depth: f32, n_u: usize, n_v: usize) -> Self {
+        Surface::parametric(|u, v| V3::new((u - 0.5) * width, (v - 0.5) * depth, 0.0), n_u, n_v, false, false)
+    }
+
+    pub fn scale(mut self, k: f32) -> Self {
+        for p in &mut self.positions {
+            *p = p.scale(k);
+        }
+        self
+    }
+
+    pub fn translate(mut self, offset: V3) -> Self {
+        for p in &mut self.positions {
+            *p += offset;
+        }
+        self
+    }
+
+    /// the normal of each vertex: the average of the normals of its triangles, weighted by their area
+    pub fn normals(&self) -> Vec<V3> {
+        let mut normals = vec![V3::null(); self.positions.len()];
+        for t in &self.triangles {
+            let [a, b, c] = t.map(|i| self.positions[i]);
+            let normal = V3::cross(b - a, c - a);
+            for &i in t {
+                normals[i] += normal;
+            }
+        }
+        normals.into_iter().map(V3::unit).collect()
+    }
+
+    /// move each vertex along its normal, by `amount(position)`
+    pub fn displace(&mut self, amount: impl Fn(V3) -> f32) {
+        let normals = self.normals();
+        for (p, normal) in self.positions.iter_mut().zip(normals) {
+            *p += normal.scale(amount(*p));
+        }
+    }
+
+    /// Add the triangles to the buffers.
+    /// The other values of each vertex are given by `attributes(position, normal)`
+    pub fn push(&self, points: &mut Vec<f32>, indices: &mut Vec<u16>, mut attributes: impl FnMut(V3, V3) -> Attributes) {
+        let first = points.len() / super::SIZE_VERTEX;
+        for (&p, normal) in self.positions.iter().zip(self.normals()) {
+            let a = attributes(p, normal);
+            push_point!(points, p, (a.color), (a.ondulation), [a.frequency, a.phase, 0.0]);
+        }
+        for t in &self.triangles {
+            push_index!(indices, [first + t[0], first + t[1], first + t[2]]);
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::HashMap;
+    use super::{Surface, Attributes};
+    use super::super::{V3, SIZE_VERTEX};
+
+    /// each edge is shared by 2 triangles that use it in opposite directions
+    fn is_closed(surface: &Surface) -> bool {
+        let mut edges = HashMap::new();
+        for t in &surface.triangles {
+            for k in 0..3 {
+                *edges.entry((t[k], t[(k+1)%3])).or_insert(0) += 1;
+            }
+        }
+        edges.iter().all(|(&(a, b), &n)| n == 1 && edges.get(&(b, a)) == Some(&1))
+    }
+
+    /// the volume inside a closed surface, positive if the triangles face outside
+    fn volume(surface: &Surface) -> f32 {
+        surface.triangles.iter()
+            .map(|t| {
+                let [a, b, c] = t.map(|i| surface.positions[i]);
+                V3::dot(a, V3::cross(b, c)) / 6.0
+            })
+            .sum()
+    }
+
+
+    /// a square of n x n vertices from (0, 0) to (n-1, n-1) in the plane z = 0, row by row along y
+    pub fn grid(n: usize) -> Surface {
+        let side = (n - 1) as f32;
+        Surface::plane(side, side, n, n).translate(V3::new(side / 2.0, side / 2.0, 0.0))
+    }
+
+    impl Attributes {
+        /// a vertex of this color that does not move
+        pub fn still(color: V3) -> Self {
+            Attributes {color, ondulation: V3::null(), frequency: 0.0, phase: 0.0}
+        }
+    }
+
+    #[test]
+    fn spheres() {
+        let sphere = Surface::uv_sphere(32, 17);
+        // a single vertex at each pole
+        assert_eq!(sphere.positions.len(), 32*15 + 2);
+        assert_eq!(sphere.triangles.len(), 2*32*15);
+        assert!(is_closed(&sphere));
+        assert!(sphere.positions.iter().all(|p| (p.norm() - 1.0).abs() < 1e-5));
+        let ball = 4.0 / 3.0 * std::f32::consts::PI;
+        assert!((volume(&sphere) - ball).abs() < 0.05 * ball);
+        // the normals point outside
+        for (p, n) in sphere.positions.iter().zip(sphere.normals()) {
+            assert!(V3::dot(*p, n) > 0.99);
+        }
+
+        let sphere = Surface::icosphere(3);
+        assert_eq!(sphere.positions.len(), 10*4*4*4 + 2);
+        assert_eq!(sphere.triangles.len(), 20*4*4*4);
+        assert!(is_closed(&sphere));
+        assert!((volume(&sphere) - ball).abs() < 0.05 * ball);
+    }
+
+    #[test]
+    fn closed_shapes() {
+        use std::f32::consts::PI;
+        let torus = Surface::torus(1.0, 0.25, 32, 16);
+        assert_eq!(torus.positions.len(), 32*16);
+        assert!(is_closed(&torus));
+        let expected = 2.0 * PI * PI * 1.0 * 0.25 * 0.25;
+        assert!((volume(&torus) - expected).abs() < 0.05 * expected);
+
+        let cylinder = Surface::cylinder(0.5, 2.0, 24, 5);
+        assert_eq!(cylinder.positions.len(), 24*5 + 2);
+        assert!(is_closed(&cylinder));
+        let expected = PI * 0.25 * 2.0;
+        assert!((volume(&cylinder) - expected).abs() < 0.05 * expected);
+
+        let cone = Surface::cone(0.5, 2.0, 24, 5);
+        // the tip is a pole
+        assert_eq!(cone.positions.len(), 24*4 + 2);
+        assert!(is_closed(&cone));
+        let expected = PI * 0.25 * 2.0 / 3.0;
+        assert!((volume(&cone) - expected).abs() < 0.05 * expected);
+    }
+
+    #[test]
+    fn plane() {
+        let plane = Surface::plane(2.0, 4.0, 3, 5);
+        assert_eq!(plane.positions.len(), 15);
+        assert_eq!(plane.triangles.len(), 2*2*4);
+        assert!(plane.normals().iter().all(|n| (*n - V3::new(0.0, 0.0, 1.0)).norm() < 1e-6));
+        assert_eq!(plane.positions[14].to_array(), [1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn displace_and_push() {
+        let mut sphere = Surface::uv_sphere(16, 9).scale(2.0).translate(V3::new(1.0, 0.0, 0.0));
+        sphere.displace(|_| 0.5);
+        // the normals of the triangles are almost the directions from the center
+        for p in &sphere.positions {
+            assert!(((*p - V3::new(1.0, 0.0, 0.0)).norm() - 2.5).abs() < 1e-2);
+        }
+
+        let mut points = vec![0.0; SIZE_VERTEX];
+        let mut indices = Vec::new();
+        sphere.push(&mut points, &mut indices, |_, normal| Attributes {
+            color: V3::new(1.0, 0.5, 0.0),
+            ondulation: normal,
+            frequency: 2.0,
+            phase: 0.25,
+        });
+        assert_eq!(points.len(), (1 + sphere.positions.len()) * SIZE_VERTEX);
+        assert_eq!(indices.len(), 3 * sphere.triangles.len());
+        // after the vertex that was already there
+        assert_eq!(indices.iter().min(), Some(&1));
+        assert_eq!(points[SIZE_VERTEX+3..SIZE_VERTEX+6], [1.0, 0.5, 0.0]);
+        assert_eq!(points[SIZE_VERTEX+9..SIZE_VERTEX+12], [2.0, 0.25, 0.0]);
+    }
+}
@@ -1,6 +1,7 @@
 use std::ops::{Add, AddAssign, Sub};
+use super::interval::Interval;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct V3 {
     pub x: f32,
     pub y: f32,
@@ -43,6 +44,11 @@ impl From<(f32, f32, f32)> for V3 {
         Self {x: t.0, y: t.1, z: t.2}
     }
 }
+impl From<[f32; 3]> for V3 {
+    fn from(t: [f32; 3]) -> V3 {
+        Self {x: t[0], y: t[1], z: t[2]}
+    }
+}
 impl From<V3> for (f32, f32, f32) {
     fn from(p: V3) -> (f32, f32, f32) {
         (p.x, p.y, p.z)
@@ -70,6 +76,11 @@ impl V3 {
 
         self.scale(r)
     }
+    /// the exact direction of the vector (`normalize` is an approximation), or the null vector
+    pub fn unit(self) -> Self {
+        let norm = self.norm();
+        if norm > 0.0 {self.scale(1.0 / norm)} else {self}
+    }
     pub fn cross(a: V3, b: V3) -> V3 {
         V3::new(
             a.y*b.z - a.z*b.y,
@@ -80,10 +91,26 @@ impl V3 {
     pub fn dot(a: V3, b: V3) -> f32 {
         a.x*b.x+a.y*b.y+a.z*b.z
     }
+    /// smallest coordinates of the 2 vectors
+    pub fn min(a: V3, b: V3) -> V3 {
+        V3::new(f32::min(a.x, b.x), f32::min(a.y, b.y), f32::min(a.z, b.z))
+    }
+    /// greatest coordinates of the 2 vectors
+    pub fn max(a: V3, b: V3) -> V3 {
+        V3::new(f32::max(a.x, b.x), f32::max(a.y, b.y), f32::max(a.z, b.z))
+    }
+    pub fn to_array(self) -> [f32; 3] {
+        [self.x, self.y, self.z]
+    }
     pub fn map(&self, f: impl Fn(f32) -> f32) -> Self{
         // consume the vector
         Self::new(f(self.x), f(self.y), f(self.z))
     }
+    /// rotation around the unit vector `axis` (Rodrigues' formula)
+    pub fn rotate(self, axis: V3, angle: f32) -> Self {
+        let (sin, cos) = angle.sin_cos();
+        self.scale(cos) + V3::cross(axis, self).scale(sin) + axis.scale(V3::dot(axis, self) * (1.0 - cos))
+    }
 }
 
 
@@ -96,20 +123,31 @@ fn fast_inverse_square_root(x: f32) -> f32 {
 }
 
 
-#[derive(Copy, Clone)]
+// 1 + 2*gamma(3), see "Robust BVH Ray Traversal" by T. Ize
+const ROBUST_FACTOR: f32 = 1.0 + 2.0*3.0*f32::EPSILON;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Range {
     pub smaller_corner: V3,
     pub greater_corner: V3,
 }
 
+/// Axis aligned bounding box.
+/// Every test includes the boundary: a point on a face is inside the range
 impl Range {
     pub fn new(smaller_corner: V3, greater_corner: V3) -> Self {
         Range {smaller_corner, greater_corner}
     }
+    /// smallest range containing all the points, `None` if there is no point
+    pub fn from_points(points: impl IntoIterator<Item=V3>) -> Option<Self> {
+        let mut points = points.into_iter();
+        let first = points.next()?;
+        Some(points.fold(Range::new(first, first), |r, p| r.expand(p)))
+    }
     pub fn contain(&self, p: V3) -> bool {
-        let x_good = self.smaller_corner.x < p.x && p.x < self.greater_corner.x;
-        let y_good = self.smaller_corner.y < p.y && p.y < self.greater_corner.y;
-        let z_good = self.smaller_corner.z < p.z && p.z < self.greater_corner.z;
+        let x_good = self.smaller_corner.x <= p.x && p.x <= self.greater_corner.x;
+        let y_good = self.smaller_corner.y <= p.y && p.y <= self.greater_corner.y;
+        let z_good = self.smaller_corner.z <= p.z && p.z <= self.greater_corner.z;
 
         x_good && y_good && z_good
     }
@@ -125,11 +163,11 @@ impl Range {
         let s_z = f32::max(a_s.z, b_s.z);
 
         // new greater corner
-        let b_x = f32::max(a_g.x, b_g.x);
-        let b_y = f32::max(a_g.y, b_g.y);
-        let b_z = f32::max(a_g.z, b_g.z);
+        let b_x = f32::min(a_g.x, b_g.x);
+        let b_y = f32::min(a_g.y, b_g.y);
+        let b_z = f32::min(a_g.z, b_g.z);
 
-        if s_x < b_x && s_y < b_y && s_z < b_z {
+        if s_x <= b_x && s_y <= b_y && s_z <= b_z {
             Some(
                 Self::new(
                     V3::new(s_x, s_y, s_z),
@@ -141,9 +179,132 @@ impl Range {
             None
         }
     }
+    pub fn intersects(&self, other: &Self) -> bool {
+        self.intersection(other).is_some()
+    }
+    /// smallest range containing the 2 ranges
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(
+            V3::min(self.smaller_corner, other.smaller_corner),
+            V3::max(self.greater_corner, other.greater_corner),
+        )
+    }
+    /// smallest range containing this range and the point
+    pub fn expand(&self, p: V3) -> Self {
+        Self::new(
+            V3::min(self.smaller_corner, p),
+            V3::max(self.greater_corner, p),
+        )
+    }
+    /// add a margin in every direction
+    pub fn inflate(&self, margin: f32) -> Self {
+        let m = V3::new(margin, margin, margin);
+        Self::new(self.smaller_corner - m, self.greater_corner + m)
+    }
     pub fn diagonal(&self) -> V3 {
         self.greater_corner - self.smaller_corner
     }
+    /// area of the 6 faces of the box
+    pub fn surface_area(&self) -> f32 {
+        let d = self.diagonal();
+        2.0*(d.x*d.y + d.y*d.z + d.z*d.x)
+    }
+    pub fn center(&self) -> V3 {
+        (self.smaller_corner + self.greater_corner).scale(0.5)
+    }
+    /// half of the diagonal: vector from the center to the greater corner
+    pub fn extent(&self) -> V3 {
+        self.diagonal().scale(0.5)
+    }
+    /// the point of the range closest to `p`
+    pub fn clamp(&self, p: V3) -> V3 {
+        V3::max(self.smaller_corner, V3::min(self.greater_corner, p))
+    }
+
+    /// Intersect the ray `origin + t*dir` with the box (slab method).
+    /// Return the interval of `t` inside the box, if it is not empty and not behind the origin.
+    /// The direction does not need to be normalized
+    pub fn ray_intersection(&self, origin: V3, dir: V3) -> Option<(f32, f32)> {
+        let o = origin.to_array();
+        let d = dir.to_array();
+        let s = self.smaller_corner.to_array();
+        let g = self.greater_corner.to_array();
+
+        let mut t_min = 0.0f32;
+        let mut t_max = f32::INFINITY;
+        for i in 0..3 {
+            if d[i] == 0.0 {
+                // parallel to the slab: the origin must be between the 2 planes
+                if o[i] < s[i] || o[i] > g[i] {
+                    return None
+                }
+            }
+            else {
+                let t1 = (s[i] - o[i]) / d[i];
+                let t2 = (g[i] - o[i]) / d[i];
+                t_min = t_min.max(f32::min(t1, t2));
+                // enlarge the exit a little, so that the rounding errors
+                // don't lose the rays touching an edge of the box
+                t_max = t_max.min(f32::max(t1, t2) * ROBUST_FACTOR);
+            }
+        }
+
+        if t_min <= t_max {Some((t_min, t_max))} else {None}
+    }
+    /// true if the segment [a, b] touches the box
+    pub fn intersects_segment(&self, a: V3, b: V3) -> bool {
+        match self.ray_intersection(a, b - a) {
+            Some((t_min, _)) => t_min <= 1.0,
+            None => false,
+        }
+    }
+
+    /// one of the 8 sub-ranges obtained by cutting the range in 2 in each direction.
+    /// `corner[i]` tells if we take the greater half in dimension i
+    pub fn octant(&self, corner: [bool; 3]) -> Self {
+        let half = self.extent();
+        let smaller = self.smaller_corner + V3::new(
+            if corner[0] {half.x} else {0.0},
+            if corner[1] {half.y} else {0.0},
+            if corner[2] {half.z} else {0.0},
+        );
+        Self::new(smaller, smaller + half)
+    }
+    /// the 8 octants, the `i`th one has `corner[d] = (i >> d) & 1`
+    pub fn octants(&self) -> [Self; 8] {
+        array_init::array_init(|i| self.octant([i & 1 != 0, i & 2 != 0, i & 4 != 0]))
+    }
+
+    /// 8 corners of the box, with the same order as the octants
+    pub fn corners(&self) -> [V3; 8] {
+        let s = self.smaller_corner;
+        let g = self.greater_corner;
+        array_init::array_init(|i| V3::new(
+            if i & 1 != 0 {g.x} else {s.x},
+            if i & 2 != 0 {g.y} else {s.y},
+            if i & 4 != 0 {g.z} else {s.z},
+        ))
+    }
+
+    /// Bounding box of the range transformed by a 4x4 matrix (column major, like webgl).
+    /// The matrix must be affine: no projection.
+    /// Each coordinate of the result is a sum of terms m[i][j]*p[j] that are minimized
+    /// or maximized independently (Arvo's method)
+    pub fn transform(&self, m: [f32; 16]) -> Self {
+        let s = self.smaller_corner.to_array();
+        let g = self.greater_corner.to_array();
+        let mut new_s = [m[12], m[13], m[14]];
+        let mut new_g = [m[12], m[13], m[14]];
+        for i in 0..3 {
+            for j in 0..3 {
+                let a = m[j*4+i]*s[j];
+                let b = m[j*4+i]*g[j];
+                new_s[i] += f32::min(a, b);
+                new_g[i] += f32::max(a, b);
+            }
+        }
+        Self::new(new_s.into(), new_g.into())
+    }
 }
 
 
@@ -151,6 +312,16 @@ impl Range {
 pub trait Dist {
     // signed distance function
     fn dist(&self, point: V3) -> f32;
+
+    /// Bounds of the function over a range.
+    /// By default the function is assumed to be a true distance, that cannot change
+    /// faster than the distance to the center of the range.
+    /// Other functions (noise, polynomials...) must give their own bounds
+    fn bounds(&self, range: &Range) -> Interval {
+        let d = self.dist(range.center());
+        let r = range.extent().norm();
+        Interval::new(d - r, d + r)
+    }
 }
 
 impl<F> Dist for F where F: Fn(V3) -> f32 {
@@ -158,3 +329,177 @@ impl<F> Dist for F where F: Fn(V3) -> f32 {
         self(point)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{V3, Range};
+    use super::super::random::Rng;
+
+    const N_SAMPLES: usize = 1000;
+    const EPSILON: f32 = 1e-4;
+
+    fn rand_point_in(rng: &mut Rng, r: &Range) -> V3 {
+        let d = r.diagonal();
+        r.smaller_corner + V3::new(d.x*rng.float(), d.y*rng.float(), d.z*rng.float())
+    }
+
+    fn rand_range(rng: &mut Rng) -> Range {
+        let a = rng.unit_v3().scale(5.0*rng.float());
+        let b = rng.unit_v3().scale(5.0*rng.float());
+        Range::new(V3::min(a, b), V3::max(a, b))
+    }
+
+    fn around(r: &Range) -> Range {
+        r.inflate(2.0)
+    }
+
+    #[test]
+    fn boundary_is_inside() {
+        let r = Range::new(V3::new(0.0, 0.0, 0.0), V3::new(1.0, 2.0, 3.0));
+        for &c in &r.corners() {
+            assert!(r.contain(c));
+        }
+        assert!(r.contain(V3::new(0.5, 2.0, 1.0)));
+        assert!(!r.contain(V3::new(0.5, 2.1, 1.0)));
+    }
+
+    #[test]
+    fn intersection_and_union() {
+        let mut rng = Rng::new(1);
+        for _ in 0..N_SAMPLES {
+            let (a, b) = (rand_range(&mut rng), rand_range(&mut rng));
+            let union = a.union(&b);
+            let inter = a.intersection(&b);
+            let p = rand_point_in(&mut rng, &around(&union));
+
+            assert_eq!(
+                a.contain(p) && b.contain(p),
+                inter.is_some_and(|i| i.contain(p))
+            );
+            assert_eq!(inter.is_some(), b.intersection(&a).is_some());
+            if a.contain(p) || b.contain(p) {
+                assert!(union.contain(p));
+            }
+        }
+    }
+
+    #[test]
+    fn from_points_and_expand() {
+        let mut rng = Rng::new(2);
+        let points: Vec<V3> = (0..50).map(|_| rng.unit_v3().scale(3.0)).collect();
+        let r = Range::from_points(points.iter().cloned()).unwrap();
+        assert!(points.iter().all(|&p| r.contain(p)));
+        assert!(Range::from_points(Vec::new()).is_none());
+
+        let p = V3::new(10.0, -10.0, 0.0);
+        assert!(r.expand(p).contain(p));
+    }
+
+    #[test]
+    fn center_and_extent() {
+        let mut rng = Rng::new(3);
+        for _ in 0..N_SAMPLES {
+            let r = rand_range(&mut rng);
+            let c = r.center();
+            assert!(r.contain(c));
+            assert!((c + r.extent() - r.greater_corner).norm() < EPSILON);
+            assert!((c - r.extent() - r.smaller_corner).norm() < EPSILON);
+        }
+    }
+
+    #[test]
+    fn ray_intersection() {
+        let mut rng = Rng::new(4);
+        for _ in 0..N_SAMPLES {
+            let r = rand_range(&mut rng);
+            let origin = rand_point_in(&mut rng, &around(&r));
+            let target = rand_point_in(&mut rng, &r);
+            let dir = target - origin;
+
+            // a ray going through a point of the box hits it
+            let (t_min, t_max) = r.ray_intersection(origin, dir).unwrap();
+            assert!(t_min <= 1.0 && 1.0 <= t_max + EPSILON);
+            assert!(r.inflate(EPSILON).contain(origin + dir.scale(t_min)));
+            assert!(r.inflate(EPSILON).contain(origin + dir.scale(t_max)));
+
+            // in the opposite direction, it hits only if the origin is inside
+            assert_eq!(
+                r.ray_intersection(origin, dir.scale(-1.0)).is_some(),
+                r.contain(origin)
+            );
+
+            // the segment hits if one of the points is inside
+            let other = rand_point_in(&mut rng, &around(&r));
+            if r.contain(origin) || r.contain(other) {
+                assert!(r.intersects_segment(origin, other));
+            }
+            assert!(r.intersects_segment(origin, target));
+        }
+    }
+
+    #[test]
+    fn rays_through_edges() {
+        // the ray enters and leaves the box at the same point,
+        // so without ROBUST_FACTOR the rounding errors lose about 1 ray in 10
+        let mut rng = Rng::new(7);
+        for _ in 0..N_SAMPLES {
+            let r = rand_range(&mut rng);
+            let origin = rand_point_in(&mut rng, &r.inflate(20.0));
+            // a point on an edge: 2 coordinates on the faces of the box
+            let (s, g) = (r.smaller_corner.to_array(), r.greater_corner.to_array());
+            let mut p = [0.0; 3];
+            for (k, x) in p.iter_mut().enumerate() {
+                *x = if rng.below(2) == 0 {s[k]} else {g[k]};
+            }
+            let k = rng.below(3);
+            p[k] = s[k] + (g[k] - s[k])*rng.float();
+            let dir = (V3::from(p) - origin).normalize();
+            assert!(r.ray_intersection(origin, dir).is_some(), "{:?} misses {:?}", p, r);
+        }
+    }
+
+    #[test]
+    fn octants() {
+        let mut rng = Rng::new(5);
+        for _ in 0..N_SAMPLES {
+            let r = rand_range(&mut rng);
+            let octants = r.octants();
+            let p = rand_point_in(&mut rng, &r);
+
+            // every point of the range is in an octant
+            assert!(octants.iter().any(|o| o.contain(p)));
+            for o in &octants {
+                assert!((o.diagonal().scale(2.0) - r.diagonal()).norm() < EPSILON);
+                assert!(r.inflate(EPSILON).contain(o.smaller_corner));
+                assert!(r.inflate(EPSILON).contain(o.greater_corner));
+            }
+        }
+    }
+
+    #[test]
+    fn transform() {
+        let mut rng = Rng::new(6);
+        let transform_point = |m: &[f32; 16], p: V3| V3::new(
+            m[0]*p.x + m[4]*p.y + m[8]*p.z + m[12],
+            m[1]*p.x + m[5]*p.y + m[9]*p.z + m[13],
+            m[2]*p.x + m[6]*p.y + m[10]*p.z + m[14],
+        );
+
+        for _ in 0..N_SAMPLES/10 {
+            let mut m = [0.0; 16];
+            for x in m.iter_mut() {
+                *x = rng.float()*4.0 - 2.0;
+            }
+            m[3] = 0.0; m[7] = 0.0; m[11] = 0.0; m[15] = 1.0;
+
+            let r = rand_range(&mut rng);
+            let t = r.transform(m).inflate(EPSILON);
+            for &c in &r.corners() {
+                assert!(t.contain(transform_point(&m, c)));
+            }
+            for _ in 0..10 {
+                assert!(t.contain(transform_point(&m, rand_point_in(&mut rng, &r))));
+            }
+        }
+    }
+}
@@ -0,0 +1,395 @@
+//! Trees and plants made of tubes, from the branches of an L-system drawn by a turtle,
+//! or grown towards a cloud of points by space colonization (Runions et al., 2007).
+//!
+//! The branches sway in the wind with the ondulation of the shader:
+//! the further a vertex is from the root along the branches, the more it moves
+
+use std::collections::{HashMap, HashSet};
+
+use super::V3;
+use super::random::Rng;
+use super::surface::{Surface, Attributes};
+
+// the phase of the ondulation changes along the branches, so the sway goes up the tree
+const PHASE_PER_UNIT: f32 = 0.4;
+
+/// A piece of branch, drawn as a tube from `start` to `end`
+#[derive(Copy, Clone, Debug)]
+pub struct Segment {
+    pub start: V3,
+    pub end: V3,
+    pub start_radius: f32,
+    pub end_radius: f32,
+    /// length of the branches between the root and `start`
+    pub distance: f32,
+}
+
+/// Rewriting system: at each iteration, each symbol with a rule is replaced
+#[derive(Clone, Debug)]
+pub struct LSystem {
+    pub axiom: String,
+    pub rules: HashMap<char, String>,
+}
+
+impl LSystem {
+    pub fn new(axiom: &str) -> Self {
+        LSystem {axiom: axiom.to_string(), rules: HashMap::new()}
+    }
+
+    pub fn rule(mut self, symbol: char, replacement: &str) -> Self {
+        self.rules.insert(symbol, replacement.to_string());
+        self
+    }
+
+    pub fn expand(&self, iterations: usize) -> String {
+        let mut word = self.axiom.clone();
+        for _ in 0..iterations {
+            word = word.chars()
+                .map(|c| self.rules.get(&c).cloned().unwrap_or_else(|| c.to_string()))
+                .collect();
+        }
+        word
+    }
+}
+
+/// Draws the words of an L-system, with the symbols of "The Algorithmic Beauty of Plants":
+/// - `F`: draw a segment forward, `f`: move forward without drawing
+/// - `+` and `-`: turn left and right, `&` and `^`: pitch down and up,
+///   `\` and `/`: roll left and right, `|`: turn around
+/// - `[` and `]`: start and end a branch
+/// - `!`: make the next segments thinner
+///
+/// The other symbols are ignored
+#[derive(Copy, Clone, Debug)]
+pub struct Turtle {
+    /// length of a segment
+    pub step: f32,
+    /// angle of the rotations, in radians
+    pub angle: f32,
+    /// radius of the first segment
+    pub radius: f32,
+    /// the radius is multiplied by this at each `!`
+    pub thinning: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct TurtleState {
+    position: V3,
+    heading: V3,
+    left: V3,
+    radius: f32,
+    distance: f32,
+}
+
+impl Turtle {
+    /// the segments drawn by the turtle, that starts at `origin` going up
+    pub fn draw(&self, word: &str, origin: V3) -> Vec<Segment> {
+        let mut state = TurtleState {
+            position: origin,
+            heading: V3::new(0.0, 0.0, 1.0),
+            left: V3::new(0.0, 1.0, 0.0),
+            radius: self.radius,
+            distance: 0.0,
+        };
+        let mut stack = Vec::new();
+        let mut segments = Vec::new();
+        for c in word.chars() {
+            let up = V3::cross(state.heading, state.left);
+            match c {
+                'F' | 'f' => {
+                    let end = state.position + state.heading.scale(self.step);
+                    if c == 'F' {
+                        segments.push(Segment {
+                            start: state.position,
+                            end,
+                            start_radius: state.radius,
+                            end_radius: state.radius,
+                            distance: state.distance,
+                        });
+                    }
+                    state.position = end;
+                    state.distance += self.step;
+                }
+                '+' | '-' => {
+                    let angle = if c == '+' {self.angle} else {-self.angle};
+                    state.heading = state.heading.rotate(up, angle);
+                    state.left = state.left.rotate(up, angle);
+                }
+                '&' | '^' => {
+                    let angle = if c == '&' {self.angle} else {-self.angle};
+                    state.heading = state.heading.rotate(state.left, angle);
+                }
+                '\\' | '/' => {
+                    let angle = if c == '\\' {self.angle} else {-self.angle};
+                    state.left = state.left.rotate(state.heading, angle);
+                }
+                '|' => {
+                    state.heading = state.heading.rotate(up, std::f32::consts::PI);
+                    state.left = state.left.rotate(up, std::f32::consts::PI);
+                }
+                '[' => stack.push(state),
+                ']' => if let Some(previous) = stack.pop() {
+                    state = previous;
+                }
+                '!' => state.radius *= self.thinning,
+                _ => (),
+            }
+        }
+        segments
+    }
+}
+
+/// Growth of branches towards attraction points: each point pulls the closest node
+/// within `influence`, the nodes pulled grow by `step` towards their points,
+/// and the points closer than `kill` to a node are removed
+#[derive(Copy, Clone, Debug)]
+pub struct Colonization {
+    pub influence: f32,
+    pub kill: f32,
+    pub step: f32,
+    pub max_iterations: usize,
+    /// radius of the ends of the branches. The radius of a branch
+    /// is given by the radii of its children: r² = r1² + r2² + ...
+    pub tip_radius: f32,
+}
+
+impl Colonization {
+    /// grow from a root, going up until the first attraction points are reached
+    pub fn grow(&self, root: V3, mut attractors: Vec<V3>) -> Vec<Segment> {
+        let mut positions = vec![root];
+        let mut parents: Vec<Option<usize>> = vec![None];
+        // the first points are not reached yet
+        let mut trunk = true;
+
+        for _ in 0..self.max_iterations {
+            if attractors.is_empty() {
+                break
+            }
+            // sum of the directions towards the points that pull each node
+            let mut pulls: HashMap<usize, V3> = HashMap::new();
+            for &a in &attractors {
+                let closest = (0..positions.len())
+                    .map(|i| (i, (a - positions[i]).norm()))
+                    .min_by(|x, y| x.1.total_cmp(&y.1));
+                if let Some((i, d)) = closest.filter(|&(_, d)| d <= self.influence && d > 0.0) {
+                    *pulls.entry(i).or_insert_with(V3::null) += (a - positions[i]).scale(1.0 / d);
+                }
+            }
+
+            let n_nodes = positions.len();
+            if pulls.is_empty() {
+                // the other points are too far from the branches
+                if !trunk {
+                    break
+                }
+                // the trunk goes up until it reaches the crown
+                positions.push(positions[n_nodes - 1] + V3::new(0.0, 0.0, self.step));
+                parents.push(Some(n_nodes - 1));
+                continue
+            }
+            trunk = false;
+            // in the order of the nodes, so the tree is the same for the same points
+            let mut pulled: Vec<(usize, V3)> = pulls.into_iter().collect();
+            pulled.sort_by_key(|&(i, _)| i);
+            for (i, pull) in pulled {
+                // pulled by points on opposite sides
+                if pull.norm() < 1e-6 {
+                    continue
+                }
+                positions.push(positions[i] + pull.unit().scale(self.step));
+                parents.push(Some(i));
+            }
+            if positions.len() == n_nodes {
+                break
+            }
+            let new_nodes = &positions[n_nodes..];
+            attractors.retain(|&a| new_nodes.iter().all(|&p| (a - p).norm() > self.kill));
+        }
+
+        // the children are after their parents
+        let mut squared_radii = vec![0.0; positions.len()];
+        let mut has_children = vec![false; positions.len()];
+        for i in (0..positions.len()).rev() {
+            if !has_children[i] {
+                squared_radii[i] = self.tip_radius * self.tip_radius;
+            }
+            if let Some(parent) = parents[i] {
+                squared_radii[parent] += squared_radii[i];
+                has_children[parent] = true;
+            }
+        }
+        let mut distances = vec![0.0; positions.len()];
+        let mut segments = Vec::with_capacity(positions.len());
+        for i in 1..positions.len() {
+            let parent = parents[i].unwrap();
+            distances[i] = distances[parent] + (positions[i] - positions[parent]).norm();
+            segments.push(Segment {
+                start: positions[parent],
+                end: positions[i],
+                start_radius: squared_radii[parent].sqrt(),
+                end_radius: squared_radii[i].sqrt(),
+                distance: distances[parent],
+            });
+        }
+        segments
+    }
+}
+
+/// `n` random points inside an ellipsoid, for example the crown of a tree
+pub fn ellipsoid_points(rng: &mut Rng, center: V3, radii: V3, n: usize) -> Vec<V3> {
+    (0..n)
+        .map(|_| {
+            // the cube root spreads the points evenly in the volume
+            let p = rng.unit_v3().scale(rng.float().cbrt());
+            center + V3::new(p.x * radii.x, p.y * radii.y, p.z * radii.z)
+        })
+        .collect()
+}
+
+/// How the branches move with the ondulation of the shader
+#[derive(Copy, Clone, Debug)]
+pub struct Sway {
+    /// direction of the wind
+    pub direction: V3,
+    /// length of the ondulation vector, by unit of distance from the root
+    pub amplitude: f32,
+    pub frequency: f32,
+    pub phase: f32,
+}
+
+/// Add the segments to the buffers as tubes with `sides` sides.
+/// The ends of the branches are closed by a point
+pub fn tubes(points: &mut Vec<f32>, indices: &mut Vec<u16>, segments: &[Segment], sides: usize, color: V3, sway: Sway) {
+    let starts: HashSet<[u32; 3]> = segments.iter().map(|s| s.start.to_array().map(f32::to_bits)).collect();
+    for s in segments {
+        let direction = (s.end - s.start).unit();
+        // 2 directions perpendicular to the branch
+        let other = if direction.z.abs() < 0.9 {V3::new(0.0, 0.0, 1.0)} else {V3::new(1.0, 0.0, 0.0)};
+        let e1 = V3::cross(direction, other).unit();
+        let e2 = V3::cross(direction, e1);
+        let ring = |center: V3, radius: f32| (0..sides)
+            .map(|i| {
+                let a = i as f32 / sides as f32 * std::f32::consts::TAU;
+                center + (e1.scale(a.cos()) + e2.scale(a.sin())).scale(radius)
+            })
+            .collect();
+
+        let mut rows = vec![ring(s.start, s.start_radius), ring(s.end, s.end_radius)];
+        let tip = !starts.contains(&s.end.to_array().map(f32::to_bits));
+        if tip {
+            rows.push(vec![s.end + direction.scale(s.end_radius); sides]);
+        }
+        let length = (s.end - s.start).norm();
+        Surface::from_rows(rows, true, false).push(points, indices, |p, _| {
+            // the distance from the root of the point of the branch at the same height
+            let along = V3::dot(p - s.start, direction).clamp(0.0, length);
+            let distance = s.distance + along;
+            Attributes {
+                color,
+                ondulation: sway.direction.scale(sway.amplitude * distance),
+                frequency: sway.frequency,
+                phase: sway.phase + distance * PHASE_PER_UNIT,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LSystem, Turtle, Colonization, Sway, Segment, tubes, ellipsoid_points};
+    use super::super::{V3, SIZE_VERTEX, get_point, test_trees};
+    use super::super::random::Rng;
+
+    fn close(a: V3, b: V3) -> bool {
+        (a - b).norm() < 1e-4
+    }
+
+    #[test]
+    fn l_system() {
+        let algae = LSystem::new("A").rule('A', "AB").rule('B', "A");
+        assert_eq!(algae.expand(0), "A");
+        assert_eq!(algae.expand(4), "ABAABABA");
+
+        let turtle = Turtle {step: 1.0, angle: std::f32::consts::FRAC_PI_2, radius: 0.1, thinning: 0.5};
+        // up, a branch to the side, then up again
+        let segments = turtle.draw("F[+!F]fF", V3::null());
+        assert_eq!(segments.len(), 3);
+        assert!(close(segments[0].end, V3::new(0.0, 0.0, 1.0)));
+        assert!(close(segments[1].start, segments[0].end));
+        assert!((segments[1].end - segments[1].start).z.abs() < 1e-4);
+        assert_eq!(segments[1].start_radius, 0.05);
+        // after the branch, back to the previous state
+        assert!(close(segments[2].start, V3::new(0.0, 0.0, 2.0)));
+        assert_eq!(segments[2].start_radius, 0.1);
+        assert_eq!(segments[2].distance, 2.0);
+
+        // turning around, and rolling then pitching
+        let segments = turtle.draw("F|F", V3::null());
+        assert!(close(segments[1].end, V3::null()));
+        let segments = turtle.draw("\\&F", V3::null());
+        assert!(close(segments[0].end, V3::new(0.0, 1.0, 0.0)));
+    }
+
+    #[test]
+    fn colonization() {
+        let colonization = Colonization {influence: 2.0, kill: 0.3, step: 0.2, max_iterations: 200, tip_radius: 0.02};
+        let crown = ellipsoid_points(&mut Rng::new(1), V3::new(0.0, 0.0, 3.0), V3::new(1.0, 1.0, 0.8), 200);
+        let segments = colonization.grow(V3::null(), crown.clone());
+        assert!(segments.len() > 20);
+        // all the segments are connected to the root
+        assert!(close(segments[0].start, V3::null()));
+        for s in &segments[1..] {
+            assert!(segments.iter().any(|parent| close(parent.end, s.start)) || close(s.start, V3::null()));
+            assert!(((s.end - s.start).norm() - 0.2).abs() < 1e-4);
+        }
+        // most points were reached
+        let reached = crown.iter()
+            .filter(|&&a| segments.iter().any(|s| (s.end - a).norm() <= 0.3 + 1e-4))
+            .count();
+        assert!(reached > 150, "{}", reached);
+        // the trunk is the thickest, the branches get thinner
+        let trunk = segments[0].start_radius;
+        assert!(segments.iter().all(|s| s.start_radius <= trunk + 1e-6 && s.end_radius <= s.start_radius + 1e-6));
+        assert!(trunk > 2.0 * 0.02);
+    }
+
+    #[test]
+    fn tube_mesh() {
+        let segments = [
+            Segment {start: V3::null(), end: V3::new(0.0, 0.0, 1.0), start_radius: 0.2, end_radius: 0.1, distance: 0.0},
+            Segment {start: V3::new(0.0, 0.0, 1.0), end: V3::new(0.0, 1.0, 2.0), start_radius: 0.1, end_radius: 0.05, distance: 1.0},
+        ];
+        let sway = Sway {direction: V3::new(1.0, 0.0, 0.0), amplitude: 0.1, frequency: 1.5, phase: 0.0};
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        tubes(&mut points, &mut indices, &segments, 6, V3::new(0.4, 0.3, 0.2), sway);
+
+        // 2 rings, and 2 rings with a tip
+        assert_eq!(points.len(), (12 + 13)*SIZE_VERTEX);
+        assert_eq!(indices.len(), 3*(12 + 12 + 6));
+        // the triangles face outside of the branches
+        for t in indices.chunks(3) {
+            let [a, b, c] = [t[0], t[1], t[2]].map(|i| get_point(&points, i));
+            let (segment, center) = if t[0] < 12 {(&segments[0], V3::new(0.0, 0.0, 0.5))} else {(&segments[1], V3::new(0.0, 0.5, 1.5))};
+            let axis = segment.end - segment.start;
+            let middle = (a + b + c).scale(1.0 / 3.0) - center;
+            let outside = middle - axis.scale(V3::dot(middle, axis) / V3::dot(axis, axis));
+            assert!(V3::dot(V3::cross(b - a, c - a), outside) > 0.0);
+        }
+        // the root does not move, the tip moves the most
+        let ondulation = |i: usize| points[i*SIZE_VERTEX+6];
+        assert_eq!(ondulation(0), 0.0);
+        assert!((ondulation(24) - 0.1 * (1.0 + 2f32.sqrt())).abs() < 1e-3);
+        assert!(points.chunks(SIZE_VERTEX).all(|v| v[9] == 1.5));
+    }
+
+    #[test]
+    fn scene() {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        test_trees(&mut points, &mut indices);
+        let n_vertices = points.len() / SIZE_VERTEX;
+        assert!(n_vertices <= u16::MAX as usize + 1);
+        assert!(indices.iter().all(|&i| (i as usize) < n_vertices));
+    }
+}
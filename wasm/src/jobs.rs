@@ -0,0 +1,461 @@
+use std::collections::VecDeque;
+
+use crate::geometry;
+use crate::geometry::TriangleBuilder;
+use crate::geometry::bvh::{Bvh, BvhBuilder};
+use crate::geometry::bounds::{self, Chunk};
+use crate::geometry::octree::{Octree, CellRange, Triangulation, RegionMesh, BLOCK_DEPTH};
+use crate::geometry::octree::build::OctreeBuilder;
+
+/// A long computation cut into small steps,
+/// so that it can be spread over several frames
+pub trait Job {
+    type Output;
+    /// do a small part of the work, and return the result when it is finished
+    fn step(&mut self) -> Option<Self::Output>;
+}
+
+/// time in milliseconds, only used to measure durations
+#[cfg(target_arch = "wasm32")]
+fn now() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64()*1000.0)
+        .unwrap_or(0.0)
+}
+
+/// Queue of jobs, run one after the other
+pub struct Scheduler<J: Job> {
+    queue: VecDeque<J>,
+}
+
+impl<J: Job> Scheduler<J> {
+    pub fn new() -> Self {
+        Scheduler {queue: VecDeque::new()}
+    }
+
+    pub fn push(&mut self, job: J) {
+        self.queue.push_back(job);
+    }
+
+    /// true when all the jobs are finished
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Run steps until `budget` milliseconds are spent, and return the finished jobs.
+    /// A step cannot be interrupted, so at least one step is done,
+    /// and the last step can go over the budget
+    pub fn run(&mut self, budget: f64) -> Vec<J::Output> {
+        let start = now();
+        let mut finished = Vec::new();
+        while let Some(job) = self.queue.front_mut() {
+            if let Some(result) = job.step() {
+                finished.push(result);
+                self.queue.pop_front();
+            }
+            if now() - start >= budget {
+                break
+            }
+        }
+        finished
+    }
+}
+
+/// a part of the world
+#[derive(Copy, Clone)]
+pub enum Generator {
+    /// a function that adds all its triangles to the vertex and index buffers at once
+    Function(fn(&mut Vec<f32>, &mut Vec<u16>)),
+    /// triangles added a few at each step
+    Steps(fn() -> Box<dyn TriangleBuilder>),
+    /// an octree, built and triangulated a little at each step
+    Octree(fn() -> OctreeBuilder),
+}
+
+// work done in a step, so that a step takes about a millisecond
+const NODES_PER_STEP: usize = 4096;
+const TRIANGLES_PER_STEP: usize = 16384;
+
+/// everything the renderer needs to swap to a new world
+pub struct Mesh {
+    pub points: Vec<f32>,
+    pub indices: Vec<u16>,
+    pub chunks: Vec<Chunk>,
+    pub bvh: Bvh,
+}
+
+impl Mesh {
+    /// cut shaded triangles in chunks and build their bvh, all at once
+    pub fn new(points: Vec<f32>, indices: Vec<u16>) -> Self {
+        let chunks = bounds::split_in_chunks(&points, &indices, crate::CHUNK_SIZE);
+        let bvh = Bvh::new(&points, &indices);
+        Mesh {points, indices, chunks, bvh}
+    }
+}
+
+/// a new world
+pub struct World {
+    pub mesh: Mesh,
+    /// the octree of the last octree generator, kept to sculpt it
+    pub octree: Option<Octree>,
+}
+
+/// Build an octree a few thousand nodes at a time
+pub struct OctreeBuild(pub OctreeBuilder);
+
+impl Job for OctreeBuild {
+    type Output = Octree;
+
+    fn step(&mut self) -> Option<Octree> {
+        self.0.step(NODES_PER_STEP)
+    }
+}
+
+// an octree triangulated one block per step
+struct OctreeMeshing {
+    octree: Octree,
+    blocks: Vec<CellRange>,
+    next: usize,
+    triangulation: Triangulation,
+}
+
+enum Stage {
+    // start the `i`th generator. After the last one, shade the triangles
+    Generate(usize),
+    // add the triangles of the `i`th generator
+    Grow(usize, Box<dyn TriangleBuilder>),
+    // build the octree of the `i`th generator
+    Build(usize, Box<OctreeBuilder>),
+    // triangulate the octree of the `i`th generator
+    Triangulate(usize, Box<OctreeMeshing>),
+    // bound the `i`th chunk
+    Split(usize),
+    Index(BvhBuilder),
+    Done,
+}
+
+/// Build a new world: generate the triangles, shade them,
+/// cut them in chunks and build the hierarchy used for picking.
+/// Each step does a bounded amount of work: a function, a step of a `TriangleBuilder`,
+/// a few thousand nodes of an octree,
+/// a block of cells, a chunk or a part of the hierarchy
+pub struct WorldGeneration {
+    generators: Vec<Generator>,
+    stage: Stage,
+    points: Vec<f32>,
+    indices: Vec<u16>,
+    chunks: Vec<Chunk>,
+    octree: Option<Octree>,
+}
+
+impl WorldGeneration {
+    pub fn new(generators: Vec<Generator>) -> Self {
+        WorldGeneration {
+            generators,
+            stage: Stage::Generate(0),
+            points: Vec::new(),
+            indices: Vec::new(),
+            chunks: Vec::new(),
+            octree: None,
+        }
+    }
+
+    /// the end of the generation of triangles made elsewhere: shading, chunks and bvh
+    pub fn from_triangles(points: Vec<f32>, indices: Vec<u16>) -> Self {
+        WorldGeneration {points, indices, ..WorldGeneration::new(Vec::new())}
+    }
+
+    fn next_stage(&mut self, stage: Stage) -> Result<Stage, String> {
+        Ok(match stage {
+            Stage::Generate(i) => match self.generators.get(i) {
+                Some(Generator::Function(generate)) => {
+                    generate(&mut self.points, &mut self.indices);
+                    geometry::check_indexable(&self.points)?;
+                    Stage::Generate(i+1)
+                }
+                Some(Generator::Steps(builder)) => Stage::Grow(i, builder()),
+                Some(Generator::Octree(builder)) => Stage::Build(i, Box::new(builder())),
+                None => {
+                    geometry::shade(&mut self.points, &self.indices);
+                    Stage::Split(0)
+                }
+            }
+            Stage::Grow(i, mut builder) => {
+                let done = builder.step(&mut self.points, &mut self.indices);
+                geometry::check_indexable(&self.points)?;
+                if done {Stage::Generate(i+1)} else {Stage::Grow(i, builder)}
+            }
+            Stage::Build(i, mut builder) => match builder.step(NODES_PER_STEP) {
+                Some(octree) => Stage::Triangulate(i, Box::new(OctreeMeshing {
+                    blocks: octree.blocks(BLOCK_DEPTH),
+                    octree,
+                    next: 0,
+                    triangulation: Triangulation::default(),
+                })),
+                None => Stage::Build(i, builder),
+            }
+            Stage::Triangulate(i, mut meshing) => {
+                let m = &mut *meshing;
+                match m.blocks.get(m.next) {
+                    Some(block) => {
+                        m.triangulation.add(&m.octree, block, &mut self.points, &mut self.indices)?;
+                        m.next += 1;
+                        Stage::Triangulate(i, meshing)
+                    }
+                    None => {
+                        self.octree = Some(meshing.octree);
+                        Stage::Generate(i+1)
+                    }
+                }
+            }
+            Stage::Split(i) => match bounds::chunk(&self.points, &self.indices, crate::CHUNK_SIZE, i) {
+                Some(chunk) => {
+                    self.chunks.push(chunk);
+                    Stage::Split(i+1)
+                }
+                None => Stage::Index(BvhBuilder::new(&self.points, &self.indices)),
+            }
+            stage => stage,
+        })
+    }
+}
+
+impl Job for WorldGeneration {
+    /// the world, or why it cannot be drawn
+    type Output = Result<World, String>;
+
+    fn step(&mut self) -> Option<Result<World, String>> {
+        let stage = std::mem::replace(&mut self.stage, Stage::Done);
+        match stage {
+            Stage::Index(mut builder) => match builder.step(TRIANGLES_PER_STEP) {
+                Some(bvh) => Some(Ok(World {
+                    mesh: Mesh {
+                        points: std::mem::take(&mut self.points),
+                        indices: std::mem::take(&mut self.indices),
+                        chunks: std::mem::take(&mut self.chunks),
+                        bvh,
+                    },
+                    octree: self.octree.take(),
+                })),
+                None => {
+                    self.stage = Stage::Index(builder);
+                    None
+                }
+            }
+            Stage::Done => panic!("the world is already generated"),
+            stage => match self.next_stage(stage) {
+                Ok(stage) => {
+                    self.stage = stage;
+                    None
+                }
+                Err(e) => Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// The parts of a world triangulated by web workers, merged as they arrive
+pub struct WorldParts {
+    // the parts already merged, by index
+    received: Vec<bool>,
+    missing: usize,
+    triangulation: Triangulation,
+    points: Vec<f32>,
+    indices: Vec<u16>,
+}
+
+impl WorldParts {
+    pub fn new(n_parts: usize) -> Self {
+        WorldParts {received: vec![false; n_parts], missing: n_parts, triangulation: Triangulation::default(), points: Vec::new(), indices: Vec::new()}
+    }
+
+    /// Add the `i`th part. When all the parts are there,
+    /// return the generation of the rest of the world
+    pub fn receive(&mut self, i: usize, part: &RegionMesh) -> Result<Option<WorldGeneration>, String> {
+        match self.received.get(i) {
+            None => return Err(format!("there is no part {} of the world", i)),
+            Some(true) => return Err(format!("the part {} of the world is already there", i)),
+            Some(false) => (),
+        }
+        self.triangulation.merge(part, &mut self.points, &mut self.indices)?;
+        self.received[i] = true;
+        self.missing -= 1;
+        if self.missing > 0 {
+            return Ok(None)
+        }
+        let points = std::mem::take(&mut self.points);
+        let indices = std::mem::take(&mut self.indices);
+        Ok(Some(WorldGeneration::from_triangles(points, indices)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Job, Scheduler, WorldGeneration, WorldParts, Generator, Mesh, TriangleBuilder};
+    use crate::geometry::{self, V3, Range};
+    use crate::geometry::octree::build::OctreeBuilder;
+    use crate::geometry::sdf::Sdf;
+
+    // count to n, one number per step
+    struct Count {
+        current: u32,
+        n: u32,
+    }
+
+    impl Job for Count {
+        type Output = u32;
+        fn step(&mut self) -> Option<u32> {
+            self.current += 1;
+            if self.current == self.n {Some(self.n)} else {None}
+        }
+    }
+
+    #[test]
+    fn one_step_per_frame_without_budget() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(Count {current: 0, n: 3});
+        scheduler.push(Count {current: 0, n: 1});
+
+        assert!(scheduler.run(0.0).is_empty());
+        assert!(scheduler.run(0.0).is_empty());
+        assert_eq!(scheduler.run(0.0), vec![3]);
+        assert_eq!(scheduler.run(0.0), vec![1]);
+        assert!(scheduler.is_empty());
+        assert!(scheduler.run(0.0).is_empty());
+    }
+
+    #[test]
+    fn big_budget_finishes_everything() {
+        let mut scheduler = Scheduler::new();
+        for n in 1..5 {
+            scheduler.push(Count {current: 0, n});
+        }
+        assert_eq!(scheduler.run(1e9), vec![1, 2, 3, 4]);
+    }
+
+    fn square(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+        for &(x, y) in &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+            points.extend_from_slice(&[x, y, 0.0,  1.0, 1.0, 1.0,  0.0, 0.0, 0.0,  0.0, 0.0, 0.0]);
+        }
+        indices.extend_from_slice(&[0, 1, 2, 1, 3, 2]);
+    }
+
+    // run a generation with one step per frame
+    fn generate(generators: Vec<Generator>) -> (Mesh, usize) {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(WorldGeneration::new(generators));
+
+        let mut frames = 0;
+        loop {
+            frames += 1;
+            if let Some(world) = scheduler.run(0.0).pop() {
+                return (world.unwrap().mesh, frames)
+            }
+        }
+    }
+
+    #[test]
+    fn world_generation() {
+        let (mesh, frames) = generate(vec![Generator::Function(square), Generator::Function(square)]);
+        // 2 generators, then shading, a chunk, the end of the chunks and the bvh
+        assert_eq!(frames, 6);
+        assert_eq!(mesh.indices.len(), 12);
+        assert_eq!(mesh.chunks.len(), 1);
+        assert_eq!(mesh.bvh.len(), 4);
+    }
+
+    // a square at each step
+    struct Squares(usize);
+
+    impl TriangleBuilder for Squares {
+        fn step(&mut self, points: &mut Vec<f32>, indices: &mut Vec<u16>) -> bool {
+            let first = (points.len()/12) as u16;
+            let (mut p, mut i) = (Vec::new(), Vec::new());
+            square(&mut p, &mut i);
+            points.extend(p);
+            indices.extend(i.iter().map(|&i| first + i));
+            self.0 -= 1;
+            self.0 == 0
+        }
+    }
+
+    fn three_squares() -> Box<dyn TriangleBuilder> {
+        Box::new(Squares(3))
+    }
+
+    #[test]
+    fn generator_in_steps() {
+        let (mesh, frames) = generate(vec![Generator::Steps(three_squares), Generator::Function(square)]);
+        // the start of the builder and its 3 steps, the function,
+        // then shading, a chunk, the end of the chunks and the bvh
+        assert_eq!(frames, 9);
+        assert_eq!(mesh.indices.len(), 24);
+        assert_eq!(mesh.indices[6..12], [4, 5, 6, 5, 7, 6]);
+    }
+
+    fn sphere() -> OctreeBuilder {
+        let range = Range::new(V3::new(-1.0, -1.0, -1.0), V3::new(1.0, 1.0, 1.0));
+        OctreeBuilder::new(Sdf::Sphere {radius: 0.8}.compile(), range, 6)
+    }
+
+    #[test]
+    fn octree_in_small_steps() {
+        let (mesh, frames) = generate(vec![Generator::Octree(sphere), Generator::Function(square)]);
+
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        sphere().build().triangulate(&mut points, &mut indices).unwrap();
+        square(&mut points, &mut indices);
+        geometry::shade(&mut points, &indices);
+        assert_eq!(mesh.points, points);
+        assert_eq!(mesh.indices, indices);
+        assert_eq!(mesh.chunks.len(), (indices.len()/3).div_ceil(crate::CHUNK_SIZE));
+
+        // the nodes, the 64 blocks, the chunks and the bvh are spread over the frames
+        assert!(frames > 64 + mesh.chunks.len());
+    }
+
+    #[test]
+    fn world_from_parts() {
+        let (mesh, _) = generate(vec![Generator::Octree(sphere)]);
+
+        let mut parts = WorldParts::new(8);
+        let mut generation = None;
+        // in any order
+        for i in [3, 0, 7, 1, 2, 6, 4, 5] {
+            assert!(generation.is_none());
+            let octant = sphere().whole().octant(i);
+            let part = sphere().with_focus(octant.expand(1)).build().mesh_region(&octant).unwrap();
+            generation = parts.receive(i, &part).unwrap();
+            // a part is only merged once
+            assert!(parts.receive(i, &part).is_err());
+        }
+        let mut scheduler = Scheduler::new();
+        scheduler.push(generation.unwrap());
+        let from_parts = scheduler.run(1e9).pop().unwrap().unwrap().mesh;
+
+        assert_eq!(from_parts.points.len(), mesh.points.len());
+        assert_eq!(from_parts.indices.len(), mesh.indices.len());
+        assert_eq!(from_parts.bvh.len(), mesh.bvh.len());
+    }
+
+    fn too_many_vertices(points: &mut Vec<f32>, indices: &mut Vec<u16>) {
+        points.resize(12*70000, 0.0);
+        indices.extend_from_slice(&[0, 1, 2]);
+    }
+
+    #[test]
+    fn generation_error() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(WorldGeneration::new(vec![Generator::Function(too_many_vertices)]));
+        assert!(scheduler.run(1e9).pop().unwrap().is_err());
+        assert!(scheduler.is_empty());
+    }
+}
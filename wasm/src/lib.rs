@@ -1,24 +1,140 @@
 use wasm_bindgen::prelude::*;
 
-use  web_sys::console;
+// print in the console of the browser. The native tests print nothing
+#[cfg(target_arch = "wasm32")]
+macro_rules! log {
+    ($($msg: tt)*) => {
+        web_sys::console::log_1(&format!($($msg)*).into())
+    }
+}
 
+#[cfg(not(target_arch = "wasm32"))]
 macro_rules! log {
     ($($msg: tt)*) => {
-        unsafe {
-            console::log_1(&format!($($msg)*).into())
-        }
+        { let _ = format_args!($($msg)*); }
     }
 }
 use web_sys::WebGlRenderingContext as GL;
-use web_sys::WebGlUniformLocation;
+use web_sys::WebGlProgram;
 
 mod webgl;
-use webgl::Engine;
+use webgl::{Engine, CullingStats};
+
+mod raymarch;
+use raymarch::RayMarcher;
 
 mod camera;
 use camera::Camera;
 
-mod geometry;
+pub mod geometry;
+use geometry::V3;
+use geometry::ray::Hit;
+use geometry::export;
+use geometry::octree::{Octree, RegionMesh};
+use geometry::octree::sculpt::{Sculpture, Edit, Operation};
+
+mod jobs;
+use jobs::{Scheduler, WorldGeneration, WorldParts, Generator, Mesh, OctreeBuild};
+
+/// maximum number of triangles drawn in a single call.
+/// Each chunk is culled independently
+const CHUNK_SIZE: usize = 2048;
+
+/// time given to the generation of the world in each frame, in milliseconds
+const GENERATION_BUDGET: f64 = 8.0;
+
+/// radius of the sphere added or removed by `Universe::sculpt`
+const BRUSH_RADIUS: f32 = 0.3;
+/// the sculpted octree is triangulated and sent to the gpu by blocks of 2^4 cells on each side
+const SCULPT_BLOCK_DEPTH: u8 = 4;
+
+/// the world generated by default, by the workers when there are some
+const OCTREE_SCENE: &str = "octree";
+
+/// the generators of the worlds of `Universe::set_scene`
+fn scene_generators(name: &str) -> Option<Vec<Generator>> {
+    Some(match name {
+        OCTREE_SCENE => vec![Generator::Octree(geometry::test_octree_builder)],
+        "forest" => vec![Generator::Steps(geometry::test_forest_builder)],
+        "trees" => vec![Generator::Steps(geometry::test_trees_builder)],
+        "spheres" => vec![Generator::Steps(geometry::test_sphere_builder)],
+        "terrain" => vec![Generator::Function(geometry::rand_surface)],
+        _ => return None,
+    })
+}
+
+/// the imported models are the first part of the triangles, the world starts after them
+const MODELS_PART: usize = 0;
+const WORLD_PART: usize = 1;
+
+/// the kind of object hit by `Universe::pick`
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PickedObject {
+    /// an imported model
+    Model,
+    /// the generated world, before it is sculpted
+    World,
+    /// a block of the sculpted world
+    SculptureBlock,
+}
+
+/// Result of `Universe::pick`: where the ray from the mouse hits the scene
+#[wasm_bindgen]
+#[derive(Copy, Clone, Debug)]
+pub struct Pick {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub normal_x: f32,
+    pub normal_y: f32,
+    pub normal_z: f32,
+    pub object: PickedObject,
+    /// The number of the object among the ones of its kind, which does not change
+    /// when the others are sent again: the models in the order of their import,
+    /// the blocks of the sculpture in the order of `Sculpture::block`, 0 for the world
+    pub id: u32,
+}
+
+/// number of parts given to `generate_world_part`
+#[wasm_bindgen]
+pub fn world_parts() -> usize {
+    geometry::WORLD_PARTS
+}
+
+/// Triangles of a part of the world, for `Universe::receive_world_part`
+#[wasm_bindgen]
+pub struct WorldPart {
+    mesh: RegionMesh,
+}
+
+#[wasm_bindgen]
+impl WorldPart {
+    pub fn points(&self) -> Vec<f32> {
+        self.mesh.points.clone()
+    }
+
+    pub fn indices(&self) -> Vec<u16> {
+        self.mesh.indices.clone()
+    }
+
+    /// the point of the grid of the octree of each vertex, 3 numbers per vertex
+    pub fn grid_points(&self) -> Vec<i32> {
+        self.mesh.grid_points.iter().flatten().copied().collect()
+    }
+}
+
+/// Build and triangulate a part of the octree of the world.
+/// It is called in web workers, so that the page does not freeze
+#[wasm_bindgen]
+pub fn generate_world_part(part: usize) -> Result<WorldPart, JsValue> {
+    if part >= geometry::WORLD_PARTS {
+        return Err(JsValue::from_str(&format!("there is no part {} of the world", part)))
+    }
+    geometry::world_part(part)
+        .map(|mesh| WorldPart {mesh})
+        .map_err(|e| JsValue::from_str(&e))
+}
 
 #[wasm_bindgen]
 pub struct Universe {
@@ -26,17 +142,54 @@ pub struct Universe {
     camera: Camera,
     n_update: u32,
     last_update: u32,
+    // time of the last frame, in seconds, to pick the triangles where they were drawn
+    time: f32,
+    // the triangles sent to the gpu, kept for the picking and the export.
+    // Each part has its own buffers, so that it can be replaced without the others:
+    // the imported models, then the world or the blocks of the sculpture
+    parts: Vec<Mesh>,
+    // the imported models, shaded, with indices that start at 0
+    model_points: Vec<f32>,
+    model_indices: Vec<u16>,
+    // the first triangle of each imported model
+    model_starts: Vec<u32>,
+    // render the distance functions directly instead of the triangles
+    sdf_rendering: bool,
+    // the next worlds, generated a little at each frame
+    generation: Scheduler<WorldGeneration>,
+    // the octree of the world is generated by web workers, see `generate_world_part`
+    workers: bool,
+    // the workers must start a new world
+    world_requested: bool,
+    // number of the last world asked to the workers, sent back with its parts
+    // so that the parts of an older world are left out
+    world_request_id: u32,
+    // the parts of the next world received from the workers
+    world_parts: Option<WorldParts>,
+    // the octree of the world, kept to sculpt it
+    octree: Option<Octree>,
+    // the octree of the world, built a little at each update when it was not kept
+    octree_build: Scheduler<OctreeBuild>,
+    // the edits waiting for the octree to be built
+    queued_edits: Vec<Edit>,
+    // the octree being edited, created by the first edit.
+    // The world is not generated again after that
+    sculpture: Option<Sculpture>,
+    // the name of the generated world, see `scene_generators`
+    scene: String,
 }
  
 #[wasm_bindgen]
 impl Universe {
     #[wasm_bindgen(constructor)]
-    pub fn new(gl: GL, trans_location: WebGlUniformLocation, time_location: WebGlUniformLocation, t: u32) -> Self {
+    pub fn new(gl: GL, program: WebGlProgram, t: u32) -> Self {
         let camera = Camera {x:2.0, y:-2.0, z:0.0, angle:1.80};
-        let engine = Engine {gl, trans_location, time_location, n_indices: 0i32};
-        Self {engine, camera, n_update: 0, last_update: t}
+        let engine = Engine::new(gl, program);
+        let models = Mesh::new(Vec::new(), Vec::new());
+        Self {engine, camera, n_update: 0, last_update: t, time: 0.0, parts: vec![models], model_points: Vec::new(), model_indices: Vec::new(), model_starts: Vec::new(), sdf_rendering: false, generation: Scheduler::new(), workers: false, world_requested: false, world_request_id: 0, world_parts: None, octree: None, octree_build: Scheduler::new(), queued_edits: Vec::new(), sculpture: None, scene: OCTREE_SCENE.to_string()}
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn update(&mut self, t: u32, left: bool, right: bool, down: bool, up: bool, space: bool, shift: bool) {
 
         let dt = (t - self.last_update) as f32 / 1000.0;
@@ -50,16 +203,28 @@ impl Universe {
         if space {self.camera.up(2.0 * dt);};
         if shift {self.camera.up(-2.0*dt);};
 
-        if self.n_update % 3000 == 0 {
+        let idle = self.generation.is_empty() && self.world_parts.is_none() && self.octree_build.is_empty();
+        if self.n_update % 3000 == 0 && idle && self.sculpture.is_none() {
             // update landscape
-            let mut points   = Vec::with_capacity(10000000);
-            let mut indices = Vec::with_capacity(10000000);
-            //geometry::test_sphere(&mut points, &mut indices);
-            geometry::test_octree_shape(&mut points, &mut indices);
-            // geometry::rand_surface(&mut points, &mut indices);
-            geometry::shade(&mut points, &mut indices);
+            self.generate_world();
+        }
+
+        // the previous world is drawn until the new one is complete
+        match self.generation.run(GENERATION_BUDGET).pop().filter(|_| self.sculpture.is_none()) {
+            Some(Ok(world)) => {
+                self.octree = world.octree;
+                self.parts.truncate(WORLD_PART);
+                self.engine.truncate_meshes(WORLD_PART);
+                self.set_part(WORLD_PART, world.mesh);
+            }
+            Some(Err(e)) => log!("cannot generate the world: {}", e),
+            None => (),
+        }
 
-            self.engine.update_triangles(points, indices);
+        // the first edits wait for the octree of the world
+        if let Some(octree) = self.octree_build.run(GENERATION_BUDGET).pop() {
+            self.sculpture = Some(Sculpture::new(octree, SCULPT_BLOCK_DEPTH));
+            self.apply_queued_edits();
         }
 
         self.n_update += 1;
@@ -72,8 +237,311 @@ impl Universe {
 
     }
 
+    /// Generate the octree of the world in web workers instead of during `update`.
+    /// If the workers are stopped while a world is generated, it is generated by `update`
+    pub fn set_workers(&mut self, enabled: bool) {
+        self.workers = enabled;
+        if !enabled && self.world_parts.take().is_some() {
+            self.world_requested = false;
+            self.generate_world();
+        }
+    }
+
+    /// The number of the world, once when the workers must generate a new one:
+    /// each part from 0 to `world_parts()` is given to `generate_world_part`,
+    /// and sent back to `receive_world_part` with this number
+    pub fn take_world_request(&mut self) -> Option<u32> {
+        std::mem::take(&mut self.world_requested).then_some(self.world_request_id)
+    }
+
+    /// true if the parts of the world `request` are still waited for
+    pub fn is_world_request_pending(&self, request: u32) -> bool {
+        self.world_parts.is_some() && request == self.world_request_id
+    }
+
+    /// Add the part `part` of the world `request` made by `generate_world_part` in a worker.
+    /// The parts of a world that is not generated anymore are ignored.
+    /// When all the parts are there, the rest of the generation is done by `update`
+    pub fn receive_world_part(&mut self, request: u32, part: usize, points: Vec<f32>, indices: Vec<u16>, grid_points: Vec<i32>) -> Result<(), JsValue> {
+        if !self.is_world_request_pending(request) {
+            return Ok(())
+        }
+        let parts = self.world_parts.as_mut().expect("the world is pending");
+        let mesh = RegionMesh {
+            points,
+            indices,
+            grid_points: grid_points.chunks(3).map(|p| [p[0], p[1], p[2]]).collect(),
+        };
+        match parts.receive(part, &mesh) {
+            Ok(Some(generation)) => {
+                self.world_parts = None;
+                self.generation.push(generation);
+                Ok(())
+            }
+            Ok(None) => Ok(()),
+            Err(e) => {
+                self.world_parts = None;
+                Err(JsValue::from_str(&e))
+            }
+        }
+    }
+
+    /// find what is under the pixel (screen_x, screen_y) of the canvas,
+    /// with the triangles moved by the ondulation as in the last frame.
+    /// Return undefined if there is nothing
+    pub fn pick(&self, screen_x: f32, screen_y: f32) -> Option<Pick> {
+        let ray = self.camera.get_ray(
+            screen_x,
+            screen_y,
+            self.engine.width(),
+            self.engine.height());
+
+        let time = self.time;
+        let mut best: Option<(Hit, usize)> = None;
+        for (i, part) in self.parts.iter().enumerate() {
+            let margin = geometry::bounds::max_ondulation(&part.points);
+            let triangle = |id: u32| {
+                let t = &part.indices[3*id as usize..3*id as usize + 3];
+                [t[0], t[1], t[2]].map(|i| geometry::displaced_point(&part.points, i, time))
+            };
+            if let Some(hit) = part.bvh.cast_moving(&ray, margin, triangle) {
+                if best.as_ref().map_or(true, |(b, _)| hit.t < b.t) {
+                    best = Some((hit, i));
+                }
+            }
+        }
+        best.map(|(hit, part)| {
+            let (object, id) = if part == MODELS_PART {
+                // the triangles of a model follow the ones of the models imported before
+                let model = self.model_starts.partition_point(|&start| start <= hit.object) - 1;
+                (PickedObject::Model, model as u32)
+            } else if self.sculpture.is_some() {
+                (PickedObject::SculptureBlock, (part - WORLD_PART) as u32)
+            } else {
+                (PickedObject::World, 0)
+            };
+            Pick {
+                x: hit.point.x,
+                y: hit.point.y,
+                z: hit.point.z,
+                normal_x: hit.normal.x,
+                normal_y: hit.normal.y,
+                normal_z: hit.normal.z,
+                object,
+                id,
+            }
+        })
+    }
+
+    /// Add (or remove if `carve` is true) a ball of matter where the pixel hits the world.
+    /// Return false if there is nothing under the pixel
+    pub fn sculpt(&mut self, screen_x: f32, screen_y: f32, carve: bool) -> bool {
+        let hit = match self.pick(screen_x, screen_y) {
+            Some(hit) => hit,
+            None => return false,
+        };
+        // only the octree world can be sculpted
+        if self.sculpture.is_none() && self.scene != OCTREE_SCENE {
+            return false
+        }
+        let operation = if carve {Operation::Carve} else {Operation::Add(1)};
+        self.queued_edits.push(Edit::sphere(V3::new(hit.x, hit.y, hit.z), BRUSH_RADIUS, operation));
+
+        if self.sculpture.is_none() {
+            match self.octree.take() {
+                Some(octree) => self.sculpture = Some(Sculpture::new(octree, SCULPT_BLOCK_DEPTH)),
+                None => {
+                    // the world was generated by the workers: build its octree in `update`
+                    if self.octree_build.is_empty() {
+                        self.octree_build.push(OctreeBuild(geometry::test_octree_builder()));
+                    }
+                    return true
+                }
+            }
+        }
+        self.apply_queued_edits();
+        true
+    }
+
+    fn apply_queued_edits(&mut self) {
+        if let Some(sculpture) = &mut self.sculpture {
+            for edit in self.queued_edits.drain(..) {
+                sculpture.apply(edit);
+            }
+            self.upload_sculpture();
+        }
+    }
+
+    /// cancel the last edit of `sculpt`
+    pub fn undo(&mut self) {
+        if self.sculpture.as_mut().is_some_and(Sculpture::undo) {
+            self.upload_sculpture();
+        }
+    }
+
+    /// apply again the last edit cancelled by `undo`
+    pub fn redo(&mut self) {
+        if self.sculpture.as_mut().is_some_and(Sculpture::redo) {
+            self.upload_sculpture();
+        }
+    }
+
+    /// The sculpted octree in the binary format of `geometry::octree::serialize`
+    /// (the generated octree if nothing was sculpted yet).
+    /// Fail when the octree of the world is not built yet
+    pub fn save_world(&self) -> Result<Vec<u8>, JsValue> {
+        match (&self.sculpture, &self.octree) {
+            (Some(sculpture), _) => Ok(sculpture.octree().to_bytes()),
+            (None, Some(octree)) => Ok(octree.to_bytes()),
+            (None, None) => Err(JsValue::from_str("the octree of the world is not built yet")),
+        }
+    }
+
+    /// replace the world with an octree saved by `save_world`.
+    /// It is not generated again after that
+    pub fn load_world(&mut self, bytes: &[u8]) -> Result<(), JsValue> {
+        let octree = Octree::from_bytes(bytes).map_err(|e| JsValue::from_str(&e))?;
+        self.sculpture = Some(Sculpture::new(octree, SCULPT_BLOCK_DEPTH));
+        self.queued_edits.clear();
+        self.upload_sculpture();
+        Ok(())
+    }
+
+    /// Generate another world: "octree" (the default, the only one that can be sculpted),
+    /// "forest", "trees", "spheres" or "terrain".
+    /// The sculpture and the world being generated are dropped
+    pub fn set_scene(&mut self, name: &str) -> Result<(), JsValue> {
+        if scene_generators(name).is_none() {
+            return Err(JsValue::from_str(&format!("there is no scene {}", name)))
+        }
+        self.scene = name.to_string();
+        self.generation = Scheduler::new();
+        self.world_requested = false;
+        self.world_parts = None;
+        self.octree = None;
+        self.octree_build = Scheduler::new();
+        self.queued_edits.clear();
+        self.sculpture = None;
+        self.generate_world();
+        Ok(())
+    }
+
+    /// generate the world a little at each `update`, or ask the workers for the parts of the octree
+    fn generate_world(&mut self) {
+        if self.workers && self.scene == OCTREE_SCENE {
+            self.world_requested = true;
+            self.world_request_id = self.world_request_id.wrapping_add(1);
+            self.world_parts = Some(WorldParts::new(geometry::WORLD_PARTS));
+        }
+        else if let Some(generators) = scene_generators(&self.scene) {
+            self.generation.push(WorldGeneration::new(generators));
+        }
+    }
+
+    /// Triangulate the blocks changed by the edits, and send them to the gpu.
+    /// Each block is a part, the other blocks are not sent again
+    fn upload_sculpture(&mut self) {
+        let sculpture = match &mut self.sculpture {
+            Some(sculpture) => sculpture,
+            None => return,
+        };
+        let changed = match sculpture.update() {
+            Ok(changed) => changed,
+            Err(e) => {
+                log!("cannot triangulate the sculpture: {}", e);
+                return
+            }
+        };
+
+        let blocks: Vec<(usize, Mesh)> = changed.into_iter()
+            .map(|i| {
+                let (points, indices) = sculpture.block(i);
+                let mut points = points.to_vec();
+                geometry::shade(&mut points, indices);
+                (i, Mesh::new(points, indices.to_vec()))
+            })
+            .collect();
+        if self.parts.len() != WORLD_PART + sculpture.n_blocks() {
+            // the generated world is replaced by the blocks
+            self.parts.truncate(WORLD_PART);
+            self.engine.truncate_meshes(WORLD_PART);
+        }
+        for (i, mesh) in blocks {
+            self.set_part(WORLD_PART + i, mesh);
+        }
+    }
+
+    /// Send the `i`th part to the gpu, and keep it for the picking and the export.
+    /// The parts before it are created empty if they do not exist
+    fn set_part(&mut self, i: usize, mesh: Mesh) {
+        self.engine.update_mesh(i, &mesh.points, &mesh.indices, mesh.chunks.clone());
+        while self.parts.len() <= i {
+            self.parts.push(Mesh::new(Vec::new(), Vec::new()));
+        }
+        self.parts[i] = mesh;
+    }
+
+    /// Add a model to the world, from an obj, gltf or glb file.
+    /// The format is given by the extension of `name`
+    pub fn import(&mut self, name: &str, bytes: &[u8]) -> Result<(), JsValue> {
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        geometry::import::import(name, bytes, &mut points, &mut indices)
+            .map_err(|e| JsValue::from_str(&e))?;
+        geometry::shade(&mut points, &indices);
+
+        let start = (self.model_indices.len() / 3) as u32;
+        geometry::append(&mut self.model_points, &mut self.model_indices, &points, &indices)
+            .map_err(|e| JsValue::from_str(&format!("too many vertices in the imported models: {}", e)))?;
+        self.model_starts.push(start);
+        // only the models are sent again
+        self.set_part(MODELS_PART, Mesh::new(self.model_points.clone(), self.model_indices.clone()));
+        Ok(())
+    }
+
+    /// the triangles of the world in a file: `"obj"`, `"ply"`, `"ply-ascii"`, `"stl"` or `"glb"`
+    pub fn export(&self, format: &str) -> Result<Vec<u8>, JsValue> {
+        let format = export::Format::from_name(format)
+            .ok_or_else(|| JsValue::from_str(&format!("unknown format: {}", format)))?;
+        let mut points = Vec::new();
+        let mut indices = Vec::new();
+        for part in &self.parts {
+            geometry::append(&mut points, &mut indices, &part.points, &part.indices)
+                .map_err(|e| JsValue::from_str(&format!("the world is too big to export: {}", e)))?;
+        }
+        Ok(export::export(format, &points, &indices))
+    }
+
+    /// switch between the triangles and the ray marching of `geometry::test_sdf_scene`.
+    /// The shader is compiled the first time
+    pub fn set_sdf_rendering(&mut self, enabled: bool) {
+        if enabled && self.engine.ray_marcher.is_none() {
+            match RayMarcher::new(&self.engine.gl, &geometry::test_sdf_scene()) {
+                Ok(ray_marcher) => self.engine.ray_marcher = Some(ray_marcher),
+                Err(e) => {
+                    log!("cannot compile the ray marching shader: {}", e);
+                    return
+                }
+            }
+        }
+        self.sdf_rendering = enabled;
+    }
+
+    /// number of chunks and triangles drawn and culled during the last frame
+    pub fn culling_stats(&self) -> CullingStats {
+        self.engine.stats
+    }
+
     pub fn render(&mut self, t: u32){
         let time = (t as f32) * 0.001;
+        self.time = time;
+
+        if self.sdf_rendering {
+            self.engine.render_sdf(self.camera.get_inverse_transform(
+                                self.engine.width(),
+                                self.engine.height()));
+            return
+        }
 
         self.engine.render(self.camera.get_transform(
                                 self.engine.width(), 
@@ -0,0 +1,151 @@
+use web_sys::WebGlRenderingContext as GL;
+use web_sys::{WebGlBuffer, WebGlProgram, WebGlUniformLocation};
+
+use js_sys::Float32Array;
+
+use crate::geometry::sdf::Sdf;
+use crate::webgl::compile_program;
+
+// a triangle that covers the whole screen
+const VERTEX_SHADER: &str = "
+attribute vec2 position;
+varying vec2 v_screen;
+
+void main() {
+    v_screen = position;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+";
+
+// the `scene` function is inserted before this code
+const FRAGMENT_SHADER: &str = "
+const int MAX_STEPS = 128;
+const float MAX_DIST = 100.0;
+const float HIT_DIST = 0.001;
+
+vec3 normal(vec3 p) {
+    vec2 e = vec2(0.001, 0.0);
+    return normalize(vec3(
+        scene(p + e.xyy) - scene(p - e.xyy),
+        scene(p + e.yxy) - scene(p - e.yxy),
+        scene(p + e.yyx) - scene(p - e.yyx)));
+}
+
+// march toward the light: the closer the ray passes to an object,
+// the darker the shadow. k controls the hardness of the shadow
+float soft_shadow(vec3 p, vec3 light_dir, float k) {
+    float result = 1.0;
+    float t = 0.02;
+    for (int i = 0; i < 64; i++) {
+        float d = scene(p + light_dir*t);
+        if (d < HIT_DIST) {
+            return 0.0;
+        }
+        result = min(result, k*d/t);
+        t += d;
+        if (t > 20.0) {
+            break;
+        }
+    }
+    return clamp(result, 0.0, 1.0);
+}
+
+// compare the distance to the scene with the distance along the normal:
+// if the scene is closer, the point is in a hole
+float ambient_occlusion(vec3 p, vec3 n) {
+    float occlusion = 0.0;
+    float weight = 1.0;
+    for (int i = 1; i <= 5; i++) {
+        float h = 0.05*float(i);
+        occlusion += weight*(h - scene(p + n*h));
+        weight *= 0.7;
+    }
+    return clamp(1.0 - 3.0*occlusion, 0.0, 1.0);
+}
+
+void main() {
+    // unproject the pixel on the near and far planes
+    vec4 near = inverse_transform * vec4(v_screen, -1.0, 1.0);
+    vec4 far = inverse_transform * vec4(v_screen, 1.0, 1.0);
+    vec3 origin = near.xyz / near.w;
+    vec3 dir = normalize(far.xyz / far.w - origin);
+
+    float t = 0.0;
+    bool hit = false;
+    for (int i = 0; i < MAX_STEPS; i++) {
+        float d = scene(origin + dir*t);
+        if (d < HIT_DIST*(1.0 + t)) {
+            hit = true;
+            break;
+        }
+        t += d;
+        if (t > MAX_DIST) {
+            break;
+        }
+    }
+
+    if (!hit) {
+        gl_FragColor = vec4(0.0);
+        return;
+    }
+
+    vec3 p = origin + dir*t;
+    vec3 n = normal(p);
+    // same light as the triangles
+    vec3 light_dir = normalize(vec3(0.3, 0.3, 0.3));
+
+    float diffuse = max(dot(n, light_dir), 0.0) * soft_shadow(p + n*0.01, light_dir, 8.0);
+    float ambient = 0.3 * ambient_occlusion(p, n);
+    gl_FragColor = vec4(vec3(0.7, 0.4, 0.3) * (ambient + diffuse), 1.0);
+}
+";
+
+/// Render a `Sdf` with sphere tracing, in a fragment shader.
+/// Each pixel marches along its own ray, so there is no mesh at all
+pub struct RayMarcher {
+    program: WebGlProgram,
+    buffer: WebGlBuffer,
+    position_location: u32,
+    inverse_transform_location: Option<WebGlUniformLocation>,
+}
+
+impl RayMarcher {
+    /// compile the shader for this scene
+    pub fn new(gl: &GL, scene: &Sdf) -> Result<Self, String> {
+        let fragment_code = format!(
+            "precision highp float;\nuniform mat4 inverse_transform;\nvarying vec2 v_screen;\n\n{}\n{}",
+            scene.to_glsl()?,
+            FRAGMENT_SHADER);
+        let program = compile_program(gl, VERTEX_SHADER, &fragment_code)?;
+
+        let buffer = gl.create_buffer().ok_or("cannot create buffer")?;
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&buffer));
+        let triangle: [f32; 6] = [-1.0, -1.0, 3.0, -1.0, -1.0, 3.0];
+        unsafe {
+            gl.buffer_data_with_array_buffer_view(
+                GL::ARRAY_BUFFER,
+                &Float32Array::view(&triangle),
+                GL::STATIC_DRAW);
+        }
+
+        let position_location = gl.get_attrib_location(&program, "position") as u32;
+        let inverse_transform_location = gl.get_uniform_location(&program, "inverse_transform");
+        Ok(RayMarcher {program, buffer, position_location, inverse_transform_location})
+    }
+
+    /// `inverse_transform` is the inverse of the camera matrix, used to compute the rays
+    pub fn render(&self, gl: &GL, inverse_transform: [f32; 16]) {
+        gl.use_program(Some(&self.program));
+        gl.bind_buffer(GL::ARRAY_BUFFER, Some(&self.buffer));
+        gl.vertex_attrib_pointer_with_i32(self.position_location, 2, GL::FLOAT, false, 0, 0);
+        gl.enable_vertex_attrib_array(self.position_location);
+
+        gl.uniform_matrix4fv_with_f32_array(
+            self.inverse_transform_location.as_ref(),
+            false,
+            &inverse_transform);
+
+        gl.clear(GL::COLOR_BUFFER_BIT | GL::DEPTH_BUFFER_BIT);
+        gl.draw_arrays(GL::TRIANGLES, 0, 3);
+    }
+}
@@ -1,20 +1,147 @@
+
 use web_sys::WebGlRenderingContext as GL;
-use web_sys::WebGlUniformLocation;
+use web_sys::{WebGlUniformLocation, WebGlProgram, WebGlShader, WebGlBuffer};
 
 use js_sys::*;
+use wasm_bindgen::prelude::*;
+
+use crate::geometry::bounds::Chunk;
+use crate::camera::frustum::Frustum;
+use crate::raymarch::RayMarcher;
+
+/// What happened during the last frame: how many chunks were drawn or skipped
+#[wasm_bindgen]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct CullingStats {
+    pub drawn_chunks: u32,
+    pub culled_chunks: u32,
+    pub drawn_triangles: u32,
+    pub culled_triangles: u32,
+}
+
+/* The POINT buffer has 12 elements by vertex:
+ * x y z      |      r g b      |      dx dy dz     | frequency  | phase | other
+ * position          color       ondulation vector    other params of vertex
+ * Each attribute of the shader is given with its size, in the order of the buffer
+ */
+const ATTRIBUTES: [(&str, i32); 5] = [
+    ("coordinates", 3),
+    ("color", 3),
+    ("ondulation_vec", 3),
+    ("frequency", 1),
+    ("phase", 1),
+];
+const SIZE_VERTEX: i32 = 12;
+const FLOAT_SIZE: i32 = std::mem::size_of::<f32>() as i32;
+
+fn compile_shader(gl: &GL, shader_type: u32, code: &str) -> Result<WebGlShader, String> {
+    let shader = gl.create_shader(shader_type).ok_or("cannot create shader")?;
+    gl.shader_source(&shader, code);
+    gl.compile_shader(&shader);
+
+    if gl.get_shader_parameter(&shader, GL::COMPILE_STATUS).as_bool().unwrap_or(false) {
+        Ok(shader)
+    }
+    else {
+        Err(gl.get_shader_info_log(&shader).unwrap_or_default())
+    }
+}
+
+/// compile and link a shader program
+pub fn compile_program(gl: &GL, vertex_code: &str, fragment_code: &str) -> Result<WebGlProgram, String> {
+    let vertex_shader = compile_shader(gl, GL::VERTEX_SHADER, vertex_code)?;
+    let fragment_shader = compile_shader(gl, GL::FRAGMENT_SHADER, fragment_code)?;
+
+    let program = gl.create_program().ok_or("cannot create program")?;
+    gl.attach_shader(&program, &vertex_shader);
+    gl.attach_shader(&program, &fragment_shader);
+    gl.link_program(&program);
+
+    if gl.get_program_parameter(&program, GL::LINK_STATUS).as_bool().unwrap_or(false) {
+        Ok(program)
+    }
+    else {
+        Err(gl.get_program_info_log(&program).unwrap_or_default())
+    }
+}
+
+/// triangles with their own buffers on the gpu, drawn by chunks
+struct GpuMesh {
+    point_buffer: WebGlBuffer,
+    index_buffer: WebGlBuffer,
+    chunks: Vec<Chunk>,
+}
 
 pub struct Engine {
     pub gl: GL,
-    pub trans_location: WebGlUniformLocation,
-    pub time_location: WebGlUniformLocation,
-    pub n_indices: i32, 
+    program: WebGlProgram,
+    trans_location: Option<WebGlUniformLocation>,
+    time_location: Option<WebGlUniformLocation>,
+    // location, size and offset of the attributes used by the shader
+    attributes: Vec<(u32, i32, i32)>,
+    // drawn one after the other, each can be replaced without sending the others again
+    meshes: Vec<GpuMesh>,
+    pub stats: CullingStats,
+    /// shader used to render distance functions instead of triangles
+    pub ray_marcher: Option<RayMarcher>,
 }
 
 impl Engine {
-    pub fn update_triangles(&mut self, point_data: Vec<f32>, index_data: Vec<u16>) {
+    /// `program` is the shader used to draw the triangles
+    pub fn new(gl: GL, program: WebGlProgram) -> Self {
+        let trans_location = gl.get_uniform_location(&program, "projection");
+        let time_location = gl.get_uniform_location(&program, "time");
+        let mut attributes = Vec::new();
+        let mut offset = 0;
+        for &(name, size) in &ATTRIBUTES {
+            let loc = gl.get_attrib_location(&program, name);
+            if loc >= 0 {
+                attributes.push((loc as u32, size, offset));
+            }
+            offset += size;
+        }
+
+        Engine {
+            gl,
+            program,
+            trans_location,
+            time_location,
+            attributes,
+            meshes: Vec::new(),
+            stats: CullingStats::default(),
+            ray_marcher: None,
+        }
+    }
+
+    /// tell the triangle shader where each attribute is in the point buffer of a mesh
+    fn bind_mesh(&self, mesh: &GpuMesh) {
+        self.gl.bind_buffer(GL::ARRAY_BUFFER, Some(&mesh.point_buffer));
+        self.gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(&mesh.index_buffer));
+
+        for &(loc, size, offset) in &self.attributes {
+            self.gl.vertex_attrib_pointer_with_i32(
+                loc, size, GL::FLOAT, false,
+                SIZE_VERTEX * FLOAT_SIZE,
+                offset      * FLOAT_SIZE,
+            );
+            self.gl.enable_vertex_attrib_array(loc);
+        }
+    }
+
+    /// Send the triangles of the `i`th mesh to the gpu.
+    /// The meshes before it are created empty if they do not exist
+    pub fn update_mesh(&mut self, i: usize, point_data: &[f32], index_data: &[u16], chunks: Vec<Chunk>) {
+        while self.meshes.len() <= i {
+            let point_buffer = self.gl.create_buffer().expect("cannot create point buffer");
+            let index_buffer = self.gl.create_buffer().expect("cannot create index buffer");
+            self.meshes.push(GpuMesh {point_buffer, index_buffer, chunks: Vec::new()});
+        }
+        let mesh = &mut self.meshes[i];
+        self.gl.bind_buffer(GL::ARRAY_BUFFER, Some(&mesh.point_buffer));
+        self.gl.bind_buffer(GL::ELEMENT_ARRAY_BUFFER, Some(&mesh.index_buffer));
         unsafe {
-            let vert_array = Float32Array::view(&point_data[..]);
-            let index_array = Uint16Array::view(&index_data[..]);
+            let vert_array = Float32Array::view(point_data);
+            let index_array = Uint16Array::view(index_data);
 
             self.gl.buffer_data_with_array_buffer_view(
                 GL::ARRAY_BUFFER,
@@ -27,25 +154,105 @@ impl Engine {
                 GL::DYNAMIC_DRAW);
         }
 
-        self.n_indices = index_data.len() as i32;
+        mesh.chunks = chunks;
+    }
+
+    /// forget the meshes after the first `n`
+    pub fn truncate_meshes(&mut self, n: usize) {
+        for mesh in self.meshes.drain(n.min(self.meshes.len())..) {
+            self.gl.delete_buffer(Some(&mesh.point_buffer));
+            self.gl.delete_buffer(Some(&mesh.index_buffer));
+        }
     }
 
-    pub fn render(&self, transform: [f32; 16], time: f32) {
+    pub fn render(&mut self, transform: [f32; 16], time: f32) {
+        // the ray marcher may have used another program
+        self.gl.use_program(Some(&self.program));
+
         self.gl.uniform_matrix4fv_with_f32_array(
-            Some(&self.trans_location),
+            self.trans_location.as_ref(),
             false,
             &transform,
         );
 
         self.gl.uniform1f(
-            Some(&self.time_location),
+            self.time_location.as_ref(),
             time
         );
 
         self.gl.clear(GL::COLOR_BUFFER_BIT);
-        self.gl.draw_elements_with_i32(GL::TRIANGLES, self.n_indices, GL::UNSIGNED_SHORT, 0)
+
+        // only draw the chunks that the camera can see
+        let frustum = Frustum::from_matrix(transform);
+        let mut stats = CullingStats::default();
+        for mesh in &self.meshes {
+            if !mesh.chunks.is_empty() {
+                self.bind_mesh(mesh);
+            }
+            self.draw_chunks(mesh, &frustum, &mut stats);
+        }
+        self.stats = stats;
     }
+
+    fn draw_chunks(&self, mesh: &GpuMesh, frustum: &Frustum, stats: &mut CullingStats) {
+        for chunk in &mesh.chunks {
+            let n_triangles = (chunk.n_indices/3) as u32;
+            // the sphere test is cheaper, so do it first
+            if frustum.intersects_sphere(&chunk.sphere) && frustum.intersects_aabb(&chunk.aabb) {
+                self.gl.draw_elements_with_i32(
+                    GL::TRIANGLES,
+                    chunk.n_indices as i32,
+                    GL::UNSIGNED_SHORT,
+                    // offset is in bytes
+                    (chunk.first_index * std::mem::size_of::<u16>()) as i32,
+                );
+                stats.drawn_chunks += 1;
+                stats.drawn_triangles += n_triangles;
+            }
+            else {
+                stats.culled_chunks += 1;
+                stats.culled_triangles += n_triangles;
+            }
+        }
+    }
+
+    /// render the scene of the ray marcher, if there is one
+    pub fn render_sdf(&self, inverse_transform: [f32; 16]) {
+        if let Some(ray_marcher) = &self.ray_marcher {
+            ray_marcher.render(&self.gl, inverse_transform);
+        }
+    }
+
     pub fn width(&self) -> u32 {self.gl.drawing_buffer_width() as u32}
     pub fn height(&self) -> u32 {self.gl.drawing_buffer_height() as u32}
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{ATTRIBUTES, SIZE_VERTEX};
+    use crate::geometry::{self, V3};
+
+    // position of an attribute of the shader in a vertex
+    fn offset(name: &str) -> usize {
+        let i = ATTRIBUTES.iter().position(|&(n, _)| n == name).unwrap();
+        ATTRIBUTES[..i].iter().map(|&(_, size)| size as usize).sum()
+    }
+
+    #[test]
+    fn attributes_follow_the_vertex_layout() {
+        assert_eq!(offset("ondulation_vec"), geometry::ONDULATION);
+        assert_eq!(offset("frequency"), geometry::FREQUENCY);
+        assert_eq!(offset("phase"), geometry::PHASE);
+        assert!(ATTRIBUTES.iter().map(|&(_, size)| size).sum::<i32>() <= SIZE_VERTEX);
+
+        // the point moved as in the vertex shader, with the values given to its attributes
+        let mut points = vec![0.0; geometry::SIZE_VERTEX];
+        points[..3].copy_from_slice(&[1.0, 2.0, 3.0]);
+        points[offset("ondulation_vec")..offset("ondulation_vec")+3].copy_from_slice(&[0.0, 0.0, 1.0]);
+        points[offset("frequency")] = 2.0;
+        points[offset("phase")] = 0.5;
+        let time = 0.3;
+        let shader = V3::new(1.0, 2.0, 3.0) + V3::new(0.0, 0.0, 1.0).scale((time*2.0 + 0.5f32).cos());
+        assert!((geometry::displaced_point(&points, 0, time) - shader).norm() < 1e-6);
+    }
+}